@@ -1,6 +1,11 @@
+use chrono::{DateTime, NaiveDate, Utc};
 use clap::{Args, Parser, Subcommand};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use gisst::models::ObjectRole;
+use gisst::task::TaskState;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -46,14 +51,57 @@ pub enum GISSTCliError {
     InsertFileError(#[from] gisst::error::InsertFile),
     #[error("invalid role index {0}")]
     InvalidRoleIndex(std::num::TryFromIntError),
-    #[error("file size int conversion")]
-    FileSize(std::num::TryFromIntError),
     #[error("search index error")]
     SearchIndex(#[from] gisst::error::SearchIndex),
     #[error("Integer too big")]
     IntegerTooBig(#[from] std::num::TryFromIntError),
     #[error("No defined core {0}:{1}:{2}")]
     CoreNotFound(String, String, String),
+    #[error("screenshot error")]
+    Screenshot(#[from] gisst::error::Screenshot),
+    #[error("replay file error")]
+    Replay(#[from] gisst::error::Replay),
+    #[error("replay checkpoint error")]
+    ReplayStates(#[from] gisst::error::ReplayStates),
+    #[error("disk entry index error")]
+    DiskIndex(#[from] gisst::error::DiskIndex),
+    #[error("subobject error")]
+    Subobject(#[from] gisst::error::Subobject),
+    #[error("disk build error")]
+    DiskBuild(#[from] gisst::error::DiskBuild),
+    #[error("disk edit error")]
+    DiskEdit(#[from] gisst::error::DiskEdit),
+    #[error("patch error")]
+    PatchInstance(#[from] gisst::error::PatchInstance),
+    #[error("invalid uuid")]
+    Uuid(#[from] uuid::Error),
+    #[error("no {0} record with id {1}")]
+    MissingRecord(ListKind, String),
+    #[error("--{0} does not apply to {1}")]
+    UnsupportedFilter(&'static str, String),
+    #[error("sidecar error: {0}")]
+    Sidecar(String),
+    #[error("{0} recordings failed to import")]
+    ImportFailed(usize),
+    #[error("delete error")]
+    Delete(#[from] gisst::error::Delete),
+    #[error("manifest parse error")]
+    ManifestParse(#[from] toml::de::Error),
+    #[error("manifest error: {0}")]
+    Manifest(String),
+    #[error("http request error")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("server responded with {status}: {body}")]
+    Server {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    #[error("tus upload was not given a location")]
+    TusLocation,
+    #[error("tus upload offset mismatch, expected {expected} but server has {actual}")]
+    TusOffset { expected: u64, actual: u64 },
+    #[error("{0} can't be run with --remote")]
+    RemoteUnsupported(&'static str),
 }
 
 #[derive(Debug, Parser)]
@@ -65,15 +113,23 @@ pub struct GISSTCli {
     #[command(flatten)]
     pub verbose: Verbosity<InfoLevel>,
 
-    /// `GISST_CONFIG_PATH` environment variable must be set
-    #[clap(env)]
-    pub gisst_config_path: String,
-    /// `MEILI_URL` environment variable must be set
-    #[clap(env)]
-    pub meili_url: String,
-    /// `MEILI_API_KEY` environment variable must be set
-    #[clap(env)]
-    pub meili_api_key: String,
+    /// Base URL of a GISST server to work through instead of the database and storage.
+    /// Supports creating objects, works, environments, and instances, linking objects, and add-work-instance.
+    #[arg(long, env = "GISST_REMOTE", requires = "token")]
+    pub remote: Option<String>,
+    /// API token for --remote, made on the server with POST /tokens/create
+    #[arg(long, env = "GISST_API_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// `GISST_CONFIG_PATH` environment variable must be set unless using --remote
+    #[clap(env, required_unless_present = "remote")]
+    pub gisst_config_path: Option<String>,
+    /// `MEILI_URL` environment variable must be set unless using --remote
+    #[clap(env, required_unless_present = "remote")]
+    pub meili_url: Option<String>,
+    /// `MEILI_API_KEY` environment variable must be set unless using --remote
+    #[clap(env, required_unless_present = "remote")]
+    pub meili_api_key: Option<String>,
 }
 
 #[derive(Debug, Args)]
@@ -114,6 +170,298 @@ pub enum BaseSubcommand<C: clap::FromArgMatches + clap::Args> {
     Create(C),
 }
 
+#[derive(Debug, Subcommand)]
+pub enum ObjectSubcommand {
+    /// Create record(s)
+    Create(CreateObject),
+    /// Store a file inside a disk image object as an object of its own
+    Promote {
+        /// The disk image object
+        object: Uuid,
+        /// Path inside the image, starting with its partition (e.g. `part0/GAMES/KEEN.EXE`)
+        path: String,
+        /// Description for the new object, defaults to the path and image name
+        #[arg(long)]
+        description: Option<String>,
+        /// Folder depth to use for the new file
+        #[arg(short, long, default_value_t = 4)]
+        depth: u8,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ScreenshotSubcommand {
+    /// Create record(s)
+    Create(CreateScreenshot),
+    /// Move screenshots still stored in the database out to storage, adding thumbnails
+    MigrateStorage {
+        /// Folder depth to use for the new files
+        #[arg(short, long, default_value_t = 4)]
+        depth: u8,
+        /// Number of screenshots to move per transaction
+        #[arg(long, default_value_t = 100)]
+        batch_size: i64,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReplaySubcommand {
+    /// Create record(s)
+    Create(CreateReplay),
+    /// Create a state for each checkpoint saved in a replay file
+    ExtractCheckpoints {
+        replay: Uuid,
+        /// Folder depth to use for the new state files
+        #[arg(short, long, default_value_t = 4)]
+        depth: u8,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct BuildDiskArgs {
+    /// Directory or archive holding the files to put on the disk
+    pub source: PathBuf,
+    /// One of floppy-720k, floppy-1440k, or hdd-<N>m for an N megabyte hard disk
+    #[arg(short, long, default_value = "floppy-1440k")]
+    pub geometry: String,
+    /// Volume label, up to 11 characters
+    #[arg(long)]
+    pub label: Option<String>,
+    /// File name for the image, defaults to the source's name with `.img`
+    #[arg(long)]
+    pub name: Option<String>,
+    #[arg(long)]
+    pub description: Option<String>,
+    /// Link the image to this instance as content
+    #[arg(long)]
+    pub link: Option<Uuid>,
+    /// Content role index for the instance link; for v86, 0=fda, 1=fdb, 2=hda, 3=hdb
+    #[arg(long, default_value_t = 0)]
+    pub role_index: u16,
+    /// Folder depth to use for the new file
+    #[arg(short, long, default_value_t = 4)]
+    pub depth: u8,
+}
+
+#[derive(Debug, Args)]
+pub struct ApplyArgs {
+    /// The manifest; paths in it are relative to its folder
+    pub manifest: PathBuf,
+    /// Only show what would change
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Folder depth to use for new files
+    #[arg(short, long, default_value_t = 4)]
+    pub depth: u8,
+}
+
+/// Record kinds that `list` and `show` can print
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListKind {
+    Work,
+    Instance,
+    Environment,
+    Core,
+    Object,
+    State,
+    Replay,
+    Save,
+    Task,
+    Creator,
+}
+
+impl FromStr for ListKind {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // Take "works" as well as "work"
+        match value.strip_suffix('s').unwrap_or(value) {
+            "work" => Ok(ListKind::Work),
+            "instance" => Ok(ListKind::Instance),
+            "environment" => Ok(ListKind::Environment),
+            "core" => Ok(ListKind::Core),
+            "object" => Ok(ListKind::Object),
+            "state" => Ok(ListKind::State),
+            "replay" => Ok(ListKind::Replay),
+            "save" => Ok(ListKind::Save),
+            "task" => Ok(ListKind::Task),
+            "creator" => Ok(ListKind::Creator),
+            _ => Err(
+                "record kind must be one of works, instances, environments, cores, objects, states, replays, saves, tasks, or creators",
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ListKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ListKind::Work => "work",
+            ListKind::Instance => "instance",
+            ListKind::Environment => "environment",
+            ListKind::Core => "core",
+            ListKind::Object => "object",
+            ListKind::State => "state",
+            ListKind::Replay => "replay",
+            ListKind::Save => "save",
+            ListKind::Task => "task",
+            ListKind::Creator => "creator",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err("format must be one of table, json, or csv"),
+        }
+    }
+}
+
+/// Filters for `list`. Giving a filter the listed kind has no field for,
+/// like an object's platform, is an error.
+#[derive(Debug, Args)]
+pub struct ListFilter {
+    /// Only records for this platform
+    #[arg(long)]
+    pub platform: Option<String>,
+    /// Only records made by this creator; for instances, the creator of their work
+    #[arg(long)]
+    pub creator: Option<Uuid>,
+    /// Only records created on or after this date (YYYY-MM-DD or RFC 3339)
+    #[arg(long, value_parser = parse_date)]
+    pub since: Option<DateTime<Utc>>,
+    /// Only records created before this date (YYYY-MM-DD or RFC 3339)
+    #[arg(long, value_parser = parse_date)]
+    pub until: Option<DateTime<Utc>>,
+    /// Only this instance, or its objects, states, saves, and replays
+    #[arg(long)]
+    pub instance: Option<Uuid>,
+}
+
+fn parse_date(arg: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(arg, "%Y-%m-%d") {
+        return Ok(date.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+    DateTime::parse_from_rfc3339(arg)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|e| format!("expected YYYY-MM-DD or an RFC 3339 time, got {arg}: {e}"))
+}
+
+#[derive(Debug, Args)]
+pub struct ListArgs {
+    /// One of works, instances, environments, cores, objects, states, replays, saves, tasks, or creators
+    pub kind: ListKind,
+    #[command(flatten)]
+    pub filter: ListFilter,
+    /// Only tasks in this state: idle, active, error, cancel, or done
+    #[arg(long)]
+    pub task_state: Option<TaskState>,
+    /// Only tasks of this type, e.g. v86_clone
+    #[arg(long)]
+    pub task_type: Option<String>,
+    /// Print at most this many records
+    #[arg(long)]
+    pub limit: Option<usize>,
+    /// One of table, json, or csv
+    #[arg(short, long, default_value = "table")]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Args)]
+pub struct ShowArgs {
+    /// One of work, instance, environment, core, object, state, replay, save, task, or creator
+    pub kind: ListKind,
+    /// The record's UUID, or a core's name
+    pub id: String,
+    /// One of table, json, or csv. CSV has only the record's own fields, not related records.
+    #[arg(short, long, default_value = "table")]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportRecordingsArgs {
+    /// Folder holding recordings, each with a sidecar `<file>.json` or `<file>.toml`
+    pub dir: PathBuf,
+    /// Instance for recordings whose sidecars don't name one
+    #[arg(long)]
+    pub instance: Option<Uuid>,
+    /// Creator for recordings whose sidecars don't name one
+    #[arg(long)]
+    pub creator: Option<Uuid>,
+    /// Folder depth to use for new files
+    #[arg(short, long, default_value_t = 4)]
+    pub depth: u8,
+}
+
+#[derive(Debug, Args)]
+pub struct DeleteArgs {
+    /// One of work, environment, instance, object, state, save, or replay
+    pub kind: gisst::danger::RecordKind,
+    pub id: Uuid,
+    /// Also remove the records and links that depend on this one
+    #[arg(long)]
+    pub cascade: bool,
+    /// Only show what would be removed
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct UnlinkArgs {
+    pub object: Uuid,
+    pub instance: Uuid,
+    /// Also remove the instance's states, saves, and replays
+    #[arg(long)]
+    pub cascade: bool,
+    /// Only show what would be removed
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct EditDiskArgs {
+    /// The instance to derive a new instance and work from
+    pub instance: Uuid,
+    /// Content role index of the FAT disk image to edit
+    #[arg(long, default_value_t = 0)]
+    pub role_index: u16,
+    /// Write a local file into the image as `partN/PATH=LOCAL_FILE`, replacing any file already there
+    #[arg(long = "write", value_parser = parse_disk_write)]
+    pub writes: Vec<(PathBuf, PathBuf)>,
+    /// Delete a file or directory from the image as `partN/PATH`
+    #[arg(long = "delete")]
+    pub deletes: Vec<PathBuf>,
+    /// Name for the new work, defaults to the original's name plus "(edited)"
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Version for the new work, defaults to the original's version
+    #[arg(long)]
+    pub version: Option<String>,
+    /// Folder depth to use for the new file
+    #[arg(short, long, default_value_t = 4)]
+    pub depth: u8,
+}
+
+fn parse_disk_write(arg: &str) -> Result<(PathBuf, PathBuf), String> {
+    arg.split_once('=')
+        .map(|(dest, src)| (PathBuf::from(dest), PathBuf::from(src)))
+        .ok_or_else(|| format!("expected partN/PATH=LOCAL_FILE, got {arg}"))
+}
+
 #[derive(Debug, Args)]
 pub struct SetUserRole {
     /// Creator ID. Obtain from the database or creator page
@@ -174,6 +522,12 @@ pub enum Commands {
     InitIndices,
     /// Dump all works, states, saves, etc into search indexer
     Reindex,
+    /// Record and index the files inside every disk image object
+    IndexDiskEntries {
+        /// Recompute entries for images that already have them
+        #[arg(long, default_value_t = false)]
+        force: bool,
+    },
 
     /// Adds a work and corresponding instance with deps and content
     /// files in the given order.  This is a shorthand for adding an
@@ -194,7 +548,7 @@ pub enum Commands {
     },
 
     /// Manage object records and files
-    Object(GISSTCommand<BaseSubcommand<CreateObject>>),
+    Object(GISSTCommand<ObjectSubcommand>),
     /// Manage instance records
     Instance(GISSTCommand<BaseSubcommand<CreateInstance>>),
     /// Manage work records
@@ -208,9 +562,37 @@ pub enum Commands {
     /// Manage state records
     State(GISSTCommand<BaseSubcommand<CreateState>>),
     /// Manage replay records
-    Replay(GISSTCommand<BaseSubcommand<CreateReplay>>),
+    Replay(GISSTCommand<ReplaySubcommand>),
     /// Manage screenshot records
-    Screenshot(GISSTCommand<BaseSubcommand<CreateScreenshot>>),
+    Screenshot(GISSTCommand<ScreenshotSubcommand>),
+
+    /// Build a FAT disk image object from a directory or a zip or 7z archive
+    BuildDisk(BuildDiskArgs),
+
+    /// Create or update a work, environment, and instance with its objects and states from a TOML manifest.
+    /// Records that already exist, found by name and file hash, are reused.
+    Apply(ApplyArgs),
+
+    /// Import the states, saves, and replays in a folder, described by sidecar files beside them.
+    /// Recordings already imported are skipped, so an interrupted import can be rerun.
+    ImportRecordings(ImportRecordingsArgs),
+
+    /// List records, optionally filtered, as a table, JSON, or CSV
+    List(ListArgs),
+
+    /// Show one record along with the records related to it
+    Show(ShowArgs),
+
+    /// Delete a record along with the states, saves, links, and files that depend on it.
+    /// Refuses if anything besides the record would go, unless --cascade is given.
+    Delete(DeleteArgs),
+
+    /// Unlink an object from an instance. The instance's states, saves, and replays
+    /// count as dependents and are removed with --cascade.
+    Unlink(UnlinkArgs),
+
+    /// Derive a new instance by writing or deleting files inside one of its FAT disk images
+    EditDisk(EditDiskArgs),
 
     /// Clone a v86 machine and state into a new instance
     CloneV86 {
@@ -224,7 +606,8 @@ pub enum Commands {
     AddPatch {
         /// The instance to clone and patch along with its work
         instance: Uuid,
-        /// A JSON file containing a JSON string that parses as `PatchData` for the new work
+        /// A JSON file containing a JSON string that parses as `PatchData` for the new work.
+        /// Its files can be patched content or IPS, UPS, or BPS patches to apply to the content.
         data: String,
         #[arg(default_value_t = 4)]
         depth: u8,
@@ -434,6 +817,10 @@ pub struct CreateCreator {
 
 #[derive(Debug, Args)]
 pub struct CreateScreenshot {
+    /// Folder depth to use for input file to path based off of characters in assigned UUID
+    #[arg(short, long, default_value_t = 4)]
+    pub depth: u8,
+
     /// (DEBUG) Force the use of specific screenshot UUID
     #[arg(long = "force-uuid")]
     pub force_uuid: Option<Uuid>,
@@ -446,5 +833,6 @@ pub struct CreateScreenshot {
 pub struct PatchData {
     pub version: String,
     pub name: String,
+    /// Replacement for each content index, relative to the JSON file; empty to keep the original
     pub files: Vec<String>,
 }
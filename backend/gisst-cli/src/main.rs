@@ -2,19 +2,26 @@
 
 mod args;
 mod cliconfig;
+mod manifest;
+mod query;
+mod recordings;
+mod remote;
 
 use crate::cliconfig::CLIConfig;
 use anyhow::Result;
 use args::{
-    AddCoreArgs, AddWorkInstanceData, BaseSubcommand, Commands, CreateCreator, CreateEnvironment,
-    CreateInstance, CreateObject, CreateReplay, CreateSave, CreateScreenshot, CreateState,
-    CreateWork, GISSTCli, GISSTCliError, PatchData, SetUserRole, UpgradeEnvironmentArgs,
+    AddCoreArgs, AddWorkInstanceData, ApplyArgs, BaseSubcommand, BuildDiskArgs, Commands,
+    CreateCreator, CreateEnvironment, CreateInstance, CreateObject, CreateReplay, CreateSave,
+    CreateScreenshot, CreateState, CreateWork, EditDiskArgs, GISSTCli, GISSTCliError,
+    ObjectSubcommand, PatchData, ReplaySubcommand, ScreenshotSubcommand, SetUserRole,
+    UpgradeEnvironmentArgs,
 };
 use clap::Parser;
 use gisst::{
+    danger::Deletion,
     models::{
         Core, Creator, Duplicate, Environment, Instance, Object, ObjectLink, ObjectRole, Replay,
-        Save, Screenshot, State, Video, Work, insert_file_object,
+        Save, State, Video, Work, insert_file_object,
     },
     storage::StorageHandler,
 };
@@ -33,14 +40,30 @@ async fn main() -> Result<(), GISSTCliError> {
         .filter_level(args.verbose.log_level_filter())
         .init();
 
-    let indexer = gisst::search::MeiliIndexer::new(&args.meili_url, &args.meili_api_key)?;
+    if let Some(base_url) = &args.remote {
+        info!("Working through the server at {base_url}");
+        // clap won't take --remote without --token
+        let client =
+            remote::RemoteClient::new(base_url, args.token.as_deref().unwrap_or_default())?;
+        return remote::run(args.command, &client).await;
+    }
+    // clap requires these unless --remote is given
+    let (Some(gisst_config_path), Some(meili_url), Some(meili_api_key)) = (
+        args.gisst_config_path.clone(),
+        args.meili_url.clone(),
+        args.meili_api_key.clone(),
+    ) else {
+        unreachable!("config and search settings are required without --remote");
+    };
+
+    let indexer = gisst::search::MeiliIndexer::new(&meili_url, &meili_api_key)?;
     if let Commands::InitIndices = args.command {
         info!("Not using DB or config, just initializing indices");
         indexer.init_indices().await?;
         return Ok(());
     }
-    info!("Found config file at path: {}", args.gisst_config_path);
-    let cli_config: CLIConfig = CLIConfig::new(&args.gisst_config_path)?;
+    info!("Found config file at path: {gisst_config_path}");
+    let cli_config: CLIConfig = CLIConfig::new(&gisst_config_path)?;
 
     info!(
         "Connecting to database: {}",
@@ -57,6 +80,11 @@ async fn main() -> Result<(), GISSTCliError> {
     match dbg!(args).command {
         Commands::InitIndices => (),
         Commands::Reindex => reindex(db, &indexer).await?,
+        Commands::IndexDiskEntries { force } => {
+            let mut conn = db.acquire().await?;
+            gisst::diskindex::index_all_disk_entries(&mut conn, &storage_root, force, &indexer)
+                .await?;
+        }
         Commands::RecalcSizes => recalc_sizes(db, &storage_root).await?,
         Commands::UpgradeEnvironment(args) => {
             upgrade_env(args, db, &indexer).await?;
@@ -75,7 +103,26 @@ async fn main() -> Result<(), GISSTCliError> {
             role_index,
         } => link_record(&record_type, source_uuid, target_uuid, db, role, role_index).await?,
         Commands::Object(object) => match object.command {
-            BaseSubcommand::Create(create) => create_object(create, db, storage_root).await?,
+            ObjectSubcommand::Create(create) => create_object(create, db, storage_root).await?,
+            ObjectSubcommand::Promote {
+                object,
+                path,
+                description,
+                depth,
+            } => {
+                let mut conn = db.acquire().await?;
+                let promoted = gisst::subobject::promote_subobject(
+                    &mut conn,
+                    &storage_root,
+                    depth,
+                    object,
+                    &path,
+                    description,
+                    None,
+                )
+                .await?;
+                info!("Promoted {path} to object {}", promoted.object_id);
+            }
         },
         Commands::Creator(creator) => match creator.command {
             BaseSubcommand::Create(create) => create_creator(create, db, &indexer).await?,
@@ -100,12 +147,20 @@ async fn main() -> Result<(), GISSTCliError> {
             }
         },
         Commands::Replay(replay) => match replay.command {
-            BaseSubcommand::Create(create) => {
+            ReplaySubcommand::Create(create) => {
                 create_replay(create, db, storage_root, &indexer).await?;
             }
+            ReplaySubcommand::ExtractCheckpoints { replay, depth } => {
+                extract_replay_checkpoints(db, replay, &storage_root, depth, &indexer).await?;
+            }
         },
         Commands::Screenshot(screenshot) => match screenshot.command {
-            BaseSubcommand::Create(create) => create_screenshot(create, db).await?,
+            ScreenshotSubcommand::Create(create) => {
+                create_screenshot(create, db, &storage_root).await?;
+            }
+            ScreenshotSubcommand::MigrateStorage { depth, batch_size } => {
+                migrate_screenshots(db, &storage_root, depth, batch_size).await?;
+            }
         },
         Commands::CloneV86 {
             instance,
@@ -114,6 +169,66 @@ async fn main() -> Result<(), GISSTCliError> {
         } => {
             clone_v86_machine(db, instance, state, storage_root, depth, &indexer).await?;
         }
+        Commands::BuildDisk(args) => {
+            build_disk(args, db, &storage_root).await?;
+        }
+        Commands::Apply(args) => {
+            apply_manifest(args, db, &storage_root, &indexer).await?;
+        }
+        Commands::ImportRecordings(args) => {
+            let mut conn = db.acquire().await?;
+            let summary = recordings::import(
+                &mut conn,
+                &storage_root,
+                args.depth,
+                &args.dir,
+                args.instance,
+                args.creator,
+                &indexer,
+            )
+            .await?;
+            print!("{summary}");
+            if !summary.failed.is_empty() {
+                return Err(GISSTCliError::ImportFailed(summary.failed.len()));
+            }
+        }
+        Commands::List(args) => {
+            let mut conn = db.acquire().await?;
+            query::list(&mut conn, args).await?;
+        }
+        Commands::Show(args) => {
+            let mut conn = db.acquire().await?;
+            query::show(&mut conn, args).await?;
+        }
+        Commands::Delete(args) => {
+            let mut conn = db.acquire().await?;
+            let plan = Deletion::plan(&mut conn, args.kind, args.id).await?;
+            apply_deletion(
+                plan,
+                &mut conn,
+                &storage_root,
+                args.cascade,
+                args.dry_run,
+                &indexer,
+            )
+            .await?;
+        }
+        Commands::Unlink(args) => {
+            let mut conn = db.acquire().await?;
+            let plan = Deletion::plan_unlink(&mut conn, args.object, args.instance).await?;
+            apply_deletion(
+                plan,
+                &mut conn,
+                &storage_root,
+                args.cascade,
+                args.dry_run,
+                &indexer,
+            )
+            .await?;
+        }
+        Commands::EditDisk(args) => {
+            edit_disk(args, db, &storage_root, &indexer).await?;
+        }
         Commands::AddPatch {
             instance,
             data,
@@ -571,50 +686,68 @@ async fn recalc_sizes(db: PgPool, storage_root: &str) -> Result<(), GISSTCliErro
         }
         let mut tx = db.begin().await?;
         // for every file in the file table, recompute file size and file compressed size (if any)
-        let files = sqlx::query_as!(
-            gisst::models::File,
-            "SELECT * FROM file ORDER BY file_id OFFSET $1 LIMIT $2",
-            i * 1024,
-            this_count
-        )
-        .fetch_all(tx.as_mut())
-        .await?;
+        let files = gisst::models::File::get_batch(&mut tx, i * 1024, this_count).await?;
         info!("batch {i} : {}", files.len());
-        let root = Path::new(storage_root);
         for f in files {
-            let path = root.join(Path::new(&f.file_dest_path));
-            info!("p {path:?}, fs {}", f.file_dest_path);
-            let gz_path = if let Some(e) = path.extension().and_then(|e| e.to_str()) {
-                let mut s = e.to_string();
-                s.push_str(".gz");
-                path.with_extension(s)
-            } else {
-                path.with_extension("gz")
-            };
-            let file_size =
-                i64::try_from(std::fs::metadata(&path)?.len()).map_err(GISSTCliError::FileSize)?;
-            let file_compressed_size = std::fs::metadata(&gz_path)
-                .ok()
-                .and_then(|md| i64::try_from(md.len()).ok());
+            let (file_size, file_compressed_size) =
+                StorageHandler::get_stored_sizes(storage_root, &f.file_dest_path)?;
             info!(
-                "compute {file_size}, {file_compressed_size:?} for {}, {path:?}, {gz_path:?}",
-                f.file_id
+                "compute {file_size}, {file_compressed_size:?} for {}, {}",
+                f.file_id, f.file_dest_path
             );
             // TODO do this a bunch of files at a time
-            sqlx::query!(
-                r#"UPDATE file SET file_size=$1, file_compressed_size=$2 WHERE file_id=$3 "#,
-                file_size,
-                file_compressed_size,
-                f.file_id,
-            )
-            .execute(tx.as_mut())
-            .await?;
+            gisst::models::File::update_sizes(&mut tx, f.file_id, file_size, file_compressed_size)
+                .await?;
         }
         tx.commit().await?;
     }
     Ok(())
 }
 
+async fn build_disk(
+    args: BuildDiskArgs,
+    db: PgPool,
+    storage_root: &str,
+) -> Result<Uuid, GISSTCliError> {
+    use gisst::diskbuild::{DiskContents, Geometry, InstanceLink, build_object};
+    let geometry: Geometry = args.geometry.parse()?;
+    let name = args.name.unwrap_or_else(|| {
+        let stem = args
+            .source
+            .file_stem()
+            .map_or_else(|| "disk".into(), |stem| stem.to_string_lossy());
+        format!("{stem}.img")
+    });
+    let contents = {
+        let source = args.source.clone();
+        tokio::task::spawn_blocking(move || DiskContents::from_path(&source))
+            .await
+            .map_err(gisst::error::DiskBuild::from)??
+    };
+    let mut conn = db.acquire().await?;
+    let object = build_object(
+        &mut conn,
+        storage_root,
+        args.depth,
+        contents,
+        geometry,
+        args.label,
+        &name,
+        args.description,
+        args.link.map(|instance_id| InstanceLink {
+            instance_id,
+            role_index: args.role_index,
+        }),
+        None,
+    )
+    .await?;
+    info!(
+        "Built {geometry} image {name} as object {}",
+        object.object_id
+    );
+    Ok(object.object_id)
+}
+
 async fn clone_v86_machine(
     db: PgPool,
     instance_id: Uuid,
@@ -624,7 +757,6 @@ async fn clone_v86_machine(
     indexer: &gisst::search::MeiliIndexer,
 ) -> Result<Uuid, GISSTCliError> {
     let mut conn = db.acquire().await?;
-    // TODO: use transaction
     let uuid = gisst::v86clone::clone_v86_machine(
         &mut conn,
         instance_id,
@@ -633,11 +765,122 @@ async fn clone_v86_machine(
         depth,
         indexer,
         None,
+        |progress| info!("{progress:?}"),
     )
     .await?;
     Ok(uuid)
 }
 
+async fn extract_replay_checkpoints(
+    db: PgPool,
+    replay_id: Uuid,
+    storage_root: &str,
+    depth: u8,
+    indexer: &gisst::search::MeiliIndexer,
+) -> Result<Vec<Uuid>, GISSTCliError> {
+    let mut conn = db.acquire().await?;
+    let states = gisst::replaystates::extract_replay_states(
+        &mut conn,
+        replay_id,
+        storage_root,
+        depth,
+        indexer,
+        |progress| info!("{progress:?}"),
+    )
+    .await?;
+    info!("Created {} checkpoint states", states.len());
+    Ok(states)
+}
+
+async fn apply_manifest(
+    args: ApplyArgs,
+    db: PgPool,
+    storage_root: &str,
+    indexer: &gisst::search::MeiliIndexer,
+) -> Result<(), GISSTCliError> {
+    use manifest::{Manifest, Plan};
+    let manifest = Manifest::load(&args.manifest)?;
+    let mut conn = db.acquire().await?;
+    let plan = Plan::compute(&mut conn, manifest).await?;
+    print!("{plan}");
+    if !plan.has_changes() {
+        println!("Nothing to change.");
+        return Ok(());
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+    let instance_id = plan
+        .apply(&mut conn, storage_root, args.depth, indexer)
+        .await?;
+    println!("Applied manifest to instance {instance_id}");
+    Ok(())
+}
+
+async fn apply_deletion(
+    plan: Deletion,
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    cascade: bool,
+    dry_run: bool,
+    indexer: &gisst::search::MeiliIndexer,
+) -> Result<(), GISSTCliError> {
+    print!("{plan}");
+    if dry_run {
+        return Ok(());
+    }
+    if !cascade && plan.needs_cascade() {
+        println!("Other records depend on this; rerun with --cascade to remove them too.");
+        return Ok(());
+    }
+    plan.apply(conn, storage_root, cascade, indexer).await?;
+    println!("Done.");
+    Ok(())
+}
+
+/// Returns the new instance running the edited disk
+async fn edit_disk(
+    args: EditDiskArgs,
+    db: PgPool,
+    storage_root: &str,
+    indexer: &gisst::search::MeiliIndexer,
+) -> Result<Uuid, GISSTCliError> {
+    use gisst::diskedit::{DerivedWork, FileEdit, derive_instance};
+    let mut edits = Vec::with_capacity(args.writes.len() + args.deletes.len());
+    for (path, local) in args.writes {
+        edits.push(FileEdit::Write {
+            path,
+            data: fs::read(&local)?,
+        });
+    }
+    edits.extend(
+        args.deletes
+            .into_iter()
+            .map(|path| FileEdit::Delete { path }),
+    );
+    let mut conn = db.acquire().await?;
+    let instance = derive_instance(
+        &mut conn,
+        storage_root,
+        args.depth,
+        args.instance,
+        args.role_index,
+        edits,
+        DerivedWork {
+            work_name: args.name,
+            work_version: args.version,
+        },
+        indexer,
+        None,
+    )
+    .await?;
+    info!(
+        "Created instance {} of work {}",
+        instance.instance_id, instance.work_id
+    );
+    Ok(instance.instance_id)
+}
+
 /// Returns the new work and instance created for this hack
 async fn add_patched_instance(
     db: PgPool,
@@ -683,20 +926,59 @@ async fn add_patched_instance(
     for link in gisst::models::ObjectLink::get_all_for_instance_id(&mut tx, instance_id).await? {
         let role_index =
             u16::try_from(link.object_role_index).map_err(GISSTCliError::InvalidRoleIndex)?;
-        if link.object_role == ObjectRole::Content
-            && !data
-                .files
-                .get(role_index as usize)
-                .map_or("", String::as_str)
-                .is_empty()
-        {
-            let patch = Path::new(&data.files[role_index as usize]);
+        let replaced = !data
+            .files
+            .get(role_index as usize)
+            .map_or("", String::as_str)
+            .is_empty();
+        if link.object_role == ObjectRole::Patch && replaced {
+            // The replaced content's old patch doesn't describe the new one
+            continue;
+        }
+        if link.object_role == ObjectRole::Content && replaced {
+            let patch = patch_root.join(&data.files[role_index as usize]);
+            if is_patch_file(&patch)? {
+                info!("Applying patch {patch:?} to index {role_index} @ {link:?}");
+                let file_name = patch
+                    .file_name()
+                    .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+                let objects = gisst::rompatch::store_patch(
+                    &mut tx,
+                    &storage_root,
+                    depth,
+                    &link,
+                    &gisst::rompatch::PatchFile {
+                        role_index,
+                        path: patch,
+                        file_name,
+                    },
+                    None,
+                )
+                .await?;
+                Object::link_object_to_instance(
+                    &mut tx,
+                    objects.content_id,
+                    derived_inst_id,
+                    ObjectRole::Content,
+                    role_index,
+                )
+                .await?;
+                Object::link_object_to_instance(
+                    &mut tx,
+                    objects.patch_id,
+                    derived_inst_id,
+                    ObjectRole::Patch,
+                    role_index,
+                )
+                .await?;
+                continue;
+            }
             info!("Patching file {patch:?} for index {role_index} @ {link:?}");
             let object_id = insert_file_object(
                 &mut tx,
                 &storage_root,
                 depth,
-                &patch_root.join(patch),
+                &patch,
                 Some(link.file_filename),
                 None,
                 link.file_source_path,
@@ -728,6 +1010,14 @@ async fn add_patched_instance(
     Ok((derived_work_id, derived_inst_id))
 }
 
+/// Checks for an IPS, UPS, or BPS header
+fn is_patch_file(path: &Path) -> Result<bool, GISSTCliError> {
+    use std::io::Read;
+    let mut magic = Vec::with_capacity(5);
+    fs::File::open(path)?.take(5).read_to_end(&mut magic)?;
+    Ok(gisst::rompatch::PatchFormat::detect(&magic).is_some())
+}
+
 async fn link_record(
     record_type: &str,
     source_id: Uuid,
@@ -999,6 +1289,14 @@ async fn create_replay(
         .map_or(chrono::Utc::now(), chrono::DateTime::<chrono::Utc>::from);
     let creator_id = creator_id.unwrap_or_else(|| uuid!("00000000-0000-0000-0000-000000000000"));
     let mut conn = db.acquire().await?;
+    let instance = Instance::get_by_id(&mut conn, link)
+        .await?
+        .ok_or(GISSTCliError::RecordNotFound(link))?;
+    let environment = Environment::get_by_id(&mut conn, instance.environment_id)
+        .await?
+        .ok_or(GISSTCliError::RecordNotFound(instance.environment_id))?;
+    let replay_info = gisst::replay::parse_path(file)?;
+    replay_info.check_framework(environment.environment_framework)?;
     let cwd = Path::new("");
     let hash = StorageHandler::get_file_hash(file)?;
     let file_id = if let Some(file) = gisst::models::File::get_by_hash(&mut conn, &hash).await? {
@@ -1041,13 +1339,13 @@ async fn create_replay(
         .await?
         .file_id;
         let video_id = video_id.unwrap_or(Uuid::new_v4());
+        let video = Video::for_file(video_file_id, creator_id, path.to_path_buf()).await;
         Video::insert(
             &mut conn,
             Video {
                 video_id,
-                file_id: video_file_id,
                 created_on,
-                creator_id,
+                ..video
             },
         )
         .await
@@ -1068,7 +1366,14 @@ async fn create_replay(
         file_id,
         created_on,
         hidden: false,
-    };
+        replay_frame_count: None,
+        replay_duration: None,
+        replay_checkpoint_count: None,
+        replay_checkpoints: None,
+        replay_identifier: None,
+        replay_content_crc: None,
+    }
+    .with_file_info(&replay_info);
     Replay::insert(&mut conn, replay, indexer)
         .await
         .map(|_| ())
@@ -1076,8 +1381,13 @@ async fn create_replay(
 }
 
 async fn create_screenshot(
-    CreateScreenshot { file, force_uuid }: CreateScreenshot,
+    CreateScreenshot {
+        depth,
+        file,
+        force_uuid,
+    }: CreateScreenshot,
     db: PgPool,
+    storage_root: &str,
 ) -> Result<(), GISSTCliError> {
     let file = Path::new(&file);
 
@@ -1090,19 +1400,53 @@ async fn create_screenshot(
 
     let mut conn = db.acquire().await?;
 
-    Screenshot::insert(
+    let screenshot = gisst::screenshot::store_screenshot(
         &mut conn,
-        Screenshot {
-            screenshot_data: std::fs::read(file)?,
-            screenshot_id: force_uuid.unwrap_or_else(Uuid::new_v4),
-            created_on: chrono::Utc::now(),
-            creator_id: None,
-        },
+        storage_root,
+        depth,
+        &std::fs::read(file)?,
+        force_uuid.unwrap_or_else(Uuid::new_v4),
+        None,
     )
-    .await
-    .map_err(GISSTCliError::NewModel)?;
+    .await?;
 
-    info!("Wrote screenshot {} to database.", file.to_string_lossy());
+    info!(
+        "Wrote screenshot {} as {}.",
+        file.to_string_lossy(),
+        screenshot.screenshot_id
+    );
+    Ok(())
+}
+
+async fn migrate_screenshots(
+    db: PgPool,
+    storage_root: &str,
+    depth: u8,
+    batch_size: i64,
+) -> Result<(), GISSTCliError> {
+    use sqlx::Connection;
+    let mut conn = db.acquire().await?;
+    let (mut moved, mut skipped) = (0, 0);
+    loop {
+        let mut tx = conn.begin().await?;
+        // Skipped screenshots stay in the database, so step over them on later batches
+        let batch = gisst::screenshot::migrate_legacy_screenshots(
+            &mut tx,
+            storage_root,
+            depth,
+            i64::try_from(skipped)?,
+            batch_size,
+        )
+        .await?;
+        tx.commit().await?;
+        if batch.moved == 0 && batch.skipped == 0 {
+            break;
+        }
+        moved += batch.moved;
+        skipped += batch.skipped;
+        info!("Moved {moved} screenshots to storage, skipped {skipped}");
+    }
+    info!("Done, {moved} screenshots moved to storage and {skipped} left in the database.");
     Ok(())
 }
 
@@ -0,0 +1,962 @@
+//! Declarative manifests for `gisst-cli apply`: a TOML file describing a
+//! work, its environment, and one instance with its objects and seed states.
+//! Applying a manifest finds what already exists by name and file hash and
+//! only creates or updates the rest, so it can be applied repeatedly.
+//!
+//! ```toml
+//! creator = "0c8e5e2a-3b1f-4f6e-9a57-2d6f4c1b7e90"
+//!
+//! [work]
+//! name = "Commander Keen"
+//! version = "1.4"
+//! platform = "DOS"
+//!
+//! [environment]
+//! name = "MS-DOS 6.22"
+//! framework = "v86"
+//! core = { name = "v86" }
+//! config_file = "v86.json"
+//!
+//! [[objects]]
+//! role = "content"
+//! path = "keen.img"
+//!
+//! [[states]]
+//! name = "Title screen"
+//! path = "title.v86state"
+//! screenshot = "title.png"
+//! ```
+
+use crate::args::GISSTCliError;
+use gisst::model_enums::Framework;
+use gisst::models::{
+    Core, Duplicate, Environment, File, Instance, Object, ObjectLink, ObjectRole, State, Work,
+    insert_file_object, insert_new_file,
+};
+use gisst::search::{NullIndexer, SearchIndexer};
+use gisst::storage::StorageHandler;
+use serde::Deserialize;
+use sqlx::Connection;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Recorded as the creator of new records; needed for states
+    pub creator: Option<Uuid>,
+    pub work: WorkSpec,
+    pub environment: EnvironmentSpec,
+    #[serde(default)]
+    pub instance: InstanceSpec,
+    #[serde(default)]
+    pub objects: Vec<ObjectSpec>,
+    #[serde(default)]
+    pub states: Vec<StateSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkSpec {
+    pub name: String,
+    pub version: String,
+    pub platform: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentSpec {
+    pub name: String,
+    pub framework: Framework,
+    /// Defaults to the work's platform
+    pub platform: Option<String>,
+    pub core: CoreSpec,
+    /// Environment config written inline
+    pub config: Option<serde_json::Value>,
+    /// Environment config read from a JSON file
+    pub config_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoreSpec {
+    pub name: String,
+    /// Defaults to the newest version added for the platform
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceSpec {
+    pub config: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectSpec {
+    pub role: ObjectRole,
+    /// Defaults to the number of objects listed before this one in the same role
+    pub index: Option<u16>,
+    pub path: PathBuf,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateSpec {
+    pub name: String,
+    pub description: Option<String>,
+    pub path: PathBuf,
+    /// PNG screenshot shown for the state
+    pub screenshot: PathBuf,
+}
+
+impl Manifest {
+    /// Reads a manifest, resolving its paths against the manifest's folder
+    pub fn load(path: &Path) -> Result<Self, GISSTCliError> {
+        let mut manifest: Manifest = toml::from_str(&std::fs::read_to_string(path)?)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        if let Some(config_file) = &mut manifest.environment.config_file {
+            *config_file = base.join(&config_file);
+        }
+        for object in &mut manifest.objects {
+            object.path = base.join(&object.path);
+        }
+        for state in &mut manifest.states {
+            state.path = base.join(&state.path);
+            state.screenshot = base.join(&state.screenshot);
+        }
+        Ok(manifest)
+    }
+
+    /// The environment's platform, which defaults to the work's
+    fn environment_platform(&self) -> String {
+        self.environment
+            .platform
+            .clone()
+            .unwrap_or_else(|| self.work.platform.clone())
+    }
+
+    fn environment_config(&self) -> Result<Option<serde_json::Value>, GISSTCliError> {
+        match (&self.environment.config, &self.environment.config_file) {
+            (Some(_), Some(_)) => Err(GISSTCliError::Manifest(
+                "environment has both config and config_file".to_string(),
+            )),
+            (Some(config), None) => Ok(Some(config.clone())),
+            (None, Some(path)) => Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?)),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Gives each object its role index, refusing two objects in one slot
+    fn object_slots(&self) -> Result<Vec<(ObjectRole, u16, &ObjectSpec)>, GISSTCliError> {
+        let mut slots: Vec<(ObjectRole, u16, &ObjectSpec)> = vec![];
+        for object in &self.objects {
+            let index = match object.index {
+                Some(index) => index,
+                None => u16::try_from(
+                    slots
+                        .iter()
+                        .filter(|(role, _, _)| *role == object.role)
+                        .count(),
+                )?,
+            };
+            if slots
+                .iter()
+                .any(|(role, idx, _)| *role == object.role && *idx == index)
+            {
+                return Err(GISSTCliError::Manifest(format!(
+                    "two objects at {} {index}",
+                    role_name(object.role)
+                )));
+            }
+            slots.push((object.role, index, object));
+        }
+        Ok(slots)
+    }
+}
+
+fn role_name(role: ObjectRole) -> &'static str {
+    match role {
+        ObjectRole::Content => "content",
+        ObjectRole::Dependency => "dependency",
+        ObjectRole::Config => "config",
+        ObjectRole::Patch => "patch",
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+/// What applying the manifest does to one record
+#[derive(Debug)]
+enum Step<T> {
+    Keep(T),
+    Create(T),
+    Update(T),
+}
+
+impl<T> Step<T> {
+    fn record(&self) -> &T {
+        match self {
+            Step::Keep(record) | Step::Create(record) | Step::Update(record) => record,
+        }
+    }
+    fn changes(&self) -> bool {
+        !matches!(self, Step::Keep(_))
+    }
+    fn is_new(&self) -> bool {
+        matches!(self, Step::Create(_))
+    }
+    fn marker(&self) -> char {
+        match self {
+            Step::Keep(_) => '=',
+            Step::Create(_) => '+',
+            Step::Update(_) => '~',
+        }
+    }
+}
+
+/// A manifest file to store as an object
+#[derive(Debug)]
+struct ObjectFile {
+    path: PathBuf,
+    description: Option<String>,
+}
+
+#[derive(Debug)]
+enum LinkStep {
+    Keep {
+        role: ObjectRole,
+        index: u16,
+        file_name: String,
+    },
+    Link {
+        role: ObjectRole,
+        index: u16,
+        file: ObjectFile,
+    },
+    Replace {
+        old: ObjectLink,
+        file: ObjectFile,
+    },
+    Unlink {
+        old: ObjectLink,
+    },
+}
+
+#[derive(Debug)]
+enum StateStep {
+    Keep {
+        state_id: Uuid,
+        name: String,
+    },
+    Create {
+        name: String,
+        description: Option<String>,
+        path: PathBuf,
+        /// Already stored file with the state's data
+        file_id: Option<Uuid>,
+        screenshot: PathBuf,
+        creator_id: Uuid,
+    },
+}
+
+/// The changes applying a manifest would make
+#[derive(Debug)]
+pub struct Plan {
+    creator: Option<Uuid>,
+    work: Step<Work>,
+    environment: Step<Environment>,
+    instance: Step<Instance>,
+    links: Vec<LinkStep>,
+    states: Vec<StateStep>,
+}
+
+/// What the database already holds for a manifest
+#[derive(Debug)]
+struct Existing {
+    work: Option<Work>,
+    core: Core,
+    environment: Option<Environment>,
+    /// Only looked up when the work and environment both exist
+    instance: Option<Instance>,
+    links: Vec<ObjectLink>,
+    /// Hash of each manifest object's file, in manifest order
+    object_hashes: Vec<String>,
+    /// For each manifest state, the instance's state with the same data, or
+    /// else the already stored file with that data
+    states: Vec<(Option<State>, Option<Uuid>)>,
+}
+
+impl Plan {
+    /// Compares `manifest` with the database
+    pub async fn compute(
+        conn: &mut sqlx::PgConnection,
+        manifest: Manifest,
+    ) -> Result<Self, GISSTCliError> {
+        let work = Work::get_by_metadata(
+            conn,
+            &manifest.work.name,
+            &manifest.work.version,
+            &manifest.work.platform,
+        )
+        .await?;
+
+        let env_spec = &manifest.environment;
+        let platform = manifest.environment_platform();
+        let core = if let Some(version) = &env_spec.core.version {
+            Core::get(conn, &env_spec.core.name, version, &platform).await?
+        } else {
+            Core::get_versions(conn, &env_spec.core.name)
+                .await?
+                .into_iter()
+                .find(|core| core.core_platform == platform)
+        }
+        .ok_or_else(|| {
+            GISSTCliError::Manifest(format!(
+                "no core {} {} for {platform}, add it with add-core first",
+                env_spec.core.name,
+                env_spec.core.version.as_deref().unwrap_or("(any version)")
+            ))
+        })?;
+        let environment = Environment::get_for_core(conn, &core.core_name, &core.core_version)
+            .await?
+            .into_iter()
+            .find(|env| {
+                env.environment_name == env_spec.name
+                    && env.environment_platform == platform
+                    && env.environment_framework == env_spec.framework
+            });
+
+        let instance = match (&work, &environment) {
+            (Some(work), Some(env)) => Instance::get_all_for_work_id(conn, work.work_id)
+                .await?
+                .into_iter()
+                .filter(|inst| inst.environment_id == env.environment_id)
+                .min_by_key(|inst| inst.created_on),
+            _ => None,
+        };
+        let links = match &instance {
+            Some(inst) => ObjectLink::get_all_for_instance_id(conn, inst.instance_id).await?,
+            None => vec![],
+        };
+        let object_hashes = manifest
+            .objects
+            .iter()
+            .map(|object| StorageHandler::get_file_hash(&object.path))
+            .collect::<Result<_, _>>()?;
+
+        let mut states = vec![];
+        for state in &manifest.states {
+            let hash = StorageHandler::get_file_hash(&state.path)?;
+            let recorded = State::get_by_hash(conn, &hash).await?.filter(|existing| {
+                instance
+                    .as_ref()
+                    .is_some_and(|inst| inst.instance_id == existing.instance_id)
+            });
+            let file_id = if recorded.is_some() {
+                None
+            } else {
+                File::get_by_hash(conn, &hash)
+                    .await?
+                    .map(|file| file.file_id)
+            };
+            states.push((recorded, file_id));
+        }
+
+        Self::from_existing(
+            manifest,
+            Existing {
+                work,
+                core,
+                environment,
+                instance,
+                links,
+                object_hashes,
+                states,
+            },
+            chrono::Utc::now(),
+        )
+    }
+
+    /// Decides what to do with each record of `manifest`, given what the
+    /// database already has
+    #[allow(clippy::too_many_lines)]
+    fn from_existing(
+        manifest: Manifest,
+        existing: Existing,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Self, GISSTCliError> {
+        let creator = manifest.creator;
+        let work = match existing.work {
+            Some(work) => Step::Keep(work),
+            None => Step::Create(Work {
+                work_id: Uuid::new_v4(),
+                work_name: manifest.work.name.clone(),
+                work_version: manifest.work.version.clone(),
+                work_platform: manifest.work.platform.clone(),
+                created_on: now,
+                work_derived_from: None,
+                creator_id: creator,
+            }),
+        };
+
+        let env_config = manifest.environment_config()?;
+        let environment = match existing.environment {
+            Some(env) if env_config.is_none() || env.environment_config == env_config => {
+                Step::Keep(env)
+            }
+            Some(env) => Step::Update(Environment {
+                environment_config: env_config,
+                ..env
+            }),
+            None => Step::Create(Environment {
+                environment_id: Uuid::new_v4(),
+                environment_name: manifest.environment.name.clone(),
+                environment_framework: manifest.environment.framework,
+                environment_platform: manifest.environment_platform(),
+                environment_core_name: existing.core.core_name,
+                environment_core_version: existing.core.core_version,
+                environment_derived_from: None,
+                environment_config: env_config,
+                created_on: now,
+                creator_id: creator,
+            }),
+        };
+
+        let instance_config = manifest.instance.config.clone();
+        let instance = match existing.instance {
+            Some(inst) if instance_config.is_none() || inst.instance_config == instance_config => {
+                Step::Keep(inst)
+            }
+            Some(inst) => Step::Update(Instance {
+                instance_config,
+                ..inst
+            }),
+            None => Step::Create(Instance {
+                instance_id: Uuid::new_v4(),
+                work_id: work.record().work_id,
+                environment_id: environment.record().environment_id,
+                instance_config,
+                created_on: now,
+                derived_from_instance: None,
+                derived_from_state: None,
+                creator_id: creator,
+            }),
+        };
+
+        let mut existing_links = existing.links;
+        let mut links = vec![];
+        for ((role, index, object), hash) in manifest
+            .object_slots()?
+            .into_iter()
+            .zip(existing.object_hashes)
+        {
+            let file = ObjectFile {
+                path: object.path.clone(),
+                description: object.description.clone(),
+            };
+            let slot = existing_links.iter().position(|link| {
+                link.object_role == role && link.object_role_index == i32::from(index)
+            });
+            links.push(match slot.map(|slot| existing_links.remove(slot)) {
+                Some(old) if old.file_hash == hash => LinkStep::Keep {
+                    role,
+                    index,
+                    file_name: old.file_filename,
+                },
+                Some(old) => LinkStep::Replace { old, file },
+                None => LinkStep::Link { role, index, file },
+            });
+        }
+        links.extend(
+            existing_links
+                .into_iter()
+                .map(|old| LinkStep::Unlink { old }),
+        );
+
+        let mut states = vec![];
+        for (state, (recorded, file_id)) in manifest.states.into_iter().zip(existing.states) {
+            let Some(creator_id) = creator else {
+                return Err(GISSTCliError::Manifest("states need a creator".to_string()));
+            };
+            states.push(match recorded {
+                Some(recorded) => StateStep::Keep {
+                    state_id: recorded.state_id,
+                    name: recorded.state_name,
+                },
+                None => StateStep::Create {
+                    name: state.name,
+                    description: state.description,
+                    path: state.path,
+                    file_id,
+                    screenshot: state.screenshot,
+                    creator_id,
+                },
+            });
+        }
+
+        Ok(Plan {
+            creator,
+            work,
+            environment,
+            instance,
+            links,
+            states,
+        })
+    }
+
+    #[must_use]
+    pub fn has_changes(&self) -> bool {
+        self.work.changes()
+            || self.environment.changes()
+            || self.instance.changes()
+            || self
+                .links
+                .iter()
+                .any(|link| !matches!(link, LinkStep::Keep { .. }))
+            || self
+                .states
+                .iter()
+                .any(|state| matches!(state, StateStep::Create { .. }))
+    }
+
+    /// Makes the planned changes in one transaction, returning the instance
+    pub async fn apply(
+        self,
+        conn: &mut sqlx::PgConnection,
+        storage_root: &str,
+        depth: u8,
+        indexer: &impl SearchIndexer,
+    ) -> Result<Uuid, GISSTCliError> {
+        let mut tx = conn.begin().await?;
+        if let Step::Create(work) = self.work {
+            Work::insert(&mut tx, work).await?;
+        }
+        match self.environment {
+            Step::Create(env) => {
+                Environment::insert(&mut tx, env).await?;
+            }
+            Step::Update(env) => {
+                Environment::set_config(&mut tx, env.environment_id, env.environment_config)
+                    .await?;
+            }
+            Step::Keep(_) => (),
+        }
+        let instance = match self.instance {
+            Step::Create(inst) => Instance::insert(&mut tx, inst, &NullIndexer).await?,
+            Step::Update(inst) => {
+                Instance::set_config(&mut tx, inst.instance_id, inst.instance_config).await?
+            }
+            Step::Keep(inst) => inst,
+        };
+        let instance_id = instance.instance_id;
+
+        // Unlink everything first so objects can move between slots
+        let mut to_link = vec![];
+        for link in self.links {
+            match link {
+                LinkStep::Keep { .. } => (),
+                LinkStep::Link { role, index, file } => to_link.push((role, index, file)),
+                LinkStep::Replace { old, file } => {
+                    Object::unlink_object_from_instance(&mut tx, old.object_id, instance_id)
+                        .await?;
+                    to_link.push((old.object_role, u16::try_from(old.object_role_index)?, file));
+                }
+                LinkStep::Unlink { old } => {
+                    Object::unlink_object_from_instance(&mut tx, old.object_id, instance_id)
+                        .await?;
+                }
+            }
+        }
+        for (role, index, file) in to_link {
+            let name = file_name(&file.path);
+            let object_id = insert_file_object(
+                &mut tx,
+                storage_root,
+                depth,
+                &file.path,
+                Some(name.clone()),
+                file.description.or_else(|| Some(name.clone())),
+                name,
+                Duplicate::ReuseObject,
+                self.creator,
+            )
+            .await?;
+            Object::link_object_to_instance(&mut tx, object_id, instance_id, role, index).await?;
+        }
+
+        let mut new_states = vec![];
+        for state in self.states {
+            let StateStep::Create {
+                name,
+                description,
+                path,
+                file_id,
+                screenshot,
+                creator_id,
+            } = state
+            else {
+                continue;
+            };
+            let now = chrono::Utc::now();
+            let file_id = match file_id {
+                Some(file_id) => file_id,
+                None => {
+                    insert_new_file(
+                        &mut tx,
+                        storage_root,
+                        depth,
+                        &file_name(&path),
+                        &path,
+                        "",
+                        now,
+                        self.creator,
+                    )
+                    .await?
+                    .file_id
+                }
+            };
+            let screenshot = gisst::screenshot::store_screenshot(
+                &mut tx,
+                storage_root,
+                depth,
+                &std::fs::read(&screenshot)?,
+                Uuid::new_v4(),
+                self.creator,
+            )
+            .await?;
+            let state = State {
+                state_id: Uuid::new_v4(),
+                instance_id,
+                is_checkpoint: false,
+                file_id,
+                state_description: description.unwrap_or_else(|| name.clone()),
+                state_name: name,
+                screenshot_id: screenshot.screenshot_id,
+                replay_id: None,
+                creator_id,
+                state_replay_index: None,
+                state_derived_from: None,
+                save_derived_from: None,
+                created_on: now,
+                hidden: false,
+            };
+            new_states.push(State::insert(&mut tx, state, &NullIndexer).await?);
+        }
+        tx.commit().await?;
+        // Only index once everything has committed
+        indexer.upsert_instance(conn, &instance).await?;
+        for state in &new_states {
+            indexer.upsert_state(conn, state).await?;
+        }
+        Ok(instance_id)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let work = self.work.record();
+        writeln!(
+            f,
+            "{} work {} {} ({}) {}",
+            self.work.marker(),
+            work.work_name,
+            work.work_version,
+            work.work_platform,
+            work.work_id
+        )?;
+        let env = self.environment.record();
+        writeln!(
+            f,
+            "{} environment {} ({} {} on {}) {}{}",
+            self.environment.marker(),
+            env.environment_name,
+            env.environment_core_name,
+            env.environment_core_version,
+            env.environment_platform,
+            env.environment_id,
+            if matches!(self.environment, Step::Update(_)) {
+                ", new config"
+            } else {
+                ""
+            }
+        )?;
+        writeln!(
+            f,
+            "{} instance {}{}",
+            self.instance.marker(),
+            self.instance.record().instance_id,
+            if matches!(self.instance, Step::Update(_)) {
+                ", new config"
+            } else {
+                ""
+            }
+        )?;
+        for link in &self.links {
+            match link {
+                LinkStep::Keep {
+                    role,
+                    index,
+                    file_name,
+                } => writeln!(f, "= {} {index}: {file_name}", role_name(*role))?,
+                LinkStep::Link { role, index, file } => {
+                    writeln!(f, "+ {} {index}: {}", role_name(*role), file.path.display())?
+                }
+                LinkStep::Replace { old, file } => writeln!(
+                    f,
+                    "~ {} {}: {} -> {}",
+                    role_name(old.object_role),
+                    old.object_role_index,
+                    old.file_filename,
+                    file.path.display()
+                )?,
+                LinkStep::Unlink { old } => writeln!(
+                    f,
+                    "- {} {}: {}",
+                    role_name(old.object_role),
+                    old.object_role_index,
+                    old.file_filename
+                )?,
+            }
+        }
+        for state in &self.states {
+            match state {
+                StateStep::Keep { state_id, name } => writeln!(f, "= state {name} {state_id}")?,
+                StateStep::Create { name, path, .. } => {
+                    writeln!(f, "+ state {name}: {}", path.display())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const MANIFEST: &str = r#"
+creator = "0c8e5e2a-3b1f-4f6e-9a57-2d6f4c1b7e90"
+
+[work]
+name = "Commander Keen"
+version = "1.4"
+platform = "DOS"
+
+[environment]
+name = "MS-DOS 6.22"
+framework = "v86"
+core = { name = "v86" }
+config = { bios = "seabios" }
+
+[[objects]]
+role = "content"
+path = "keen.img"
+
+[[objects]]
+role = "dependency"
+path = "dos.img"
+
+[[states]]
+name = "Title screen"
+path = "title.v86state"
+screenshot = "title.png"
+"#;
+
+    fn manifest() -> Manifest {
+        toml::from_str(MANIFEST).unwrap()
+    }
+
+    fn link(role: ObjectRole, hash: &str, name: &str) -> ObjectLink {
+        ObjectLink {
+            object_id: Uuid::new_v4(),
+            object_role: role,
+            object_role_index: 0,
+            file_hash: hash.to_string(),
+            file_filename: name.to_string(),
+            file_source_path: String::new(),
+            file_dest_path: String::new(),
+        }
+    }
+
+    /// What the database holds after `manifest()` has been applied
+    fn applied() -> Existing {
+        let now = chrono::Utc::now();
+        let creator = manifest().creator;
+        let work = Work {
+            work_id: Uuid::new_v4(),
+            work_name: "Commander Keen".to_string(),
+            work_version: "1.4".to_string(),
+            work_platform: "DOS".to_string(),
+            created_on: now,
+            work_derived_from: None,
+            creator_id: creator,
+        };
+        let environment = Environment {
+            environment_id: Uuid::new_v4(),
+            environment_name: "MS-DOS 6.22".to_string(),
+            environment_framework: Framework::V86,
+            environment_platform: "DOS".to_string(),
+            environment_core_name: "v86".to_string(),
+            environment_core_version: "1".to_string(),
+            environment_derived_from: None,
+            environment_config: Some(json!({ "bios": "seabios" })),
+            created_on: now,
+            creator_id: creator,
+        };
+        let instance = Instance {
+            instance_id: Uuid::new_v4(),
+            work_id: work.work_id,
+            environment_id: environment.environment_id,
+            instance_config: None,
+            created_on: now,
+            derived_from_instance: None,
+            derived_from_state: None,
+            creator_id: creator,
+        };
+        let state = State {
+            state_id: Uuid::new_v4(),
+            instance_id: instance.instance_id,
+            is_checkpoint: false,
+            file_id: Uuid::new_v4(),
+            state_name: "Title screen".to_string(),
+            state_description: "Title screen".to_string(),
+            screenshot_id: Uuid::new_v4(),
+            replay_id: None,
+            creator_id: creator.unwrap(),
+            state_replay_index: None,
+            state_derived_from: None,
+            save_derived_from: None,
+            created_on: now,
+            hidden: false,
+        };
+        Existing {
+            work: Some(work),
+            core: Core {
+                core_name: "v86".to_string(),
+                core_version: "1".to_string(),
+                core_metadata: json!({}),
+                core_platform: "DOS".to_string(),
+                created_on: now,
+            },
+            environment: Some(environment),
+            instance: Some(instance),
+            links: vec![
+                link(ObjectRole::Content, "keen", "keen.img"),
+                link(ObjectRole::Dependency, "dos", "dos.img"),
+            ],
+            object_hashes: vec!["keen".to_string(), "dos".to_string()],
+            states: vec![(Some(state), None)],
+        }
+    }
+
+    #[test]
+    fn reapplying_keeps_everything() {
+        let plan = Plan::from_existing(manifest(), applied(), chrono::Utc::now()).unwrap();
+        assert!(matches!(plan.work, Step::Keep(_)));
+        assert!(matches!(plan.environment, Step::Keep(_)));
+        assert!(matches!(plan.instance, Step::Keep(_)));
+        assert!(
+            plan.links
+                .iter()
+                .all(|link| matches!(link, LinkStep::Keep { .. }))
+        );
+        assert!(matches!(plan.states[..], [StateStep::Keep { .. }]));
+        assert!(!plan.has_changes());
+    }
+
+    #[test]
+    fn first_apply_creates_everything() {
+        let existing = Existing {
+            work: None,
+            environment: None,
+            instance: None,
+            links: vec![],
+            states: vec![(None, None)],
+            ..applied()
+        };
+        let plan = Plan::from_existing(manifest(), existing, chrono::Utc::now()).unwrap();
+        assert!(matches!(plan.work, Step::Create(_)));
+        assert!(matches!(plan.environment, Step::Create(_)));
+        let Step::Create(instance) = &plan.instance else {
+            panic!("expected a new instance, got {:?}", plan.instance);
+        };
+        assert_eq!(instance.work_id, plan.work.record().work_id);
+        assert_eq!(
+            instance.environment_id,
+            plan.environment.record().environment_id
+        );
+        assert!(
+            plan.links
+                .iter()
+                .all(|link| matches!(link, LinkStep::Link { .. }))
+        );
+        assert!(matches!(
+            plan.states[..],
+            [StateStep::Create { creator_id, .. }] if Some(creator_id) == manifest().creator
+        ));
+    }
+
+    #[test]
+    fn changed_config_updates() {
+        let mut manifest = manifest();
+        manifest.environment.config = Some(json!({ "bios": "custom" }));
+        manifest.instance.config = Some(json!({ "memory_size": 64 }));
+        let plan = Plan::from_existing(manifest, applied(), chrono::Utc::now()).unwrap();
+        let Step::Update(env) = &plan.environment else {
+            panic!("expected an update, got {:?}", plan.environment);
+        };
+        assert_eq!(env.environment_config, Some(json!({ "bios": "custom" })));
+        let Step::Update(instance) = &plan.instance else {
+            panic!("expected an update, got {:?}", plan.instance);
+        };
+        assert_eq!(instance.instance_config, Some(json!({ "memory_size": 64 })));
+        assert!(matches!(plan.work, Step::Keep(_)));
+        assert!(plan.has_changes());
+    }
+
+    #[test]
+    fn changed_file_replaces_its_link() {
+        let mut existing = applied();
+        let old_id = existing.links[0].object_id;
+        existing.object_hashes[0] = "keen-patched".to_string();
+        let plan = Plan::from_existing(manifest(), existing, chrono::Utc::now()).unwrap();
+        assert!(matches!(
+            &plan.links[..],
+            [LinkStep::Replace { old, file }, LinkStep::Keep { .. }]
+                if old.object_id == old_id && file.path == Path::new("keen.img")
+        ));
+        assert!(plan.has_changes());
+    }
+
+    #[test]
+    fn removed_object_is_unlinked() {
+        let mut manifest = manifest();
+        manifest.objects.pop();
+        let mut existing = applied();
+        existing.object_hashes.pop();
+        let dos_id = existing.links[1].object_id;
+        let plan = Plan::from_existing(manifest, existing, chrono::Utc::now()).unwrap();
+        assert!(matches!(
+            &plan.links[..],
+            [LinkStep::Keep { .. }, LinkStep::Unlink { old }] if old.object_id == dos_id
+        ));
+        assert!(plan.has_changes());
+    }
+
+    #[test]
+    fn states_need_a_creator() {
+        let mut manifest = manifest();
+        manifest.creator = None;
+        assert!(matches!(
+            Plan::from_existing(manifest, applied(), chrono::Utc::now()),
+            Err(GISSTCliError::Manifest(_))
+        ));
+    }
+}
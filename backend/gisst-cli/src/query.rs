@@ -0,0 +1,597 @@
+use crate::args::{GISSTCliError, ListArgs, ListFilter, ListKind, OutputFormat, ShowArgs};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use gisst::models::{
+    Core, Creator, CreatorReplayInfo, CreatorSaveInfo, CreatorStateInfo, Environment, File,
+    Instance, InstanceWork, Object, ObjectLink, Replay, Save, State, Work,
+};
+use gisst::task::Task;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// Longest cell shown in table output before it's cut short
+const MAX_CELL_WIDTH: usize = 48;
+
+/// Records to print, each as a JSON object with fields in declaration order
+struct Listing {
+    rows: Vec<Value>,
+    /// Fields shown in table output; JSON and CSV output have every field
+    columns: &'static [&'static str],
+}
+
+impl Listing {
+    fn new<T: Serialize>(
+        records: impl IntoIterator<Item = T>,
+        columns: &'static [&'static str],
+    ) -> Result<Self, GISSTCliError> {
+        Ok(Self {
+            rows: records
+                .into_iter()
+                .map(serde_json::to_value)
+                .collect::<Result<_, _>>()?,
+            columns,
+        })
+    }
+}
+
+/// The filters a kind of record has fields for
+#[allow(clippy::struct_excessive_bools)]
+struct Filterable {
+    platform: bool,
+    creator: bool,
+    dates: bool,
+    instance: bool,
+}
+
+impl Filterable {
+    const ALL: Self = Self {
+        platform: true,
+        creator: true,
+        dates: true,
+        instance: true,
+    };
+}
+
+impl ListFilter {
+    /// Refuses filters that `listing` has no field for, which would otherwise
+    /// quietly match nothing
+    fn check(&self, listing: &str, can: &Filterable) -> Result<(), GISSTCliError> {
+        let given = [
+            ("platform", self.platform.is_some(), can.platform),
+            ("creator", self.creator.is_some(), can.creator),
+            ("since", self.since.is_some(), can.dates),
+            ("until", self.until.is_some(), can.dates),
+            ("instance", self.instance.is_some(), can.instance),
+        ];
+        match given.into_iter().find(|(_, given, can)| *given && !*can) {
+            Some((flag, _, _)) => Err(GISSTCliError::UnsupportedFilter(flag, listing.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a record with these fields passes every given filter
+    fn matches(
+        &self,
+        platform: Option<&str>,
+        creator: Option<Uuid>,
+        created_on: Option<DateTime<Utc>>,
+        instance: Option<Uuid>,
+    ) -> bool {
+        self.platform.as_deref().is_none_or(|p| platform == Some(p))
+            && self.creator.is_none_or(|c| creator == Some(c))
+            && self
+                .since
+                .is_none_or(|since| created_on.is_some_and(|on| on >= since))
+            && self
+                .until
+                .is_none_or(|until| created_on.is_some_and(|on| on < until))
+            && self.instance.is_none_or(|i| instance == Some(i))
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+pub async fn list(conn: &mut PgConnection, args: ListArgs) -> Result<(), GISSTCliError> {
+    let filter = &args.filter;
+    let filterable = match args.kind {
+        ListKind::Instance | ListKind::State | ListKind::Replay | ListKind::Save => Filterable::ALL,
+        ListKind::Work | ListKind::Environment => Filterable {
+            instance: false,
+            ..Filterable::ALL
+        },
+        ListKind::Core => Filterable {
+            creator: false,
+            instance: false,
+            ..Filterable::ALL
+        },
+        // An instance's object links have neither creators nor dates
+        ListKind::Object if filter.instance.is_some() => Filterable {
+            platform: false,
+            creator: false,
+            dates: false,
+            instance: true,
+        },
+        ListKind::Object => Filterable {
+            platform: false,
+            ..Filterable::ALL
+        },
+        ListKind::Task => Filterable {
+            platform: false,
+            creator: false,
+            dates: true,
+            instance: false,
+        },
+        ListKind::Creator => Filterable {
+            platform: false,
+            instance: false,
+            ..Filterable::ALL
+        },
+    };
+    let listing_name = if args.kind == ListKind::Object && filter.instance.is_some() {
+        "objects of an instance".to_string()
+    } else {
+        format!("{}s", args.kind)
+    };
+    filter.check(&listing_name, &filterable)?;
+    let mut listing = match args.kind {
+        ListKind::Work => {
+            let works = if let Some(platform) = &filter.platform {
+                Work::get_works_for_platform(conn, platform).await?
+            } else {
+                Work::get_all(conn).await?
+            };
+            Listing::new(
+                works.into_iter().filter(|w| {
+                    filter.matches(
+                        Some(w.work_platform.as_str()),
+                        w.creator_id,
+                        Some(w.created_on),
+                        None,
+                    )
+                }),
+                &[
+                    "work_id",
+                    "work_name",
+                    "work_version",
+                    "work_platform",
+                    "created_on",
+                ],
+            )?
+        }
+        ListKind::Instance => Listing::new(
+            InstanceWork::fetch_stream(conn)
+                .try_collect::<Vec<_>>()
+                .await?
+                .into_iter()
+                .filter(|i| {
+                    filter.matches(
+                        Some(i.work_platform.as_str()),
+                        i.work_creator,
+                        Some(i.instance_created_on),
+                        Some(i.instance_id),
+                    )
+                }),
+            &[
+                "instance_id",
+                "work_name",
+                "work_version",
+                "work_platform",
+                "environment_name",
+                "instance_created_on",
+            ],
+        )?,
+        ListKind::Environment => Listing::new(
+            Environment::get_all(conn).await?.into_iter().filter(|e| {
+                filter.matches(
+                    Some(e.environment_platform.as_str()),
+                    e.creator_id,
+                    Some(e.created_on),
+                    None,
+                )
+            }),
+            &[
+                "environment_id",
+                "environment_name",
+                "environment_framework",
+                "environment_platform",
+                "environment_core_name",
+                "environment_core_version",
+            ],
+        )?,
+        ListKind::Core => Listing::new(
+            Core::get_latest(conn).await?.into_iter().filter(|c| {
+                filter.matches(
+                    Some(c.core_platform.as_str()),
+                    None,
+                    Some(c.created_on),
+                    None,
+                )
+            }),
+            &["core_name", "core_version", "core_platform", "created_on"],
+        )?,
+        ListKind::Object => {
+            if let Some(instance_id) = filter.instance {
+                Listing::new(
+                    ObjectLink::get_all_for_instance_id(conn, instance_id)
+                        .await?
+                        .into_iter()
+                        .filter(|_| filter.matches(None, None, None, Some(instance_id))),
+                    &[
+                        "object_id",
+                        "object_role",
+                        "object_role_index",
+                        "file_filename",
+                        "file_hash",
+                    ],
+                )?
+            } else {
+                Listing::new(
+                    Object::get_all(conn)
+                        .await?
+                        .into_iter()
+                        .filter(|o| filter.matches(None, o.creator_id, Some(o.created_on), None)),
+                    &["object_id", "file_id", "object_description", "created_on"],
+                )?
+            }
+        }
+        ListKind::State => Listing::new(
+            CreatorStateInfo::fetch_stream(conn)
+                .try_collect::<Vec<_>>()
+                .await?
+                .into_iter()
+                .filter(|s| {
+                    filter.matches(
+                        Some(s.work_platform.as_str()),
+                        Some(s.creator_id),
+                        Some(s.created_on),
+                        Some(s.instance_id),
+                    )
+                }),
+            &[
+                "state_id",
+                "state_name",
+                "work_name",
+                "instance_id",
+                "creator_username",
+                "created_on",
+            ],
+        )?,
+        ListKind::Replay => Listing::new(
+            CreatorReplayInfo::fetch_stream(conn)
+                .try_collect::<Vec<_>>()
+                .await?
+                .into_iter()
+                .filter(|r| {
+                    filter.matches(
+                        Some(r.work_platform.as_str()),
+                        Some(r.creator_id),
+                        Some(r.created_on),
+                        Some(r.instance_id),
+                    )
+                }),
+            &[
+                "replay_id",
+                "replay_name",
+                "work_name",
+                "instance_id",
+                "creator_username",
+                "created_on",
+            ],
+        )?,
+        ListKind::Save => Listing::new(
+            CreatorSaveInfo::fetch_stream(conn)
+                .try_collect::<Vec<_>>()
+                .await?
+                .into_iter()
+                .filter(|s| {
+                    filter.matches(
+                        Some(s.work_platform.as_str()),
+                        Some(s.creator_id),
+                        Some(s.created_on),
+                        Some(s.instance_id),
+                    )
+                }),
+            &[
+                "save_id",
+                "save_short_desc",
+                "work_name",
+                "instance_id",
+                "creator_username",
+                "created_on",
+            ],
+        )?,
+        ListKind::Task => Listing::new(
+            Task::get_tasks(conn, args.task_state, args.task_type.as_deref())
+                .await?
+                .into_iter()
+                .filter(|t| filter.matches(None, None, Some(t.task_created_on), None)),
+            &[
+                "task_id",
+                "task_type",
+                "task_state",
+                "task_retry_count",
+                "task_created_on",
+                "task_updated_on",
+            ],
+        )?,
+        ListKind::Creator => Listing::new(
+            Creator::fetch_stream(conn)
+                .try_collect::<Vec<_>>()
+                .await?
+                .into_iter()
+                .filter(|c| filter.matches(None, Some(c.creator_id), Some(c.created_on), None)),
+            &[
+                "creator_id",
+                "creator_username",
+                "creator_full_name",
+                "created_on",
+            ],
+        )?,
+    };
+    if let Some(limit) = args.limit {
+        listing.rows.truncate(limit);
+    }
+    match args.format {
+        OutputFormat::Table if listing.rows.is_empty() => println!("No {}s found.", args.kind),
+        OutputFormat::Table => print_table(&listing),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&listing.rows)?),
+        OutputFormat::Csv => print_csv(&listing.rows),
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_lines)]
+pub async fn show(conn: &mut PgConnection, args: ShowArgs) -> Result<(), GISSTCliError> {
+    let not_found = || GISSTCliError::MissingRecord(args.kind, args.id.clone());
+    let mut related = vec![];
+    let record = if args.kind == ListKind::Core {
+        // Cores are keyed by name and version, so show the latest with the rest listed
+        let mut versions = Core::get_versions(conn, &args.id).await?;
+        if versions.is_empty() {
+            return Err(not_found());
+        }
+        let latest = serde_json::to_value(versions.remove(0))?;
+        related.push((
+            "older_versions",
+            Listing::new(
+                versions,
+                &["core_name", "core_version", "core_platform", "created_on"],
+            )?,
+        ));
+        latest
+    } else {
+        let id = Uuid::parse_str(&args.id)?;
+        match args.kind {
+            ListKind::Work => {
+                let work = Work::get_by_id(conn, id).await?.ok_or_else(not_found)?;
+                related.push((
+                    "instances",
+                    Listing::new(
+                        Instance::get_all_for_work_id(conn, id).await?,
+                        &["instance_id", "environment_id", "created_on"],
+                    )?,
+                ));
+                serde_json::to_value(work)?
+            }
+            ListKind::Instance => {
+                let instance = Instance::get_by_id(conn, id).await?.ok_or_else(not_found)?;
+                related.push((
+                    "objects",
+                    Listing::new(
+                        ObjectLink::get_all_for_instance_id(conn, id).await?,
+                        &[
+                            "object_id",
+                            "object_role",
+                            "object_role_index",
+                            "file_filename",
+                        ],
+                    )?,
+                ));
+                related.push((
+                    "states",
+                    Listing::new(
+                        State::get_all_for_instance(conn, id).await?,
+                        &["state_id", "state_name", "created_on"],
+                    )?,
+                ));
+                related.push((
+                    "saves",
+                    Listing::new(
+                        Save::get_all_for_instance(conn, id).await?,
+                        &["save_id", "save_short_desc", "created_on"],
+                    )?,
+                ));
+                related.push((
+                    "replays",
+                    Listing::new(
+                        Replay::get_all_for_instance(conn, id).await?,
+                        &["replay_id", "replay_name", "created_on"],
+                    )?,
+                ));
+                serde_json::to_value(instance)?
+            }
+            ListKind::Environment => {
+                let environment = Environment::get_by_id(conn, id)
+                    .await?
+                    .ok_or_else(not_found)?;
+                related.push((
+                    "instances",
+                    Listing::new(
+                        Instance::get_all_for_environment_id(conn, id).await?,
+                        &["instance_id", "work_id", "created_on"],
+                    )?,
+                ));
+                serde_json::to_value(environment)?
+            }
+            ListKind::Object => {
+                let object = Object::get_by_id(conn, id).await?.ok_or_else(not_found)?;
+                related.push(file_listing(conn, object.file_id).await?);
+                related.push((
+                    "children",
+                    Listing::new(
+                        Object::get_children(conn, id).await?,
+                        &["object_id", "object_parent_path", "file_id"],
+                    )?,
+                ));
+                serde_json::to_value(object)?
+            }
+            ListKind::State => {
+                let state = State::get_by_id(conn, id).await?.ok_or_else(not_found)?;
+                related.push(file_listing(conn, state.file_id).await?);
+                serde_json::to_value(state)?
+            }
+            ListKind::Save => {
+                let save = Save::get_by_id(conn, id).await?.ok_or_else(not_found)?;
+                related.push(file_listing(conn, save.file_id).await?);
+                serde_json::to_value(save)?
+            }
+            ListKind::Replay => {
+                let replay = Replay::get_by_id(conn, id).await?.ok_or_else(not_found)?;
+                related.push(file_listing(conn, replay.file_id).await?);
+                serde_json::to_value(replay)?
+            }
+            ListKind::Task => {
+                serde_json::to_value(Task::get_by_id(conn, id).await?.ok_or_else(not_found)?)?
+            }
+            ListKind::Creator => {
+                serde_json::to_value(Creator::get_by_id(conn, id).await?.ok_or_else(not_found)?)?
+            }
+            ListKind::Core => unreachable!("cores are looked up by name"),
+        }
+    };
+
+    match args.format {
+        OutputFormat::Table => {
+            let Value::Object(fields) = &record else {
+                unreachable!("records serialize to objects");
+            };
+            let width = fields.keys().map(String::len).max().unwrap_or_default();
+            for (name, value) in fields {
+                println!("{name:width$}  {}", cell(value));
+            }
+            for (name, listing) in &related {
+                println!("\n{name}:");
+                if listing.rows.is_empty() {
+                    println!("(none)");
+                } else {
+                    print_table(listing);
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let mut record = record;
+            if let Value::Object(fields) = &mut record {
+                for (name, listing) in related {
+                    fields.insert(name.to_string(), Value::Array(listing.rows));
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&record)?);
+        }
+        OutputFormat::Csv => print_csv(&[record]),
+    }
+    Ok(())
+}
+
+/// The file backing a record, as its own listing
+async fn file_listing(
+    conn: &mut PgConnection,
+    file_id: Uuid,
+) -> Result<(&'static str, Listing), GISSTCliError> {
+    Ok((
+        "file",
+        Listing::new(
+            File::get_by_id(conn, file_id).await?,
+            &["file_id", "file_filename", "file_size", "file_hash"],
+        )?,
+    ))
+}
+
+/// A value as text: strings bare, nulls empty, and anything else as JSON
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn print_table(listing: &Listing) {
+    let rows: Vec<Vec<String>> = listing
+        .rows
+        .iter()
+        .map(|row| {
+            listing
+                .columns
+                .iter()
+                .map(|column| {
+                    let text = row.get(column).map(cell).unwrap_or_default();
+                    if text.chars().count() > MAX_CELL_WIDTH {
+                        let mut cut: String = text.chars().take(MAX_CELL_WIDTH - 3).collect();
+                        cut.push_str("...");
+                        cut
+                    } else {
+                        text
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = listing
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain([column.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let header: Vec<String> = listing.columns.iter().map(ToString::to_string).collect();
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    for cells in [&header, &rule].into_iter().chain(&rows) {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(text, width)| format!("{text:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Prints every field of the rows, headed by the first row's field names
+fn print_csv(rows: &[Value]) {
+    let Some(Value::Object(first)) = rows.first() else {
+        return;
+    };
+    let header: Vec<&String> = first.keys().collect();
+    println!(
+        "{}",
+        header
+            .iter()
+            .map(|name| csv_field(name))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    for row in rows {
+        println!(
+            "{}",
+            header
+                .iter()
+                .map(|name| csv_field(&row.get(name.as_str()).map(cell).unwrap_or_default()))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+    }
+}
+
+/// Quotes a field if it holds a comma, quote, or line break, as in RFC 4180
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,564 @@
+//! Bulk import for `gisst-cli import-recordings`: every state, save, or
+//! replay file under a folder with a sidecar `<file>.json` or `<file>.toml`
+//! beside it is stored and recorded, one transaction per file. Recordings whose
+//! data is already recorded as the same kind on the same instance are skipped,
+//! so an interrupted import can be run again as is. Replays with checkpoints
+//! queue a task to store them as states, as uploaded replays do.
+//!
+//! ```toml
+//! # title.v86state.toml
+//! kind = "state"
+//! instance = "8d4c3f0e-0b7e-4a4e-9d55-35f3e1b0b3a1"
+//! name = "Title screen"
+//! screenshot = "title.png"
+//! ```
+//!
+//! Sidecars can give their record an `id`, which other sidecars in the same
+//! import can use in `derived_from`, `from_state`, `from_save`, or
+//! `from_replay`; those recordings are imported after the ones they refer to.
+
+use crate::args::GISSTCliError;
+use chrono::{DateTime, Utc};
+use gisst::models::{Environment, File, Instance, Replay, Save, State, insert_new_file};
+use gisst::search::{NullIndexer, SearchIndexer};
+use gisst::storage::StorageHandler;
+use gisst::task::{REPLAY_CHECKPOINTS_TASK, Task};
+use log::warn;
+use serde::Deserialize;
+use sqlx::Connection;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingKind {
+    State,
+    Save,
+    Replay,
+}
+
+impl fmt::Display for RecordingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            RecordingKind::State => "state",
+            RecordingKind::Save => "save",
+            RecordingKind::Replay => "replay",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Metadata for one recording, read from the file beside it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sidecar {
+    pub kind: RecordingKind,
+    /// Defaults to the instance given on the command line
+    pub instance: Option<Uuid>,
+    /// Id for the new record, so that other sidecars can refer to it
+    pub id: Option<Uuid>,
+    pub name: String,
+    pub description: Option<String>,
+    /// Defaults to the creator given on the command line; one of the two is
+    /// needed
+    pub creator: Option<Uuid>,
+    pub created_on: Option<DateTime<Utc>>,
+    /// The state, save, or replay of the same kind this one continues from
+    pub derived_from: Option<Uuid>,
+    /// For saves, the state they were made from
+    pub from_state: Option<Uuid>,
+    /// For states, the save they were loaded from
+    pub from_save: Option<Uuid>,
+    /// For saves, the replay they were made during
+    pub from_replay: Option<Uuid>,
+    /// PNG shown for a state, relative to the sidecar; states need one
+    pub screenshot: Option<PathBuf>,
+}
+
+impl Sidecar {
+    /// Reads the sidecar for `path`, if it has one, resolving its paths
+    /// against the sidecar's folder
+    fn load(path: &Path) -> Result<Option<Self>, GISSTCliError> {
+        let base = path.parent().unwrap_or(Path::new(""));
+        let mut sidecar: Sidecar = if let Some(json) = with_suffix(path, "json")
+            && json.is_file()
+        {
+            serde_json::from_str(&std::fs::read_to_string(&json)?)
+                .map_err(|e| GISSTCliError::Sidecar(format!("{}: {e}", json.display())))?
+        } else if let Some(toml) = with_suffix(path, "toml")
+            && toml.is_file()
+        {
+            toml::from_str(&std::fs::read_to_string(&toml)?)
+                .map_err(|e| GISSTCliError::Sidecar(format!("{}: {e}", toml.display())))?
+        } else {
+            return Ok(None);
+        };
+        if let Some(screenshot) = &mut sidecar.screenshot {
+            *screenshot = base.join(&screenshot);
+        }
+        let misplaced = match sidecar.kind {
+            RecordingKind::State if sidecar.screenshot.is_none() => {
+                Some("states need a screenshot")
+            }
+            RecordingKind::State
+                if sidecar.from_state.is_some() || sidecar.from_replay.is_some() =>
+            {
+                Some("from_state and from_replay only apply to saves")
+            }
+            RecordingKind::Save if sidecar.from_save.is_some() => {
+                Some("from_save only applies to states")
+            }
+            RecordingKind::Save | RecordingKind::Replay if sidecar.screenshot.is_some() => {
+                Some("screenshot only applies to states")
+            }
+            RecordingKind::Replay
+                if sidecar.from_state.is_some()
+                    || sidecar.from_save.is_some()
+                    || sidecar.from_replay.is_some() =>
+            {
+                Some("replays can only use derived_from")
+            }
+            _ => None,
+        };
+        if let Some(reason) = misplaced {
+            return Err(GISSTCliError::Sidecar(format!(
+                "{}: {reason}",
+                path.display()
+            )));
+        }
+        Ok(Some(sidecar))
+    }
+
+    /// Records this one refers to
+    fn references(&self) -> impl Iterator<Item = Uuid> {
+        [
+            self.derived_from,
+            self.from_state,
+            self.from_save,
+            self.from_replay,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// `path` with `.suffix` added after its extension, e.g. `a.state` -> `a.state.json`
+fn with_suffix(path: &Path, suffix: &str) -> Option<PathBuf> {
+    let mut name = path.file_name()?.to_os_string();
+    name.push(".");
+    name.push(suffix);
+    Some(path.with_file_name(name))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+/// A recording file with its sidecar
+#[derive(Debug)]
+struct Recording {
+    path: PathBuf,
+    sidecar: Sidecar,
+}
+
+/// Finds the recordings under `dir`, ordered so that none comes before a
+/// recording it refers to, along with the files whose sidecars can't be used
+#[allow(clippy::type_complexity)]
+fn find_recordings(
+    dir: &Path,
+) -> Result<(Vec<Recording>, Vec<(PathBuf, GISSTCliError)>), GISSTCliError> {
+    let mut pending = vec![];
+    let mut failed = vec![];
+    for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path();
+        let is_sidecar = path
+            .extension()
+            .is_some_and(|ext| ext == "json" || ext == "toml");
+        if !entry.file_type().is_file() || is_sidecar {
+            continue;
+        }
+        match Sidecar::load(path) {
+            Ok(Some(sidecar)) => pending.push(Recording {
+                path: path.to_path_buf(),
+                sidecar,
+            }),
+            Ok(None) => (),
+            Err(err) => failed.push((path.to_path_buf(), err)),
+        }
+    }
+    let mut waiting_on: HashSet<Uuid> = pending.iter().filter_map(|r| r.sidecar.id).collect();
+    let mut ordered = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let (ready, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|recording| {
+            recording
+                .sidecar
+                .references()
+                .all(|id| !waiting_on.contains(&id))
+        });
+        if ready.is_empty() {
+            failed.extend(rest.into_iter().map(|recording| {
+                (
+                    recording.path,
+                    GISSTCliError::Sidecar(
+                        "refers to other recordings that refer back to it".to_string(),
+                    ),
+                )
+            }));
+            break;
+        }
+        for recording in &ready {
+            if let Some(id) = recording.sidecar.id {
+                waiting_on.remove(&id);
+            }
+        }
+        ordered.extend(ready);
+        pending = rest;
+    }
+    Ok((ordered, failed))
+}
+
+/// What happened to each recording in an import
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub imported: Vec<(RecordingKind, Uuid, PathBuf)>,
+    /// Recordings already in the database, with their existing record
+    pub skipped: Vec<(RecordingKind, Uuid, PathBuf)>,
+    pub failed: Vec<(PathBuf, GISSTCliError)>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (kind, id, path) in &self.imported {
+            writeln!(f, "+ {kind} {id} from {}", path.display())?;
+        }
+        for (kind, id, path) in &self.skipped {
+            writeln!(
+                f,
+                "= {kind} {id} from {} (already imported)",
+                path.display()
+            )?;
+        }
+        for (path, err) in &self.failed {
+            write!(f, "! {}: {err}", path.display())?;
+            let mut source = std::error::Error::source(err);
+            while let Some(err) = source {
+                write!(f, ": {err}")?;
+                source = std::error::Error::source(err);
+            }
+            writeln!(f)?;
+        }
+        let count = |kind| self.imported.iter().filter(|(k, _, _)| *k == kind).count();
+        writeln!(
+            f,
+            "Imported {} states, {} saves, and {} replays; skipped {} already imported; {} failed.",
+            count(RecordingKind::State),
+            count(RecordingKind::Save),
+            count(RecordingKind::Replay),
+            self.skipped.len(),
+            self.failed.len()
+        )
+    }
+}
+
+/// Imports every recording under `dir`. A recording that fails to import is
+/// rolled back and reported in the summary without stopping the others.
+pub async fn import(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    dir: &Path,
+    default_instance: Option<Uuid>,
+    default_creator: Option<Uuid>,
+    indexer: &impl SearchIndexer,
+) -> Result<Summary, GISSTCliError> {
+    let (recordings, failed) = find_recordings(dir)?;
+    let mut summary = Summary {
+        failed,
+        ..Summary::default()
+    };
+    for recording in recordings {
+        let kind = recording.sidecar.kind;
+        match import_one(
+            conn,
+            storage_root,
+            depth,
+            &recording,
+            default_instance,
+            default_creator,
+            indexer,
+        )
+        .await
+        {
+            Ok(Imported::New(id)) => summary.imported.push((kind, id, recording.path)),
+            Ok(Imported::Existing(id)) => summary.skipped.push((kind, id, recording.path)),
+            Err(err) => summary.failed.push((recording.path, err)),
+        }
+    }
+    Ok(summary)
+}
+
+enum Imported {
+    New(Uuid),
+    Existing(Uuid),
+}
+
+/// The record an import stored, to be indexed once it commits
+enum Stored {
+    State(State),
+    Save(Save),
+    Replay(Replay),
+}
+
+/// The storage id and file name of a stored file, for removing it again
+fn stored_file(file: &File) -> (Uuid, String) {
+    (file.file_id, file_name(Path::new(&file.file_dest_path)))
+}
+
+/// Stores one recording's files and record in a transaction, indexing the
+/// record once that commits. Files written for a recording that fails are
+/// removed again; a record that commits but can't be indexed is only warned
+/// about, since a re-run would skip it.
+async fn import_one(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    recording: &Recording,
+    default_instance: Option<Uuid>,
+    default_creator: Option<Uuid>,
+    indexer: &impl SearchIndexer,
+) -> Result<Imported, GISSTCliError> {
+    let Recording { path, sidecar } = recording;
+    let instance_id = sidecar.instance.or(default_instance).ok_or_else(|| {
+        GISSTCliError::Sidecar("no instance in the sidecar and no --instance given".to_string())
+    })?;
+    let creator_id = sidecar.creator.or(default_creator).ok_or_else(|| {
+        GISSTCliError::Sidecar("no creator in the sidecar and no --creator given".to_string())
+    })?;
+    let hash = StorageHandler::get_file_hash(path)?;
+    let existing = match sidecar.kind {
+        RecordingKind::State => State::get_by_hash(conn, &hash)
+            .await?
+            .filter(|state| state.instance_id == instance_id)
+            .map(|state| state.state_id),
+        RecordingKind::Save => Save::get_by_hash(conn, &hash)
+            .await?
+            .filter(|save| save.instance_id == instance_id)
+            .map(|save| save.save_id),
+        RecordingKind::Replay => Replay::get_by_hash(conn, &hash)
+            .await?
+            .filter(|replay| replay.instance_id == instance_id)
+            .map(|replay| replay.replay_id),
+    };
+    if let Some(id) = existing {
+        return Ok(Imported::Existing(id));
+    }
+
+    let instance = Instance::get_by_id(conn, instance_id)
+        .await?
+        .ok_or(GISSTCliError::RecordNotFound(instance_id))?;
+    // Check replays before storing anything
+    let replay_info = if sidecar.kind == RecordingKind::Replay {
+        let environment = Environment::get_by_id(conn, instance.environment_id)
+            .await?
+            .ok_or(GISSTCliError::RecordNotFound(instance.environment_id))?;
+        let info = gisst::replay::parse_path(path)?;
+        info.check_framework(environment.environment_framework)?;
+        Some(info)
+    } else {
+        None
+    };
+
+    // Files written to storage so far, to be removed again if the import fails
+    let mut written = vec![];
+    let result = async {
+        let mut tx = conn.begin().await?;
+        let stored = store_recording(
+            &mut tx,
+            storage_root,
+            depth,
+            recording,
+            &hash,
+            instance_id,
+            creator_id,
+            replay_info.as_ref(),
+            &mut written,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok::<_, GISSTCliError>(stored)
+    }
+    .await;
+    let stored = match result {
+        Ok(stored) => stored,
+        Err(err) => {
+            for (file_uuid, dest_filename) in written {
+                StorageHandler::delete_file_with_uuid(
+                    storage_root,
+                    depth,
+                    file_uuid,
+                    &dest_filename,
+                )
+                .await?;
+            }
+            return Err(err);
+        }
+    };
+    let (id, indexed) = match &stored {
+        Stored::State(state) => (state.state_id, indexer.upsert_state(conn, state).await),
+        Stored::Save(save) => (save.save_id, indexer.upsert_save(conn, save).await),
+        Stored::Replay(replay) => (replay.replay_id, indexer.upsert_replay(conn, replay).await),
+    };
+    if let Err(err) = indexed {
+        warn!(
+            "Imported {} {id} from {} but could not index it: {err}",
+            sidecar.kind,
+            path.display()
+        );
+    }
+    Ok(Imported::New(id))
+}
+
+/// Stores a recording's files and record in `conn`, adding each file it
+/// writes to `written`
+#[allow(clippy::too_many_arguments, clippy::too_many_lines)]
+async fn store_recording(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    recording: &Recording,
+    hash: &str,
+    instance_id: Uuid,
+    creator_id: Uuid,
+    replay_info: Option<&gisst::replay::ReplayInfo>,
+    written: &mut Vec<(Uuid, String)>,
+) -> Result<Stored, GISSTCliError> {
+    let Recording { path, sidecar } = recording;
+    let id = sidecar.id.unwrap_or_else(Uuid::new_v4);
+    let created_on = sidecar.created_on.unwrap_or_else(Utc::now);
+    let name = sidecar.name.clone();
+    let description = sidecar.description.clone().unwrap_or_else(|| name.clone());
+
+    let file_id = if let Some(file) = File::get_by_hash(conn, hash).await? {
+        file.file_id
+    } else {
+        let file = insert_new_file(
+            conn,
+            storage_root,
+            depth,
+            &file_name(path),
+            path,
+            "",
+            created_on,
+            Some(creator_id),
+        )
+        .await?;
+        written.push(stored_file(&file));
+        file.file_id
+    };
+    match sidecar.kind {
+        RecordingKind::State => {
+            let Some(screenshot_path) = &sidecar.screenshot else {
+                return Err(GISSTCliError::Sidecar(
+                    "states need a screenshot".to_string(),
+                ));
+            };
+            let screenshot_id = Uuid::new_v4();
+            let screenshot = gisst::screenshot::store_screenshot(
+                conn,
+                storage_root,
+                depth,
+                &std::fs::read(screenshot_path)?,
+                screenshot_id,
+                Some(creator_id),
+            )
+            .await?;
+            if screenshot.screenshot_id == screenshot_id {
+                for file_id in [screenshot.file_id, screenshot.thumbnail_file_id]
+                    .into_iter()
+                    .flatten()
+                {
+                    if let Some(file) = File::get_by_id(conn, file_id).await? {
+                        written.push(stored_file(&file));
+                    }
+                }
+            }
+            let state = State {
+                state_id: id,
+                instance_id,
+                is_checkpoint: false,
+                file_id,
+                state_name: name,
+                state_description: description,
+                screenshot_id: screenshot.screenshot_id,
+                replay_id: None,
+                creator_id,
+                state_replay_index: None,
+                state_derived_from: sidecar.derived_from,
+                save_derived_from: sidecar.from_save,
+                created_on,
+                hidden: false,
+            };
+            Ok(Stored::State(
+                State::insert(conn, state, &NullIndexer).await?,
+            ))
+        }
+        RecordingKind::Save => {
+            let save = Save {
+                save_id: id,
+                instance_id,
+                save_short_desc: name,
+                save_description: description,
+                file_id,
+                creator_id,
+                created_on,
+                state_derived_from: sidecar.from_state,
+                save_derived_from: sidecar.derived_from,
+                replay_derived_from: sidecar.from_replay,
+                hidden: false,
+            };
+            Ok(Stored::Save(Save::insert(conn, save, &NullIndexer).await?))
+        }
+        RecordingKind::Replay => {
+            let replay = Replay {
+                replay_id: id,
+                replay_name: name,
+                replay_description: description,
+                instance_id,
+                creator_id,
+                replay_forked_from: sidecar.derived_from,
+                file_id,
+                created_on,
+                hidden: false,
+                video_id: None,
+                replay_frame_count: None,
+                replay_duration: None,
+                replay_checkpoint_count: None,
+                replay_checkpoints: None,
+                replay_identifier: None,
+                replay_content_crc: None,
+            };
+            let replay = match replay_info {
+                Some(info) => replay.with_file_info(info),
+                None => replay,
+            };
+            let replay = Replay::insert(conn, replay, &NullIndexer).await?;
+            if replay_info.is_some_and(|info| !info.checkpoints.is_empty()) {
+                // Checkpoint states are made by a worker, as for uploaded replays
+                Task::create(
+                    conn,
+                    REPLAY_CHECKPOINTS_TASK,
+                    serde_json::json!({
+                        "replay_id": replay.replay_id,
+                        "creator_id": replay.creator_id,
+                    }),
+                )
+                .await?;
+            }
+            Ok(Stored::Replay(replay))
+        }
+    }
+}
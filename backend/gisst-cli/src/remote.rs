@@ -0,0 +1,461 @@
+//! Runs commands through a GISST server's HTTP API, for machines without
+//! access to its database or storage. Files go up through the server's tus
+//! endpoints, and requests carry a user API token, so new records belong to
+//! that user. The server picks IDs for the new records, so `--force-uuid`
+//! style flags are ignored; each new record is printed as JSON.
+
+use crate::args::{
+    AddWorkInstanceData, BaseSubcommand, Commands, CreateEnvironment, CreateInstance, CreateObject,
+    CreateWork, GISSTCliError, ObjectSubcommand,
+};
+use base64::{Engine, engine::general_purpose};
+use gisst::models::{Environment, Instance, Object, ObjectLink, ObjectRole, Work};
+use gisst::storage::StorageHandler;
+use log::{debug, info, warn};
+use reqwest::header;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+/// Bytes sent per tus PATCH, well under the server's request body limit
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+pub struct RemoteClient {
+    http: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+impl RemoteClient {
+    pub fn new(base_url: &str, token: &str) -> Result<Self, GISSTCliError> {
+        Ok(Self {
+            http: reqwest::Client::builder().build()?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+        })
+    }
+
+    fn bearer(&self) -> String {
+        format!("Bearer {}", self.token)
+    }
+
+    async fn check(response: reqwest::Response) -> Result<reqwest::Response, GISSTCliError> {
+        let status = response.status();
+        if status.is_success() {
+            Ok(response)
+        } else {
+            let body = response.text().await.unwrap_or_default();
+            Err(GISSTCliError::Server { status, body })
+        }
+    }
+
+    async fn post<T: Serialize + ?Sized, R: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<R, GISSTCliError> {
+        let response = self
+            .http
+            .post(format!("{}{path}", self.base_url))
+            .header(header::AUTHORIZATION, self.bearer())
+            .header(header::ACCEPT, "application/json")
+            .json(body)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Uploads a file through the tus endpoints and returns the new file record's ID
+    pub async fn upload(&self, path: &Path) -> Result<Uuid, GISSTCliError> {
+        let hash = {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || StorageHandler::get_file_hash(path))
+                .await
+                .map_err(std::io::Error::other)??
+        };
+        let filename = path
+            .file_name()
+            .map_or_else(|| hash.clone(), |name| name.to_string_lossy().to_string());
+        let length = tokio::fs::metadata(path).await?.len();
+        let metadata = format!(
+            "filename {},hash {}",
+            general_purpose::STANDARD.encode(&filename),
+            general_purpose::STANDARD.encode(&hash)
+        );
+        let response = Self::check(
+            self.http
+                .post(format!("{}/resources", self.base_url))
+                .header(header::AUTHORIZATION, self.bearer())
+                .header("Tus-Resumable", "1.0.0")
+                .header("Upload-Length", length.to_string())
+                .header("Upload-Metadata", metadata)
+                .send()
+                .await?,
+        )
+        .await?;
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|loc| loc.to_str().ok())
+            .ok_or(GISSTCliError::TusLocation)?
+            .to_string();
+        let file_id = location
+            .rsplit('/')
+            .next()
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .ok_or(GISSTCliError::TusLocation)?;
+        info!("Uploading {filename} ({length} bytes) to {location}");
+
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut offset: u64 = 0;
+        let mut buf = vec![0_u8; UPLOAD_CHUNK_SIZE];
+        while offset < length {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let read = file.read(&mut buf[filled..]).await?;
+                if read == 0 {
+                    break;
+                }
+                filled += read;
+            }
+            if filled == 0 {
+                // The file shrank underneath us
+                return Err(GISSTCliError::TusOffset {
+                    expected: length,
+                    actual: offset,
+                });
+            }
+            let response = Self::check(
+                self.http
+                    .patch(&location)
+                    .header(header::AUTHORIZATION, self.bearer())
+                    .header("Tus-Resumable", "1.0.0")
+                    .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+                    .header("Upload-Offset", offset.to_string())
+                    .body(buf[..filled].to_vec())
+                    .send()
+                    .await?,
+            )
+            .await?;
+            let server_offset = response
+                .headers()
+                .get("Upload-Offset")
+                .and_then(|off| off.to_str().ok())
+                .and_then(|off| off.parse::<u64>().ok())
+                .unwrap_or(offset);
+            let expected = offset + filled as u64;
+            if server_offset != expected {
+                return Err(GISSTCliError::TusOffset {
+                    expected,
+                    actual: server_offset,
+                });
+            }
+            debug!("Uploaded {server_offset}/{length} of {filename}");
+            offset = server_offset;
+        }
+        Ok(file_id)
+    }
+
+    /// Uploads a file and makes an object for it
+    pub async fn upload_object(
+        &self,
+        path: &Path,
+        description: Option<String>,
+    ) -> Result<Object, GISSTCliError> {
+        let file_id = self.upload(path).await?;
+        self.post(
+            "/objects/create",
+            &json!({"file_id": file_id, "object_description": description}),
+        )
+        .await
+    }
+
+    pub async fn link(
+        &self,
+        instance_id: Uuid,
+        object_id: Uuid,
+        role: ObjectRole,
+        role_index: u16,
+    ) -> Result<Vec<ObjectLink>, GISSTCliError> {
+        self.post(
+            &format!("/instances/{instance_id}/link"),
+            &json!({
+                "object_id": object_id,
+                "object_role": role,
+                "object_role_index": role_index,
+            }),
+        )
+        .await
+    }
+}
+
+fn print_record(record: &impl Serialize) -> Result<(), GISSTCliError> {
+    println!("{}", serde_json::to_string_pretty(record)?);
+    Ok(())
+}
+
+fn read_json<T: DeserializeOwned>(
+    file: Option<&str>,
+    string: Option<&str>,
+) -> Result<Option<T>, GISSTCliError> {
+    Ok(match (file, string) {
+        (Some(file_path), None) => Some(serde_json::from_str(&fs::read_to_string(file_path)?)?),
+        (None, Some(json_str)) => Some(serde_json::from_str(json_str)?),
+        (_, _) => None,
+    })
+}
+
+/// Runs `command` against the server, or fails if it needs direct access
+pub async fn run(command: Commands, client: &RemoteClient) -> Result<(), GISSTCliError> {
+    match command {
+        Commands::AddWorkInstance(cmd) => add_work_instance(cmd, client).await,
+        Commands::Link {
+            record_type,
+            source_uuid,
+            target_uuid,
+            role,
+            role_index,
+        } => match (record_type.as_str(), role) {
+            ("object", Some(role)) => print_record(
+                &client
+                    .link(target_uuid, source_uuid, role, role_index.unwrap_or(0))
+                    .await?,
+            ),
+            ("object", None) => Err(GISSTCliError::InvalidRecordType(
+                "record type object needs a role for link".to_string(),
+            )),
+            _ => Err(GISSTCliError::InvalidRecordType(format!(
+                "{record_type} is not a valid record type",
+            ))),
+        },
+        Commands::Object(object) => match object.command {
+            ObjectSubcommand::Create(create) => create_object(create, client).await,
+            ObjectSubcommand::Promote { .. } => {
+                Err(GISSTCliError::RemoteUnsupported("object promote"))
+            }
+        },
+        Commands::Environment(environment) => match environment.command {
+            BaseSubcommand::Create(create) => create_environment(create, client).await,
+        },
+        Commands::Instance(instance) => match instance.command {
+            BaseSubcommand::Create(create) => create_instance(create, client).await,
+        },
+        Commands::Work(work) => match work.command {
+            BaseSubcommand::Create(create) => create_work(create, client).await,
+        },
+        Commands::SetUserRole(_) => Err(GISSTCliError::RemoteUnsupported("set-user-role")),
+        Commands::AddCore(_) => Err(GISSTCliError::RemoteUnsupported("add-core")),
+        Commands::UpgradeEnvironment(_) => {
+            Err(GISSTCliError::RemoteUnsupported("upgrade-environment"))
+        }
+        Commands::RecalcSizes => Err(GISSTCliError::RemoteUnsupported("recalc-sizes")),
+        Commands::InitIndices => Err(GISSTCliError::RemoteUnsupported("init-indices")),
+        Commands::Reindex => Err(GISSTCliError::RemoteUnsupported("reindex")),
+        Commands::IndexDiskEntries { .. } => {
+            Err(GISSTCliError::RemoteUnsupported("index-disk-entries"))
+        }
+        Commands::Creator(_) => Err(GISSTCliError::RemoteUnsupported("creator create")),
+        Commands::Save(_) => Err(GISSTCliError::RemoteUnsupported("save create")),
+        Commands::State(_) => Err(GISSTCliError::RemoteUnsupported("state create")),
+        Commands::Replay(_) => Err(GISSTCliError::RemoteUnsupported("replay")),
+        Commands::Screenshot(_) => Err(GISSTCliError::RemoteUnsupported("screenshot")),
+        Commands::BuildDisk(_) => Err(GISSTCliError::RemoteUnsupported("build-disk")),
+        Commands::Apply(_) => Err(GISSTCliError::RemoteUnsupported("apply")),
+        Commands::ImportRecordings(_) => Err(GISSTCliError::RemoteUnsupported("import-recordings")),
+        Commands::List(_) => Err(GISSTCliError::RemoteUnsupported("list")),
+        Commands::Show(_) => Err(GISSTCliError::RemoteUnsupported("show")),
+        Commands::Delete(_) => Err(GISSTCliError::RemoteUnsupported("delete")),
+        Commands::Unlink(_) => Err(GISSTCliError::RemoteUnsupported("unlink")),
+        Commands::EditDisk(_) => Err(GISSTCliError::RemoteUnsupported("edit-disk")),
+        Commands::CloneV86 { .. } => Err(GISSTCliError::RemoteUnsupported("clone-v86")),
+        Commands::AddPatch { .. } => Err(GISSTCliError::RemoteUnsupported("add-patch")),
+    }
+}
+
+/// Resolves `file` against `cwd` like the local commands do
+fn local_path(cwd: Option<&str>, file: &str) -> (PathBuf, String) {
+    let path = cwd.map_or(Path::new(""), Path::new).join(file);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    (path, file_name)
+}
+
+async fn create_object(
+    CreateObject {
+        link,
+        role,
+        role_index,
+        file,
+        cwd,
+        ..
+    }: CreateObject,
+    client: &RemoteClient,
+) -> Result<(), GISSTCliError> {
+    warn!("--force-uuid and --depth are ignored with --remote");
+    let (path, file_name) = local_path(cwd.as_deref(), &file);
+    let object = client.upload_object(&path, Some(file_name)).await?;
+    if let Some(inst) = link {
+        client
+            .link(inst, object.object_id, role, role_index)
+            .await?;
+    }
+    print_record(&object)
+}
+
+async fn create_work(
+    CreateWork {
+        json_file,
+        json_string,
+    }: CreateWork,
+    client: &RemoteClient,
+) -> Result<(), GISSTCliError> {
+    let work: Work = read_json(json_file.as_deref(), json_string.as_deref())?.ok_or(
+        GISSTCliError::CreateWork(
+            "Please provide JSON to parse for creating a work record.".to_string(),
+        ),
+    )?;
+    let work: Work = client.post("/works/create", &work).await?;
+    print_record(&work)
+}
+
+async fn create_environment(
+    CreateEnvironment {
+        json_file,
+        json_string,
+        environment_config_json_file,
+        environment_config_json_string,
+    }: CreateEnvironment,
+    client: &RemoteClient,
+) -> Result<(), GISSTCliError> {
+    let environment: Environment = read_json(json_file.as_deref(), json_string.as_deref())?.ok_or(
+        GISSTCliError::CreateEnvironment(
+            "Need to provide a JSON string or file to create environment record.".to_string(),
+        ),
+    )?;
+    let environment_config = read_json(
+        environment_config_json_file.as_deref(),
+        environment_config_json_string.as_deref(),
+    )?
+    .or(environment.environment_config.clone());
+    let environment: Environment = client
+        .post(
+            "/environments/create",
+            &Environment {
+                environment_config,
+                ..environment
+            },
+        )
+        .await?;
+    print_record(&environment)
+}
+
+async fn create_instance(
+    CreateInstance {
+        json_file,
+        json_string,
+        instance_config_json_file,
+        instance_config_json_string,
+    }: CreateInstance,
+    client: &RemoteClient,
+) -> Result<(), GISSTCliError> {
+    let instance: Instance = read_json(json_file.as_deref(), json_string.as_deref())?.ok_or(
+        GISSTCliError::CreateInstance(
+            "Need to provide a JSON string or file to create instance record.".to_string(),
+        ),
+    )?;
+    let instance_config = read_json(
+        instance_config_json_file.as_deref(),
+        instance_config_json_string.as_deref(),
+    )?;
+    let instance: Instance = client
+        .post(
+            "/instances/create",
+            &json!({
+                "instance": Instance {
+                    instance_config,
+                    ..instance
+                },
+                "dependencies": [],
+                "configs": [],
+                "content": [],
+            }),
+        )
+        .await?;
+    print_record(&instance)
+}
+
+async fn add_work_instance(
+    AddWorkInstanceData {
+        platform_name,
+        work_version,
+        work_name,
+        work_id,
+        instance_id,
+        environment_id,
+        configs,
+        deps,
+        content,
+        cwd,
+        ..
+    }: AddWorkInstanceData,
+    client: &RemoteClient,
+) -> Result<(), GISSTCliError> {
+    if instance_id.is_some() {
+        warn!("--instance-id is ignored with --remote");
+    }
+    let work_id = if let Some(work_id) = work_id {
+        info!("Using existing work ID, ignoring work metadata");
+        work_id
+    } else {
+        // The server reuses a work with the same name, version, and platform
+        let work: Work = client
+            .post(
+                "/works/create",
+                &json!({
+                    "work_id": null,
+                    "work_name": work_name,
+                    "work_version": work_version,
+                    "work_platform": platform_name,
+                    "work_derived_from": null,
+                }),
+            )
+            .await?;
+        work.work_id
+    };
+    let mut objects = [vec![], vec![], vec![]];
+    for (files, objects) in [configs, deps, content].into_iter().zip(&mut objects) {
+        for file in files {
+            let (path, file_name) = local_path(cwd.as_deref(), &file);
+            objects.push(
+                client
+                    .upload_object(&path, Some(file_name))
+                    .await?
+                    .object_id,
+            );
+        }
+    }
+    let [configs, dependencies, content] = objects;
+    let instance: Instance = client
+        .post(
+            "/instances/create",
+            &json!({
+                "instance": {
+                    "instance_id": null,
+                    "work_id": work_id,
+                    "environment_id": environment_id,
+                    "instance_config": null,
+                },
+                "dependencies": dependencies,
+                "configs": configs,
+                "content": content,
+            }),
+        )
+        .await?;
+    print_record(&instance)
+}
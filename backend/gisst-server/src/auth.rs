@@ -158,6 +158,118 @@ impl User {
     }
 }
 
+/// Bearer tokens users make for scripts, like `gisst-cli --remote`, start
+/// with this; task worker keys start with `worker-` instead
+pub const API_TOKEN_PREFIX: &str = "gisst-";
+
+/// A user's API token. Only a hash of the token itself is stored.
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+pub struct ApiToken {
+    pub token_id: Uuid,
+    pub token_description: Option<String>,
+    pub created_on: chrono::DateTime<Utc>,
+    pub last_used_on: Option<chrono::DateTime<Utc>>,
+}
+
+fn hash_api_token(token: &str) -> String {
+    use base64::{Engine, engine::general_purpose};
+    use sha2::{Digest, Sha256};
+    general_purpose::STANDARD.encode(Sha256::digest(token.as_bytes()))
+}
+
+impl ApiToken {
+    /// Makes a new token for `user`, returning its record and the token,
+    /// which can't be recovered later
+    #[tracing::instrument(skip(conn))]
+    pub async fn create(
+        conn: &mut PgConnection,
+        user: &User,
+        description: Option<String>,
+    ) -> Result<(Self, String), AuthError> {
+        let token = format!(
+            "{API_TOKEN_PREFIX}{}{}",
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+        let record = sqlx::query_as!(
+            Self,
+            r#"INSERT INTO api_tokens (token_id, user_id, token_hash, token_description)
+            VALUES ($1, $2, $3, $4)
+            RETURNING token_id, token_description, created_on, last_used_on
+            "#,
+            Uuid::new_v4(),
+            user.id,
+            hash_api_token(&token),
+            description,
+        )
+        .fetch_one(conn)
+        .await?;
+        Ok((record, token))
+    }
+
+    #[tracing::instrument(skip(conn))]
+    pub async fn get_all_for_user(
+        conn: &mut PgConnection,
+        user: &User,
+    ) -> Result<Vec<Self>, AuthError> {
+        Ok(sqlx::query_as!(
+            Self,
+            r#"SELECT token_id, token_description, created_on, last_used_on
+            FROM api_tokens WHERE user_id = $1 ORDER BY created_on
+            "#,
+            user.id,
+        )
+        .fetch_all(conn)
+        .await?)
+    }
+
+    /// Returns false if `user` has no token with this ID
+    #[tracing::instrument(skip(conn))]
+    pub async fn revoke(
+        conn: &mut PgConnection,
+        user: &User,
+        token_id: Uuid,
+    ) -> Result<bool, AuthError> {
+        let result = sqlx::query!(
+            "DELETE FROM api_tokens WHERE token_id = $1 AND user_id = $2",
+            token_id,
+            user.id,
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Logs in a request carrying `Authorization: Bearer gisst-...` for that
+/// request alone, so scripts can use the same routes as the browser without
+/// a session. Worker keys are left for the routes that accept them.
+pub async fn api_token_auth(
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Result<axum::response::Response, ServerError> {
+    let token = request
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|hv| hv.strip_prefix("Bearer "))
+        .filter(|token| token.starts_with(API_TOKEN_PREFIX))
+        .map(String::from);
+    if let Some(token) = token
+        && let Some(auth) = request
+            .extensions_mut()
+            .get_mut::<axum_login::AuthSession<AuthBackend>>()
+    {
+        let user = auth
+            .backend
+            .get_user_by_api_token(&token)
+            .await?
+            .ok_or(ServerError::InvalidApiToken)?;
+        auth.user = Some(user);
+    }
+    Ok(next.run(request).await)
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AuthRequest {
     code: String,
@@ -340,6 +452,27 @@ impl AuthBackend {
             .add_scope(oauth2::Scope::new("openid profile email".to_string()))
             .url()
     }
+    /// Finds the user an API token belongs to, noting that it was used
+    pub async fn get_user_by_api_token(&self, token: &str) -> Result<Option<User>, AuthError> {
+        let mut conn = self.pool.acquire().await?;
+        let token_hash = hash_api_token(token);
+        let user = sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE id = (SELECT user_id FROM api_tokens WHERE token_hash = $1)",
+            token_hash,
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if user.is_some() {
+            sqlx::query!(
+                "UPDATE api_tokens SET last_used_on = current_timestamp WHERE token_hash = $1",
+                token_hash,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(user)
+    }
     #[cfg(not(feature = "dummy_auth"))]
     async fn do_login(
         &self,
@@ -56,6 +56,8 @@ pub enum ServerError {
     MimeType,
     #[error("user not logged in")]
     AuthUserNotAuthenticated,
+    #[error("unknown or revoked API token")]
+    InvalidApiToken,
     #[error("error linking uuid: {} to {} reference", .table, .uuid)]
     RecordLinking { table: Table, uuid: Uuid },
     #[error("multipart request error")]
@@ -74,6 +76,18 @@ pub enum ServerError {
     RoleIndexTooBig(std::num::TryFromIntError),
     #[error("No task ready for work yet")]
     NoTaskReady,
+    #[error("task artifact error")]
+    TaskArtifact(#[from] gisst::task::TaskArtifactError),
+    #[error("screenshot error")]
+    Screenshot(#[from] gisst::error::Screenshot),
+    #[error("replay file error")]
+    Replay(#[from] gisst::error::Replay),
+    #[error("subobject promotion error")]
+    Promote(#[from] gisst::error::Subobject),
+    #[error("disk build error")]
+    DiskBuild(#[from] gisst::error::DiskBuild),
+    #[error("rom patch error")]
+    Patch(#[from] gisst::error::PatchInstance),
     #[allow(unused)]
     #[error("Route not yet implemented")]
     NotYetImplemented,
@@ -148,11 +162,55 @@ impl IntoResponse for ServerError {
             ServerError::Join(_) => (StatusCode::INTERNAL_SERVER_ERROR, "tokio task error"),
             ServerError::FileNotFound => (StatusCode::NOT_FOUND, "file not found"),
             ServerError::NoTaskReady => (StatusCode::NOT_FOUND, "no task ready for work yet"),
+            ServerError::TaskArtifact(_) => (
+                StatusCode::BAD_REQUEST,
+                "task artifacts could not be stored",
+            ),
+            ServerError::Screenshot(
+                gisst::error::Screenshot::Png(_)
+                | gisst::error::Screenshot::Dimensions(..)
+                | gisst::error::Screenshot::ColorType(_),
+            ) => (StatusCode::BAD_REQUEST, "screenshot must be a PNG image"),
+            ServerError::Screenshot(_) => (StatusCode::INTERNAL_SERVER_ERROR, "screenshot error"),
+            ServerError::Replay(gisst::error::Replay::IO(_)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "replay file error")
+            }
+            ServerError::Replay(_) => (StatusCode::BAD_REQUEST, "not a valid replay file"),
+            ServerError::Promote(
+                gisst::error::Subobject::FSList(_) | gisst::error::Subobject::Path(_),
+            ) => (StatusCode::BAD_REQUEST, "no such file in the disk image"),
+            ServerError::Promote(gisst::error::Subobject::Missing(..)) => {
+                (StatusCode::NOT_FOUND, "disk image object not found")
+            }
+            ServerError::Promote(_) => (StatusCode::INTERNAL_SERVER_ERROR, "subobject error"),
+            ServerError::DiskBuild(
+                gisst::error::DiskBuild::Geometry(_)
+                | gisst::error::DiskBuild::Label(_)
+                | gisst::error::DiskBuild::Source(_)
+                | gisst::error::DiskBuild::FSList(_),
+            ) => (StatusCode::BAD_REQUEST, "can't build a disk from that"),
+            ServerError::DiskBuild(gisst::error::DiskBuild::Missing(..)) => {
+                (StatusCode::NOT_FOUND, "instance not found")
+            }
+            ServerError::DiskBuild(_) => (StatusCode::INTERNAL_SERVER_ERROR, "disk build error"),
+            ServerError::Patch(
+                gisst::error::PatchInstance::Patch(_) | gisst::error::PatchInstance::NoContent(_),
+            ) => (
+                StatusCode::BAD_REQUEST,
+                "patch does not apply to this instance",
+            ),
+            ServerError::Patch(gisst::error::PatchInstance::Missing(..)) => {
+                (StatusCode::NOT_FOUND, "instance or patch not found")
+            }
+            ServerError::Patch(_) => (StatusCode::INTERNAL_SERVER_ERROR, "rom patch error"),
             ServerError::Reqwest(_) => (StatusCode::INTERNAL_SERVER_ERROR, "oauth reqwest error"),
             ServerError::AuthUserSerdeLogin(_) => (StatusCode::INTERNAL_SERVER_ERROR, "auth error"),
             ServerError::AuthUserNotAuthenticated => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "auth error")
             }
+            ServerError::InvalidApiToken => {
+                (StatusCode::UNAUTHORIZED, "unknown or revoked API token")
+            }
             ServerError::MiniJinja(_) => (StatusCode::INTERNAL_SERVER_ERROR, "minijinja error"),
             ServerError::MimeType => (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -5,7 +5,6 @@ mod routes;
 mod selective_serve_dir;
 mod server;
 mod serverconfig;
-mod task;
 mod tus;
 mod utils;
 
@@ -9,6 +9,7 @@ mod save;
 mod screenshot;
 mod state;
 mod task;
+mod token;
 mod video;
 mod work;
 
@@ -20,7 +21,9 @@ pub use replay::router as replay_router;
 pub use save::router as save_router;
 pub use screenshot::router as screenshot_router;
 pub use state::router as state_router;
+pub use task::authenticate_worker;
 pub use task::router as task_router;
+pub use token::router as token_router;
 pub use video::router as video_router;
 pub use work::router as work_router;
 
@@ -1,17 +1,20 @@
 use super::LoggedInUserInfo;
-use crate::auth::AuthBackend;
+use crate::auth::{AuthBackend, User};
 use crate::server::BASE_URL;
 use crate::{auth, error::ServerError, server::ServerState, utils::parse_header};
 use axum::{
     Extension, Router,
     extract::{Json, Path, Query},
-    http::header::HeaderMap,
-    response::{Html, IntoResponse},
+    http::{StatusCode, header::HeaderMap},
+    response::{Html, IntoResponse, Redirect},
     routing::{get, post},
 };
 use axum_login::login_required;
 use gisst::error::Table;
-use gisst::models::{Environment, Instance, InstanceWork, Object, ObjectLink, ObjectRole, Work};
+use gisst::models::{
+    Environment, File, Instance, InstanceWork, Object, ObjectLink, ObjectRole, Work,
+};
+use gisst::task::{Task, TaskState, V86_CLONE_TASK};
 use minijinja::context;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -21,7 +24,11 @@ pub fn router() -> Router {
         .route("/", get(get_instances))
         .route("/new", get(get_new_instance_page))
         .route("/{id}/clone", get(clone_v86_instance))
+        .route("/{id}/changes", get(get_instance_changes))
+        .route("/clone/{task_id}", get(get_clone_progress))
         .route("/create", post(create_or_derive_instance))
+        .route("/{id}/patch", post(patch_instance))
+        .route("/{id}/link", post(link_object))
         .route_layer(login_required!(AuthBackend, login_url = "/login"))
         .route("/{id}", get(get_all_for_instance))
 }
@@ -172,6 +179,119 @@ async fn get_all_for_instance(
     }
 }
 
+/// An object slot of a derived instance whose object differs from its parent's
+#[derive(Debug, Serialize)]
+struct ObjectChange {
+    object_role: ObjectRole,
+    object_role_index: i32,
+    old: Option<ObjectLink>,
+    new: Option<ObjectLink>,
+    /// File changes, when both objects are disk images
+    changes: Option<Vec<gisst::fslist::FSChange>>,
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn get_instance_changes(
+    app_state: Extension<ServerState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+) -> Result<axum::response::Response, ServerError> {
+    tracing::Span::current().record(
+        "userid",
+        auth.user.as_ref().map(|u| u.creator_id.to_string()),
+    );
+    let mut conn = app_state.pool.acquire().await?;
+    let instance = Instance::get_by_id(&mut conn, id)
+        .await?
+        .ok_or(ServerError::RecordMissing {
+            table: Table::Instance,
+            uuid: id,
+        })?;
+    let work =
+        Work::get_by_id(&mut conn, instance.work_id)
+            .await?
+            .ok_or(ServerError::RecordMissing {
+                table: Table::Work,
+                uuid: instance.work_id,
+            })?;
+    let mut object_changes = vec![];
+    if let Some(parent_id) = instance.derived_from_instance {
+        let mut old_objects = ObjectLink::get_all_for_instance_id(&mut conn, parent_id).await?;
+        let new_objects = ObjectLink::get_all_for_instance_id(&mut conn, id).await?;
+        let object_path = |link: &ObjectLink| {
+            std::path::PathBuf::from(format!(
+                "{}/{}",
+                app_state.root_storage_path, link.file_dest_path
+            ))
+        };
+        for new in new_objects {
+            let old = old_objects
+                .iter()
+                .position(|old| {
+                    old.object_role == new.object_role
+                        && old.object_role_index == new.object_role_index
+                })
+                .map(|idx| old_objects.remove(idx));
+            if old
+                .as_ref()
+                .is_some_and(|old| old.object_id == new.object_id)
+            {
+                continue;
+            }
+            let changes = match &old {
+                Some(old) => {
+                    gisst::fslist::cached_diff(
+                        &mut conn,
+                        &old.file_hash,
+                        object_path(old),
+                        &new.file_hash,
+                        object_path(&new),
+                    )
+                    .await?
+                }
+                None => None,
+            };
+            object_changes.push(ObjectChange {
+                object_role: new.object_role,
+                object_role_index: new.object_role_index,
+                old,
+                new: Some(new),
+                changes,
+            });
+        }
+        object_changes.extend(old_objects.into_iter().map(|old| ObjectChange {
+            object_role: old.object_role,
+            object_role_index: old.object_role_index,
+            old: Some(old),
+            new: None,
+            changes: None,
+        }));
+    }
+
+    let accept: Option<String> = parse_header(&headers, "Accept");
+    Ok(
+        (if accept.is_none() || accept.as_ref().is_some_and(|hv| hv.contains("text/html")) {
+            let changes_page = app_state.templates.get_template("instance_changes.html")?;
+            Html(changes_page.render(context!(
+                base_url => BASE_URL.get(),
+                instance => instance,
+                work => work,
+                object_changes => object_changes,
+            ))?)
+            .into_response()
+        } else if accept
+            .as_ref()
+            .is_some_and(|hv| hv.contains("application/json"))
+        {
+            Json(object_changes).into_response()
+        } else {
+            Err(ServerError::MimeType)?
+        })
+        .into_response(),
+    )
+}
+
 #[derive(Deserialize, Debug)]
 struct CloneParams {
     state: Option<Uuid>,
@@ -180,11 +300,11 @@ struct CloneParams {
 #[tracing::instrument(skip(app_state, auth), fields(userid))]
 async fn clone_v86_instance(
     app_state: Extension<ServerState>,
+    headers: HeaderMap,
     Path(id): Path<Uuid>,
     auth: axum_login::AuthSession<auth::AuthBackend>,
     Query(params): Query<CloneParams>,
 ) -> Result<axum::response::Response, ServerError> {
-    use sqlx::Acquire;
     tracing::Span::current().record(
         "userid",
         auth.user.as_ref().map(|u| u.creator_id.to_string()),
@@ -193,23 +313,85 @@ async fn clone_v86_instance(
         .user
         .ok_or(ServerError::AuthUserNotAuthenticated)?
         .creator_id;
-    let mut conn = app_state.pool.acquire().await?;
-    let mut tx = conn.begin().await?;
     let state_id = params.state.ok_or(ServerError::StateRequired)?;
-    let storage_path = &app_state.root_storage_path;
-    let storage_depth = app_state.folder_depth;
-    let new_instance = gisst::v86clone::clone_v86_machine(
-        &mut tx,
-        id,
-        state_id,
-        storage_path,
-        storage_depth,
-        &app_state.indexer,
-        Some(creator_id),
+    let mut conn = app_state.pool.acquire().await?;
+    // Dumping the disks can take much longer than a request should, so a worker does it
+    let task = Task::create(
+        &mut conn,
+        V86_CLONE_TASK,
+        serde_json::json!({
+            "instance_id": id,
+            "state_id": state_id,
+            "creator_id": creator_id,
+        }),
     )
     .await?;
-    tx.commit().await?;
-    Ok(axum::response::Redirect::permanent(&format!("/instances/{new_instance}")).into_response())
+    let accept: Option<String> = parse_header(&headers, "Accept");
+    if accept
+        .as_ref()
+        .is_some_and(|hv| hv.contains("application/json"))
+    {
+        Ok((
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({"task_id": task.task_id})),
+        )
+            .into_response())
+    } else {
+        Ok(Redirect::to(&format!("/instances/clone/{}", task.task_id)).into_response())
+    }
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn get_clone_progress(
+    app_state: Extension<ServerState>,
+    headers: HeaderMap,
+    Path(task_id): Path<Uuid>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+) -> Result<axum::response::Response, ServerError> {
+    tracing::Span::current().record(
+        "userid",
+        auth.user.as_ref().map(|u| u.creator_id.to_string()),
+    );
+    let mut conn = app_state.pool.acquire().await?;
+    let task = Task::get_by_id(&mut conn, task_id)
+        .await?
+        .filter(|t| t.task_type == V86_CLONE_TASK)
+        .ok_or(ServerError::RecordMissing {
+            table: Table::Task,
+            uuid: task_id,
+        })?;
+    let new_instance = task
+        .task_output
+        .get("instance_id")
+        .and_then(|id| id.as_str())
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .filter(|_| task.task_state == TaskState::Done);
+    let accept: Option<String> = parse_header(&headers, "Accept");
+    if accept
+        .as_ref()
+        .is_some_and(|hv| hv.contains("application/json"))
+    {
+        return Ok(Json(serde_json::json!({
+            "task_id": task.task_id,
+            "task_state": task.task_state,
+            "task_status": task.task_status,
+            "failed": task.is_failed(),
+            "instance_id": new_instance,
+        }))
+        .into_response());
+    }
+    if let Some(new_instance) = new_instance {
+        return Ok(Redirect::to(&format!("/instances/{new_instance}")).into_response());
+    }
+    let user = auth.user.as_ref().map(LoggedInUserInfo::generate_from_user);
+    let progress = app_state.templates.get_template("clone_progress.html")?;
+    Ok(Html(progress.render(context!(
+        base_url => BASE_URL.get(),
+        task => task,
+        failed => task.is_failed(),
+        user => user,
+    ))?)
+    .into_response())
 }
 
 async fn get_new_instance_page(
@@ -344,3 +526,124 @@ async fn create_or_derive_instance(
     tx.commit().await?;
     return Ok(Json(inserted_instance));
 }
+
+#[derive(Debug, Deserialize)]
+struct LinkObject {
+    object_id: Uuid,
+    object_role: ObjectRole,
+    #[serde(default)]
+    object_role_index: u16,
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn link_object(
+    app_state: Extension<ServerState>,
+    auth: axum_login::AuthSession<crate::auth::AuthBackend>,
+    Path(id): Path<Uuid>,
+    Json(link): Json<LinkObject>,
+) -> Result<Json<Vec<ObjectLink>>, ServerError> {
+    let user = auth
+        .user
+        .as_ref()
+        .map(LoggedInUserInfo::generate_from_user)
+        .ok_or(ServerError::AuthUserNotAuthenticated)?;
+    tracing::Span::current().record("userid", user.creator_id.to_string());
+    let mut conn = app_state.pool.acquire().await?;
+    let instance = Instance::get_by_id(&mut conn, id)
+        .await?
+        .ok_or(ServerError::RecordMissing {
+            table: Table::Instance,
+            uuid: id,
+        })?;
+    if instance.creator_id != Some(user.creator_id) && user.role > User::ROLE_ADMIN {
+        return Err(ServerError::PermissionDenied);
+    }
+    if Object::get_by_id(&mut conn, link.object_id)
+        .await?
+        .is_none()
+    {
+        return Err(ServerError::RecordMissing {
+            table: Table::Object,
+            uuid: link.object_id,
+        });
+    }
+    Object::link_object_to_instance(
+        &mut conn,
+        link.object_id,
+        id,
+        link.object_role,
+        link.object_role_index,
+    )
+    .await?;
+    Ok(Json(
+        ObjectLink::get_all_for_instance_id(&mut conn, id).await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchUpload {
+    /// Content index the patch applies to
+    #[serde(default)]
+    role_index: u16,
+    /// An uploaded IPS, UPS, or BPS file
+    file_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchInstanceParams {
+    patches: Vec<PatchUpload>,
+    work_name: Option<String>,
+    work_version: Option<String>,
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn patch_instance(
+    app_state: Extension<ServerState>,
+    auth: axum_login::AuthSession<crate::auth::AuthBackend>,
+    Path(id): Path<Uuid>,
+    Json(params): Json<PatchInstanceParams>,
+) -> Result<Json<Instance>, ServerError> {
+    use gisst::diskedit::DerivedWork;
+    use gisst::fslist::file_to_path;
+    use gisst::rompatch::{PatchFile, derive_patched_instance};
+    tracing::Span::current().record(
+        "userid",
+        auth.user.as_ref().map(|u| u.creator_id.to_string()),
+    );
+    let creator_id = auth
+        .user
+        .ok_or(ServerError::AuthUserNotAuthenticated)?
+        .creator_id;
+    let mut conn = app_state.pool.acquire().await?;
+    let mut patches = Vec::with_capacity(params.patches.len());
+    for patch in params.patches {
+        let file =
+            File::get_by_id(&mut conn, patch.file_id)
+                .await?
+                .ok_or(ServerError::RecordMissing {
+                    table: Table::File,
+                    uuid: patch.file_id,
+                })?;
+        patches.push(PatchFile {
+            role_index: patch.role_index,
+            path: file_to_path(&app_state.root_storage_path, &file),
+            file_name: file.file_filename,
+        });
+    }
+    Ok(Json(
+        derive_patched_instance(
+            &mut conn,
+            &app_state.root_storage_path,
+            app_state.folder_depth,
+            id,
+            patches,
+            DerivedWork {
+                work_name: params.work_name,
+                work_version: params.work_version,
+            },
+            &app_state.indexer,
+            Some(creator_id),
+        )
+        .await?,
+    ))
+}
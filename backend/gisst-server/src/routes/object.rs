@@ -1,24 +1,30 @@
-use crate::auth::AuthBackend;
+use super::LoggedInUserInfo;
+use crate::auth::{AuthBackend, User};
 use crate::server::BASE_URL;
+use crate::utils::{ByteRange, parse_byte_range};
 use crate::{auth, error::ServerError, server::ServerState, utils::parse_header};
 use axum::{
     Extension, Router,
-    extract::{Json, Path},
+    extract::{Json, Path, Query},
     http::header::HeaderMap,
-    response::{Html, IntoResponse},
+    response::{AppendHeaders, Html, IntoResponse},
     routing::{get, post},
 };
 use axum_login::login_required;
-use gisst::models::{File, Object};
+use gisst::models::{File, Instance, Object};
 use gisst::{error::Table, inc_metric};
 use minijinja::context;
 use uuid::Uuid;
 
 pub fn router() -> Router {
     Router::new()
+        .route("/search", get(search_disk_entries))
         .route("/{id}", get(get_single_object))
+        .route("/{id}/diff/{other_id}", get(get_object_diff))
+        .route("/{id}/promote", post(promote_subobject))
         .route("/{id}/{*path}", get(get_subobject))
         .route("/create", post(create_object))
+        .route("/build", post(build_disk_object))
         .route_layer(login_required!(AuthBackend, login_url = "/login"))
 }
 
@@ -53,22 +59,31 @@ async fn get_single_object(
 
     Ok(
         (if accept.is_none() || accept.as_ref().is_some_and(|hv| hv.contains("text/html")) {
-            use gisst::fslist::{file_to_path, is_disk_image, recursive_listing};
+            use gisst::fslist::{cached_listing, file_to_path};
             let object_page = app_state.templates.get_template("object_listing.html")?;
-            // TODO reuse cookie instead of reloading every time
+            let directory = cached_listing(&mut conn, &app_state.root_storage_path, &file)
+                .await?
+                .unwrap_or_default();
             let path = file_to_path(&app_state.root_storage_path, &file);
-            let directory = if is_disk_image(&path) {
-                inc_metric!(conn, fslist_recursive_listing, 1, path = path.to_str());
-                let image = std::fs::File::open(path)?;
-                recursive_listing(image)?
-            } else {
-                vec![]
+            // CHDs that need a parent image can't be opened on their own
+            let chd = tokio::task::spawn_blocking(move || gisst::chdimage::chd_info(&path))
+                .await?
+                .inspect_err(|e| tracing::warn!("could not read chd header {e}"))
+                .ok()
+                .flatten();
+            let parent = match object.object_parent_id {
+                Some(parent_id) => Object::get_by_id(&mut conn, parent_id).await?,
+                None => None,
             };
+            let children = Object::get_children(&mut conn, object.object_id).await?;
             Html(object_page.render(context!(
                 base_url => BASE_URL.get(),
                 object => object,
                 file => file,
                 directory => directory,
+                chd => chd,
+                parent => parent,
+                children => children,
             ))?)
             .into_response()
         } else if accept
@@ -83,6 +98,100 @@ async fn get_single_object(
     )
 }
 
+/// What a subobject download turned out to be, known before its body is sent
+enum Download {
+    File {
+        size: u64,
+        mime: String,
+        range: ByteRange,
+    },
+    Directory,
+}
+
+/// Hands bytes written on a blocking thread to a response body; writes fail
+/// once the client has gone away
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<bytes::Bytes>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(bytes::Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Size of the chunks a subobject download is sent in
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Opens `subpath` in the image at `path` and writes it to `body`: a file's
+/// bytes, or just the requested range of them, or a directory as a zip. What
+/// was found goes to `download` before any of the body is written.
+fn stream_subobject(
+    path: &std::path::Path,
+    subpath: &std::path::Path,
+    headers: &HeaderMap,
+    download: tokio::sync::oneshot::Sender<Result<Download, gisst::error::FSList>>,
+    body: ChannelWriter,
+) -> Result<(), gisst::error::FSList> {
+    use gisst::fslist::{open_path, sniff_mime, write_dir_zip};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    let opened = std::fs::File::open(path)
+        .map_err(gisst::error::FSList::from)
+        .and_then(|image| open_path(image, subpath));
+    let (mut fs, subpath) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let _ = download.send(Err(e));
+            return Ok(());
+        }
+    };
+    let mut body = std::io::BufWriter::with_capacity(DOWNLOAD_CHUNK_SIZE, body);
+    if fs.is_dir(&subpath).unwrap_or(false) {
+        if download.send(Ok(Download::Directory)).is_ok() {
+            write_dir_zip(fs.as_mut(), &subpath, &mut body)?;
+            body.flush()?;
+        }
+        return Ok(());
+    }
+    let opened = fs.open_file(&subpath).and_then(|mut file| {
+        let size = file.seek(SeekFrom::End(0))?;
+        file.rewind()?;
+        let mime = sniff_mime(file.as_mut())?;
+        Ok((file, size, mime))
+    });
+    let (mut file, size, mime) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let _ = download.send(Err(e));
+            return Ok(());
+        }
+    };
+    tracing::info!("download single file {subpath:?}, size {size}");
+    let range = parse_byte_range(headers, size);
+    if download
+        .send(Ok(Download::File { size, mime, range }))
+        .is_err()
+    {
+        return Ok(());
+    }
+    match range {
+        ByteRange::Full => {
+            std::io::copy(&mut file, &mut body)?;
+        }
+        ByteRange::Partial { first, last } => {
+            file.seek(SeekFrom::Start(first))?;
+            std::io::copy(&mut file.take(last - first + 1), &mut body)?;
+        }
+        ByteRange::Unsatisfiable => {}
+    }
+    body.flush()?;
+    Ok(())
+}
+
 #[tracing::instrument(skip(app_state, auth), fields(userid))]
 async fn get_subobject(
     app_state: Extension<ServerState>,
@@ -90,7 +199,8 @@ async fn get_subobject(
     Path((id, subpath)): Path<(Uuid, String)>,
     auth: axum_login::AuthSession<auth::AuthBackend>,
 ) -> Result<axum::response::Response, ServerError> {
-    use gisst::fslist::{file_to_path, get_file_at_path, is_disk_image};
+    use axum::http::{StatusCode, header};
+    use gisst::fslist::{cached_listing, file_to_path};
     tracing::Span::current().record(
         "userid",
         auth.user.as_ref().map(|u| u.creator_id.to_string()),
@@ -112,44 +222,342 @@ async fn get_subobject(
                 uuid: object.file_id,
             })?;
     let path = file_to_path(&app_state.root_storage_path, &file);
-    let (mime, data) = {
+    if cached_listing(&mut conn, &app_state.root_storage_path, &file)
+        .await?
+        .is_none()
+    {
+        return Err(ServerError::Subobject(format!("{id}:{subpath}")));
+    }
+    inc_metric!(
+        conn,
+        fslist_get_file_at_path,
+        1,
+        path = path.to_str(),
+        subpath = &subpath
+    );
+    let disposition = format!(
+        "attachment; filename=\"{}\"",
+        std::path::Path::new(&subpath)
+            .file_name()
+            .ok_or(ServerError::Subobject(
+                "can't download empty thing".to_string()
+            ))?
+            .to_string_lossy()
+    );
+
+    // The image is read on a blocking thread, which sends what it found and
+    // then the body's chunks as they are read
+    let (download_tx, download_rx) = tokio::sync::oneshot::channel();
+    let (body_tx, body_rx) = tokio::sync::mpsc::channel(4);
+    {
         let subpath = subpath.clone();
-        let is_disk = is_disk_image(&path);
-        if is_disk {
-            inc_metric!(
-                conn,
-                fslist_get_file_at_path,
-                1,
-                path = path.to_str(),
-                subpath = &subpath
-            );
-        }
         tokio::task::spawn_blocking(move || {
-            if is_disk {
-                get_file_at_path(std::fs::File::open(path)?, std::path::Path::new(&subpath))
-                    .map_err(ServerError::from)
-            } else {
-                Err(ServerError::Subobject(format!("{id}:{subpath}")))
+            let errors = body_tx.clone();
+            if let Err(e) = stream_subobject(
+                &path,
+                std::path::Path::new(&subpath),
+                &headers,
+                download_tx,
+                ChannelWriter(body_tx),
+            ) {
+                tracing::warn!("subobject download of {subpath} stopped: {e:?}");
+                let _ = errors.blocking_send(Err(std::io::Error::other(e.to_string())));
             }
-        })
-        .await??
+        });
+    }
+    let download = download_rx
+        .await
+        .map_err(|_| ServerError::Subobject(format!("{id}:{subpath}")))??;
+    let body =
+        axum::body::Body::from_stream(futures::stream::unfold(body_rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        }));
+
+    let mut response_headers = vec![(header::CONTENT_DISPOSITION, disposition)];
+    let status = match download {
+        Download::Directory => {
+            response_headers.push((header::CONTENT_TYPE, "application/zip".to_string()));
+            StatusCode::OK
+        }
+        Download::File { size, mime, range } => {
+            response_headers.push((header::CONTENT_TYPE, mime));
+            response_headers.push((header::ACCEPT_RANGES, "bytes".to_string()));
+            match range {
+                ByteRange::Full => {
+                    response_headers.push((header::CONTENT_LENGTH, size.to_string()));
+                    StatusCode::OK
+                }
+                ByteRange::Partial { first, last } => {
+                    response_headers.push((
+                        header::CONTENT_RANGE,
+                        format!("bytes {first}-{last}/{size}"),
+                    ));
+                    response_headers.push((header::CONTENT_LENGTH, (last - first + 1).to_string()));
+                    StatusCode::PARTIAL_CONTENT
+                }
+                ByteRange::Unsatisfiable => {
+                    return Ok((
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+                    )
+                        .into_response());
+                }
+            }
+        }
     };
-    let headers = [
-        (axum::http::header::CONTENT_TYPE, mime),
-        (
-            axum::http::header::CONTENT_DISPOSITION,
-            format!(
-                "attachment; filename=\"{}\"",
-                std::path::Path::new(&subpath)
-                    .file_name()
-                    .ok_or(ServerError::Subobject(
-                        "can't download empty thing".to_string()
-                    ))?
-                    .to_string_lossy()
-            ),
-        ),
-    ];
-    Ok((headers, data).into_response())
+    Ok((status, AppendHeaders(response_headers), body).into_response())
+}
+
+async fn get_object_with_file(
+    conn: &mut sqlx::PgConnection,
+    id: Uuid,
+) -> Result<(Object, File), ServerError> {
+    let object = Object::get_by_id(conn, id)
+        .await?
+        .ok_or(ServerError::RecordMissing {
+            table: Table::Object,
+            uuid: id,
+        })?;
+    let file = File::get_by_id(conn, object.file_id)
+        .await?
+        .ok_or(ServerError::RecordMissing {
+            table: Table::File,
+            uuid: object.file_id,
+        })?;
+    Ok((object, file))
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn get_object_diff(
+    app_state: Extension<ServerState>,
+    headers: HeaderMap,
+    Path((id, other_id)): Path<(Uuid, Uuid)>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+) -> Result<axum::response::Response, ServerError> {
+    use gisst::fslist::{cached_diff, file_to_path};
+    tracing::Span::current().record(
+        "userid",
+        auth.user.as_ref().map(|u| u.creator_id.to_string()),
+    );
+    let mut conn = app_state.pool.acquire().await?;
+    let (old_object, old_file) = get_object_with_file(&mut conn, id).await?;
+    let (new_object, new_file) = get_object_with_file(&mut conn, other_id).await?;
+    let changes = cached_diff(
+        &mut conn,
+        &old_file.file_hash,
+        file_to_path(&app_state.root_storage_path, &old_file),
+        &new_file.file_hash,
+        file_to_path(&app_state.root_storage_path, &new_file),
+    )
+    .await?
+    .ok_or_else(|| ServerError::Subobject(format!("{id}:diff:{other_id}")))?;
+
+    let accept: Option<String> = parse_header(&headers, "Accept");
+    Ok(
+        (if accept.is_none() || accept.as_ref().is_some_and(|hv| hv.contains("text/html")) {
+            let diff_page = app_state.templates.get_template("object_diff.html")?;
+            Html(diff_page.render(context!(
+                base_url => BASE_URL.get(),
+                old_object => old_object,
+                old_file => old_file,
+                new_object => new_object,
+                new_file => new_file,
+                changes => changes,
+            ))?)
+            .into_response()
+        } else if accept
+            .as_ref()
+            .is_some_and(|hv| hv.contains("application/json"))
+        {
+            Json(changes).into_response()
+        } else {
+            Err(ServerError::MimeType)?
+        })
+        .into_response(),
+    )
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DiskEntrySearchParams {
+    page_num: Option<u32>,
+    limit: Option<u32>,
+    contains: Option<String>,
+    platform: Option<String>,
+    instance: Option<Uuid>,
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn search_disk_entries(
+    app_state: Extension<ServerState>,
+    headers: HeaderMap,
+    params: Query<DiskEntrySearchParams>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+) -> Result<axum::response::Response, ServerError> {
+    use gisst::models::DiskEntryInfo;
+    tracing::Span::current().record(
+        "userid",
+        auth.user.as_ref().map(|u| u.creator_id.to_string()),
+    );
+    let page_num = params.page_num.unwrap_or(1);
+    let limit = params.limit.unwrap_or(100).min(100);
+    let filter = params
+        .platform
+        .as_ref()
+        .map(|p| format!("work_platforms = \"{}\"", p.replace('"', "\\\"")))
+        .into_iter()
+        .chain(params.instance.map(|i| format!("instance_ids = \"{i}\"")))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let search = app_state.search.disk_entries();
+    let results: meilisearch_sdk::search::SearchResults<DiskEntryInfo> = search
+        .search()
+        .with_hits_per_page(limit as usize)
+        .with_page(page_num as usize)
+        .with_filter(&filter)
+        .with_query(&params.contains.clone().unwrap_or_default())
+        .execute()
+        .await
+        .map_err(gisst::error::Search::from)?;
+    let entries: Vec<_> = results.hits.into_iter().map(|r| r.result).collect();
+
+    let accept: Option<String> = parse_header(&headers, "Accept");
+    Ok(
+        (if accept.is_none() || accept.as_ref().is_some_and(|hv| hv.contains("text/html")) {
+            let search_page = app_state.templates.get_template("disk_entry_search.html")?;
+            Html(search_page.render(context!(
+                base_url => BASE_URL.get(),
+                contains => params.contains,
+                platform => params.platform,
+                instance => params.instance,
+                page_num => page_num,
+                entries => entries,
+            ))?)
+            .into_response()
+        } else if accept
+            .as_ref()
+            .is_some_and(|hv| hv.contains("application/json"))
+        {
+            Json(entries).into_response()
+        } else {
+            Err(ServerError::MimeType)?
+        })
+        .into_response(),
+    )
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PromoteSubobject {
+    /// Path inside the image, starting with its partition
+    pub path: String,
+    pub object_description: Option<String>,
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn promote_subobject(
+    app_state: Extension<ServerState>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+    Path(id): Path<Uuid>,
+    Json(promote): Json<PromoteSubobject>,
+) -> Result<Json<Object>, ServerError> {
+    let creator_id = auth
+        .user
+        .as_ref()
+        .ok_or(ServerError::AuthUserNotAuthenticated)?
+        .creator_id;
+    tracing::Span::current().record("userid", creator_id.to_string());
+    let mut conn = app_state.pool.acquire().await?;
+    Ok(Json(
+        gisst::subobject::promote_subobject(
+            &mut conn,
+            &app_state.root_storage_path,
+            app_state.folder_depth,
+            id,
+            &promote.path,
+            promote.object_description,
+            Some(creator_id),
+        )
+        .await?,
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct BuildDiskObject {
+    /// An uploaded zip or 7z archive holding the files to put on the disk
+    pub file_id: Uuid,
+    /// `floppy-720k`, `floppy-1440k`, or `hdd-<N>m`
+    pub geometry: String,
+    pub volume_label: Option<String>,
+    pub file_name: Option<String>,
+    pub object_description: Option<String>,
+    /// Instance to link the image to as content
+    pub instance_id: Option<Uuid>,
+    #[serde(default)]
+    pub role_index: u16,
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn build_disk_object(
+    app_state: Extension<ServerState>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+    Json(build): Json<BuildDiskObject>,
+) -> Result<Json<Object>, ServerError> {
+    use gisst::diskbuild::{DiskContents, Geometry, InstanceLink, build_object};
+    use gisst::fslist::file_to_path;
+    let user = auth
+        .user
+        .as_ref()
+        .map(LoggedInUserInfo::generate_from_user)
+        .ok_or(ServerError::AuthUserNotAuthenticated)?;
+    tracing::Span::current().record("userid", user.creator_id.to_string());
+    let geometry: Geometry = build.geometry.parse()?;
+    let mut conn = app_state.pool.acquire().await?;
+    if let Some(instance_id) = build.instance_id {
+        let instance = Instance::get_by_id(&mut conn, instance_id).await?.ok_or(
+            ServerError::RecordMissing {
+                table: Table::Instance,
+                uuid: instance_id,
+            },
+        )?;
+        if instance.creator_id != Some(user.creator_id) && user.role > User::ROLE_ADMIN {
+            return Err(ServerError::PermissionDenied);
+        }
+    }
+    let file =
+        File::get_by_id(&mut conn, build.file_id)
+            .await?
+            .ok_or(ServerError::RecordMissing {
+                table: Table::File,
+                uuid: build.file_id,
+            })?;
+    let name = build.file_name.unwrap_or_else(|| {
+        let stem = std::path::Path::new(&file.file_filename)
+            .file_stem()
+            .map_or_else(|| "disk".into(), |stem| stem.to_string_lossy());
+        format!("{stem}.img")
+    });
+    let path = file_to_path(&app_state.root_storage_path, &file);
+    let contents = tokio::task::spawn_blocking(move || {
+        DiskContents::from_archive(std::fs::File::open(&path)?, &path)
+    })
+    .await??;
+    Ok(Json(
+        build_object(
+            &mut conn,
+            &app_state.root_storage_path,
+            app_state.folder_depth,
+            contents,
+            geometry,
+            build.volume_label,
+            &name,
+            build.object_description,
+            build.instance_id.map(|instance_id| InstanceLink {
+                instance_id,
+                role_index: build.role_index,
+            }),
+            Some(user.creator_id),
+        )
+        .await?,
+    ))
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -188,6 +596,8 @@ async fn create_object(
                         .or_else(|| Some(file.file_filename.clone())),
                     creator_id: Some(creator_id),
                     created_on: chrono::Utc::now(),
+                    object_parent_id: None,
+                    object_parent_path: None,
                 },
             )
             .await?,
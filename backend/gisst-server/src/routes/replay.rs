@@ -9,7 +9,8 @@ use axum::{
 };
 use axum_login::login_required;
 use gisst::error::Table;
-use gisst::models::{File, Replay};
+use gisst::models::{Environment, File, Instance, Replay};
+use gisst::task::{REPLAY_CHECKPOINTS_TASK, Task};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -79,33 +80,66 @@ async fn create_replay(
         auth.user.as_ref().map(|u| u.creator_id.to_string()),
     );
     let mut conn = app_state.pool.acquire().await?;
-    if File::get_by_id(&mut conn, replay.file_id).await?.is_some() {
-        Ok(Json(
-            Replay::insert(
-                &mut conn,
-                Replay {
-                    replay_id: Uuid::new_v4(),
-                    video_id: replay.video_id,
-                    replay_name: replay.replay_name,
-                    replay_description: replay.replay_description,
-                    instance_id: replay.instance_id,
-                    creator_id: auth
-                        .user
-                        .ok_or(ServerError::AuthUserNotAuthenticated)?
-                        .creator_id,
-                    replay_forked_from: replay.replay_forked_from,
-                    file_id: replay.file_id,
-                    created_on: chrono::Utc::now(),
-                    hidden: false,
-                },
-                &app_state.indexer,
-            )
-            .await?,
-        ))
-    } else {
-        Err(ServerError::RecordMissing {
-            table: Table::File,
-            uuid: replay.file_id,
-        })?
+    let file =
+        File::get_by_id(&mut conn, replay.file_id)
+            .await?
+            .ok_or(ServerError::RecordMissing {
+                table: Table::File,
+                uuid: replay.file_id,
+            })?;
+    let instance = Instance::get_by_id(&mut conn, replay.instance_id)
+        .await?
+        .ok_or(ServerError::RecordMissing {
+            table: Table::Instance,
+            uuid: replay.instance_id,
+        })?;
+    let environment = Environment::get_by_id(&mut conn, instance.environment_id)
+        .await?
+        .ok_or(ServerError::RecordMissing {
+            table: Table::Environment,
+            uuid: instance.environment_id,
+        })?;
+    let path = std::path::Path::new(&app_state.root_storage_path).join(&file.file_dest_path);
+    let info = tokio::task::spawn_blocking(move || gisst::replay::parse_path(&path)).await??;
+    info.check_framework(environment.environment_framework)?;
+    let replay = Replay::insert(
+        &mut conn,
+        Replay {
+            replay_id: Uuid::new_v4(),
+            video_id: replay.video_id,
+            replay_name: replay.replay_name,
+            replay_description: replay.replay_description,
+            instance_id: replay.instance_id,
+            creator_id: auth
+                .user
+                .ok_or(ServerError::AuthUserNotAuthenticated)?
+                .creator_id,
+            replay_forked_from: replay.replay_forked_from,
+            file_id: replay.file_id,
+            created_on: chrono::Utc::now(),
+            hidden: false,
+            replay_frame_count: None,
+            replay_duration: None,
+            replay_checkpoint_count: None,
+            replay_checkpoints: None,
+            replay_identifier: None,
+            replay_content_crc: None,
+        }
+        .with_file_info(&info),
+        &app_state.indexer,
+    )
+    .await?;
+    if !info.checkpoints.is_empty() {
+        // Storing every checkpoint as a state is slow for long replays, so a worker does it
+        Task::create(
+            &mut conn,
+            REPLAY_CHECKPOINTS_TASK,
+            serde_json::json!({
+                "replay_id": replay.replay_id,
+                "creator_id": replay.creator_id,
+            }),
+        )
+        .await?;
     }
+    Ok(Json(replay))
 }
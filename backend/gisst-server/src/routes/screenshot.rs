@@ -1,18 +1,23 @@
 use crate::auth::AuthBackend;
-use crate::{auth, error::ServerError, server::ServerState};
+use crate::{auth, error::ServerError, server::ServerState, utils::parse_header};
 use axum::{
     Extension, Router,
     extract::{Json, Path},
+    http::header::{self, HeaderMap},
+    response::IntoResponse,
     routing::{get, post},
 };
 use axum_login::login_required;
-use serde::Deserialize;
+use gisst::error::Table;
+use gisst::models::Screenshot;
+use serde::{Deserialize, Serialize};
 use serde_with::{base64::Base64, serde_as};
 use uuid::Uuid;
 
 pub fn router() -> Router {
     Router::new()
         .route("/{id}", get(get_single_screenshot))
+        .route("/{id}/thumbnail", get(get_screenshot_thumbnail))
         .route("/create", post(create_screenshot))
         .route_layer(login_required!(AuthBackend, login_url = "/login"))
 }
@@ -24,37 +29,100 @@ struct ScreenshotCreateInfo {
     screenshot_data: Vec<u8>,
 }
 
-#[tracing::instrument(skip(app_state, auth), fields(userid))]
+/// JSON form of a screenshot, with its image inlined for players that show it directly
+#[serde_as]
+#[derive(Debug, Serialize)]
+struct ScreenshotWithData {
+    #[serde(flatten)]
+    screenshot: Screenshot,
+    #[serde_as(as = "Base64")]
+    screenshot_data: Vec<u8>,
+}
+
+#[tracing::instrument(skip(app_state, auth, screenshot), fields(userid))]
 async fn create_screenshot(
     app_state: Extension<ServerState>,
     auth: axum_login::AuthSession<auth::AuthBackend>,
     Json(screenshot): Json<ScreenshotCreateInfo>,
-) -> Result<Json<gisst::models::Screenshot>, ServerError> {
+) -> Result<Json<Screenshot>, ServerError> {
     let user_id = auth.user.as_ref().map(|u| u.creator_id);
     tracing::Span::current().record("userid", user_id.map(|u| u.to_string()));
     let mut conn = app_state.pool.acquire().await?;
     Ok(Json(
-        gisst::models::Screenshot::insert(
+        gisst::screenshot::store_screenshot(
             &mut conn,
-            gisst::models::Screenshot {
-                screenshot_id: Uuid::new_v4(),
-                screenshot_data: screenshot.screenshot_data,
-                created_on: chrono::Utc::now(),
-                creator_id: user_id,
-            },
+            &app_state.root_storage_path,
+            app_state.folder_depth,
+            &screenshot.screenshot_data,
+            Uuid::new_v4(),
+            user_id,
         )
         .await?,
     ))
 }
 
+async fn get_screenshot(
+    conn: &mut sqlx::PgConnection,
+    id: Uuid,
+) -> Result<Screenshot, ServerError> {
+    Screenshot::get_by_id(conn, id)
+        .await?
+        .ok_or(ServerError::RecordMissing {
+            table: Table::Screenshot,
+            uuid: id,
+        })
+}
+
+fn png_response(data: Vec<u8>) -> axum::response::Response {
+    (
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            // Screenshot images never change once stored
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+        ],
+        data,
+    )
+        .into_response()
+}
+
 async fn get_single_screenshot(
     app_state: Extension<ServerState>,
+    headers: HeaderMap,
     Path(id): Path<Uuid>,
-) -> Result<Json<gisst::models::Screenshot>, ServerError> {
+) -> Result<axum::response::Response, ServerError> {
     let mut conn = app_state.pool.acquire().await?;
-    Ok(Json(
-        gisst::models::Screenshot::get_by_id(&mut conn, id)
-            .await?
-            .unwrap(),
-    ))
+    let screenshot = get_screenshot(&mut conn, id).await?;
+    let data = gisst::screenshot::read_screenshot(
+        &mut conn,
+        &app_state.root_storage_path,
+        &screenshot,
+        false,
+    )
+    .await?;
+    let accept: Option<String> = parse_header(&headers, "Accept");
+    if accept.is_some_and(|hv| hv.contains("image/")) {
+        Ok(png_response(data))
+    } else {
+        Ok(Json(ScreenshotWithData {
+            screenshot,
+            screenshot_data: data,
+        })
+        .into_response())
+    }
+}
+
+async fn get_screenshot_thumbnail(
+    app_state: Extension<ServerState>,
+    Path(id): Path<Uuid>,
+) -> Result<axum::response::Response, ServerError> {
+    let mut conn = app_state.pool.acquire().await?;
+    let screenshot = get_screenshot(&mut conn, id).await?;
+    let data = gisst::screenshot::read_screenshot(
+        &mut conn,
+        &app_state.root_storage_path,
+        &screenshot,
+        true,
+    )
+    .await?;
+    Ok(png_response(data))
 }
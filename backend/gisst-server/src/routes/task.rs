@@ -1,8 +1,4 @@
-use crate::{
-    error::ServerError,
-    server::ServerState,
-    task::{Task, TaskState},
-};
+use crate::{error::ServerError, server::ServerState};
 use axum::{
     Extension, Router,
     extract::{Json, Path, Query},
@@ -10,6 +6,7 @@ use axum::{
     response::IntoResponse,
     routing::{get, post},
 };
+use gisst::task::{Task, TaskState};
 use serde::Deserialize;
 use uuid::Uuid;
 
@@ -53,7 +50,7 @@ struct TaskClaimParams {
     task_type: Option<String>,
 }
 
-fn authenticate_worker(
+pub fn authenticate_worker(
     headers: &HeaderMap,
     app_state: &ServerState,
 ) -> Result<String, ServerError> {
@@ -125,7 +122,17 @@ async fn task_update(
     match update {
         TaskUpdate::Status => Task::update_status(&mut conn, id, status).await?,
         TaskUpdate::Error => Task::error(&mut conn, id, status).await?,
-        TaskUpdate::Complete => Task::complete(&mut conn, id, status).await?,
+        TaskUpdate::Complete => {
+            Task::complete(
+                &mut conn,
+                &task,
+                status,
+                &app_state.root_storage_path,
+                app_state.folder_depth,
+                &app_state.indexer,
+            )
+            .await?;
+        }
     }
     Ok(Json(serde_json::json!({})).into_response())
 }
@@ -0,0 +1,72 @@
+use crate::auth::{self, ApiToken, AuthBackend};
+use crate::{error::ServerError, server::ServerState};
+use axum::{
+    Extension, Router,
+    extract::{Json, Path},
+    http::StatusCode,
+    routing::{get, post},
+};
+use axum_login::login_required;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", get(get_tokens))
+        .route("/create", post(create_token))
+        .route("/{id}/revoke", post(revoke_token))
+        .route_layer(login_required!(AuthBackend, login_url = "/login"))
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn get_tokens(
+    app_state: Extension<ServerState>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+) -> Result<Json<Vec<ApiToken>>, ServerError> {
+    let user = auth.user.ok_or(ServerError::AuthUserNotAuthenticated)?;
+    tracing::Span::current().record("userid", user.creator_id.to_string());
+    let mut conn = app_state.pool.acquire().await?;
+    Ok(Json(ApiToken::get_all_for_user(&mut conn, &user).await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateToken {
+    token_description: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    record: ApiToken,
+    /// Shown only this once
+    token: String,
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn create_token(
+    app_state: Extension<ServerState>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+    Json(params): Json<CreateToken>,
+) -> Result<Json<CreatedToken>, ServerError> {
+    let user = auth.user.ok_or(ServerError::AuthUserNotAuthenticated)?;
+    tracing::Span::current().record("userid", user.creator_id.to_string());
+    let mut conn = app_state.pool.acquire().await?;
+    let (record, token) = ApiToken::create(&mut conn, &user, params.token_description).await?;
+    Ok(Json(CreatedToken { record, token }))
+}
+
+#[tracing::instrument(skip(app_state, auth), fields(userid))]
+async fn revoke_token(
+    app_state: Extension<ServerState>,
+    auth: axum_login::AuthSession<auth::AuthBackend>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, ServerError> {
+    let user = auth.user.ok_or(ServerError::AuthUserNotAuthenticated)?;
+    tracing::Span::current().record("userid", user.creator_id.to_string());
+    let mut conn = app_state.pool.acquire().await?;
+    Ok(if ApiToken::revoke(&mut conn, &user, id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
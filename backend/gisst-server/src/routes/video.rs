@@ -14,6 +14,7 @@ use gisst::{
     models::{File, Video},
 };
 use serde::Deserialize;
+use std::path::PathBuf;
 use uuid::Uuid;
 
 pub fn router() -> Router {
@@ -37,26 +38,18 @@ async fn create_video(
     let user_id = auth.user.as_ref().map(|u| u.creator_id);
     tracing::Span::current().record("userid", user_id.map(|u| u.to_string()));
     let mut conn = app_state.pool.acquire().await?;
-    if File::get_by_id(&mut conn, video.file_id).await?.is_some() {
-        tracing::info!("Inserting video {video:?}");
-        Ok(Json(
-            Video::insert(
-                &mut conn,
-                Video {
-                    video_id: Uuid::new_v4(),
-                    file_id: video.file_id,
-                    creator_id: user_id.ok_or(ServerError::AuthUserNotAuthenticated)?,
-                    created_on: chrono::Utc::now(),
-                },
-            )
-            .await?,
-        ))
-    } else {
-        Err(ServerError::RecordMissing {
-            table: Table::File,
-            uuid: video.file_id,
-        })
-    }
+    let creator_id = user_id.ok_or(ServerError::AuthUserNotAuthenticated)?;
+    let file =
+        File::get_by_id(&mut conn, video.file_id)
+            .await?
+            .ok_or(ServerError::RecordMissing {
+                table: Table::File,
+                uuid: video.file_id,
+            })?;
+    let path = PathBuf::from(&app_state.root_storage_path).join(&file.file_dest_path);
+    let video = Video::for_file(file.file_id, creator_id, path).await;
+    tracing::info!("Inserting video {video:?}");
+    Ok(Json(Video::insert(&mut conn, video).await?))
 }
 
 async fn get_single_video(
@@ -64,5 +57,11 @@ async fn get_single_video(
     Path(id): Path<Uuid>,
 ) -> Result<Json<Video>, ServerError> {
     let mut conn = app_state.pool.acquire().await?;
-    Ok(Json(Video::get_by_id(&mut conn, id).await?.unwrap()))
+    let video = Video::get_by_id(&mut conn, id)
+        .await?
+        .ok_or(ServerError::RecordMissing {
+            table: Table::Video,
+            uuid: id,
+        })?;
+    Ok(Json(video))
 }
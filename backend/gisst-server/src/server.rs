@@ -6,8 +6,8 @@ use crate::{
     db,
     routes::{
         creator_router, environment_router, instance_router, lookup, object_router, players,
-        replay_router, save_router, screenshot_router, state_router, task_router, video_router,
-        work_router,
+        replay_router, save_router, screenshot_router, state_router, task_router, token_router,
+        video_router, work_router,
     },
     serverconfig::ServerConfig,
     tus,
@@ -154,33 +154,7 @@ pub async fn launch(config: &ServerConfig) -> Result<()> {
         .layer(HandleErrorLayer::new(handle_error))
         // This map_err is needed to get the types to work out after handleerror and before servedir.
         .map_err(|e| unreachable!("somehow a handled error wasn't actually handled {e:?}"));
-    let app = Router::new()
-        .route("/play/{instance_id}", get(players::get_player))
-        .route("/resources/{id}", patch(tus::patch).head(tus::head))
-        .route("/resources", post(tus::creation))
-        .route("/cores", get(lookup::get_cores))
-        .route("/cores/{corename}", get(lookup::get_cores))
-        .route("/lookup-work", get(lookup::lookup_work))
-        .nest("/objects", object_router())
-        .route("/logout", get(auth::logout_handler))
-        .nest("/creators", creator_router())
-        .nest("/instances", instance_router())
-        .nest("/replays", replay_router())
-        .nest("/saves", save_router())
-        .nest("/screenshots", screenshot_router())
-        .nest("/states", state_router())
-        .nest("/works", work_router())
-        .nest("/videos", video_router())
-        .nest("/environments", environment_router())
-        .route_layer(
-            // This is ugly, but it achieves the goal; the unwrap is fine
-            // because BASE_URL was initialized earlier in this function.
-            axum_login::login_required!(AuthBackend, login_url=&{format!("{}/login", BASE_URL.get().unwrap())})
-        )
-        .nest("/tasks", task_router())
-        .route("/data/{instance_id}", get(players::get_data))
-        .route("/login", get(auth::login_handler))
-        .route("/auth/google/callback", get(auth::oauth_callback_handler))
+    let app = api_routes()
         .nest_service(
             "/ui",
             builder.clone()
@@ -217,6 +191,8 @@ pub async fn launch(config: &ServerConfig) -> Result<()> {
         .layer(TraceLayer::new_for_http()
                .make_span_with(tower_http::trace::DefaultMakeSpan::new()
                                .include_headers(config.env.trace_include_headers)))
+        // Runs after auth_layer has put the request's session in place
+        .layer(axum::middleware::from_fn(auth::api_token_auth))
         .layer(auth_layer);
 
     let addr = SocketAddr::new(
@@ -232,7 +208,7 @@ pub async fn launch(config: &ServerConfig) -> Result<()> {
             interval.tick().await;
             tracing::info!("retire stale tasks");
             let Ok(mut conn) = task_pool.acquire().await else { tracing::error!("Error during task stale timeout: can't connect to DB"); continue; };
-            match crate::task::Task::timeout_stale(conn.as_mut(), TASK_STALE_DURATION).await {
+            match gisst::task::Task::timeout_stale(conn.as_mut(), TASK_STALE_DURATION).await {
                 Ok(stale) => {info!("Stale tasks: {stale:?}");},
                 Err(e) => {
                     tracing::error!("Error during task stale timeout {e}");
@@ -271,6 +247,40 @@ pub async fn launch(config: &ServerConfig) -> Result<()> {
     Ok(())
 }
 
+/// Routes backed by handlers rather than static files; the login layer only
+/// covers the routes registered before it.
+fn api_routes() -> Router {
+    Router::new()
+        .route("/play/{instance_id}", get(players::get_player))
+        .route("/cores", get(lookup::get_cores))
+        .route("/cores/{corename}", get(lookup::get_cores))
+        .route("/lookup-work", get(lookup::lookup_work))
+        .nest("/objects", object_router())
+        .route("/logout", get(auth::logout_handler))
+        .nest("/creators", creator_router())
+        .nest("/instances", instance_router())
+        .nest("/replays", replay_router())
+        .nest("/saves", save_router())
+        .nest("/screenshots", screenshot_router())
+        .nest("/states", state_router())
+        .nest("/works", work_router())
+        .nest("/videos", video_router())
+        .nest("/environments", environment_router())
+        .nest("/tokens", token_router())
+        .route_layer(
+            // This is ugly, but it achieves the goal; the unwrap is fine
+            // because BASE_URL is initialized before the routes are built.
+            axum_login::login_required!(AuthBackend, login_url=&{format!("{}/login", BASE_URL.get().unwrap())})
+        )
+        .nest("/tasks", task_router())
+        .route("/data/{instance_id}", get(players::get_data))
+        .route("/login", get(auth::login_handler))
+        .route("/auth/google/callback", get(auth::oauth_callback_handler))
+        // Task workers upload artifacts with their worker key and no login session
+        .route("/resources/{id}", patch(tus::patch).head(tus::head))
+        .route("/resources", post(tus::creation))
+}
+
 async fn handle_error(error: axum::BoxError) -> impl axum::response::IntoResponse {
     (
         axum::http::StatusCode::INTERNAL_SERVER_ERROR,
@@ -313,3 +323,167 @@ async fn set_embeddable_headers<B>(
     );
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode, header};
+    use base64::{Engine, engine::general_purpose};
+    use gisst::models::File as GFile;
+    use gisst::task::Task;
+    use tower::ServiceExt;
+
+    const WORKER_KEY: &str = "worker-test";
+
+    fn test_app(pool: PgPool, storage: &std::path::Path) -> Router {
+        let _ = BASE_URL.set("http://localhost:3000".to_string());
+        let root = storage.join("storage").to_string_lossy().to_string();
+        let temp = storage.join("tmp").to_string_lossy().to_string();
+        StorageHandler::init_storage(&root, &temp).unwrap();
+        let indexer = gisst::search::MeiliIndexer::new("http://localhost:7700", "test").unwrap();
+        let app_state = ServerState {
+            pool: pool.clone(),
+            root_storage_path: root,
+            temp_storage_path: temp,
+            folder_depth: 4,
+            default_chunk_size: 1_048_576,
+            pending_uploads: Arc::default(),
+            templates: minijinja::Environment::new(),
+            indexer: indexer.clone(),
+            search: gisst::search::MeiliSearch::new(
+                "http://localhost:7700",
+                "http://localhost:7700",
+                "test",
+            )
+            .unwrap(),
+            task_worker_keys: vec![WORKER_KEY.to_string()],
+        };
+        let backend = AuthBackend::new(
+            pool,
+            auth::build_oauth_client("http://localhost:3000", "id", "secret"),
+            vec![],
+            indexer,
+        );
+        let auth_layer = axum_login::AuthManagerLayerBuilder::new(
+            backend,
+            SessionManagerLayer::new(MemoryStore::default()),
+        )
+        .build();
+        api_routes()
+            .layer(Extension(app_state))
+            .layer(axum::middleware::from_fn(auth::api_token_auth))
+            .layer(auth_layer)
+    }
+
+    fn creation_request(
+        authorization: Option<&str>,
+        task_id: Option<Uuid>,
+        body: &[u8],
+    ) -> Request<Body> {
+        let mut metadata = format!(
+            "filename {},hash {}",
+            general_purpose::STANDARD.encode("artifact.bin"),
+            general_purpose::STANDARD.encode(StorageHandler::get_bytes_hash(body)),
+        );
+        if let Some(task_id) = task_id {
+            metadata.push_str(&format!(
+                ",task {}",
+                general_purpose::STANDARD.encode(task_id.to_string())
+            ));
+        }
+        let mut request = Request::post("/resources")
+            .header("Tus-Resumable", "1.0.0")
+            .header("Upload-Length", body.len().to_string())
+            .header("Upload-Metadata", metadata);
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn worker_key_uploads_a_file(pool: PgPool) {
+        let storage = std::env::temp_dir().join(format!("gisst-tus-{}", Uuid::new_v4()));
+        let app = test_app(pool.clone(), &storage);
+        let body = b"worker artifact";
+        let authorization = format!("Bearer {WORKER_KEY}");
+        let mut conn = pool.acquire().await.unwrap();
+        Task::create(&mut conn, "test", serde_json::json!({}))
+            .await
+            .unwrap();
+        let task = Task::claim_available(&mut conn, Some("test"), WORKER_KEY)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let created = app
+            .clone()
+            .oneshot(creation_request(
+                Some(&authorization),
+                Some(task.task_id),
+                body,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let location = created.headers()[header::LOCATION].to_str().unwrap();
+        let file_id: Uuid = location.rsplit('/').next().unwrap().parse().unwrap();
+
+        let patched = app
+            .oneshot(
+                Request::patch(format!("/resources/{file_id}"))
+                    .header(header::AUTHORIZATION, &authorization)
+                    .header("Tus-Resumable", "1.0.0")
+                    .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+                    .header("Upload-Offset", "0")
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(patched.status(), StatusCode::NO_CONTENT);
+
+        let file = GFile::get_by_id(&mut conn, file_id).await.unwrap().unwrap();
+        assert_eq!(file.file_size, i64::try_from(body.len()).unwrap());
+        assert_eq!(file.creator_id, None);
+        let uploader = sqlx::query_scalar!(
+            "SELECT task_id FROM task_upload WHERE file_id = $1",
+            file_id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap();
+        assert_eq!(uploader, task.task_id);
+        std::fs::remove_dir_all(storage).unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn uploads_need_a_user_or_claimed_task(pool: PgPool) {
+        let storage = std::env::temp_dir().join(format!("gisst-tus-{}", Uuid::new_v4()));
+        let app = test_app(pool.clone(), &storage);
+        let mut conn = pool.acquire().await.unwrap();
+        let unclaimed = Task::create(&mut conn, "test", serde_json::json!({}))
+            .await
+            .unwrap();
+        let authorization = format!("Bearer {WORKER_KEY}");
+        for (authorization, task_id) in [
+            (None, None),
+            (Some("Bearer worker-unknown"), Some(unclaimed.task_id)),
+            (Some(authorization.as_str()), Some(unclaimed.task_id)),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(creation_request(authorization, task_id, b"data"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        let response = app
+            .oneshot(creation_request(Some(&authorization), None, b"data"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        std::fs::remove_dir_all(storage).unwrap();
+    }
+}
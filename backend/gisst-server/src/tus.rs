@@ -7,6 +7,8 @@ use crate::error::ServerError;
 use bytes::Bytes;
 use gisst::inc_metric;
 use gisst::models::File as GFile;
+use gisst::task::{Task, TaskState};
+use sqlx::Acquire;
 use uuid::Uuid;
 
 use axum::{
@@ -49,6 +51,39 @@ impl ServerStateExt for ServerState {
     }
 }
 
+/// Uploads come from a logged-in creator, or from a task worker presenting
+/// its worker key
+enum Uploader {
+    Creator(Uuid),
+    Worker(String),
+}
+
+fn authenticate_uploader(
+    auth: &axum_login::AuthSession<crate::auth::AuthBackend>,
+    headers: &HeaderMap,
+    app_state: &ServerState,
+) -> Result<Uploader, ServerError> {
+    match &auth.user {
+        Some(user) => Ok(Uploader::Creator(user.creator_id)),
+        None => crate::routes::authenticate_worker(headers, app_state).map(Uploader::Worker),
+    }
+}
+
+/// Workers may only upload for a task they currently hold
+async fn check_task_claim(
+    conn: &mut sqlx::PgConnection,
+    task_id: Uuid,
+    claimant: &str,
+) -> Result<(), ServerError> {
+    let task = Task::get_by_id(conn, task_id)
+        .await?
+        .ok_or(ServerError::PermissionDenied)?;
+    if task.task_state != TaskState::Active || task.task_claimant.as_deref() != Some(claimant) {
+        return Err(ServerError::PermissionDenied);
+    }
+    Ok(())
+}
+
 #[tracing::instrument(skip(app_state, auth), fields(userid))]
 pub async fn head(
     app_state: Extension<ServerState>,
@@ -60,6 +95,7 @@ pub async fn head(
         "userid",
         auth.user.as_ref().map(|u| u.creator_id.to_string()),
     );
+    authenticate_uploader(&auth, &headers, &app_state)?;
     let tus_resumable: Option<String> = parse_header(&headers, "Tus-Resumable");
     if tus_resumable.is_none() || tus_resumable.unwrap() != "1.0.0" {
         return Ok(([("Tus-Version", "1.0.0")], StatusCode::PRECONDITION_FAILED).into_response());
@@ -113,10 +149,7 @@ pub async fn patch(
         "userid",
         auth.user.as_ref().map(|u| u.creator_id.to_string()),
     );
-    let creator_id = auth
-        .user
-        .ok_or(ServerError::AuthUserNotAuthenticated)?
-        .creator_id;
+    let uploader = authenticate_uploader(&auth, &headers, &app_state)?;
 
     if !check_header(&headers, "Content-Type", is_octet_stream) {
         return Ok((StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unknown content-type.").into_response());
@@ -139,14 +172,24 @@ pub async fn patch(
     }
 
     // Check that file upload exists
-    let Some((mut pu_offset, pu_length)) = app_state
+    let Some((mut pu_offset, pu_length, task_id)) = app_state
         .get_pending_uploads()
-        .and_then(|ups| ups.get(&id).map(|pu| (pu.offset, pu.length)))
+        .and_then(|ups| ups.get(&id).map(|pu| (pu.offset, pu.length, pu.task_id)))
     else {
         inc_metric!(conn, tus_patch_failed_missing, 1, id = id.to_string());
         return Err(ServerError::FileNotFound);
     };
 
+    // Worker uploads are finished by the worker holding the task they were started for
+    let creator_id = match (&uploader, task_id) {
+        (Uploader::Creator(creator_id), None) => Some(*creator_id),
+        (Uploader::Worker(claimant), Some(task_id)) => {
+            check_task_claim(&mut conn, task_id, claimant).await?;
+            None
+        }
+        _ => return Err(ServerError::PermissionDenied),
+    };
+
     // Check that offset is correct
     if pu_offset != offset {
         inc_metric!(
@@ -243,8 +286,9 @@ pub async fn patch(
                 .ok()
                 .and_then(|md| i64::try_from(md.len()).ok())
         });
+        let mut tx = conn.begin().await?;
         GFile::insert(
-            &mut conn,
+            &mut tx,
             GFile {
                 file_id: id,
                 file_hash: file_info.file_hash.clone(),
@@ -254,10 +298,14 @@ pub async fn patch(
                 file_size: i64::try_from(pu_length).map_err(ServerError::UploadTooBig)?,
                 file_compressed_size: gz_length,
                 created_on: chrono::Utc::now(),
-                creator_id: Some(creator_id),
+                creator_id,
             },
         )
         .await?;
+        if let Some(task_id) = task_id {
+            Task::record_upload(&mut tx, task_id, id).await?;
+        }
+        tx.commit().await?;
         let _ = app_state.remove_pending_upload(id);
     }
 
@@ -281,6 +329,7 @@ pub async fn creation(
         "userid",
         auth.user.as_ref().map(|u| u.creator_id.to_string()),
     );
+    let uploader = authenticate_uploader(&auth, &headers, &app_state)?;
     // Get file length header information
     // We are not allowing deferred length at this time
     let length: Option<usize> = parse_header(&headers, "Upload-Length");
@@ -312,6 +361,22 @@ pub async fn creation(
         }
     }
 
+    // Workers name the claimed task they are uploading an artifact for
+    let task_id = match uploader {
+        Uploader::Creator(_) => None,
+        Uploader::Worker(claimant) => {
+            let Some(task_id) = metadata.get("task").and_then(|t| Uuid::parse_str(t).ok()) else {
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    "Upload-Metadata header must contain a task ID for worker uploads.",
+                )
+                    .into_response());
+            };
+            check_task_claim(&mut conn, task_id, &claimant).await?;
+            Some(task_id)
+        }
+    };
+
     // Initialize pending upload
     let new_uuid = Uuid::new_v4();
     let filename = metadata.get("filename").unwrap();
@@ -349,6 +414,7 @@ pub async fn creation(
             file_information: file_info,
             length: length.unwrap(),
             offset: 0,
+            task_id,
         },
     );
     // This unwrap is fine since the url is initialized on server start
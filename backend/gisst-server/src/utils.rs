@@ -65,3 +65,63 @@ where
         Err(err) => Err(err),
     }
 }
+
+/// What a `Range` header asks for out of a body of known length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No range, or one this server doesn't handle, so the whole body
+    Full,
+    /// The inclusive span `first..=last`
+    Partial { first: u64, last: u64 },
+    /// A range starting past the end of the body
+    Unsatisfiable,
+}
+
+/// Reads a single `bytes=first-last`, `bytes=first-` or `bytes=-suffix` range
+/// against a body of `len` bytes. Malformed headers and multiple ranges are
+/// ignored, which the spec allows.
+pub fn parse_byte_range(header_map: &HeaderMap, len: u64) -> ByteRange {
+    let Some(spec) = header_map
+        .get(axum::http::header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial {
+                first: len.saturating_sub(suffix),
+                last: len - 1,
+            },
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let Ok(first) = first.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let last = if last.is_empty() {
+        u64::MAX
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= first => last,
+            _ => return ByteRange::Full,
+        }
+    };
+    if first >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial {
+        first,
+        last: last.min(len - 1),
+    }
+}
@@ -0,0 +1,47 @@
+use clap::Parser;
+use clap_verbosity_flag::{InfoLevel, Verbosity};
+
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct GISSTWorker {
+    #[command(flatten)]
+    pub verbose: Verbosity<InfoLevel>,
+
+    /// `GISST_CONFIG_PATH` environment variable must be set
+    #[clap(env)]
+    pub gisst_config_path: String,
+    /// `MEILI_URL` environment variable must be set
+    #[clap(env)]
+    pub meili_url: String,
+    /// `MEILI_API_KEY` environment variable must be set
+    #[clap(env)]
+    pub meili_api_key: String,
+    /// `GISST_WORKER_KEY` environment variable must be set, and must
+    /// be one of the server's `auth.task_worker_keys` (it starts with `worker-`)
+    #[clap(env)]
+    pub gisst_worker_key: String,
+
+    /// Base URL of the GISST server, e.g. `https://localhost:3000`
+    #[arg(long, env = "GISST_SERVER_URL")]
+    pub server_url: String,
+
+    /// Only claim tasks of these types (defaults to every registered type)
+    #[arg(long = "task-type")]
+    pub task_types: Vec<String>,
+
+    /// Seconds to wait between claim attempts when no task is ready
+    #[arg(long, default_value_t = 10)]
+    pub poll_interval: u64,
+
+    /// Seconds between heartbeat status updates while a task runs
+    #[arg(long, default_value_t = 60)]
+    pub heartbeat_interval: u64,
+
+    /// Upper bound in seconds for the backoff after failed requests
+    #[arg(long, default_value_t = 300)]
+    pub max_backoff: u64,
+
+    /// Accept self-signed certificates (for the development server)
+    #[arg(long, default_value_t = false)]
+    pub insecure: bool,
+}
@@ -0,0 +1,227 @@
+use crate::error::GISSTWorkerError;
+use base64::{Engine, engine::general_purpose};
+use gisst::storage::StorageHandler;
+use log::{debug, info};
+use reqwest::{StatusCode, header};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+/// The subset of the server's task record a worker needs
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClaimedTask {
+    pub task_id: Uuid,
+    pub task_type: String,
+    pub task_retry_count: i32,
+    pub task_input: serde_json::Value,
+    pub task_last_status: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct ClaimParams<'a> {
+    task_type: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct UpdateParams<'a> {
+    status: &'a serde_json::Value,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TaskUpdate {
+    Status,
+    Error,
+    Complete,
+}
+
+impl TaskUpdate {
+    fn path_segment(self) -> &'static str {
+        match self {
+            Self::Status => "status",
+            Self::Error => "error",
+            Self::Complete => "complete",
+        }
+    }
+}
+
+/// HTTP client for the server's `/tasks` and `/resources` (tus) endpoints
+#[derive(Clone)]
+pub struct TaskClient {
+    http: reqwest::Client,
+    base_url: String,
+    worker_key: String,
+    chunk_size: usize,
+}
+
+impl TaskClient {
+    pub fn new(
+        base_url: &str,
+        worker_key: &str,
+        chunk_size: usize,
+        insecure: bool,
+    ) -> Result<Self, GISSTWorkerError> {
+        let http = reqwest::Client::builder()
+            .danger_accept_invalid_certs(insecure)
+            .build()?;
+        Ok(Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            worker_key: worker_key.to_string(),
+            chunk_size,
+        })
+    }
+
+    fn bearer(&self) -> String {
+        format!("Bearer {}", self.worker_key)
+    }
+
+    async fn check(response: reqwest::Response) -> Result<reqwest::Response, GISSTWorkerError> {
+        let status = response.status();
+        if status.is_success() {
+            Ok(response)
+        } else {
+            let body = response.text().await.unwrap_or_default();
+            Err(GISSTWorkerError::Server { status, body })
+        }
+    }
+
+    /// Returns `Ok(None)` when the server has no task of this type ready
+    pub async fn claim(
+        &self,
+        task_type: Option<&str>,
+    ) -> Result<Option<ClaimedTask>, GISSTWorkerError> {
+        let response = self
+            .http
+            .post(format!("{}/tasks/claim", self.base_url))
+            .header(header::AUTHORIZATION, self.bearer())
+            .json(&ClaimParams { task_type })
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(Self::check(response).await?.json().await?))
+    }
+
+    pub async fn update(
+        &self,
+        task_id: Uuid,
+        update: TaskUpdate,
+        status: &serde_json::Value,
+    ) -> Result<(), GISSTWorkerError> {
+        let response = self
+            .http
+            .post(format!(
+                "{}/tasks/{task_id}/{}",
+                self.base_url,
+                update.path_segment()
+            ))
+            .header(header::AUTHORIZATION, self.bearer())
+            .json(&UpdateParams { status })
+            .send()
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
+
+    /// Uploads a file for the claimed task `task_id` through the tus endpoints
+    /// and returns the new file record's ID
+    pub async fn upload(
+        &self,
+        task_id: Uuid,
+        path: &Path,
+        filename: Option<&str>,
+    ) -> Result<Uuid, GISSTWorkerError> {
+        let hash = {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || StorageHandler::get_file_hash(path)).await??
+        };
+        let filename = filename
+            .map(String::from)
+            .or_else(|| {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            .unwrap_or_else(|| hash.clone());
+        let length = tokio::fs::metadata(path).await?.len();
+        let metadata = format!(
+            "filename {},hash {},task {}",
+            general_purpose::STANDARD.encode(&filename),
+            general_purpose::STANDARD.encode(&hash),
+            general_purpose::STANDARD.encode(task_id.to_string())
+        );
+        let response = Self::check(
+            self.http
+                .post(format!("{}/resources", self.base_url))
+                .header(header::AUTHORIZATION, self.bearer())
+                .header("Tus-Resumable", "1.0.0")
+                .header("Upload-Length", length.to_string())
+                .header("Upload-Metadata", metadata)
+                .send()
+                .await?,
+        )
+        .await?;
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|loc| loc.to_str().ok())
+            .ok_or(GISSTWorkerError::TusLocation)?
+            .to_string();
+        let file_id = location
+            .rsplit('/')
+            .next()
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .ok_or(GISSTWorkerError::TusLocation)?;
+        info!("Uploading {filename} ({length} bytes) to {location}");
+
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut offset: u64 = 0;
+        let mut buf = vec![0_u8; self.chunk_size];
+        while offset < length {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let read = file.read(&mut buf[filled..]).await?;
+                if read == 0 {
+                    break;
+                }
+                filled += read;
+            }
+            if filled == 0 {
+                // The file shrank underneath us
+                return Err(GISSTWorkerError::TusOffset {
+                    expected: length,
+                    actual: offset,
+                });
+            }
+            let response = Self::check(
+                self.http
+                    .patch(&location)
+                    .header(header::AUTHORIZATION, self.bearer())
+                    .header("Tus-Resumable", "1.0.0")
+                    .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+                    .header("Upload-Offset", offset.to_string())
+                    .body(buf[..filled].to_vec())
+                    .send()
+                    .await?,
+            )
+            .await?;
+            let server_offset = response
+                .headers()
+                .get("Upload-Offset")
+                .and_then(|off| off.to_str().ok())
+                .and_then(|off| off.parse::<u64>().ok())
+                .unwrap_or(offset);
+            let expected = offset + filled as u64;
+            if server_offset != expected {
+                return Err(GISSTWorkerError::TusOffset {
+                    expected,
+                    actual: server_offset,
+                });
+            }
+            debug!("Uploaded {server_offset}/{length} of {filename}");
+            offset = server_offset;
+        }
+        Ok(file_id)
+    }
+}
@@ -0,0 +1,42 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GISSTWorkerError {
+    #[error("http request error")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("server responded with {status}: {body}")]
+    Server {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    #[error("tus upload was not given a location")]
+    TusLocation,
+    #[error("tus upload offset mismatch, expected {expected} but server has {actual}")]
+    TusOffset { expected: u64, actual: u64 },
+    #[error("file read error")]
+    Io(#[from] std::io::Error),
+    #[error("database error")]
+    Sql(#[from] sqlx::Error),
+    #[error("json parse error")]
+    JsonParse(#[from] serde_json::Error),
+    #[error("storage error")]
+    Storage(#[from] gisst::error::Storage),
+    #[error("search index error")]
+    SearchIndex(#[from] gisst::error::SearchIndex),
+    #[error("v86 clone error")]
+    V86Clone(#[from] gisst::error::V86Clone),
+    #[error("replay checkpoint error")]
+    ReplayStates(#[from] gisst::error::ReplayStates),
+    #[error("configuration error")]
+    Config(#[from] config::ConfigError),
+    #[error("file size int conversion")]
+    FileSize(#[from] std::num::TryFromIntError),
+    #[error("no handler registered for task type {0}")]
+    UnknownTaskType(String),
+    #[error("invalid task input: {0}")]
+    InvalidInput(String),
+    #[error("worker shut down before the task finished")]
+    Shutdown,
+    #[error("tokio task error")]
+    Join(#[from] tokio::task::JoinError),
+}
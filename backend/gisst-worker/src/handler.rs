@@ -0,0 +1,95 @@
+use crate::client::{ClaimedTask, TaskClient};
+use crate::error::GISSTWorkerError;
+use futures::future::BoxFuture;
+use gisst::search::MeiliIndexer;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// Everything a handler may touch while it works on a claimed task
+pub struct TaskContext {
+    pub task: ClaimedTask,
+    pub db: PgPool,
+    pub indexer: MeiliIndexer,
+    pub storage_root: String,
+    pub temp_root: String,
+    pub folder_depth: u8,
+    client: TaskClient,
+    status: watch::Sender<serde_json::Value>,
+}
+
+impl TaskContext {
+    pub(crate) fn new(
+        task: ClaimedTask,
+        resources: &WorkerResources,
+        client: TaskClient,
+        status: watch::Sender<serde_json::Value>,
+    ) -> Self {
+        Self {
+            task,
+            db: resources.db.clone(),
+            indexer: resources.indexer.clone(),
+            storage_root: resources.storage_root.clone(),
+            temp_root: resources.temp_root.clone(),
+            folder_depth: resources.folder_depth,
+            client,
+            status,
+        }
+    }
+
+    /// Publishes a new status for the task; the heartbeat loop sends it to the server
+    pub fn set_status(&self, status: serde_json::Value) {
+        self.status.send_replace(status);
+    }
+
+    /// Uploads an artifact through tus, returning the ID of its new file record
+    pub async fn upload_artifact(
+        &self,
+        path: &Path,
+        filename: Option<&str>,
+    ) -> Result<Uuid, GISSTWorkerError> {
+        self.client.upload(self.task.task_id, path, filename).await
+    }
+}
+
+/// Shared, long-lived resources handed to each task context
+pub struct WorkerResources {
+    pub db: PgPool,
+    pub indexer: MeiliIndexer,
+    pub storage_root: String,
+    pub temp_root: String,
+    pub folder_depth: u8,
+}
+
+/// A unit of work for one `task_type`. The returned value becomes the
+/// task's output; an error marks the task as errored so it can be retried.
+pub trait TaskHandler: Send + Sync {
+    fn task_type(&self) -> &'static str;
+    fn run<'a>(
+        &'a self,
+        ctx: &'a TaskContext,
+    ) -> BoxFuture<'a, Result<serde_json::Value, GISSTWorkerError>>;
+}
+
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<&'static str, Arc<dyn TaskHandler>>,
+}
+
+impl HandlerRegistry {
+    pub fn register(&mut self, handler: impl TaskHandler + 'static) -> &mut Self {
+        self.handlers.insert(handler.task_type(), Arc::new(handler));
+        self
+    }
+
+    pub fn get(&self, task_type: &str) -> Option<Arc<dyn TaskHandler>> {
+        self.handlers.get(task_type).cloned()
+    }
+
+    pub fn task_types(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.handlers.keys().copied()
+    }
+}
@@ -0,0 +1,247 @@
+use crate::error::GISSTWorkerError;
+use crate::handler::{TaskContext, TaskHandler};
+use futures::future::BoxFuture;
+use gisst::models::File;
+use gisst::search::SearchIndexer;
+use gisst::storage::StorageHandler;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+const BATCH_SIZE: i64 = 1024;
+
+async fn file_count(ctx: &TaskContext) -> Result<i64, GISSTWorkerError> {
+    let mut conn = ctx.db.acquire().await?;
+    Ok(sqlx::query_scalar!("SELECT count(*) FROM file")
+        .fetch_one(conn.as_mut())
+        .await?
+        .unwrap_or(0))
+}
+
+/// Rebuilds every search index from the database
+pub struct Reindex;
+
+impl TaskHandler for Reindex {
+    fn task_type(&self) -> &'static str {
+        "reindex"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a TaskContext,
+    ) -> BoxFuture<'a, Result<serde_json::Value, GISSTWorkerError>> {
+        Box::pin(async move {
+            ctx.set_status(json!({"step":"reindexing"}));
+            let mut conn = ctx.db.acquire().await?;
+            let results = ctx.indexer.reindex(&mut conn).await;
+            let batches = results.len();
+            info!("reindex: {batches} batches");
+            if let Some(err) = results.into_iter().find_map(Result::err) {
+                return Err(err.into());
+            }
+            Ok(json!({"batches": batches}))
+        })
+    }
+}
+
+/// Recomputes `file_size` and `file_compressed_size` from what is on disk
+pub struct RecalcSizes;
+
+impl TaskHandler for RecalcSizes {
+    fn task_type(&self) -> &'static str {
+        "recalc_sizes"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a TaskContext,
+    ) -> BoxFuture<'a, Result<serde_json::Value, GISSTWorkerError>> {
+        Box::pin(async move {
+            let count = file_count(ctx).await?;
+            let mut updated = 0_i64;
+            let mut missing = vec![];
+            let mut offset = 0;
+            while offset < count {
+                let mut tx = ctx.db.begin().await?;
+                let files = File::get_batch(&mut tx, offset, BATCH_SIZE).await?;
+                if files.is_empty() {
+                    break;
+                }
+                for f in &files {
+                    match StorageHandler::get_stored_sizes(&ctx.storage_root, &f.file_dest_path) {
+                        Ok((file_size, file_compressed_size)) => {
+                            File::update_sizes(&mut tx, f.file_id, file_size, file_compressed_size)
+                                .await?;
+                            updated += 1;
+                        }
+                        Err(e) => {
+                            warn!("couldn't measure {}: {e}", f.file_dest_path);
+                            missing.push(f.file_id);
+                        }
+                    }
+                }
+                tx.commit().await?;
+                offset += i64::try_from(files.len())?;
+                ctx.set_status(json!({"done": offset, "total": count}));
+            }
+            Ok(json!({"updated": updated, "missing": missing}))
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct VerifyStorageInput {
+    /// Also recompute each file's md5 hash, which reads every byte in storage
+    #[serde(default)]
+    check_hashes: bool,
+}
+
+/// Checks that every file record has matching data in storage
+pub struct VerifyStorage;
+
+impl TaskHandler for VerifyStorage {
+    fn task_type(&self) -> &'static str {
+        "verify-storage"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a TaskContext,
+    ) -> BoxFuture<'a, Result<serde_json::Value, GISSTWorkerError>> {
+        Box::pin(async move {
+            let input: VerifyStorageInput = if ctx.task.task_input.is_null() {
+                VerifyStorageInput::default()
+            } else {
+                serde_json::from_value(ctx.task.task_input.clone())
+                    .map_err(|e| GISSTWorkerError::InvalidInput(e.to_string()))?
+            };
+            let count = file_count(ctx).await?;
+            let mut problems = vec![];
+            let mut offset = 0;
+            while offset < count {
+                let files = {
+                    let mut conn = ctx.db.acquire().await?;
+                    File::get_batch(&mut conn, offset, BATCH_SIZE).await?
+                };
+                if files.is_empty() {
+                    break;
+                }
+                for f in &files {
+                    if let Some(problem) = verify_file(ctx, f, input.check_hashes).await? {
+                        problems.push(json!({"file_id": f.file_id, "problem": problem}));
+                    }
+                }
+                offset += i64::try_from(files.len())?;
+                ctx.set_status(json!({
+                    "checked": offset,
+                    "total": count,
+                    "problems": problems.len()
+                }));
+            }
+            Ok(json!({"checked": offset, "problems": problems}))
+        })
+    }
+}
+
+async fn verify_file(
+    ctx: &TaskContext,
+    f: &File,
+    check_hash: bool,
+) -> Result<Option<String>, GISSTWorkerError> {
+    let path = std::path::Path::new(&ctx.storage_root).join(&f.file_dest_path);
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+        return Ok(Some("missing".to_string()));
+    };
+    let size = i64::try_from(metadata.len())?;
+    if size != f.file_size {
+        return Ok(Some(format!(
+            "size mismatch: recorded {} but stored {size}",
+            f.file_size
+        )));
+    }
+    if check_hash {
+        let hash =
+            tokio::task::spawn_blocking(move || StorageHandler::get_file_hash(path)).await??;
+        if hash != f.file_hash {
+            return Ok(Some(format!(
+                "hash mismatch: recorded {} but stored {hash}",
+                f.file_hash
+            )));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Debug, Deserialize)]
+struct V86CloneInput {
+    instance_id: Uuid,
+    state_id: Uuid,
+    creator_id: Option<Uuid>,
+}
+
+/// Clones a v86 instance with the disks as they are in one of its states
+pub struct V86Clone;
+
+impl TaskHandler for V86Clone {
+    fn task_type(&self) -> &'static str {
+        gisst::task::V86_CLONE_TASK
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a TaskContext,
+    ) -> BoxFuture<'a, Result<serde_json::Value, GISSTWorkerError>> {
+        Box::pin(async move {
+            let input: V86CloneInput = serde_json::from_value(ctx.task.task_input.clone())
+                .map_err(|e| GISSTWorkerError::InvalidInput(e.to_string()))?;
+            let mut conn = ctx.db.acquire().await?;
+            let instance_id = gisst::v86clone::clone_v86_machine(
+                &mut conn,
+                input.instance_id,
+                input.state_id,
+                &ctx.storage_root,
+                ctx.folder_depth,
+                &ctx.indexer,
+                input.creator_id,
+                |progress| {
+                    ctx.set_status(serde_json::to_value(&progress).unwrap_or_default());
+                },
+            )
+            .await?;
+            Ok(json!({"instance_id": instance_id}))
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayCheckpointsInput {
+    replay_id: Uuid,
+}
+
+/// Creates a state for each checkpoint stored in a replay file
+pub struct ReplayCheckpoints;
+
+impl TaskHandler for ReplayCheckpoints {
+    fn task_type(&self) -> &'static str {
+        gisst::task::REPLAY_CHECKPOINTS_TASK
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a TaskContext,
+    ) -> BoxFuture<'a, Result<serde_json::Value, GISSTWorkerError>> {
+        Box::pin(async move {
+            let input: ReplayCheckpointsInput = serde_json::from_value(ctx.task.task_input.clone())
+                .map_err(|e| GISSTWorkerError::InvalidInput(e.to_string()))?;
+            let mut conn = ctx.db.acquire().await?;
+            let state_ids = gisst::replaystates::extract_replay_states(
+                &mut conn,
+                input.replay_id,
+                &ctx.storage_root,
+                ctx.folder_depth,
+                &ctx.indexer,
+                |progress| {
+                    ctx.set_status(serde_json::to_value(&progress).unwrap_or_default());
+                },
+            )
+            .await?;
+            Ok(json!({"state_ids": state_ids}))
+        })
+    }
+}
@@ -0,0 +1,12 @@
+//! Reference worker for the GISST task queue.
+//!
+//! A worker claims tasks from the server's `/tasks` endpoints, runs the
+//! [`handler::TaskHandler`] registered for each task's `task_type`, and
+//! reports status, errors and results back. Custom workers can register
+//! their own handlers alongside the built-in ones in [`handlers`].
+pub mod client;
+pub mod error;
+pub mod handler;
+pub mod handlers;
+pub mod worker;
+pub mod workerconfig;
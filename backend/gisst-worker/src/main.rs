@@ -0,0 +1,75 @@
+mod args;
+
+use crate::args::GISSTWorker;
+use clap::Parser;
+use gisst_worker::client::TaskClient;
+use gisst_worker::error::GISSTWorkerError;
+use gisst_worker::handler::{HandlerRegistry, WorkerResources};
+use gisst_worker::handlers;
+use gisst_worker::worker::{self, Worker};
+use gisst_worker::workerconfig::WorkerConfig;
+use log::info;
+use sqlx::pool::PoolOptions;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), GISSTWorkerError> {
+    let args = GISSTWorker::parse();
+    env_logger::Builder::new()
+        .filter_level(args.verbose.log_level_filter())
+        .init();
+
+    info!("Found config file at path: {}", args.gisst_config_path);
+    let config = WorkerConfig::new(&args.gisst_config_path)?;
+    info!("Connecting to database: {}", config.database.database_url);
+    let db = PoolOptions::new()
+        .connect(&config.database.database_url)
+        .await?;
+    info!("DB connection successful.");
+    let indexer = gisst::search::MeiliIndexer::new(&args.meili_url, &args.meili_api_key)?;
+    let client = TaskClient::new(
+        &args.server_url,
+        &args.gisst_worker_key,
+        config.storage.chunk_size,
+        args.insecure,
+    )?;
+
+    let mut registry = HandlerRegistry::default();
+    registry
+        .register(handlers::Reindex)
+        .register(handlers::RecalcSizes)
+        .register(handlers::VerifyStorage)
+        .register(handlers::V86Clone)
+        .register(handlers::ReplayCheckpoints);
+
+    let task_types: Vec<String> = if args.task_types.is_empty() {
+        registry.task_types().map(String::from).collect()
+    } else {
+        if let Some(unknown) = args.task_types.iter().find(|tt| registry.get(tt).is_none()) {
+            return Err(GISSTWorkerError::UnknownTaskType(unknown.clone()));
+        }
+        args.task_types.clone()
+    };
+    info!("Handling task types: {}", task_types.join(", "));
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(0);
+    tokio::spawn(worker::listen_for_shutdown(shutdown_tx));
+
+    let worker = Worker {
+        client,
+        registry,
+        resources: WorkerResources {
+            db,
+            indexer,
+            storage_root: config.storage.root_folder_path,
+            temp_root: config.storage.temp_folder_path,
+            folder_depth: config.storage.folder_depth,
+        },
+        task_types,
+        poll_interval: Duration::from_secs(args.poll_interval),
+        heartbeat_interval: Duration::from_secs(args.heartbeat_interval),
+        max_backoff: Duration::from_secs(args.max_backoff),
+    };
+    worker.run(shutdown_rx).await;
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use crate::client::{ClaimedTask, TaskClient, TaskUpdate};
+use crate::error::GISSTWorkerError;
+use crate::handler::{HandlerRegistry, TaskContext, WorkerResources};
+use log::{error, info, warn};
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+// How many times to try reporting a finished task before giving up on it
+// (the server's stale task timeout will eventually put it back in the queue)
+const REPORT_ATTEMPTS: u32 = 5;
+
+/// Exponential backoff for failed requests to the server
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            current: base,
+        }
+    }
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+}
+
+/// Shutdown is requested once on the first signal (finish the current
+/// task, then stop) and forced on the second (abandon the current task).
+pub type ShutdownSignal = watch::Receiver<u8>;
+
+pub async fn listen_for_shutdown(tx: watch::Sender<u8>) {
+    #[cfg(unix)]
+    let mut sigterm =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).ok();
+    loop {
+        #[cfg(unix)]
+        let term = async {
+            match sigterm.as_mut() {
+                Some(s) => {
+                    s.recv().await;
+                }
+                None => std::future::pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let term = std::future::pending::<()>();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            () = term => {},
+        }
+        tx.send_modify(|n| *n = n.saturating_add(1));
+        if *tx.borrow() == 1 {
+            info!("Shutdown requested, finishing current task. Signal again to abort it.");
+        } else {
+            warn!("Aborting current task.");
+        }
+    }
+}
+
+async fn forced_shutdown(shutdown: &mut ShutdownSignal) {
+    if shutdown.wait_for(|n| *n >= 2).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+pub struct Worker {
+    pub client: TaskClient,
+    pub registry: HandlerRegistry,
+    pub resources: WorkerResources,
+    pub task_types: Vec<String>,
+    pub poll_interval: Duration,
+    pub heartbeat_interval: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Worker {
+    pub async fn run(&self, mut shutdown: ShutdownSignal) {
+        let mut backoff = Backoff::new(self.poll_interval, self.max_backoff);
+        while *shutdown.borrow() == 0 {
+            let delay = match self.claim_next().await {
+                Ok(Some(task)) => {
+                    backoff.reset();
+                    self.execute(task, &mut shutdown).await;
+                    continue;
+                }
+                Ok(None) => {
+                    backoff.reset();
+                    self.poll_interval
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!("Couldn't claim a task ({e}), retrying in {delay:?}");
+                    delay
+                }
+            };
+            tokio::select! {
+                () = tokio::time::sleep(delay) => {},
+                _ = shutdown.changed() => {},
+            }
+        }
+        info!("Worker shut down");
+    }
+
+    async fn claim_next(&self) -> Result<Option<ClaimedTask>, GISSTWorkerError> {
+        for task_type in &self.task_types {
+            if let Some(task) = self.client.claim(Some(task_type)).await? {
+                return Ok(Some(task));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn execute(&self, task: ClaimedTask, shutdown: &mut ShutdownSignal) {
+        let task_id = task.task_id;
+        info!(
+            "Claimed {} task {task_id} (attempt {})",
+            task.task_type, task.task_retry_count
+        );
+        let Some(handler) = self.registry.get(&task.task_type) else {
+            let err = GISSTWorkerError::UnknownTaskType(task.task_type.clone());
+            self.report(
+                task_id,
+                TaskUpdate::Error,
+                json!({"reason": err.to_string()}),
+            )
+            .await;
+            return;
+        };
+        let (status_tx, status_rx) = watch::channel(json!({"step": "claimed"}));
+        let ctx = TaskContext::new(task, &self.resources, self.client.clone(), status_tx);
+        let heartbeat = tokio::spawn(heartbeat(
+            self.client.clone(),
+            task_id,
+            status_rx.clone(),
+            self.heartbeat_interval,
+        ));
+        let result = tokio::select! {
+            r = handler.run(&ctx) => r,
+            () = forced_shutdown(shutdown) => Err(GISSTWorkerError::Shutdown),
+        };
+        heartbeat.abort();
+        match result {
+            Ok(output) => {
+                info!("Task {task_id} complete");
+                self.report(task_id, TaskUpdate::Complete, output).await;
+            }
+            Err(e) => {
+                error!("Task {task_id} failed: {e}");
+                let status = status_rx.borrow().clone();
+                self.report(
+                    task_id,
+                    TaskUpdate::Error,
+                    json!({"reason": e.to_string(), "status": status}),
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn report(&self, task_id: Uuid, update: TaskUpdate, status: serde_json::Value) {
+        let mut backoff = Backoff::new(Duration::from_secs(1), self.max_backoff);
+        for attempt in 1..=REPORT_ATTEMPTS {
+            match self.client.update(task_id, update, &status).await {
+                Ok(()) => return,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!(
+                        "Couldn't report {update:?} for task {task_id} (attempt {attempt}): {e}, retrying in {delay:?}"
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+        error!("Giving up on reporting {update:?} for task {task_id}");
+    }
+}
+
+/// Keeps the task's `task_updated_on` fresh so the server doesn't time it
+/// out, and forwards status changes published by the handler.
+async fn heartbeat(
+    client: TaskClient,
+    task_id: Uuid,
+    mut status: watch::Receiver<serde_json::Value>,
+    every: Duration,
+) {
+    let mut ticker = tokio::time::interval(every);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            changed = status.changed() => if changed.is_err() { break; },
+        }
+        let current = status.borrow_and_update().clone();
+        if let Err(e) = client.update(task_id, TaskUpdate::Status, &current).await {
+            warn!("Heartbeat for task {task_id} failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,276 @@
+//! Read-only access to zip and 7z archives as directory trees.
+//!
+//! Member names are normalized to relative paths with `/` separators; members
+//! that would escape the archive root are skipped. Directories that only
+//! exist implicitly, as prefixes of member names, are listed too.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::FSList;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// Largest member that will be extracted into memory
+const MAX_MEMBER_SIZE: u64 = 1 << 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    SevenZip,
+}
+
+/// Checks for a zip or 7z signature at the start of the stream, leaving it rewound
+pub fn detect<R: Read + Seek>(image: &mut R) -> Option<ArchiveKind> {
+    let mut magic = [0; 6];
+    let read = image.rewind().is_ok() && image.read_exact(&mut magic).is_ok();
+    if image.rewind().is_err() || !read {
+        return None;
+    }
+    match magic {
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] | [b'P', b'K', 7, 8, ..] => {
+            Some(ArchiveKind::Zip)
+        }
+        [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C] => Some(ArchiveKind::SevenZip),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// A member as it is stored in the archive
+#[derive(Clone, Debug)]
+struct Member {
+    name: String,
+    size: u64,
+}
+
+enum Backend<R: Read + Seek> {
+    Zip(zip::ZipArchive<R>),
+    SevenZip(Box<sevenz_rust::SevenZReader<R>>),
+}
+
+pub struct Archive<R: Read + Seek> {
+    backend: Backend<R>,
+    files: BTreeMap<PathBuf, Member>,
+    dirs: BTreeMap<PathBuf, Vec<ArchiveEntry>>,
+}
+
+impl<R: Read + Seek> std::fmt::Debug for Archive<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Archive")
+            .field("kind", &self.kind())
+            .field("files", &self.files.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Turns a stored member name into a relative path, or `None` if it leaves the root
+fn member_path(name: &str) -> Option<PathBuf> {
+    let name = name.replace('\\', "/");
+    let mut path = PathBuf::new();
+    for component in Path::new(&name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(path)
+}
+
+impl<R: Read + Seek> Archive<R> {
+    pub fn open(mut reader: R, kind: ArchiveKind) -> Result<Self, FSList> {
+        let mut members = vec![];
+        let backend = match kind {
+            ArchiveKind::Zip => {
+                let mut archive = zip::ZipArchive::new(reader)?;
+                for idx in 0..archive.len() {
+                    let file = archive.by_index_raw(idx)?;
+                    members.push((file.name().to_string(), file.is_dir(), file.size()));
+                }
+                Backend::Zip(archive)
+            }
+            ArchiveKind::SevenZip => {
+                let len = reader.seek(SeekFrom::End(0))?;
+                reader.rewind()?;
+                let archive =
+                    sevenz_rust::SevenZReader::new(reader, len, sevenz_rust::Password::empty())?;
+                for file in &archive.archive().files {
+                    members.push((file.name.clone(), file.is_directory, file.size));
+                }
+                Backend::SevenZip(Box::new(archive))
+            }
+        };
+        let mut archive = Self {
+            backend,
+            files: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+        };
+        archive.dirs.insert(PathBuf::new(), vec![]);
+        for (name, is_dir, size) in members {
+            let Some(path) = member_path(&name) else {
+                tracing::warn!("skipping archive member outside the archive root {name}");
+                continue;
+            };
+            if is_dir {
+                archive.add_dir(&path);
+            } else if !path.as_os_str().is_empty() && !archive.files.contains_key(&path) {
+                let parent = path.parent().unwrap_or(Path::new(""));
+                archive.add_dir(parent);
+                archive.add_entry(&path, false);
+                archive.files.insert(path, Member { name, size });
+            }
+        }
+        Ok(archive)
+    }
+
+    #[must_use]
+    pub fn kind(&self) -> ArchiveKind {
+        match self.backend {
+            Backend::Zip(_) => ArchiveKind::Zip,
+            Backend::SevenZip(_) => ArchiveKind::SevenZip,
+        }
+    }
+
+    fn add_entry(&mut self, path: &Path, is_dir: bool) {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return;
+        };
+        if let Some(siblings) = self.dirs.get_mut(parent) {
+            siblings.push(ArchiveEntry {
+                name: name.to_string_lossy().into_owned(),
+                is_dir,
+            });
+        }
+    }
+
+    fn add_dir(&mut self, path: &Path) {
+        if self.dirs.contains_key(path) {
+            return;
+        }
+        if let Some(parent) = path.parent() {
+            self.add_dir(parent);
+        }
+        self.dirs.insert(path.to_owned(), vec![]);
+        self.add_entry(path, true);
+    }
+
+    #[must_use]
+    pub fn is_dir(&self, path: &Path) -> bool {
+        self.dirs.contains_key(path)
+    }
+
+    pub fn read_dir(&self, path: &Path) -> Result<Vec<ArchiveEntry>, FSList> {
+        self.dirs
+            .get(path)
+            .cloned()
+            .ok_or_else(|| FSList::TraversalPath(path.to_string_lossy().into_owned()))
+    }
+
+    pub fn read_file(&mut self, path: &Path) -> Result<Vec<u8>, FSList> {
+        let member = self
+            .files
+            .get(path)
+            .ok_or_else(|| FSList::FileNotFound(path.to_string_lossy().into_owned()))?;
+        if member.size > MAX_MEMBER_SIZE {
+            return Err(FSList::Archive(format!(
+                "member too big to read {}",
+                path.display()
+            )));
+        }
+        let mut bytes = vec![];
+        match &mut self.backend {
+            Backend::Zip(archive) => {
+                archive.by_name(&member.name)?.read_to_end(&mut bytes)?;
+            }
+            Backend::SevenZip(archive) => {
+                // Solid blocks have to be decoded from the start, so walk the entries in order
+                let mut found = false;
+                archive.for_each_entries(|entry, reader| {
+                    if entry.name != member.name {
+                        return Ok(true);
+                    }
+                    reader.read_to_end(&mut bytes)?;
+                    found = true;
+                    Ok(false)
+                })?;
+                if !found {
+                    return Err(FSList::FileNotFound(path.to_string_lossy().into_owned()));
+                }
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    #[test]
+    fn zip_members_and_implicit_dirs() {
+        let mut data = vec![];
+        {
+            let mut writer = zip::ZipWriter::new(Cursor::new(&mut data));
+            let options = zip::write::SimpleFileOptions::default();
+            writer.add_directory("GAMES/", options).unwrap();
+            writer.start_file("GAMES/KEEN/KEEN.EXE", options).unwrap();
+            writer.write_all(b"commander").unwrap();
+            writer.start_file("README.TXT", options).unwrap();
+            writer.write_all(b"hello").unwrap();
+            writer.start_file("../EVIL.TXT", options).unwrap();
+            writer.write_all(b"nope").unwrap();
+            writer.finish().unwrap();
+        }
+        let mut cursor = Cursor::new(data);
+        assert_eq!(detect(&mut cursor), Some(ArchiveKind::Zip));
+        let mut archive = Archive::open(cursor, ArchiveKind::Zip).unwrap();
+        let root = archive.read_dir(Path::new("")).unwrap();
+        assert_eq!(
+            root,
+            vec![
+                ArchiveEntry {
+                    name: "GAMES".to_string(),
+                    is_dir: true
+                },
+                ArchiveEntry {
+                    name: "README.TXT".to_string(),
+                    is_dir: false
+                },
+            ]
+        );
+        assert!(archive.is_dir(Path::new("GAMES/KEEN")));
+        assert_eq!(
+            archive.read_file(Path::new("GAMES/KEEN/KEEN.EXE")).unwrap(),
+            b"commander"
+        );
+        assert!(archive.read_file(Path::new("EVIL.TXT")).is_err());
+    }
+
+    #[test]
+    fn seven_zip_members() {
+        let mut writer = sevenz_rust::SevenZWriter::new(Cursor::new(vec![])).unwrap();
+        for (name, data) in [("DOOM/DOOM.WAD", &b"IWAD"[..]), ("DOOM/DOOM.EXE", b"MZ")] {
+            let mut entry = sevenz_rust::SevenZArchiveEntry::new();
+            entry.name = name.to_string();
+            writer.push_archive_entry(entry, Some(data)).unwrap();
+        }
+        let mut cursor = writer.finish().unwrap();
+        assert_eq!(detect(&mut cursor), Some(ArchiveKind::SevenZip));
+        let mut archive = Archive::open(cursor, ArchiveKind::SevenZip).unwrap();
+        assert_eq!(archive.read_dir(Path::new("DOOM")).unwrap().len(), 2);
+        assert_eq!(
+            archive.read_file(Path::new("DOOM/DOOM.EXE")).unwrap(),
+            b"MZ"
+        );
+        assert_eq!(
+            archive.read_file(Path::new("DOOM/DOOM.WAD")).unwrap(),
+            b"IWAD"
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Records a worker task produces, declared in its completion payload.
+//!
+//! A task completes with a JSON output; if that output has an `artifacts`
+//! array, the server creates the declared records from files the worker
+//! uploaded while it held the task and links them to existing records. This
+//! is the wire format shared by the server and workers:
+//!
+//! ```json
+//! {"artifacts": [
+//!   {"kind": "video", "file_id": "…",
+//!    "link": {"table": "replay", "id": "…", "field": "video_id"}},
+//!   {"kind": "state", "file_id": "…", "screenshot_file_id": "…",
+//!    "instance_id": "…", "name": "Level 2", "replay_id": "…", "replay_index": 3}
+//! ]}
+//! ```
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Key of the artifact list in a task's completion payload
+pub const ARTIFACTS_KEY: &str = "artifacts";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Artifact {
+    Video {
+        file_id: Uuid,
+        link: Option<ArtifactLink>,
+    },
+    Screenshot {
+        file_id: Uuid,
+        link: Option<ArtifactLink>,
+    },
+    State {
+        file_id: Uuid,
+        screenshot_file_id: Uuid,
+        instance_id: Uuid,
+        name: String,
+        #[serde(default)]
+        description: String,
+        replay_id: Option<Uuid>,
+        replay_index: Option<i32>,
+    },
+}
+
+/// Sets `field` on the `table` record `id` to the ID of the newly created record
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ArtifactLink {
+    pub table: String,
+    pub id: Uuid,
+    pub field: String,
+}
+
+impl ArtifactLink {
+    #[must_use]
+    pub fn replay_video(replay_id: Uuid) -> Self {
+        Self {
+            table: "replay".to_string(),
+            id: replay_id,
+            field: "video_id".to_string(),
+        }
+    }
+    #[must_use]
+    pub fn state_screenshot(state_id: Uuid) -> Self {
+        Self {
+            table: "state".to_string(),
+            id: state_id,
+            field: "screenshot_id".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn wire_format() {
+        let file_id = Uuid::new_v4();
+        let replay_id = Uuid::new_v4();
+        let artifacts: Vec<Artifact> = serde_json::from_value(json!([
+            {"kind": "video", "file_id": file_id,
+             "link": {"table": "replay", "id": replay_id, "field": "video_id"}},
+            {"kind": "screenshot", "file_id": file_id}
+        ]))
+        .unwrap();
+        assert_eq!(
+            artifacts,
+            vec![
+                Artifact::Video {
+                    file_id,
+                    link: Some(ArtifactLink::replay_video(replay_id))
+                },
+                Artifact::Screenshot {
+                    file_id,
+                    link: None
+                }
+            ]
+        );
+        assert!(serde_json::from_value::<Artifact>(json!({"kind": "save"})).is_err());
+    }
+}
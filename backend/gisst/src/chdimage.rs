@@ -0,0 +1,311 @@
+//! CHD (MAME compressed hunks of data) images as plain disk images.
+//!
+//! A hard disk CHD reads as its logical bytes. A CD CHD stores each frame as
+//! 2352 sector bytes plus 96 subcode bytes, with tracks padded to a multiple
+//! of four frames; it reads as the sectors of its first data track, in the
+//! track's own sector size, so ISO 9660 detection sees an ordinary `.iso` or
+//! raw `.bin`.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::FSList;
+use ::chd::Chd;
+use ::chd::metadata::Metadata;
+use serde::Serialize;
+use std::io::{Read, Seek, SeekFrom};
+
+pub const CHD_MAGIC: &[u8; 8] = b"MComprHD";
+const CD_TRACK_PADDING: u64 = 4;
+const CD_FRAME_SIZE: u64 = 2352 + 96;
+
+/// Metadata tags, as big-endian four character codes
+const TAG_CD_TRACK: u32 = u32::from_be_bytes(*b"CHTR");
+const TAG_CD_TRACK2: u32 = u32::from_be_bytes(*b"CHT2");
+const TAG_GD_TRACK: u32 = u32::from_be_bytes(*b"CHGD");
+const TAG_HARD_DISK: u32 = u32::from_be_bytes(*b"GDDD");
+
+/// Checks for the CHD magic at the start of the file
+pub fn is_chd<R: Read + Seek>(image: &mut R) -> bool {
+    let mut magic = [0; 8];
+    let found = image.seek(SeekFrom::Start(0)).is_ok()
+        && image.read_exact(&mut magic).is_ok()
+        && &magic == CHD_MAGIC;
+    image.seek(SeekFrom::Start(0)).is_ok() && found
+}
+
+/// A track of a CD CHD, from its `CHT2`/`CHTR`/`CHGD` metadata
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ChdTrack {
+    pub number: u32,
+    pub track_type: String,
+    pub subtype: String,
+    pub frames: u64,
+    pub pregap: u64,
+    pub pregap_type: String,
+    pub postgap: u64,
+}
+
+impl ChdTrack {
+    /// Bytes of each sector stored for this track type
+    #[must_use]
+    pub fn sector_size(&self) -> u64 {
+        match self.track_type.as_str() {
+            "MODE1" | "MODE2_FORM1" => 2048,
+            "MODE2_FORM2" => 2324,
+            "MODE2" | "MODE2_FORM_MIX" => 2336,
+            _ => 2352,
+        }
+    }
+    #[must_use]
+    pub fn is_audio(&self) -> bool {
+        self.track_type == "AUDIO"
+    }
+}
+
+/// What a CHD's header and metadata say about it
+#[derive(Debug, Clone, Serialize)]
+pub struct ChdInfo {
+    pub logical_bytes: u64,
+    pub hunk_bytes: u32,
+    pub unit_bytes: u32,
+    /// SHA-1 of the data and metadata, as shown by `chdman info`
+    pub sha1: Option<String>,
+    /// SHA-1 of the data alone
+    pub raw_sha1: Option<String>,
+    pub tracks: Vec<ChdTrack>,
+    /// Cylinders, heads, sectors and bytes per sector of a hard disk
+    pub hard_disk_geometry: Option<String>,
+}
+
+impl ChdInfo {
+    #[must_use]
+    pub fn is_cd(&self) -> bool {
+        !self.tracks.is_empty()
+    }
+}
+
+fn hex(bytes: Option<[u8; 20]>) -> Option<String> {
+    bytes.map(|b| b.iter().map(|x| format!("{x:02x}")).collect())
+}
+
+/// Splits `KEY:value KEY:value` metadata text into pairs
+fn metadata_fields(value: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(value)
+        .trim_end_matches('\0')
+        .split_whitespace()
+        .filter_map(|field| field.split_once(':'))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn parse_track(value: &[u8]) -> Option<ChdTrack> {
+    let fields = metadata_fields(value);
+    let get = |key: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    };
+    let num = |key: &str| get(key).and_then(|v| v.parse().ok()).unwrap_or(0);
+    Some(ChdTrack {
+        number: get("TRACK")?.parse().ok()?,
+        track_type: get("TYPE")?,
+        subtype: get("SUBTYPE").unwrap_or_default(),
+        frames: num("FRAMES"),
+        pregap: num("PREGAP"),
+        pregap_type: get("PGTYPE").unwrap_or_default(),
+        postgap: num("POSTGAP"),
+    })
+}
+
+/// Where the stream's bytes come from in the CHD's logical bytes
+#[derive(Debug, Clone, Copy)]
+enum Mapping {
+    Linear,
+    CdTrack { start_frame: u64, sector_size: u64 },
+}
+
+/// The decompressed contents of a CHD as a seekable stream
+pub struct ChdStream<F: Read + Seek> {
+    chd: Chd<F>,
+    info: ChdInfo,
+    mapping: Mapping,
+    len: u64,
+    pos: u64,
+    hunk: Vec<u8>,
+    hunk_index: Option<u32>,
+    compressed: Vec<u8>,
+}
+
+impl<F: Read + Seek> std::fmt::Debug for ChdStream<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChdStream")
+            .field("info", &self.info)
+            .field("mapping", &self.mapping)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F: Read + Seek> ChdStream<F> {
+    pub fn open(file: F) -> Result<Self, FSList> {
+        let mut chd = Chd::open(file, None)?;
+        let header = chd.header();
+        let mut info = ChdInfo {
+            logical_bytes: header.logical_bytes(),
+            hunk_bytes: header.hunk_size(),
+            unit_bytes: header.unit_bytes(),
+            sha1: hex(header.sha1()),
+            raw_sha1: hex(header.raw_sha1()),
+            tracks: vec![],
+            hard_disk_geometry: None,
+        };
+        let metadata: Vec<Metadata> = chd.metadata_refs().try_into()?;
+        for meta in metadata {
+            match meta.metatag {
+                TAG_CD_TRACK | TAG_CD_TRACK2 | TAG_GD_TRACK => {
+                    info.tracks.extend(parse_track(&meta.value));
+                }
+                TAG_HARD_DISK => {
+                    let text = String::from_utf8_lossy(&meta.value);
+                    info.hard_disk_geometry = Some(text.trim_end_matches('\0').to_string());
+                }
+                _ => {}
+            }
+        }
+        info.tracks.sort_by_key(|t| t.number);
+        let (mapping, len) = if info.is_cd() {
+            let mut start_frame = 0;
+            let mut data_track = None;
+            for track in &info.tracks {
+                if !track.is_audio() {
+                    data_track = Some(track);
+                    break;
+                }
+                start_frame += track.frames.next_multiple_of(CD_TRACK_PADDING);
+            }
+            let track = data_track.ok_or_else(|| FSList::CHDLayout("no data track".to_string()))?;
+            // A pregap stored in the image comes before the track's first sector
+            let pregap = if track.pregap_type.starts_with('V') {
+                track.pregap
+            } else {
+                0
+            };
+            let sector_size = track.sector_size();
+            (
+                Mapping::CdTrack {
+                    start_frame: start_frame + pregap,
+                    sector_size,
+                },
+                track.frames.saturating_sub(pregap) * sector_size,
+            )
+        } else {
+            (Mapping::Linear, info.logical_bytes)
+        };
+        let hunk = chd.get_hunksized_buffer();
+        Ok(Self {
+            chd,
+            info,
+            mapping,
+            len,
+            pos: 0,
+            hunk,
+            hunk_index: None,
+            compressed: Vec::new(),
+        })
+    }
+
+    #[must_use]
+    pub fn info(&self) -> &ChdInfo {
+        &self.info
+    }
+
+    /// Maps a stream offset to a logical offset in the CHD, along with how
+    /// many bytes from there on are contiguous in the stream
+    fn map(&self, pos: u64) -> (u64, u64) {
+        match self.mapping {
+            Mapping::Linear => (pos, self.len - pos),
+            Mapping::CdTrack {
+                start_frame,
+                sector_size,
+            } => {
+                let (sector, within) = (pos / sector_size, pos % sector_size);
+                (
+                    (start_frame + sector) * CD_FRAME_SIZE + within,
+                    sector_size - within,
+                )
+            }
+        }
+    }
+}
+
+impl<F: Read + Seek> Read for ChdStream<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let (logical, contiguous) = self.map(self.pos);
+        let hunk_bytes = u64::from(self.info.hunk_bytes);
+        let hunk_index = u32::try_from(logical / hunk_bytes).map_err(std::io::Error::other)?;
+        if self.hunk_index != Some(hunk_index) {
+            self.hunk_index = None;
+            self.chd
+                .hunk(hunk_index)
+                .and_then(|mut hunk| hunk.read_hunk_in(&mut self.compressed, &mut self.hunk))
+                .map_err(std::io::Error::other)?;
+            self.hunk_index = Some(hunk_index);
+        }
+        let within = logical % hunk_bytes;
+        let available = (hunk_bytes - within)
+            .min(contiguous)
+            .min(self.len - self.pos);
+        let n = usize::try_from(available)
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+        let within = usize::try_from(within).map_err(std::io::Error::other)?;
+        buf[..n].copy_from_slice(&self.hunk[within..within + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<F: Read + Seek> Seek for ChdStream<F> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        }
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad seek"))?;
+        self.pos = new;
+        Ok(new)
+    }
+}
+
+/// Reads a CHD's header and metadata, for showing alongside its listing, or
+/// `None` if the file isn't a CHD
+pub fn chd_info(path: &std::path::Path) -> Result<Option<ChdInfo>, FSList> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    if !is_chd(&mut file) {
+        return Ok(None);
+    }
+    Ok(Some(ChdStream::open(file)?.info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_metadata() {
+        let track = parse_track(
+            b"TRACK:1 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:1234 PREGAP:150 PGTYPE:VMODE2_RAW PGSUB:RW POSTGAP:0\0",
+        )
+        .unwrap();
+        assert_eq!(track.number, 1);
+        assert_eq!(track.sector_size(), 2352);
+        assert_eq!(track.frames, 1234);
+        assert_eq!(track.pregap, 150);
+        assert!(track.pregap_type.starts_with('V'));
+        assert!(!track.is_audio());
+        assert!(parse_track(b"TYPE:AUDIO").is_none());
+    }
+}
@@ -1,11 +1,21 @@
-use sqlx::{PgConnection, postgres::PgQueryResult};
+use sqlx::{Connection, PgConnection, postgres::PgQueryResult};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::info;
 use uuid::Uuid;
 
-use crate::models::{Environment, Object};
+use crate::error::{Delete, Table};
+use crate::models::{Environment, File, Instance, Object, ObjectLink, Replay, Save, State, Work};
+use crate::search::{IndexKind, SearchIndexer};
+use crate::storage::StorageHandler;
+
 /// # Destructive Traits
 /// This trait allows for potentially destructive modification of database entries.
 /// This is mainly used by implementations that want to modify environments to update their core, and
-/// to unlink and instance and an object.
+/// to unlink and instance and an object. [`Deletion`] removes records along with
+/// whatever depends on them.
 #[allow(async_fn_in_trait)]
 pub trait DestructiveEnvironment {
     async fn update_core(
@@ -54,3 +64,644 @@ impl DestructiveObject for Object {
         .await
     }
 }
+
+/// Kinds of record a [`Deletion`] can remove
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RecordKind {
+    Work,
+    Environment,
+    Instance,
+    Object,
+    State,
+    Save,
+    Replay,
+}
+
+impl RecordKind {
+    fn table(self) -> Table {
+        match self {
+            RecordKind::Work => Table::Work,
+            RecordKind::Environment => Table::Environment,
+            RecordKind::Instance => Table::Instance,
+            RecordKind::Object => Table::Object,
+            RecordKind::State => Table::State,
+            RecordKind::Save => Table::Save,
+            RecordKind::Replay => Table::Replay,
+        }
+    }
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.table().fmt(f)
+    }
+}
+
+impl FromStr for RecordKind {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "work" => Ok(RecordKind::Work),
+            "environment" => Ok(RecordKind::Environment),
+            "instance" => Ok(RecordKind::Instance),
+            "object" => Ok(RecordKind::Object),
+            "state" => Ok(RecordKind::State),
+            "save" => Ok(RecordKind::Save),
+            "replay" => Ok(RecordKind::Replay),
+            _ => Err(
+                "record kind must be one of work, environment, instance, object, state, save, or replay",
+            ),
+        }
+    }
+}
+
+/// Everything deleting a record, or unlinking an object from an instance,
+/// takes with it. Make one with [`Deletion::plan`] or [`Deletion::plan_unlink`],
+/// show it, then [`Deletion::apply`] it.
+#[derive(Debug, Default)]
+pub struct Deletion {
+    /// The requested records first, then everything that depends on them
+    pub records: Vec<(RecordKind, Uuid)>,
+    requested: usize,
+    /// The requested link, as (instance, object)
+    pub unlinked: Option<(Uuid, Uuid)>,
+    /// Links of deleted objects to instances that stay, as (instance, object)
+    pub links: Vec<(Uuid, Uuid)>,
+    /// Records that stay but were derived from deleted ones; they lose that reference
+    pub detached: Vec<(RecordKind, Uuid)>,
+    /// Files only used by deleted records, removed from the database and storage
+    pub files: Vec<File>,
+    /// Search documents for the disk entries of deleted objects
+    disk_entry_ids: Vec<String>,
+    /// Images whose disk entry documents name instances that go away
+    refresh_hashes: BTreeSet<String>,
+}
+
+impl Deletion {
+    /// Finds everything deleting the `kind` record `id` would remove
+    pub async fn plan(conn: &mut PgConnection, kind: RecordKind, id: Uuid) -> Result<Self, Delete> {
+        let exists = match kind {
+            RecordKind::Work => Work::get_by_id(conn, id).await?.is_some(),
+            RecordKind::Environment => Environment::get_by_id(conn, id).await?.is_some(),
+            RecordKind::Instance => Instance::get_by_id(conn, id).await?.is_some(),
+            RecordKind::Object => Object::get_by_id(conn, id).await?.is_some(),
+            RecordKind::State => State::get_by_id(conn, id).await?.is_some(),
+            RecordKind::Save => Save::get_by_id(conn, id).await?.is_some(),
+            RecordKind::Replay => Replay::get_by_id(conn, id).await?.is_some(),
+        };
+        if !exists {
+            return Err(Delete::Missing(kind.table(), id));
+        }
+        let mut plan = Self {
+            records: vec![(kind, id)],
+            requested: 1,
+            ..Self::default()
+        };
+        plan.expand(conn).await?;
+        Ok(plan)
+    }
+
+    /// Finds what unlinking `object_id` from `instance_id` would affect. The
+    /// instance's states, saves, and replays were made with the object in
+    /// place and may not load without it, so they count as dependents.
+    pub async fn plan_unlink(
+        conn: &mut PgConnection,
+        object_id: Uuid,
+        instance_id: Uuid,
+    ) -> Result<Self, Delete> {
+        if !ObjectLink::get_all_for_instance_id(conn, instance_id)
+            .await?
+            .iter()
+            .any(|link| link.object_id == object_id)
+        {
+            return Err(Delete::NotLinked(object_id, instance_id));
+        }
+        let mut plan = Self {
+            unlinked: Some((instance_id, object_id)),
+            ..Self::default()
+        };
+        plan.records.extend(
+            Self::instance_records(conn, instance_id)
+                .await?
+                .into_iter()
+                .filter(|(kind, _)| *kind != RecordKind::Object),
+        );
+        plan.refresh_hashes.extend(
+            sqlx::query_scalar!(
+                "SELECT file_hash FROM object JOIN file USING (file_id) WHERE object_id = $1",
+                object_id
+            )
+            .fetch_all(&mut *conn)
+            .await?,
+        );
+        plan.expand(conn).await?;
+        Ok(plan)
+    }
+
+    /// Records that go only because something requested depends on them
+    #[must_use]
+    pub fn dependents(&self) -> &[(RecordKind, Uuid)] {
+        &self.records[self.requested..]
+    }
+
+    /// Whether applying this removes more than was asked for
+    #[must_use]
+    pub fn needs_cascade(&self) -> bool {
+        !self.dependents().is_empty() || !self.links.is_empty()
+    }
+
+    fn ids(&self, kind: RecordKind) -> Vec<Uuid> {
+        self.records
+            .iter()
+            .filter(|(k, _)| *k == kind)
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    /// States, saves, and replays made on an instance, plus its objects'
+    /// links; the objects themselves stay
+    async fn instance_records(
+        conn: &mut PgConnection,
+        instance_id: Uuid,
+    ) -> sqlx::Result<Vec<(RecordKind, Uuid)>> {
+        let mut records = vec![];
+        for state_id in sqlx::query_scalar!(
+            "SELECT state_id FROM state WHERE instance_id = $1",
+            instance_id
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        {
+            records.push((RecordKind::State, state_id));
+        }
+        for save_id in sqlx::query_scalar!(
+            "SELECT save_id FROM save WHERE instance_id = $1",
+            instance_id
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        {
+            records.push((RecordKind::Save, save_id));
+        }
+        for replay_id in sqlx::query_scalar!(
+            "SELECT replay_id FROM replay WHERE instance_id = $1",
+            instance_id
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        {
+            records.push((RecordKind::Replay, replay_id));
+        }
+        Ok(records)
+    }
+
+    /// Follows each record to the ones that depend on it until none are new,
+    /// then works out which files are left unused
+    #[allow(clippy::too_many_lines)]
+    async fn expand(&mut self, conn: &mut PgConnection) -> Result<(), Delete> {
+        let mut seen: HashSet<(RecordKind, Uuid)> = self.records.iter().copied().collect();
+        let mut detached = vec![];
+        let mut links = vec![];
+        let mut next = 0;
+        while next < self.records.len() {
+            let (kind, id) = self.records[next];
+            next += 1;
+            let mut found = vec![];
+            match kind {
+                RecordKind::Work => {
+                    for instance_id in sqlx::query_scalar!(
+                        "SELECT instance_id FROM instance WHERE work_id = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        found.push((RecordKind::Instance, instance_id));
+                    }
+                    for work_id in sqlx::query_scalar!(
+                        "SELECT work_id FROM work WHERE work_derived_from = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        detached.push((RecordKind::Work, work_id));
+                    }
+                }
+                RecordKind::Environment => {
+                    for instance_id in sqlx::query_scalar!(
+                        "SELECT instance_id FROM instance WHERE environment_id = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        found.push((RecordKind::Instance, instance_id));
+                    }
+                    for environment_id in sqlx::query_scalar!(
+                        "SELECT environment_id FROM environment WHERE environment_derived_from = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        detached.push((RecordKind::Environment, environment_id));
+                    }
+                }
+                RecordKind::Instance => {
+                    found.extend(Self::instance_records(conn, id).await?);
+                    for instance_id in sqlx::query_scalar!(
+                        "SELECT instance_id FROM instance WHERE derived_from_instance = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        detached.push((RecordKind::Instance, instance_id));
+                    }
+                    self.refresh_hashes.extend(
+                        sqlx::query_scalar!(
+                            r#"SELECT file_hash FROM instanceObject
+                               JOIN object USING (object_id) JOIN file USING (file_id)
+                               WHERE instance_id = $1"#,
+                            id
+                        )
+                        .fetch_all(&mut *conn)
+                        .await?,
+                    );
+                }
+                RecordKind::Object => {
+                    for instance_id in sqlx::query_scalar!(
+                        "SELECT instance_id FROM instanceObject WHERE object_id = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        links.push((instance_id, id));
+                    }
+                    for object_id in sqlx::query_scalar!(
+                        "SELECT object_id FROM object WHERE object_parent_id = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        detached.push((RecordKind::Object, object_id));
+                    }
+                    self.disk_entry_ids.extend(
+                        sqlx::query_scalar!(
+                            r#"SELECT object.object_id::text || '-' || disk_entry.disk_entry_id::text as "id!"
+                               FROM object JOIN file USING (file_id)
+                               JOIN disk_entry ON (disk_entry.file_hash = file.file_hash)
+                               WHERE object.object_id = $1"#,
+                            id
+                        )
+                        .fetch_all(&mut *conn)
+                        .await?,
+                    );
+                }
+                RecordKind::State => {
+                    for state_id in sqlx::query_scalar!(
+                        "SELECT state_id FROM state WHERE state_derived_from = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        found.push((RecordKind::State, state_id));
+                    }
+                    for save_id in sqlx::query_scalar!(
+                        "SELECT save_id FROM save WHERE state_derived_from = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        found.push((RecordKind::Save, save_id));
+                    }
+                    for instance_id in sqlx::query_scalar!(
+                        "SELECT instance_id FROM instance WHERE derived_from_state = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        detached.push((RecordKind::Instance, instance_id));
+                    }
+                }
+                RecordKind::Save => {
+                    for save_id in sqlx::query_scalar!(
+                        "SELECT save_id FROM save WHERE save_derived_from = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        found.push((RecordKind::Save, save_id));
+                    }
+                    for state_id in sqlx::query_scalar!(
+                        "SELECT state_id FROM state WHERE save_derived_from = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        detached.push((RecordKind::State, state_id));
+                    }
+                }
+                RecordKind::Replay => {
+                    // Checkpoint states refer to their replay
+                    for state_id in
+                        sqlx::query_scalar!("SELECT state_id FROM state WHERE replay_id = $1", id)
+                            .fetch_all(&mut *conn)
+                            .await?
+                    {
+                        found.push((RecordKind::State, state_id));
+                    }
+                    for replay_id in sqlx::query_scalar!(
+                        "SELECT replay_id FROM replay WHERE replay_forked_from = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        found.push((RecordKind::Replay, replay_id));
+                    }
+                    for save_id in sqlx::query_scalar!(
+                        "SELECT save_id FROM save WHERE replay_derived_from = $1",
+                        id
+                    )
+                    .fetch_all(&mut *conn)
+                    .await?
+                    {
+                        found.push((RecordKind::Save, save_id));
+                    }
+                }
+            }
+            for record in found {
+                if seen.insert(record) {
+                    self.records.push(record);
+                }
+            }
+        }
+
+        let deleted_instances = self.ids(RecordKind::Instance);
+        links.retain(|(instance_id, _)| !deleted_instances.contains(instance_id));
+        links.dedup();
+        self.links = links;
+        detached.retain(|record| !seen.contains(record));
+        detached.sort_unstable();
+        detached.dedup();
+        self.detached = detached;
+
+        let objects = self.ids(RecordKind::Object);
+        let states = self.ids(RecordKind::State);
+        let saves = self.ids(RecordKind::Save);
+        let replays = self.ids(RecordKind::Replay);
+        let mut file_ids = BTreeSet::new();
+        file_ids.extend(
+            sqlx::query_scalar!(
+                "SELECT file_id FROM object WHERE object_id = ANY($1)",
+                &objects
+            )
+            .fetch_all(&mut *conn)
+            .await?,
+        );
+        file_ids.extend(
+            sqlx::query_scalar!(
+                "SELECT file_id FROM state WHERE state_id = ANY($1)",
+                &states
+            )
+            .fetch_all(&mut *conn)
+            .await?,
+        );
+        file_ids.extend(
+            sqlx::query_scalar!("SELECT file_id FROM save WHERE save_id = ANY($1)", &saves)
+                .fetch_all(&mut *conn)
+                .await?,
+        );
+        file_ids.extend(
+            sqlx::query_scalar!(
+                "SELECT file_id FROM replay WHERE replay_id = ANY($1)",
+                &replays
+            )
+            .fetch_all(&mut *conn)
+            .await?,
+        );
+        for file_id in file_ids {
+            let used = sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM object WHERE file_id = $1 AND NOT object_id = ANY($2))
+                       OR EXISTS (SELECT 1 FROM state WHERE file_id = $1 AND NOT state_id = ANY($3))
+                       OR EXISTS (SELECT 1 FROM save WHERE file_id = $1 AND NOT save_id = ANY($4))
+                       OR EXISTS (SELECT 1 FROM replay WHERE file_id = $1 AND NOT replay_id = ANY($5))
+                       OR EXISTS (SELECT 1 FROM video WHERE file_id = $1)
+                       OR EXISTS (SELECT 1 FROM screenshot WHERE file_id = $1 OR thumbnail_file_id = $1)
+                       OR EXISTS (SELECT 1 FROM core_file WHERE file_id = $1) as "used!""#,
+                file_id,
+                &objects,
+                &states,
+                &saves,
+                &replays
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+            if !used && let Some(file) = File::get_by_id(conn, file_id).await? {
+                self.files.push(file);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes everything in the plan in one transaction, then the unused
+    /// files' data and the search documents of deleted records. Fails if
+    /// that would take more than was asked for, unless `cascade` is set.
+    #[tracing::instrument(skip(self, conn, indexer))]
+    pub async fn apply(
+        self,
+        conn: &mut PgConnection,
+        storage_root: &str,
+        cascade: bool,
+        indexer: &impl SearchIndexer,
+    ) -> Result<(), Delete> {
+        if !cascade && self.needs_cascade() {
+            return Err(Delete::Dependents(
+                self.dependents().len() + self.links.len(),
+            ));
+        }
+        let works = self.ids(RecordKind::Work);
+        let environments = self.ids(RecordKind::Environment);
+        let instances = self.ids(RecordKind::Instance);
+        let objects = self.ids(RecordKind::Object);
+        let states = self.ids(RecordKind::State);
+        let saves = self.ids(RecordKind::Save);
+        let replays = self.ids(RecordKind::Replay);
+        let file_ids: Vec<Uuid> = self.files.iter().map(|file| file.file_id).collect();
+        let file_hashes: Vec<String> = self
+            .files
+            .iter()
+            .map(|file| file.file_hash.clone())
+            .collect();
+
+        let mut tx = conn.begin().await?;
+        // Derived-from references without a foreign key have to be cleared by hand
+        sqlx::query!(
+            "UPDATE work SET work_derived_from = NULL WHERE work_derived_from = ANY($1)",
+            &works
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE environment SET environment_derived_from = NULL WHERE environment_derived_from = ANY($1)",
+            &environments
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE instance SET derived_from_instance = NULL WHERE derived_from_instance = ANY($1)",
+            &instances
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE instance SET derived_from_state = NULL WHERE derived_from_state = ANY($1)",
+            &states
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE state SET save_derived_from = NULL WHERE save_derived_from = ANY($1)",
+            &saves
+        )
+        .execute(&mut *tx)
+        .await?;
+        for (instance_id, object_id) in self.unlinked.iter().chain(&self.links) {
+            Object::unlink(&mut tx, *object_id, *instance_id).await?;
+        }
+        // Saves and states point at each other and at replays, so go in this order
+        sqlx::query!(
+            "DELETE FROM instance_save WHERE save_id = ANY($1) OR instance_id = ANY($2)",
+            &saves,
+            &instances
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM save WHERE save_id = ANY($1)", &saves)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM state WHERE state_id = ANY($1)", &states)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM replay WHERE replay_id = ANY($1)", &replays)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            "DELETE FROM instance WHERE instance_id = ANY($1)",
+            &instances
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM object WHERE object_id = ANY($1)", &objects)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM work WHERE work_id = ANY($1)", &works)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            "DELETE FROM environment WHERE environment_id = ANY($1)",
+            &environments
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM file WHERE file_id = ANY($1)", &file_ids)
+            .execute(&mut *tx)
+            .await?;
+        // Listings and entries are shared by every file with the same hash
+        sqlx::query!(
+            r#"DELETE FROM disk_entry WHERE file_hash = ANY($1)
+               AND NOT EXISTS (SELECT 1 FROM file WHERE file.file_hash = disk_entry.file_hash)"#,
+            &file_hashes
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"DELETE FROM file_listing WHERE file_hash = ANY($1)
+               AND NOT EXISTS (SELECT 1 FROM file WHERE file.file_hash = file_listing.file_hash)"#,
+            &file_hashes
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        for file in &self.files {
+            // Files stored with reused data share one copy
+            let shared = sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM file WHERE file_dest_path = $1) as "shared!""#,
+                file.file_dest_path
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+            if !shared {
+                let path = Path::new(storage_root).join(&file.file_dest_path);
+                info!("Deleting file at path: {}", path.to_string_lossy());
+                if let Err(err) = tokio::fs::remove_file(&path).await {
+                    tracing::warn!("Error deleting file {path:?}: {err:?}");
+                }
+                tokio::fs::remove_file(StorageHandler::get_gz_path(&path))
+                    .await
+                    .ok();
+            }
+        }
+
+        // Only touch the search index once the records are gone
+        let to_strings = |ids: &[Uuid]| ids.iter().map(Uuid::to_string).collect::<Vec<_>>();
+        for (index, ids) in [
+            (IndexKind::Instance, to_strings(&instances)),
+            (IndexKind::State, to_strings(&states)),
+            (IndexKind::Save, to_strings(&saves)),
+            (IndexKind::Replay, to_strings(&replays)),
+            (IndexKind::DiskEntry, self.disk_entry_ids),
+        ] {
+            if !ids.is_empty() {
+                indexer.remove_documents(index, &ids).await?;
+            }
+        }
+        for hash in &self.refresh_hashes {
+            indexer.upsert_disk_entries(conn, hash).await?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Deletion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((instance_id, object_id)) = self.unlinked {
+            writeln!(f, "unlink object {object_id} from instance {instance_id}")?;
+        }
+        for (i, (kind, id)) in self.records.iter().enumerate() {
+            if i < self.requested {
+                writeln!(f, "delete {kind} {id}")?;
+            } else {
+                writeln!(f, "delete {kind} {id} (dependent)")?;
+            }
+        }
+        for (instance_id, object_id) in &self.links {
+            writeln!(
+                f,
+                "unlink object {object_id} from instance {instance_id} (dependent)"
+            )?;
+        }
+        for (kind, id) in &self.detached {
+            writeln!(f, "detach {kind} {id} from what it was derived from")?;
+        }
+        for file in &self.files {
+            writeln!(
+                f,
+                "remove file {} ({}, {} bytes at {})",
+                file.file_id, file.file_filename, file.file_size, file.file_dest_path
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,488 @@
+//! Builds FAT disk images from a set of files, for v86 floppy and hard disk
+//! objects.
+//!
+//! Floppies are a bare FAT12 volume with the usual BIOS geometry. Hard disks
+//! get an MBR with one active partition starting on the second track (16
+//! heads, 63 sectors per track), the layout DOS `FDISK` makes, so that a
+//! `SYS` is all it takes to make one boot.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::{DiskBuild, Table};
+use crate::models::{Duplicate, Instance, Object, ObjectRole, insert_file_object};
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const SECTOR_SIZE: u64 = 512;
+const HDD_HEADS: u64 = 16;
+const HDD_SECTORS_PER_TRACK: u64 = 63;
+const HDD_MIN_MEGABYTES: u32 = 4;
+const HDD_MAX_MEGABYTES: u32 = 8192;
+/// Partitions this big or bigger get FAT32, smaller ones FAT16
+const FAT32_MIN_SECTORS: u64 = 512 * 2048;
+/// Most clusters a FAT16 volume can have
+const FAT16_MAX_CLUSTERS: u64 = 65524;
+/// Where the hidden sector count lives in a FAT boot sector
+const BPB_HIDDEN_SECTORS: u64 = 0x1C;
+
+/// The shape of disk to build
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Geometry {
+    Floppy720K,
+    Floppy1440K,
+    /// A hard disk of about this many megabytes, rounded down to whole cylinders
+    Hdd {
+        megabytes: u32,
+    },
+}
+
+impl std::str::FromStr for Geometry {
+    type Err = DiskBuild;
+
+    /// Parses `floppy-720k`, `floppy-1440k` (or `floppy-1.44m`), or `hdd-<N>m`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let lower = value.to_ascii_lowercase();
+        match lower.as_str() {
+            "floppy-720k" => Ok(Self::Floppy720K),
+            "floppy-1440k" | "floppy-1.44m" => Ok(Self::Floppy1440K),
+            _ => lower
+                .strip_prefix("hdd-")
+                .and_then(|size| size.strip_suffix('m'))
+                .and_then(|size| size.parse::<u32>().ok())
+                .filter(|size| (HDD_MIN_MEGABYTES..=HDD_MAX_MEGABYTES).contains(size))
+                .map(|megabytes| Self::Hdd { megabytes })
+                .ok_or_else(|| DiskBuild::Geometry(value.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for Geometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Floppy720K => write!(f, "floppy-720k"),
+            Self::Floppy1440K => write!(f, "floppy-1440k"),
+            Self::Hdd { megabytes } => write!(f, "hdd-{megabytes}m"),
+        }
+    }
+}
+
+/// Where the volume goes on the disk and how to format it
+#[derive(Debug)]
+struct Layout {
+    disk_sectors: u64,
+    partition_start: u64,
+    partition_sectors: u64,
+    fat_type: fatfs::FatType,
+    bytes_per_cluster: Option<u32>,
+    max_root_dir_entries: Option<u16>,
+    media: u8,
+    sectors_per_track: u16,
+    heads: u16,
+    /// MBR partition type, for partitioned disks
+    partition_type: Option<u8>,
+}
+
+impl Geometry {
+    fn layout(self) -> Layout {
+        let floppy = |sectors, sectors_per_track, media, root_entries, cluster| Layout {
+            disk_sectors: sectors,
+            partition_start: 0,
+            partition_sectors: sectors,
+            fat_type: fatfs::FatType::Fat12,
+            bytes_per_cluster: Some(cluster),
+            max_root_dir_entries: Some(root_entries),
+            media,
+            sectors_per_track,
+            heads: 2,
+            partition_type: None,
+        };
+        match self {
+            Self::Floppy720K => floppy(1440, 9, 0xF9, 112, 1024),
+            Self::Floppy1440K => floppy(2880, 18, 0xF0, 224, 512),
+            Self::Hdd { megabytes } => {
+                let cylinder = HDD_HEADS * HDD_SECTORS_PER_TRACK;
+                let disk_sectors = u64::from(megabytes) * 2048 / cylinder * cylinder;
+                let partition_sectors = disk_sectors - HDD_SECTORS_PER_TRACK;
+                let (fat_type, bytes_per_cluster, partition_type) =
+                    if partition_sectors >= FAT32_MIN_SECTORS {
+                        (fatfs::FatType::Fat32, None, 0x0C)
+                    } else {
+                        // The smallest clusters that keep the volume within FAT16's limits
+                        let mut cluster = SECTOR_SIZE;
+                        while partition_sectors * SECTOR_SIZE / cluster > FAT16_MAX_CLUSTERS {
+                            cluster *= 2;
+                        }
+                        let partition_type = if partition_sectors < 65536 {
+                            0x04
+                        } else {
+                            0x06
+                        };
+                        (
+                            fatfs::FatType::Fat16,
+                            u32::try_from(cluster).ok(),
+                            partition_type,
+                        )
+                    };
+                Layout {
+                    disk_sectors,
+                    partition_start: HDD_SECTORS_PER_TRACK,
+                    partition_sectors,
+                    fat_type,
+                    bytes_per_cluster,
+                    max_root_dir_entries: None,
+                    media: 0xF8,
+                    sectors_per_track: 63,
+                    heads: 16,
+                    partition_type: Some(partition_type),
+                }
+            }
+        }
+    }
+
+    /// Size of the whole disk image in bytes
+    #[must_use]
+    pub fn image_size(self) -> u64 {
+        self.layout().disk_sectors * SECTOR_SIZE
+    }
+}
+
+/// Turns `label` into a padded, upper case FAT volume label
+fn volume_label(label: &str) -> Result<[u8; 11], DiskBuild> {
+    let upper = label.to_ascii_uppercase();
+    if upper.len() > 11
+        || !upper
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b" _-!#$%&'()@^`{}~".contains(&b))
+    {
+        return Err(DiskBuild::Label(label.to_string()));
+    }
+    let mut out = [b' '; 11];
+    out[..upper.len()].copy_from_slice(upper.as_bytes());
+    Ok(out)
+}
+
+/// Cylinder, head, and sector of `lba` as packed into an MBR partition entry,
+/// or the usual all-ones marker past cylinder 1023
+fn chs(lba: u64) -> [u8; 3] {
+    let cylinder = lba / (HDD_HEADS * HDD_SECTORS_PER_TRACK);
+    if cylinder > 1023 {
+        return [254, 0xFF, 0xFF];
+    }
+    let head = (lba / HDD_SECTORS_PER_TRACK) % HDD_HEADS;
+    let sector = lba % HDD_SECTORS_PER_TRACK + 1;
+    [
+        u8::try_from(head).unwrap_or(0),
+        u8::try_from(sector | ((cylinder >> 2) & 0xC0)).unwrap_or(0),
+        u8::try_from(cylinder & 0xFF).unwrap_or(0),
+    ]
+}
+
+/// An MBR with a single active partition and no boot code
+fn master_boot_record(layout: &Layout, partition_type: u8) -> Result<[u8; 512], DiskBuild> {
+    let mut mbr = [0; 512];
+    let last = layout.partition_start + layout.partition_sectors - 1;
+    let entry = &mut mbr[446..462];
+    entry[0] = 0x80;
+    entry[1..4].copy_from_slice(&chs(layout.partition_start));
+    entry[4] = partition_type;
+    entry[5..8].copy_from_slice(&chs(last));
+    let too_big = |_| DiskBuild::Geometry("partition too big for an MBR".to_string());
+    entry[8..12].copy_from_slice(
+        &u32::try_from(layout.partition_start)
+            .map_err(too_big)?
+            .to_le_bytes(),
+    );
+    entry[12..16].copy_from_slice(
+        &u32::try_from(layout.partition_sectors)
+            .map_err(too_big)?
+            .to_le_bytes(),
+    );
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    Ok(mbr)
+}
+
+/// What goes into a built image: directories and files at relative paths
+#[derive(Debug, Default)]
+pub struct DiskContents {
+    dirs: Vec<PathBuf>,
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl DiskContents {
+    /// Reads every file under `root`
+    pub fn from_directory(root: &Path) -> Result<Self, DiskBuild> {
+        let mut contents = Self::default();
+        let mut stack = vec![PathBuf::new()];
+        while let Some(dir) = stack.pop() {
+            for entry in std::fs::read_dir(root.join(&dir))? {
+                let entry = entry?;
+                let path = dir.join(entry.file_name());
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    contents.dirs.push(path.clone());
+                    stack.push(path);
+                } else if file_type.is_file() {
+                    contents.files.insert(path, std::fs::read(entry.path())?);
+                }
+            }
+        }
+        Ok(contents)
+    }
+
+    /// Reads every member of a zip or 7z archive
+    pub fn from_archive(mut file: std::fs::File, name: &Path) -> Result<Self, DiskBuild> {
+        use crate::archive::{Archive, detect};
+        let kind = detect(&mut file).ok_or_else(|| DiskBuild::Source(name.to_owned()))?;
+        let mut archive = Archive::open(std::io::BufReader::new(file), kind)?;
+        let mut contents = Self::default();
+        let mut stack = vec![PathBuf::new()];
+        while let Some(dir) = stack.pop() {
+            for entry in archive.read_dir(&dir)? {
+                let path = dir.join(&entry.name);
+                if entry.is_dir {
+                    contents.dirs.push(path.clone());
+                    stack.push(path);
+                } else {
+                    let data = archive.read_file(&path)?;
+                    contents.files.insert(path, data);
+                }
+            }
+        }
+        Ok(contents)
+    }
+
+    /// Reads a directory or an archive, whichever `path` is
+    pub fn from_path(path: &Path) -> Result<Self, DiskBuild> {
+        if path.is_dir() {
+            Self::from_directory(path)
+        } else {
+            Self::from_archive(std::fs::File::open(path)?, path)
+        }
+    }
+
+    pub fn add_file(&mut self, path: PathBuf, data: Vec<u8>) {
+        self.files.insert(path, data);
+    }
+
+    /// Every directory, parents before children, including ones that only hold files
+    fn all_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = std::collections::BTreeSet::new();
+        let parents = self.files.keys().filter_map(|file| file.parent());
+        for dir in self.dirs.iter().map(PathBuf::as_path).chain(parents) {
+            dirs.extend(
+                dir.ancestors()
+                    .filter(|d| !d.as_os_str().is_empty())
+                    .map(Path::to_path_buf),
+            );
+        }
+        // Sorting puts each directory after its ancestors
+        dirs.into_iter().collect()
+    }
+}
+
+/// FAT paths are `/`-separated whatever the host uses
+pub(crate) fn fat_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Formats `out` as `geometry` and copies `contents` onto it. `out` is
+/// grown to the size of the disk.
+#[tracing::instrument(skip(out, contents))]
+pub fn build_image<F: Read + Write + Seek>(
+    out: &mut F,
+    geometry: Geometry,
+    label: Option<&str>,
+    contents: &DiskContents,
+) -> Result<(), DiskBuild> {
+    let layout = geometry.layout();
+    let too_big = |_| DiskBuild::Geometry(geometry.to_string());
+    // Zero the whole disk first so it reads the same wherever fatfs doesn't write
+    out.rewind()?;
+    std::io::copy(
+        &mut std::io::repeat(0).take(layout.disk_sectors * SECTOR_SIZE),
+        out,
+    )?;
+
+    let start = layout.partition_start * SECTOR_SIZE;
+    let end = start + layout.partition_sectors * SECTOR_SIZE;
+    let volume = fscommon::BufStream::new(fscommon::StreamSlice::new(&mut *out, start, end)?);
+    let mut storage = fatfs::StdIoWrapper::new(volume);
+    let mut options = fatfs::FormatVolumeOptions::new()
+        .fat_type(layout.fat_type)
+        .bytes_per_sector(512)
+        .total_sectors(u32::try_from(layout.partition_sectors).map_err(too_big)?)
+        .media(layout.media)
+        .sectors_per_track(layout.sectors_per_track)
+        .heads(layout.heads);
+    if let Some(bytes_per_cluster) = layout.bytes_per_cluster {
+        options = options.bytes_per_cluster(bytes_per_cluster);
+    }
+    if let Some(entries) = layout.max_root_dir_entries {
+        options = options.max_root_dir_entries(entries);
+    }
+    if let Some(label) = label {
+        options = options.volume_label(volume_label(label)?);
+    }
+    fatfs::format_volume(&mut storage, options)?;
+
+    let fs = fatfs::FileSystem::new(storage, fatfs::FsOptions::new())?;
+    {
+        let root = fs.root_dir();
+        for dir in contents.all_dirs() {
+            root.create_dir(&fat_path(&dir))?;
+        }
+        for (path, data) in &contents.files {
+            let mut file = root.create_file(&fat_path(path))?;
+            fatfs::Write::write_all(&mut file, data)?;
+        }
+    }
+    fs.unmount()?;
+
+    if let Some(partition_type) = layout.partition_type {
+        // The boot sector has to know where its partition starts to boot
+        out.seek(SeekFrom::Start(start + BPB_HIDDEN_SECTORS))?;
+        out.write_all(
+            &u32::try_from(layout.partition_start)
+                .map_err(too_big)?
+                .to_le_bytes(),
+        )?;
+        out.rewind()?;
+        out.write_all(&master_boot_record(&layout, partition_type)?)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Where a built image should be linked
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceLink {
+    pub instance_id: Uuid,
+    pub role_index: u16,
+}
+
+/// Builds an image of `contents` and stores it as a new object named
+/// `file_name`, linked to an instance as content if `link` is given
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(conn, contents))]
+pub async fn build_object(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    contents: DiskContents,
+    geometry: Geometry,
+    label: Option<String>,
+    file_name: &str,
+    object_description: Option<String>,
+    link: Option<InstanceLink>,
+    creator_id: Option<Uuid>,
+) -> Result<Object, DiskBuild> {
+    if let Some(link) = link
+        && Instance::get_by_id(conn, link.instance_id).await?.is_none()
+    {
+        return Err(DiskBuild::Missing(Table::Instance, link.instance_id));
+    }
+    let temp_path = std::env::temp_dir().join(format!("{}-{file_name}", Uuid::new_v4()));
+    let result = {
+        let temp_path = temp_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut out = std::fs::File::create_new(&temp_path)?;
+            build_image(&mut out, geometry, label.as_deref(), &contents)
+        })
+        .await?
+    };
+    let object_id = match result {
+        Ok(()) => {
+            insert_file_object(
+                conn,
+                storage_root,
+                depth,
+                &temp_path,
+                Some(file_name.to_string()),
+                object_description.or_else(|| Some(format!("{geometry} image {file_name}"))),
+                String::new(),
+                Duplicate::ReuseData,
+                creator_id,
+            )
+            .await
+        }
+        Err(e) => {
+            std::fs::remove_file(&temp_path).ok();
+            return Err(e);
+        }
+    };
+    tokio::fs::remove_file(&temp_path).await?;
+    let object_id = object_id?;
+    if let Some(link) = link {
+        Object::link_object_to_instance(
+            conn,
+            object_id,
+            link.instance_id,
+            ObjectRole::Content,
+            link.role_index,
+        )
+        .await?;
+    }
+    Object::get_by_id(conn, object_id)
+        .await?
+        .ok_or(DiskBuild::Missing(Table::Object, object_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn geometry_names() {
+        for name in ["floppy-720k", "floppy-1440k", "hdd-32m"] {
+            assert_eq!(name.parse::<Geometry>().unwrap().to_string(), name);
+        }
+        assert_eq!(
+            "FLOPPY-1.44M".parse::<Geometry>().unwrap(),
+            Geometry::Floppy1440K
+        );
+        assert!("hdd-1m".parse::<Geometry>().is_err());
+        assert!("zip-100m".parse::<Geometry>().is_err());
+        assert_eq!(Geometry::Floppy1440K.image_size(), 1_474_560);
+    }
+
+    #[test]
+    fn partition_entry() {
+        assert_eq!(chs(0), [0, 1, 0]);
+        assert_eq!(chs(63), [1, 1, 0]);
+        assert_eq!(chs(16 * 63 * 300), [0, 1 | 0x40, 300 - 256]);
+        let layout = Geometry::Hdd { megabytes: 32 }.layout();
+        let mbr = master_boot_record(&layout, 0x06).unwrap();
+        assert_eq!(mbr[446], 0x80);
+        assert_eq!(&mbr[454..458], &63u32.to_le_bytes());
+        assert_eq!(&mbr[510..], &[0x55, 0xAA]);
+    }
+
+    #[test]
+    fn builds_readable_images() {
+        let mut contents = DiskContents::default();
+        contents.add_file(PathBuf::from("README.TXT"), b"hello".to_vec());
+        contents.add_file(PathBuf::from("GAMES/KEEN/KEEN1.EXE"), vec![7; 3000]);
+        for geometry in [Geometry::Floppy1440K, Geometry::Hdd { megabytes: 8 }] {
+            let mut image = Cursor::new(vec![]);
+            build_image(&mut image, geometry, Some("gisst"), &contents).unwrap();
+            let data = image.into_inner();
+            assert_eq!(data.len() as u64, geometry.image_size());
+            let start = usize::try_from(geometry.layout().partition_start * SECTOR_SIZE).unwrap();
+            let fs = fatfs::FileSystem::new(
+                Cursor::new(data[start..].to_vec()),
+                fatfs::FsOptions::new(),
+            )
+            .unwrap();
+            let mut file = fs.root_dir().open_file("GAMES/KEEN/KEEN1.EXE").unwrap();
+            let mut read = vec![0; 3000];
+            fatfs::Read::read_exact(&mut file, &mut read).unwrap();
+            assert_eq!(read, vec![7; 3000]);
+        }
+    }
+}
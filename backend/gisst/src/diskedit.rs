@@ -0,0 +1,296 @@
+//! Writes and deletes files inside FAT disk image objects, e.g. to drop a
+//! config file or a patch onto a DOS hard disk. The edited copy is stored as
+//! a new object and linked into a new instance derived from the original.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::diskbuild::fat_path;
+use crate::error::{DiskEdit, Table};
+use crate::fslist::{ReadSeek, partition_bounds, split_partition};
+use crate::models::{
+    Duplicate, Instance, Object, ObjectLink, ObjectRole, Work, insert_file_object,
+};
+use crate::search::{NullIndexer, SearchIndexer};
+use sqlx::Connection;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A change to one path (`partN/...`) of a disk image
+#[derive(Clone, Debug)]
+pub enum FileEdit {
+    /// Creates or overwrites a file, making its parent directories as needed
+    Write { path: PathBuf, data: Vec<u8> },
+    /// Removes a file, or a directory and everything in it
+    Delete { path: PathBuf },
+}
+
+impl FileEdit {
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        match self {
+            FileEdit::Write { path, .. } | FileEdit::Delete { path } => path,
+        }
+    }
+}
+
+/// Applies `edits` in place to the FAT partitions of `image`. Edits to the
+/// same partition are applied in order.
+#[tracing::instrument(skip(image, edits))]
+pub fn edit_image<F: Read + Write + Seek>(
+    image: &mut F,
+    edits: &[FileEdit],
+) -> Result<(), DiskEdit> {
+    let mut partitions: BTreeMap<usize, Vec<(String, &FileEdit)>> = BTreeMap::new();
+    for edit in edits {
+        let (partid, subpath) = split_partition(edit.path())?;
+        let subpath = fat_path(subpath);
+        if subpath.is_empty() {
+            return Err(DiskEdit::Path(edit.path().to_string_lossy().into_owned()));
+        }
+        partitions.entry(partid).or_default().push((subpath, edit));
+    }
+    for (partid, edits) in partitions {
+        let (start, end) = partition_bounds(&mut *image as &mut dyn ReadSeek, partid)?;
+        let volume = fscommon::BufStream::new(fscommon::StreamSlice::new(&mut *image, start, end)?);
+        let fs = fatfs::FileSystem::new(fatfs::StdIoWrapper::new(volume), fatfs::FsOptions::new())?;
+        {
+            let root = fs.root_dir();
+            for (path, edit) in edits {
+                match edit {
+                    FileEdit::Write { data, .. } => {
+                        if let Some((parent, _)) = path.rsplit_once('/') {
+                            let mut dir = String::new();
+                            for name in parent.split('/') {
+                                if !dir.is_empty() {
+                                    dir.push('/');
+                                }
+                                dir.push_str(name);
+                                root.create_dir(&dir)?;
+                            }
+                        }
+                        // Opening an existing file keeps its old contents
+                        let mut file = root.create_file(&path)?;
+                        file.truncate()?;
+                        fatfs::Write::write_all(&mut file, data)?;
+                    }
+                    FileEdit::Delete { .. } => {
+                        // Directories have to be emptied before they can be removed
+                        let mut found = vec![];
+                        let mut pending = vec![(path.clone(), root.open_dir(&path).is_ok())];
+                        while let Some((path, is_dir)) = pending.pop() {
+                            if is_dir {
+                                for entry in root.open_dir(&path)?.iter() {
+                                    let entry = entry?;
+                                    let name = entry.file_name();
+                                    if name != "." && name != ".." {
+                                        pending.push((format!("{path}/{name}"), entry.is_dir()));
+                                    }
+                                }
+                            }
+                            found.push(path);
+                        }
+                        for path in found.iter().rev() {
+                            root.remove(path)?;
+                        }
+                    }
+                }
+            }
+        }
+        fs.unmount()?;
+    }
+    image.flush()?;
+    Ok(())
+}
+
+/// Names the work of a derived instance, defaulting to the original's
+#[derive(Clone, Debug, Default)]
+pub struct DerivedWork {
+    pub work_name: Option<String>,
+    pub work_version: Option<String>,
+}
+
+impl DerivedWork {
+    /// Makes the new work and instance records, both pointing back at the
+    /// originals. Unnamed works get the original's name plus `(how)`.
+    pub(crate) fn records(
+        self,
+        work: Work,
+        inst: Instance,
+        how: &str,
+        creator_id: Option<Uuid>,
+    ) -> (Work, Instance) {
+        let now = chrono::Utc::now();
+        let new_work = Work {
+            work_id: Uuid::new_v4(),
+            work_name: self
+                .work_name
+                .unwrap_or_else(|| format!("{} ({how})", work.work_name)),
+            work_version: self.work_version.unwrap_or(work.work_version),
+            work_platform: work.work_platform,
+            created_on: now,
+            work_derived_from: Some(work.work_id),
+            creator_id,
+        };
+        let new_inst = Instance {
+            instance_id: Uuid::new_v4(),
+            work_id: new_work.work_id,
+            derived_from_instance: Some(inst.instance_id),
+            derived_from_state: None,
+            created_on: now,
+            creator_id,
+            ..inst
+        };
+        (new_work, new_inst)
+    }
+}
+
+/// Copies the content object at `role_index` of instance `instance_id`,
+/// applies `edits` to the copy, and stores it as a new object. Returns a new
+/// instance of a new work, both derived from the originals, which uses the
+/// edited object in place of the original one.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(conn, edits, indexer))]
+pub async fn derive_instance(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    instance_id: Uuid,
+    role_index: u16,
+    edits: Vec<FileEdit>,
+    derived: DerivedWork,
+    indexer: &impl SearchIndexer,
+    creator_id: Option<Uuid>,
+) -> Result<Instance, DiskEdit> {
+    let inst = Instance::get_by_id(conn, instance_id)
+        .await?
+        .ok_or(DiskEdit::Missing(Table::Instance, instance_id))?;
+    let work = Work::get_by_id(conn, inst.work_id)
+        .await?
+        .ok_or(DiskEdit::Missing(Table::Work, inst.work_id))?;
+    let links = ObjectLink::get_all_for_instance_id(conn, instance_id).await?;
+    let disk = links
+        .iter()
+        .find(|link| {
+            link.object_role == ObjectRole::Content
+                && link.object_role_index == i32::from(role_index)
+        })
+        .ok_or(DiskEdit::NoContent(role_index))?;
+
+    let image_path = PathBuf::from(format!("{storage_root}/{}", disk.file_dest_path));
+    let temp_path = std::env::temp_dir().join(format!("{}-{}", Uuid::new_v4(), disk.file_filename));
+    let result = {
+        let temp_path = temp_path.clone();
+        tokio::task::spawn_blocking(move || {
+            if crate::chdimage::is_chd(&mut std::fs::File::open(&image_path)?) {
+                return Err(DiskEdit::Compressed(
+                    image_path.to_string_lossy().into_owned(),
+                ));
+            }
+            std::fs::copy(&image_path, &temp_path)?;
+            let mut image = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&temp_path)?;
+            edit_image(&mut image, &edits)
+        })
+        .await?
+    };
+    if let Err(e) = result {
+        std::fs::remove_file(&temp_path).ok();
+        return Err(e);
+    }
+
+    let (new_work, new_inst) = derived.records(work, inst, "edited", creator_id);
+    let mut tx = conn.begin().await?;
+    let object_id = insert_file_object(
+        &mut tx,
+        storage_root,
+        depth,
+        &temp_path,
+        Some(disk.file_filename.clone()),
+        Some(format!(
+            "{} edited for {}",
+            disk.file_filename, new_work.work_name
+        )),
+        disk.file_source_path.clone(),
+        // The edited image could match an earlier edit, but it's still its own object
+        Duplicate::ReuseData,
+        creator_id,
+    )
+    .await;
+    tokio::fs::remove_file(&temp_path).await?;
+    let object_id = object_id?;
+    Work::insert(&mut tx, new_work).await?;
+    let new_inst = Instance::insert(&mut tx, new_inst, &NullIndexer).await?;
+    for link in links {
+        let swapped = link.object_role == ObjectRole::Content
+            && link.object_role_index == i32::from(role_index);
+        Object::link_object_to_instance(
+            &mut tx,
+            if swapped { object_id } else { link.object_id },
+            new_inst.instance_id,
+            link.object_role,
+            u16::try_from(link.object_role_index)?,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    // Only index once the whole instance has committed
+    indexer.upsert_instance(conn, &new_inst).await?;
+    Ok(new_inst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diskbuild::{DiskContents, Geometry, build_image};
+    use std::io::Cursor;
+
+    #[test]
+    fn edits_images() {
+        let mut contents = DiskContents::default();
+        contents.add_file(PathBuf::from("README.TXT"), b"a much longer hello".to_vec());
+        contents.add_file(PathBuf::from("GAMES/KEEN/KEEN1.EXE"), vec![7; 3000]);
+        let mut image = Cursor::new(vec![]);
+        build_image(&mut image, Geometry::Floppy1440K, None, &contents).unwrap();
+        edit_image(
+            &mut image,
+            &[
+                FileEdit::Write {
+                    path: PathBuf::from("part0/README.TXT"),
+                    data: b"hello".to_vec(),
+                },
+                FileEdit::Write {
+                    path: PathBuf::from("part0/DOS/CONFIG.SYS"),
+                    data: b"FILES=30".to_vec(),
+                },
+                FileEdit::Delete {
+                    path: PathBuf::from("part0/GAMES"),
+                },
+            ],
+        )
+        .unwrap();
+        image.rewind().unwrap();
+        let fs = fatfs::FileSystem::new(image, fatfs::FsOptions::new()).unwrap();
+        let root = fs.root_dir();
+        let mut read = vec![];
+        for path in ["README.TXT", "DOS/CONFIG.SYS"] {
+            let mut file = root.open_file(path).unwrap();
+            let mut data = vec![0; 64];
+            let len = fatfs::Read::read(&mut file, &mut data).unwrap();
+            read.push(data[..len].to_vec());
+        }
+        assert_eq!(read, vec![b"hello".to_vec(), b"FILES=30".to_vec()]);
+        assert!(root.open_dir("GAMES").is_err());
+        assert!(
+            edit_image(
+                &mut Cursor::new(vec![0; 512]),
+                &[FileEdit::Delete {
+                    path: PathBuf::from("part0"),
+                }],
+            )
+            .is_err()
+        );
+    }
+}
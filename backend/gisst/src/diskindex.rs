@@ -0,0 +1,90 @@
+//! Records the files inside disk-image objects as `disk_entry` rows and
+//! pushes them to search, so users can find which images (and instances)
+//! contain a given file.
+use crate::error::DiskIndex;
+use crate::fslist::{cached_listing, file_summaries, file_to_path};
+use crate::models::{DiskEntry, DiskEntryInfo, File};
+use crate::search::IndexKind;
+use log::info;
+use sqlx::{Connection, PgConnection};
+use uuid::Uuid;
+
+/// Records the entries of one image file, unless its hash already has some
+/// and `force` is off. Returns how many entries were recorded.
+#[allow(clippy::missing_errors_doc)]
+#[tracing::instrument(skip(conn, indexer, file), fields(file_id = %file.file_id))]
+pub async fn index_disk_entries(
+    conn: &mut PgConnection,
+    storage_root: &str,
+    file: &File,
+    force: bool,
+    indexer: &impl crate::search::SearchIndexer,
+) -> Result<usize, DiskIndex> {
+    if !force && DiskEntry::count_for_file_hash(conn, &file.file_hash).await? > 0 {
+        return Ok(0);
+    }
+    if cached_listing(conn, storage_root, file).await?.is_none() {
+        return Ok(0);
+    }
+    let path = file_to_path(storage_root, file);
+    let summaries =
+        tokio::task::spawn_blocking(move || file_summaries(std::fs::File::open(path)?)).await??;
+    let mut tx = conn.begin().await?;
+    // Replaced entries get new IDs, so their search documents have to go
+    let stale: Vec<String> = DiskEntryInfo::get_for_file_hash(&mut tx, &file.file_hash)
+        .await?
+        .into_iter()
+        .map(|entry| entry.disk_entry_search_id)
+        .collect();
+    DiskEntry::delete_for_file_hash(&mut tx, &file.file_hash).await?;
+    for (path, summary) in &summaries {
+        DiskEntry::insert(
+            &mut tx,
+            DiskEntry {
+                disk_entry_id: Uuid::new_v4(),
+                file_hash: file.file_hash.clone(),
+                entry_path: path.to_string_lossy().into_owned(),
+                entry_name: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                entry_size: i64::try_from(summary.size)?,
+                entry_hash: summary.hash.clone(),
+                created_on: chrono::Utc::now(),
+            },
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    if !stale.is_empty() {
+        indexer
+            .remove_documents(IndexKind::DiskEntry, &stale)
+            .await?;
+    }
+    indexer.upsert_disk_entries(conn, &file.file_hash).await?;
+    Ok(summaries.len())
+}
+
+/// Records the entries of every image that backs an object. Images that
+/// can't be read are logged and skipped so one bad file doesn't stop the rest.
+#[allow(clippy::missing_errors_doc)]
+pub async fn index_all_disk_entries(
+    conn: &mut PgConnection,
+    storage_root: &str,
+    force: bool,
+    indexer: &impl crate::search::SearchIndexer,
+) -> Result<usize, DiskIndex> {
+    let files = File::get_all_for_objects(conn).await?;
+    let mut total = 0;
+    for file in &files {
+        match index_disk_entries(conn, storage_root, file, force, indexer).await {
+            Ok(count) => total += count,
+            Err(e) => log::error!("Could not index entries of file {}: {e:?}", file.file_id),
+        }
+    }
+    info!(
+        "Recorded {total} disk entries from {} object files",
+        files.len()
+    );
+    Ok(total)
+}
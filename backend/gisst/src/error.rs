@@ -26,6 +26,7 @@ impl fmt::Display for Action {
 pub enum Table {
     Creator,
     Video,
+    DiskEntry,
     Environment,
     File,
     Instance,
@@ -35,6 +36,7 @@ pub enum Table {
     Save,
     Screenshot,
     State,
+    Task,
     Users,
     Work,
     Core,
@@ -47,6 +49,7 @@ impl fmt::Display for Table {
         let s = match self {
             Table::Creator => "creator",
             Table::Video => "video",
+            Table::DiskEntry => "disk_entry",
             Table::Environment => "environment",
             Table::File => "file",
             Table::Instance => "instance",
@@ -56,6 +59,7 @@ impl fmt::Display for Table {
             Table::Save => "save",
             Table::Screenshot => "screenshot",
             Table::State => "state",
+            Table::Task => "task",
             Table::Users => "users",
             Table::Work => "work",
             Table::Core => "core",
@@ -113,6 +117,24 @@ pub enum FSList {
     FATFS(#[from] fatfs::Error<std::io::Error>),
     #[error("filesystem error")]
     FATError(String),
+    #[error("iso9660 filesystem error: {0}")]
+    ISO9660(String),
+    #[error("ext filesystem error: {0}")]
+    Ext(String),
+    #[error("chd image error")]
+    CHD(#[from] chd::Error),
+    #[error("chd image layout error: {0}")]
+    CHDLayout(String),
+    #[error("7z archive error")]
+    SevenZip(#[from] sevenz_rust::Error),
+    #[error("archive error: {0}")]
+    Archive(String),
+    #[error("listing database error")]
+    Sql(#[from] sqlx::Error),
+    #[error("listing serialization error")]
+    Json(#[from] serde_json::Error),
+    #[error("listing task error")]
+    Join(#[from] tokio::task::JoinError),
     #[error("filetype DB error")]
     FiletypeDB,
     #[error("subobject path error")]
@@ -147,8 +169,10 @@ pub enum V86Clone {
     StateNotFound(Uuid),
     #[error("file path not valid UTF-8 {0}")]
     PathNotUTF8(#[from] std::string::FromUtf8Error),
-    #[error("clone script not found")]
-    NoCloneScript,
+    #[error("v86 state error")]
+    State(#[from] crate::error::V86State),
+    #[error("tokio task error")]
+    Join(#[from] tokio::task::JoinError),
     #[error("storage error")]
     Storage(#[from] crate::error::Storage),
     #[error("database error")]
@@ -157,8 +181,6 @@ pub enum V86Clone {
     Record(#[from] RecordSQL),
     #[error("couldn't insert new record {0}")]
     Insert(#[from] crate::error::Insert),
-    #[error("v86dump error: {0}")]
-    V86Dump(String),
     #[error("IO error")]
     IO(#[from] std::io::Error),
     #[error("incomplete clone for {0}")]
@@ -167,6 +189,271 @@ pub enum V86Clone {
     DiskTooBig(std::num::TryFromIntError),
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DiskIndex {
+    #[error("database error")]
+    Sql(#[from] sqlx::Error),
+    #[error("insert error")]
+    Insert(#[from] Insert),
+    #[error("filesystem listing error")]
+    FSList(#[from] FSList),
+    #[error("file task error")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("search index error")]
+    SearchIndex(#[from] SearchIndex),
+    #[error("disk entry too large: {0}")]
+    EntrySize(#[from] std::num::TryFromIntError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayStates {
+    #[error("replay {0} not found")]
+    ReplayNotFound(Uuid),
+    #[error("file {0} not found")]
+    FileNotFound(Uuid),
+    #[error("replay file error")]
+    Replay(#[from] crate::error::Replay),
+    #[error("checkpoint screenshot error")]
+    Screenshot(#[from] crate::error::Screenshot),
+    #[error("checkpoint index {0} out of range")]
+    IndexOutOfRange(usize),
+    #[error("tokio task error")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("storage error")]
+    Storage(#[from] crate::error::Storage),
+    #[error("database error")]
+    Sql(#[from] sqlx::Error),
+    #[error("couldn't insert new record {0}")]
+    Insert(#[from] crate::error::Insert),
+    #[error("search index error")]
+    SearchIndex(#[from] SearchIndex),
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum V86State {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("state too short ({0} bytes)")]
+    TooShort(usize),
+    #[error("not a v86 state, magic is {0:#x}")]
+    BadMagic(u32),
+    #[error("unsupported v86 state version {0}")]
+    UnsupportedVersion(i32),
+    #[error("state header says {expected} bytes but state is {actual} bytes")]
+    Length { expected: usize, actual: usize },
+    #[error("invalid length in state header")]
+    HeaderLength(#[from] std::num::TryFromIntError),
+    #[error("state info block is not valid JSON")]
+    Info(#[from] serde_json::Error),
+    #[error("state info block is missing {0}")]
+    MissingField(&'static str),
+    #[error("state buffer {0} is out of range")]
+    BufferOutOfRange(usize),
+    #[error("state buffer {0} not found")]
+    MissingBuffer(usize),
+    #[error("disk overlay needs the original disk image")]
+    MissingOriginal,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VideoMeta {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("not an MP4 or WebM file")]
+    UnknownContainer,
+    #[error("malformed video container: {0}")]
+    Malformed(&'static str),
+    #[error("video header too large to read ({0} bytes)")]
+    HeaderTooLarge(u64),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Screenshot {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("sql error")]
+    Sql(#[from] sqlx::Error),
+    #[error("screenshot insert error")]
+    Insert(#[from] Insert),
+    #[error("screenshot file error")]
+    InsertFile(#[from] InsertFile),
+    #[error("not a PNG image: {0}")]
+    Png(#[from] png::DecodingError),
+    #[error("PNG encoding error")]
+    PngEncode(#[from] png::EncodingError),
+    #[error("unsupported screenshot dimensions {0}x{1}")]
+    Dimensions(u32, u32),
+    #[error("unsupported PNG color type {0:?}")]
+    ColorType(png::ColorType),
+    #[error("screenshot too big to decode")]
+    TooBig,
+    #[error("screenshot {0} has no image data")]
+    Missing(Uuid),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Subobject {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("sql error")]
+    Sql(#[from] sqlx::Error),
+    #[error("object insert error")]
+    Insert(#[from] Insert),
+    #[error("object file error")]
+    InsertFile(#[from] InsertFile),
+    #[error("disk image error")]
+    FSList(#[from] FSList),
+    #[error("tokio task error")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("{0} record with uuid {1} is missing")]
+    Missing(Table, Uuid),
+    #[error("no file name in path {0}")]
+    Path(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiskBuild {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("FAT filesystem error")]
+    FAT(#[from] fatfs::Error<std::io::Error>),
+    #[error("source archive error")]
+    FSList(#[from] FSList),
+    #[error("sql error")]
+    Sql(#[from] sqlx::Error),
+    #[error("object file error")]
+    InsertFile(#[from] InsertFile),
+    #[error("tokio task error")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("unknown disk geometry {0}")]
+    Geometry(String),
+    #[error("invalid volume label {0}")]
+    Label(String),
+    #[error("source is not a directory or a zip or 7z archive {0}")]
+    Source(std::path::PathBuf),
+    #[error("{0} record with uuid {1} is missing")]
+    Missing(Table, Uuid),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiskEdit {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("FAT filesystem error")]
+    FAT(#[from] fatfs::Error<std::io::Error>),
+    #[error("disk image error")]
+    FSList(#[from] FSList),
+    #[error("sql error")]
+    Sql(#[from] sqlx::Error),
+    #[error("record insert error")]
+    Insert(#[from] Insert),
+    #[error("object file error")]
+    InsertFile(#[from] InsertFile),
+    #[error("search index error")]
+    SearchIndex(#[from] SearchIndex),
+    #[error("tokio task error")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("{0} record with uuid {1} is missing")]
+    Missing(Table, Uuid),
+    #[error("instance has no content object at index {0}")]
+    NoContent(u16),
+    #[error("compressed image {0} cannot be edited")]
+    Compressed(String),
+    #[error("no file in partition path {0}")]
+    Path(String),
+    #[error("invalid object role index")]
+    RoleIndex(#[from] std::num::TryFromIntError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RomPatch {
+    #[error("not an IPS, BPS, or UPS patch")]
+    UnknownFormat,
+    #[error("patch ends in the middle of its {0}")]
+    Truncated(&'static str),
+    #[error("patch reaches past the end of its {0}")]
+    OutOfBounds(&'static str),
+    #[error("malformed patch {0}")]
+    Malformed(&'static str),
+    #[error("patch output of {0} bytes is too large")]
+    TooLarge(usize),
+    #[error("patch expects a {expected} byte source, got {found} bytes")]
+    SourceSize { expected: usize, found: usize },
+    #[error("patch expects source CRC32 {expected:08x}, got {found:08x}")]
+    SourceChecksum { expected: u32, found: u32 },
+    #[error("patched output CRC32 {found:08x} does not match {expected:08x}")]
+    TargetChecksum { expected: u32, found: u32 },
+    #[error("patch file is corrupt, its CRC32 is {found:08x} instead of {expected:08x}")]
+    PatchChecksum { expected: u32, found: u32 },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatchInstance {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("patch error")]
+    Patch(#[from] RomPatch),
+    #[error("sql error")]
+    Sql(#[from] sqlx::Error),
+    #[error("record insert error")]
+    Insert(#[from] Insert),
+    #[error("object file error")]
+    InsertFile(#[from] InsertFile),
+    #[error("search index error")]
+    SearchIndex(#[from] SearchIndex),
+    #[error("tokio task error")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("{0} record with uuid {1} is missing")]
+    Missing(Table, Uuid),
+    #[error("instance has no content object at index {0}")]
+    NoContent(u16),
+    #[error("invalid object role index")]
+    RoleIndex(#[from] std::num::TryFromIntError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Delete {
+    #[error("sql error")]
+    Sql(#[from] sqlx::Error),
+    #[error("search index error")]
+    SearchIndex(#[from] SearchIndex),
+    #[error("{0} record with uuid {1} is missing")]
+    Missing(Table, Uuid),
+    #[error("object {0} is not linked to instance {1}")]
+    NotLinked(Uuid, Uuid),
+    #[error("{0} dependent records or links would be removed too; cascade to remove them")]
+    Dependents(usize),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Replay {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("not a v86 or RetroArch replay")]
+    UnknownFormat,
+    #[error("unsupported replay version {0}")]
+    UnsupportedVersion(u32),
+    #[error("replay ends in the middle of its {0}")]
+    Truncated(&'static str),
+    #[error("unknown replay event code {0}")]
+    UnknownEvent(u8),
+    #[error("unknown replay frame token {0:#x}")]
+    UnknownToken(u8),
+    #[error("malformed replay: {0}")]
+    Malformed(&'static str),
+    #[error("replay checkpoint refers to missing block {0}")]
+    MissingBlock(u32),
+    #[error("{found} replay can't be used with a {expected} environment")]
+    WrongFramework {
+        expected: crate::model_enums::Framework,
+        found: crate::model_enums::Framework,
+    },
+    #[error("replay too big for this platform")]
+    TooBig(#[from] std::num::TryFromIntError),
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum InsertFile {
     #[error("Invalid path or no file at path")]
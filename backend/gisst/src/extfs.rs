@@ -0,0 +1,586 @@
+//! Read-only ext2, ext3 and ext4 filesystems.
+//!
+//! Regular files and directories are read through either classic block maps
+//! or ext4 extent trees, and small ext4 files may be inline in their inode.
+//! The journal is not replayed, so a filesystem that wasn't cleanly unmounted
+//! reads as of its last checkpoint. Symlinks and device nodes are left out of
+//! directory listings.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::FSList;
+use std::io::{Read, Seek, SeekFrom};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+const INCOMPAT_COMPRESSION: u32 = 0x1;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INODE_FLAG_EXTENTS: u32 = 0x0008_0000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+const EXTENT_MAGIC: u16 = 0xF30A;
+const MAX_EXTENT_DEPTH: u16 = 5;
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIR: u16 = 0x4000;
+const MODE_FILE: u16 = 0x8000;
+const DIRENT_FILE: u8 = 1;
+const DIRENT_DIR: u8 = 2;
+/// Files are read whole into memory, refuse anything bigger than this
+const MAX_FILE_SIZE: u64 = 1 << 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtEntry {
+    pub name: String,
+    pub is_dir: bool,
+    inode: u32,
+}
+
+/// The parts of an inode needed to read its contents
+struct Inode {
+    mode: u16,
+    size: u64,
+    flags: u32,
+    block: [u8; 60],
+}
+
+/// A run of `len` blocks at `physical` holding the file's blocks from `logical` on
+struct Run {
+    logical: u64,
+    physical: u64,
+    len: u64,
+}
+
+/// An ext2/3/4 filesystem over a partition
+pub struct ExtFs<R> {
+    image: R,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    desc_size: u64,
+    gdt_block: u64,
+    is_64bit: bool,
+    has_filetype: bool,
+}
+
+impl<R> std::fmt::Debug for ExtFs<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtFs")
+            .field("block_size", &self.block_size)
+            .field("is_64bit", &self.is_64bit)
+            .finish_non_exhaustive()
+    }
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn le_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn ext_error(what: &str) -> FSList {
+    FSList::Ext(what.to_string())
+}
+
+/// Checks for an ext2/3/4 superblock
+pub fn detect<R: Read + Seek>(image: &mut R) -> bool {
+    let mut magic = [0; 2];
+    image
+        .seek(SeekFrom::Start(SUPERBLOCK_OFFSET + 56))
+        .and_then(|_| image.read_exact(&mut magic))
+        .is_ok()
+        && u16::from_le_bytes(magic) == EXT_MAGIC
+}
+
+impl<R: Read + Seek> ExtFs<R> {
+    pub fn open(mut image: R) -> Result<Self, FSList> {
+        let mut sb = [0; 1024];
+        image.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        image.read_exact(&mut sb)?;
+        if le_u16(&sb, 56) != EXT_MAGIC {
+            return Err(ext_error("no ext superblock"));
+        }
+        let log_block_size = le_u32(&sb, 24);
+        if log_block_size > 6 {
+            return Err(ext_error("unsupported block size"));
+        }
+        let block_size = 1024 << log_block_size;
+        let incompat = le_u32(&sb, 96);
+        if incompat & (INCOMPAT_COMPRESSION | INCOMPAT_META_BG) != 0 {
+            return Err(ext_error("unsupported filesystem features"));
+        }
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        // Revision 0 filesystems have fixed size inodes
+        let inode_size = if le_u32(&sb, 76) == 0 {
+            128
+        } else {
+            u64::from(le_u16(&sb, 88))
+        };
+        let desc_size = if is_64bit {
+            u64::from(le_u16(&sb, 254)).max(32)
+        } else {
+            32
+        };
+        let inodes_per_group = le_u32(&sb, 40);
+        if inode_size < 128 || inodes_per_group == 0 {
+            return Err(ext_error("bad superblock"));
+        }
+        Ok(Self {
+            image,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            desc_size,
+            // The descriptors follow the superblock's block
+            gdt_block: u64::from(le_u32(&sb, 20)) + 1,
+            is_64bit,
+            has_filetype: incompat & INCOMPAT_FILETYPE != 0,
+        })
+    }
+
+    #[must_use]
+    pub fn root(&self) -> ExtEntry {
+        ExtEntry {
+            name: String::new(),
+            is_dir: true,
+            inode: ROOT_INODE,
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FSList> {
+        self.image.seek(SeekFrom::Start(offset))?;
+        Ok(self.image.read_exact(buf)?)
+    }
+
+    fn read_inode(&mut self, inode: u32) -> Result<Inode, FSList> {
+        let index = inode
+            .checked_sub(1)
+            .ok_or_else(|| ext_error("inode 0 does not exist"))?;
+        let (group, index) = (
+            u64::from(index / self.inodes_per_group),
+            u64::from(index % self.inodes_per_group),
+        );
+        let mut desc = vec![0; usize::try_from(self.desc_size).unwrap_or(32)];
+        self.read_at(
+            self.gdt_block * self.block_size + group * self.desc_size,
+            &mut desc,
+        )?;
+        let mut inode_table = u64::from(le_u32(&desc, 8));
+        if self.is_64bit && desc.len() >= 64 {
+            inode_table |= u64::from(le_u32(&desc, 0x28)) << 32;
+        }
+        let mut raw = [0; 128];
+        self.read_at(
+            inode_table * self.block_size + index * self.inode_size,
+            &mut raw,
+        )?;
+        let mut block = [0; 60];
+        block.copy_from_slice(&raw[40..100]);
+        Ok(Inode {
+            mode: le_u16(&raw, 0),
+            size: u64::from(le_u32(&raw, 4)) | u64::from(le_u32(&raw, 108)) << 32,
+            flags: le_u32(&raw, 32),
+            block,
+        })
+    }
+
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>, FSList> {
+        let mut data = vec![0; usize::try_from(self.block_size).unwrap_or(1024)];
+        self.read_at(block * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    /// Collects the runs of an extent tree node, `node` being its raw bytes
+    fn extent_runs(&mut self, node: &[u8], depth: u16, runs: &mut Vec<Run>) -> Result<(), FSList> {
+        if node.len() < 12 || le_u16(node, 0) != EXTENT_MAGIC {
+            return Err(ext_error("bad extent header"));
+        }
+        let entries = usize::from(le_u16(node, 2));
+        let node_depth = le_u16(node, 6);
+        if depth > MAX_EXTENT_DEPTH || node.len() < 12 + entries * 12 {
+            return Err(ext_error("bad extent tree"));
+        }
+        for entry in node[12..12 + entries * 12].chunks_exact(12) {
+            if node_depth == 0 {
+                let len = le_u16(entry, 4);
+                // Lengths over 32768 mark uninitialized extents, which read as zeros
+                if len <= 32768 {
+                    runs.push(Run {
+                        logical: u64::from(le_u32(entry, 0)),
+                        physical: u64::from(le_u16(entry, 6)) << 32 | u64::from(le_u32(entry, 8)),
+                        len: u64::from(len),
+                    });
+                }
+            } else {
+                let leaf = u64::from(le_u16(entry, 8)) << 32 | u64::from(le_u32(entry, 4));
+                let child = self.read_block(leaf)?;
+                self.extent_runs(&child, depth + 1, runs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the runs of a block map, `pointers` being the block numbers at `level` of indirection
+    fn map_runs(
+        &mut self,
+        pointers: &[u8],
+        level: u32,
+        logical: &mut u64,
+        blocks: u64,
+        runs: &mut Vec<Run>,
+    ) -> Result<(), FSList> {
+        let per_block = self.block_size / 4;
+        for pointer in pointers.chunks_exact(4) {
+            if *logical >= blocks {
+                break;
+            }
+            let pointer = u64::from(le_u32(pointer, 0));
+            let span = per_block.pow(level);
+            if pointer == 0 {
+                // A hole
+                *logical += span;
+            } else if level == 0 {
+                match runs.last_mut() {
+                    Some(run)
+                        if run.logical + run.len == *logical
+                            && run.physical + run.len == pointer =>
+                    {
+                        run.len += 1;
+                    }
+                    _ => runs.push(Run {
+                        logical: *logical,
+                        physical: pointer,
+                        len: 1,
+                    }),
+                }
+                *logical += 1;
+            } else {
+                let indirect = self.read_block(pointer)?;
+                self.map_runs(&indirect, level - 1, logical, blocks, runs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the runs holding the blocks of an inode that doesn't store its data inline
+    fn inode_runs(&mut self, inode: &Inode) -> Result<Vec<Run>, FSList> {
+        let blocks = inode.size.div_ceil(self.block_size);
+        let mut runs = vec![];
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.extent_runs(&inode.block, 0, &mut runs)?;
+        } else {
+            let mut logical = 0;
+            let block = inode.block;
+            self.map_runs(&block[..48], 0, &mut logical, blocks, &mut runs)?;
+            for (level, pointer) in block[48..].chunks_exact(4).enumerate() {
+                let level = u32::try_from(level + 1).unwrap_or(3);
+                self.map_runs(pointer, level, &mut logical, blocks, &mut runs)?;
+            }
+        }
+        Ok(runs)
+    }
+
+    fn read_inode_data(&mut self, inode: &Inode) -> Result<Vec<u8>, FSList> {
+        if inode.size > MAX_FILE_SIZE {
+            return Err(ext_error("file too big to read"));
+        }
+        let size = usize::try_from(inode.size).map_err(|_| ext_error("file too big to read"))?;
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            // Only the part in i_block, the rest would be in an extended attribute
+            return Ok(inode.block[..size.min(60)].to_vec());
+        }
+        let runs = self.inode_runs(inode)?;
+        let mut data = vec![0; size];
+        for run in runs {
+            let start = run.logical.saturating_mul(self.block_size);
+            if start >= inode.size {
+                continue;
+            }
+            let end = (start + run.len * self.block_size).min(inode.size);
+            let (start, end) = (
+                usize::try_from(start).map_err(|_| ext_error("bad extent"))?,
+                usize::try_from(end).map_err(|_| ext_error("bad extent"))?,
+            );
+            self.read_at(run.physical * self.block_size, &mut data[start..end])?;
+        }
+        Ok(data)
+    }
+
+    /// Lists a directory, leaving out `.`, `..`, and anything that isn't a file or directory
+    pub fn read_dir(&mut self, dir: &ExtEntry) -> Result<Vec<ExtEntry>, FSList> {
+        let inode = self.read_inode(dir.inode)?;
+        if inode.mode & MODE_TYPE_MASK != MODE_DIR {
+            return Err(ext_error("not a directory"));
+        }
+        let data = self.read_inode_data(&inode)?;
+        // Inline directories start with their parent's inode number
+        let mut pos = if inode.flags & INODE_FLAG_INLINE_DATA == 0 {
+            0
+        } else {
+            4
+        };
+        let mut entries = vec![];
+        while pos + 8 <= data.len() {
+            let inode = le_u32(&data, pos);
+            let rec_len = usize::from(le_u16(&data, pos + 4));
+            let name_len = usize::from(data[pos + 6]);
+            if rec_len < 8 || pos + 8 + name_len > data.len() {
+                break;
+            }
+            let name = &data[pos + 8..pos + 8 + name_len];
+            let file_type = data[pos + 7];
+            pos += rec_len;
+            if inode == 0 || name == b"." || name == b".." {
+                continue;
+            }
+            let is_dir = if self.has_filetype {
+                match file_type {
+                    DIRENT_FILE => false,
+                    DIRENT_DIR => true,
+                    _ => continue,
+                }
+            } else {
+                match self.read_inode(inode)?.mode & MODE_TYPE_MASK {
+                    MODE_FILE => false,
+                    MODE_DIR => true,
+                    _ => continue,
+                }
+            };
+            entries.push(ExtEntry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                is_dir,
+                inode,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Finds the entry at `path`, relative to the root; an empty path is the root
+    pub fn find(&mut self, path: &std::path::Path) -> Result<ExtEntry, FSList> {
+        let mut entry = self.root();
+        for component in path.components() {
+            let std::path::Component::Normal(name) = component else {
+                continue;
+            };
+            let name = name.to_string_lossy();
+            entry = self
+                .read_dir(&entry)?
+                .into_iter()
+                .find(|e| e.name == name)
+                .ok_or_else(|| FSList::FileNotFound(path.to_string_lossy().into_owned()))?;
+        }
+        Ok(entry)
+    }
+
+    pub fn read_file(&mut self, file: &ExtEntry) -> Result<Vec<u8>, FSList> {
+        let inode = self.read_inode(file.inode)?;
+        self.read_inode_data(&inode)
+    }
+
+    /// Opens a file for reading a piece at a time, with no limit on its size
+    pub fn open_file(&mut self, file: &ExtEntry) -> Result<ExtFile<'_, R>, FSList> {
+        let inode = self.read_inode(file.inode)?;
+        if inode.mode & MODE_TYPE_MASK != MODE_FILE {
+            return Err(ext_error("not a file"));
+        }
+        let (inline, runs) = if inode.flags & INODE_FLAG_INLINE_DATA == 0 {
+            (None, self.inode_runs(&inode)?)
+        } else {
+            let len = usize::try_from(inode.size).unwrap_or(60).min(60);
+            (Some(inode.block[..len].to_vec()), vec![])
+        };
+        Ok(ExtFile {
+            size: inline.as_ref().map_or(inode.size, |data| data.len() as u64),
+            fs: self,
+            inline,
+            runs,
+            pos: 0,
+        })
+    }
+}
+
+/// A file on an ext filesystem, readable and seekable without loading it whole
+pub struct ExtFile<'a, R> {
+    fs: &'a mut ExtFs<R>,
+    inline: Option<Vec<u8>>,
+    runs: Vec<Run>,
+    size: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for ExtFile<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let remaining = usize::try_from(self.size - self.pos).unwrap_or(usize::MAX);
+        let n;
+        if let Some(data) = &self.inline {
+            let start = usize::try_from(self.pos).map_err(std::io::Error::other)?;
+            n = remaining.min(buf.len());
+            buf[..n].copy_from_slice(&data[start..start + n]);
+        } else {
+            let block_size = self.fs.block_size;
+            let (block, within) = (self.pos / block_size, self.pos % block_size);
+            let run = self
+                .runs
+                .iter()
+                .find(|run| run.logical <= block && block < run.logical + run.len);
+            if let Some(run) = run {
+                // Read as far as the run goes
+                let left_in_run = (run.logical + run.len - block) * block_size - within;
+                n = usize::try_from(left_in_run)
+                    .unwrap_or(usize::MAX)
+                    .min(remaining)
+                    .min(buf.len());
+                let offset = (run.physical + block - run.logical) * block_size + within;
+                self.fs.image.seek(SeekFrom::Start(offset))?;
+                self.fs.image.read_exact(&mut buf[..n])?;
+            } else {
+                // A hole, or an uninitialized extent
+                let left_in_block = usize::try_from(block_size - within).unwrap_or(usize::MAX);
+                n = left_in_block.min(remaining).min(buf.len());
+                buf[..n].fill(0);
+            }
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for ExtFile<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        }
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad seek"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BS: usize = 1024;
+
+    fn put_u16(buf: &mut [u8], at: usize, v: u16) {
+        buf[at..at + 2].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_u32(buf: &mut [u8], at: usize, v: u32) {
+        buf[at..at + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn dirents(entries: &[(u32, u8, &[u8])]) -> Vec<u8> {
+        let mut out = vec![];
+        for (i, (inode, file_type, name)) in entries.iter().enumerate() {
+            let rec_len = if i + 1 == entries.len() {
+                BS - out.len()
+            } else {
+                (8 + name.len()).next_multiple_of(4)
+            };
+            let mut entry = vec![0; rec_len];
+            put_u32(&mut entry, 0, *inode);
+            put_u16(&mut entry, 4, u16::try_from(rec_len).unwrap());
+            entry[6] = u8::try_from(name.len()).unwrap();
+            entry[7] = *file_type;
+            entry[8..8 + name.len()].copy_from_slice(name);
+            out.extend(entry);
+        }
+        out
+    }
+
+    /// One 1K block group: root holds `hello.txt` (block map) and `sub/`,
+    /// which holds `x.bin` (extents)
+    fn image() -> Vec<u8> {
+        let mut img = vec![0; 16 * BS];
+        let sb = &mut img[1024..2048];
+        put_u32(sb, 0, 16);
+        put_u32(sb, 4, 16);
+        put_u32(sb, 20, 1);
+        put_u32(sb, 32, 8192);
+        put_u32(sb, 40, 16);
+        put_u16(sb, 56, EXT_MAGIC);
+        put_u32(sb, 76, 1);
+        put_u16(sb, 88, 128);
+        put_u32(sb, 96, INCOMPAT_FILETYPE);
+        // Inode table at block 5
+        put_u32(&mut img[2 * BS..], 8, 5);
+        let mut inode = |n: usize, mode: u16, size: u32, flags: u32, block: &[u8]| {
+            let at = 5 * BS + (n - 1) * 128;
+            put_u16(&mut img, at, mode);
+            put_u32(&mut img, at + 4, size);
+            put_u32(&mut img, at + 32, flags);
+            img[at + 40..at + 40 + block.len()].copy_from_slice(block);
+        };
+        inode(2, 0x41ED, 1024, 0, &7u32.to_le_bytes());
+        inode(12, 0x81A4, 5, 0, &8u32.to_le_bytes());
+        inode(13, 0x41ED, 1024, 0, &9u32.to_le_bytes());
+        let mut extents = [0; 24];
+        put_u16(&mut extents, 0, EXTENT_MAGIC);
+        put_u16(&mut extents, 2, 1);
+        put_u16(&mut extents, 4, 4);
+        put_u16(&mut extents, 16, 1);
+        put_u32(&mut extents, 20, 10);
+        inode(14, 0x81A4, 3, INODE_FLAG_EXTENTS, &extents);
+        let root = dirents(&[
+            (2, DIRENT_DIR, b"."),
+            (2, DIRENT_DIR, b".."),
+            (12, DIRENT_FILE, b"hello.txt"),
+            (13, DIRENT_DIR, b"sub"),
+        ]);
+        img[7 * BS..8 * BS].copy_from_slice(&root);
+        img[8 * BS..8 * BS + 5].copy_from_slice(b"hello");
+        let sub = dirents(&[
+            (13, DIRENT_DIR, b"."),
+            (2, DIRENT_DIR, b".."),
+            (14, DIRENT_FILE, b"x.bin"),
+        ]);
+        img[9 * BS..10 * BS].copy_from_slice(&sub);
+        img[10 * BS..10 * BS + 3].copy_from_slice(&[1, 2, 3]);
+        img
+    }
+
+    #[test]
+    fn lists_and_reads_files() {
+        let mut data = Cursor::new(image());
+        assert!(detect(&mut data));
+        let mut fs = ExtFs::open(data).unwrap();
+        let root = fs.root();
+        let names: Vec<_> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.is_dir))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("hello.txt".to_string(), false), ("sub".to_string(), true)]
+        );
+        let file = fs.find(std::path::Path::new("hello.txt")).unwrap();
+        assert_eq!(fs.read_file(&file).unwrap(), b"hello");
+        let file = fs.find(std::path::Path::new("sub/x.bin")).unwrap();
+        assert_eq!(fs.read_file(&file).unwrap(), vec![1, 2, 3]);
+        assert!(fs.find(std::path::Path::new("sub/nope")).is_err());
+        assert!(!detect(&mut Cursor::new(vec![0; 4096])));
+    }
+
+    #[test]
+    fn streams_files() {
+        let mut fs = ExtFs::open(Cursor::new(image())).unwrap();
+        let file = fs.find(std::path::Path::new("hello.txt")).unwrap();
+        let mut stream = fs.open_file(&file).unwrap();
+        stream.seek(SeekFrom::Start(1)).unwrap();
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"ello");
+        let dir = fs.find(std::path::Path::new("sub")).unwrap();
+        assert!(fs.open_file(&dir).is_err());
+    }
+}
@@ -2,38 +2,362 @@
 
 use fatfs::FsOptions;
 
+use crate::archive::{Archive, ArchiveKind};
 use crate::error::FSList;
+use crate::extfs::ExtFs;
+use crate::iso9660::IsoImage;
 
 const DEPTH_LIMIT: usize = 1024;
+/// How much of a file to look at when guessing its mime type
+const SNIFF_LEN: u64 = 8 * 1024;
 
-#[derive(serde::Serialize, Clone, Debug)]
+/// Version of the listing format and of what can be listed; bump it whenever
+/// either changes so that saved listings get recomputed
+pub const LISTING_VERSION: i32 = 1;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct FSFileListing {
     pub name: String,
     pub path: std::path::PathBuf,
     pub children: Vec<FSFileListing>,
     pub list_type: FSFileListingType,
+    /// The partition's filesystem, for partitions that could be read
+    pub fs_type: Option<FSType>,
 }
-#[derive(serde::Serialize, Clone, Debug, Copy, PartialEq, Eq)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Copy, PartialEq, Eq)]
 pub enum FSFileListingType {
     Partition,
     Directory,
     File,
 }
 
-#[tracing::instrument]
-fn get_partitions(image_file: &std::fs::File) -> Result<Vec<(usize, u64, u64)>, FSList> {
-    use tracing::info;
-    let file_len = image_file.metadata()?.len();
+/// Filesystems a partition can be read as
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Copy, PartialEq, Eq)]
+pub enum FSType {
+    FAT,
+    ISO9660,
+    Ext,
+    Zip,
+    SevenZip,
+}
+
+/// One entry of a directory listing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FSEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// A read-only filesystem on one partition. Paths are relative to its root,
+/// with the empty path being the root itself.
+pub trait Filesystem {
+    fn fs_type(&self) -> FSType;
+    /// Lists the directory at `path`, leaving out `.` and `..`
+    fn read_dir(&mut self, path: &std::path::Path) -> Result<Vec<FSEntry>, FSList>;
+    fn is_dir(&mut self, path: &std::path::Path) -> Result<bool, FSList>;
+    fn read_file(&mut self, path: &std::path::Path) -> Result<Vec<u8>, FSList>;
+    /// Opens the file at `path` for reading a piece at a time; by default it is read whole
+    fn open_file<'a>(
+        &'a mut self,
+        path: &std::path::Path,
+    ) -> Result<Box<dyn ReadSeek + 'a>, FSList> {
+        Ok(Box::new(std::io::Cursor::new(self.read_file(path)?)))
+    }
+}
+
+/// A disk image's bytes, read straight from its file or decompressed from a CHD
+pub trait ReadSeek: std::io::Read + std::io::Seek {}
+impl<T: std::io::Read + std::io::Seek> ReadSeek for T {}
+
+type DiskImage = Box<dyn ReadSeek>;
+type PartitionStream = fscommon::BufStream<fscommon::StreamSlice<DiskImage>>;
+type FATStorage = fatfs::StdIoWrapper<PartitionStream>;
+
+struct FatFilesystem(fatfs::FileSystem<FATStorage>);
+
+/// A FAT file behind the standard IO traits
+struct FatFile<'a>(
+    fatfs::File<'a, FATStorage, fatfs::DefaultTimeProvider, fatfs::LossyOemCpConverter>,
+);
+
+impl std::io::Read for FatFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        fatfs::Read::read(&mut self.0, buf).map_err(|e| std::io::Error::other(e.to_string()))
+    }
+}
+
+impl std::io::Seek for FatFile<'_> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(p) => fatfs::SeekFrom::Start(p),
+            std::io::SeekFrom::End(d) => fatfs::SeekFrom::End(d),
+            std::io::SeekFrom::Current(d) => fatfs::SeekFrom::Current(d),
+        };
+        fatfs::Seek::seek(&mut self.0, pos).map_err(|e| std::io::Error::other(e.to_string()))
+    }
+}
+
+impl Filesystem for FatFilesystem {
+    fn fs_type(&self) -> FSType {
+        FSType::FAT
+    }
+    fn read_dir(&mut self, path: &std::path::Path) -> Result<Vec<FSEntry>, FSList> {
+        let dir = if path.as_os_str().is_empty() {
+            self.0.root_dir()
+        } else {
+            self.0.root_dir().open_dir(&path.to_string_lossy())?
+        };
+        let mut entries = vec![];
+        for entry in dir.iter() {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            if !entry.is_dir() && !entry.is_file() {
+                return Err(FSList::FATError(format!(
+                    "fat file entry is neither dir nor file {}",
+                    path.join(&name).display(),
+                )));
+            }
+            entries.push(FSEntry {
+                is_dir: entry.is_dir(),
+                name,
+            });
+        }
+        Ok(entries)
+    }
+    fn is_dir(&mut self, path: &std::path::Path) -> Result<bool, FSList> {
+        Ok(path.as_os_str().is_empty()
+            || self.0.root_dir().open_dir(&path.to_string_lossy()).is_ok())
+    }
+    fn read_file(&mut self, path: &std::path::Path) -> Result<Vec<u8>, FSList> {
+        use fatfs::Read;
+        let mut file = self.0.root_dir().open_file(&path.to_string_lossy())?;
+        let file_size = file
+            .extents()
+            .try_fold(0, |sz, e| e.map(|ext| sz + ext.size))?;
+        tracing::info!("download single file {path:?}, size {file_size:?}");
+        let file_size = usize::try_from(file_size)
+            .map_err(|_| FSList::FATError(format!("file too big to read {}", path.display())))?;
+        let mut bytes = vec![0; file_size];
+        let mut read = 0;
+        while read < bytes.len() {
+            match file.read(&mut bytes[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) => return Err(FSList::from(e)),
+            }
+        }
+        Ok(bytes)
+    }
+    fn open_file<'a>(
+        &'a mut self,
+        path: &std::path::Path,
+    ) -> Result<Box<dyn ReadSeek + 'a>, FSList> {
+        let file = self.0.root_dir().open_file(&path.to_string_lossy())?;
+        Ok(Box::new(FatFile(file)))
+    }
+}
+
+impl<R: std::io::Read + std::io::Seek> Filesystem for IsoImage<R> {
+    fn fs_type(&self) -> FSType {
+        FSType::ISO9660
+    }
+    fn read_dir(&mut self, path: &std::path::Path) -> Result<Vec<FSEntry>, FSList> {
+        let dir = self.find(path)?;
+        Ok(IsoImage::read_dir(self, &dir)?
+            .into_iter()
+            .map(|e| FSEntry {
+                name: e.name,
+                is_dir: e.is_dir,
+            })
+            .collect())
+    }
+    fn is_dir(&mut self, path: &std::path::Path) -> Result<bool, FSList> {
+        Ok(self.find(path)?.is_dir)
+    }
+    fn read_file(&mut self, path: &std::path::Path) -> Result<Vec<u8>, FSList> {
+        let file = self.find(path)?;
+        tracing::info!("download single file {path:?}, size {}", file.size());
+        IsoImage::read_file(self, &file)
+    }
+    fn open_file<'a>(
+        &'a mut self,
+        path: &std::path::Path,
+    ) -> Result<Box<dyn ReadSeek + 'a>, FSList> {
+        let file = self.find(path)?;
+        if file.is_dir {
+            return Err(FSList::FileNotFound(path.to_string_lossy().into_owned()));
+        }
+        Ok(Box::new(IsoImage::open_file(self, &file)))
+    }
+}
+
+impl<R: std::io::Read + std::io::Seek> Filesystem for ExtFs<R> {
+    fn fs_type(&self) -> FSType {
+        FSType::Ext
+    }
+    fn read_dir(&mut self, path: &std::path::Path) -> Result<Vec<FSEntry>, FSList> {
+        let dir = self.find(path)?;
+        Ok(ExtFs::read_dir(self, &dir)?
+            .into_iter()
+            .map(|e| FSEntry {
+                name: e.name,
+                is_dir: e.is_dir,
+            })
+            .collect())
+    }
+    fn is_dir(&mut self, path: &std::path::Path) -> Result<bool, FSList> {
+        Ok(self.find(path)?.is_dir)
+    }
+    fn read_file(&mut self, path: &std::path::Path) -> Result<Vec<u8>, FSList> {
+        let file = self.find(path)?;
+        ExtFs::read_file(self, &file)
+    }
+    fn open_file<'a>(
+        &'a mut self,
+        path: &std::path::Path,
+    ) -> Result<Box<dyn ReadSeek + 'a>, FSList> {
+        let file = self.find(path)?;
+        Ok(Box::new(ExtFs::open_file(self, &file)?))
+    }
+}
+
+impl<R: std::io::Read + std::io::Seek> Filesystem for Archive<R> {
+    fn fs_type(&self) -> FSType {
+        match self.kind() {
+            ArchiveKind::Zip => FSType::Zip,
+            ArchiveKind::SevenZip => FSType::SevenZip,
+        }
+    }
+    fn read_dir(&mut self, path: &std::path::Path) -> Result<Vec<FSEntry>, FSList> {
+        Ok(Archive::read_dir(self, path)?
+            .into_iter()
+            .map(|e| FSEntry {
+                name: e.name,
+                is_dir: e.is_dir,
+            })
+            .collect())
+    }
+    fn is_dir(&mut self, path: &std::path::Path) -> Result<bool, FSList> {
+        Ok(Archive::is_dir(self, path))
+    }
+    fn read_file(&mut self, path: &std::path::Path) -> Result<Vec<u8>, FSList> {
+        Archive::read_file(self, path)
+    }
+}
+
+/// Opens an image file, decompressing it on the fly if it is a CHD
+fn open_image(mut image_file: std::fs::File) -> Result<DiskImage, FSList> {
+    if crate::chdimage::is_chd(&mut image_file) {
+        let stream = crate::chdimage::ChdStream::open(std::io::BufReader::new(image_file))?;
+        return Ok(Box::new(stream));
+    }
+    Ok(Box::new(image_file))
+}
+
+fn image_len(image: &mut dyn ReadSeek) -> Result<u64, FSList> {
+    use std::io::{Seek, SeekFrom};
+    let len = image.seek(SeekFrom::End(0))?;
+    image.rewind()?;
+    Ok(len)
+}
+
+/// Opens the filesystem in bytes `start..end` of the image, whichever kind it is
+#[tracing::instrument(skip(image))]
+fn open_filesystem(image: DiskImage, start: u64, end: u64) -> Result<Box<dyn Filesystem>, FSList> {
+    use fscommon::{BufStream, StreamSlice};
+    use std::io::Seek;
+    let mut stream: PartitionStream = BufStream::new(StreamSlice::new(image, start, end)?);
+    if let Some(kind) = crate::archive::detect(&mut stream) {
+        return Ok(Box::new(Archive::open(stream, kind)?));
+    }
+    if crate::extfs::detect(&mut stream) {
+        return Ok(Box::new(ExtFs::open(stream)?));
+    }
+    if crate::iso9660::detect(&mut stream).is_some() {
+        return Ok(Box::new(IsoImage::open(stream)?));
+    }
+    stream.rewind()?;
+    let fs = fatfs::FileSystem::new(stream, FsOptions::new())?;
+    Ok(Box::new(FatFilesystem(fs)))
+}
+
+/// Sector sizes a GPT header may be laid out for
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+const GPT_MAX_ENTRIES: u32 = 256;
+
+/// Reads a GUID partition table, numbering partitions from 1 like MBR ones
+fn gpt_partitions(image_file: &mut dyn ReadSeek) -> Option<Vec<(usize, u64, u64)>> {
+    use std::io::{Read, Seek, SeekFrom};
     let mut image = fscommon::BufStream::new(image_file);
+    for sector_size in GPT_SECTOR_SIZES {
+        let mut header = [0; 92];
+        if image.seek(SeekFrom::Start(sector_size)).is_err()
+            || image.read_exact(&mut header).is_err()
+            || &header[..8] != b"EFI PART"
+        {
+            continue;
+        }
+        let entries_lba = u64::from_le_bytes(header[72..80].try_into().ok()?);
+        let entry_count = u32::from_le_bytes(header[80..84].try_into().ok()?);
+        let entry_size = u32::from_le_bytes(header[84..88].try_into().ok()?);
+        if entry_size < 128 || entry_count > GPT_MAX_ENTRIES {
+            return None;
+        }
+        // Values from a malformed header can overflow, skip the table then
+        let entries_start = entries_lba.checked_mul(sector_size)?;
+        let mut entry = vec![0; usize::try_from(entry_size).ok()?];
+        let mut parts = vec![];
+        for idx in 0..entry_count {
+            let entry_start = entries_start.checked_add(u64::from(idx) * u64::from(entry_size))?;
+            image.seek(SeekFrom::Start(entry_start)).ok()?;
+            image.read_exact(&mut entry).ok()?;
+            // Unused entries have an all-zero type GUID
+            if entry[..16].iter().all(|b| *b == 0) {
+                continue;
+            }
+            let first = u64::from_le_bytes(entry[32..40].try_into().ok()?);
+            let last = u64::from_le_bytes(entry[40..48].try_into().ok()?);
+            parts.push((
+                usize::try_from(idx).ok()? + 1,
+                first.checked_mul(sector_size)?,
+                last.checked_add(1)?.checked_mul(sector_size)?,
+            ));
+        }
+        return Some(parts);
+    }
+    None
+}
+
+/// Finds the partitions of a disk image as (index, start byte, end byte)
+#[tracing::instrument(skip(image_file))]
+fn get_partitions(image_file: &mut dyn ReadSeek) -> Result<Vec<(usize, u64, u64)>, FSList> {
+    use tracing::info;
+    let file_len = image_len(image_file)?;
+    // Archives are listed like a disk with a single unpartitioned filesystem
+    if crate::archive::detect(image_file).is_some() {
+        return Ok(vec![(0, 0, file_len)]);
+    }
+    // Hybrid CD images can have a partition table too, the ISO 9660 view is the one to show
+    if is_iso(image_file) {
+        return Ok(vec![(0, 0, file_len)]);
+    }
+    if let Some(parts) = gpt_partitions(image_file) {
+        return Ok(parts);
+    }
+    let mut image = fscommon::BufStream::new(&mut *image_file);
     let parts: Vec<_> = mbrman::MBR::read_from(&mut image, 512)
         .map(|mbr| {
             mbr.iter()
                 .filter(|(_, part)| part.is_used())
                 .map(|(idx, part)| {
+                    let start = u64::from(part.starting_lba) * u64::from(mbr.sector_size);
                     (
                         idx,
-                        u64::from(part.starting_lba) * u64::from(mbr.sector_size),
-                        u64::from(part.sectors) * u64::from(mbr.sector_size),
+                        start,
+                        start + u64::from(part.sectors) * u64::from(mbr.sector_size),
                     )
                 })
                 .collect()
@@ -45,97 +369,82 @@ fn get_partitions(image_file: &std::fs::File) -> Result<Vec<(usize, u64, u64)>,
     Ok(parts)
 }
 
+/// Checks for an ISO 9660 volume, leaving the file rewound for the other checks
+fn is_iso(image_file: &mut dyn ReadSeek) -> bool {
+    use std::io::Seek;
+    let iso = crate::iso9660::detect(&mut fscommon::BufStream::new(&mut *image_file)).is_some();
+    image_file.rewind().is_ok() && iso
+}
+
 #[tracing::instrument]
-pub fn recursive_listing(mut image_file: std::fs::File) -> Result<Vec<FSFileListing>, FSList> {
-    use tracing::info;
-    let partitions = get_partitions(&image_file)?;
+pub fn recursive_listing(image_file: std::fs::File) -> Result<Vec<FSFileListing>, FSList> {
+    use tracing::{info, warn};
+    let partitions = get_partitions(open_image(image_file.try_clone()?)?.as_mut())?;
     let mut result = Vec::with_capacity(partitions.len());
-    for (idx, start_byte, sz) in partitions {
-        info!("Slicing buffer for partition {idx}: {start_byte} <> {sz}");
-        let root = recursive_listing_fat_partition(
-            &mut image_file,
-            start_byte,
-            start_byte + sz,
-            std::path::Path::new(&format!("part{idx}")),
-        )?;
-        result.push(root);
+    for (idx, start_byte, end_byte) in partitions {
+        info!("Slicing buffer for partition {idx}: {start_byte} <> {end_byte}");
+        let path = std::path::PathBuf::from(format!("part{idx}"));
+        let listing = open_filesystem(open_image(image_file.try_clone()?)?, start_byte, end_byte)
+            .and_then(|mut fs| recursive_listing_partition(fs.as_mut(), &path));
+        match listing {
+            Ok(root) => result.push(root),
+            Err(e) => {
+                // Swap, unformatted, and unsupported partitions still show up, just empty
+                warn!("Could not list partition {idx}: {e}");
+                result.push(FSFileListing {
+                    name: path.to_string_lossy().to_string(),
+                    path,
+                    children: vec![],
+                    list_type: FSFileListingType::Partition,
+                    fs_type: None,
+                });
+            }
+        }
     }
     Ok(result)
 }
 
-#[tracing::instrument]
-fn recursive_listing_fat_partition(
-    image: &mut std::fs::File,
-    start_byte: u64,
-    sz: u64,
+#[tracing::instrument(skip(fs))]
+fn recursive_listing_partition(
+    fs: &mut dyn Filesystem,
     path: &std::path::Path,
 ) -> Result<FSFileListing, FSList> {
-    use fatfs::{FileSystem, FsOptions};
-    use fscommon::{BufStream, StreamSlice};
-    use tracing::{debug, info};
-    info!(
-        "Loading image {:?} with bounds {}..{} as FAT filesystem",
-        image, start_byte, sz
-    );
-    let image = BufStream::new(StreamSlice::new(image, start_byte, start_byte + sz)?);
-    debug!("Stream slice initialized",);
-    let fs = FileSystem::new(image, FsOptions::new())?;
-    debug!("FS initialized",);
-    let mut stack = vec![(fs.root_dir(), vec![], path.to_owned())];
+    let mut stack = vec![(std::path::PathBuf::new(), vec![])];
     let mut root_lst = FSFileListing {
         name: path.to_string_lossy().to_string(),
         path: path.to_owned(),
         children: Vec::with_capacity(16),
         list_type: FSFileListingType::Partition,
+        fs_type: Some(fs.fs_type()),
     };
-    while let Some((dir, idx, path)) = stack.pop() {
+    while let Some((dir, idx)) = stack.pop() {
         if idx.len() > DEPTH_LIMIT {
             return Err(FSList::TraversalDepth);
         }
         let lst = get_lst_mut(&mut root_lst, &idx)
-            .ok_or(FSList::TraversalPath(path.to_string_lossy().into_owned()))?;
-        for (eidx, entry) in dir
-            .iter()
-            .filter(|entry| {
-                let Ok(entry) = entry.as_ref() else {
-                    return false;
-                };
-                let filename = entry.file_name();
-                filename != "." && filename != ".."
-            })
-            .enumerate()
-        {
-            let entry = entry?;
-            let filename = entry.file_name();
-            // add a file entry or dir entry
+            .ok_or(FSList::TraversalPath(dir.to_string_lossy().into_owned()))?;
+        for (eidx, entry) in fs.read_dir(&dir)?.into_iter().enumerate() {
             lst.children.push(FSFileListing {
-                path: path.join(&filename),
-                children: if entry.is_dir() {
-                    Vec::with_capacity(16)
-                } else {
-                    vec![]
-                },
-                list_type: if entry.is_dir() {
+                path: path.join(&dir).join(&entry.name),
+                children: vec![],
+                list_type: if entry.is_dir {
                     FSFileListingType::Directory
-                } else if entry.is_file() {
-                    FSFileListingType::File
                 } else {
-                    return Err(FSList::FATError(format!(
-                        "fat file entry is neither dir nor file {}",
-                        path.join(filename).display(),
-                    )));
+                    FSFileListingType::File
                 },
-                name: filename,
+                fs_type: None,
+                name: entry.name.clone(),
             });
-            if entry.is_dir() {
+            if entry.is_dir {
                 let mut idx = idx.clone();
                 idx.push(eidx);
-                stack.push((entry.to_dir(), idx, path.join(entry.file_name())));
+                stack.push((dir.join(entry.name), idx));
             }
         }
     }
     Ok(root_lst)
 }
+
 fn get_lst_mut<'a>(
     lst: &'a mut FSFileListing,
     idx_path: &[usize],
@@ -147,17 +456,107 @@ fn get_lst_mut<'a>(
     }
 }
 
+/// Lists a stored file, reusing the listing saved for its hash if there is
+/// one. Returns `None` for files that aren't disk images or archives.
+pub async fn cached_listing(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    file: &crate::models::File,
+) -> Result<Option<Vec<FSFileListing>>, FSList> {
+    use crate::inc_metric;
+    use crate::models::FileListing;
+    if let Some(saved) = FileListing::get_by_hash(conn, &file.file_hash).await?
+        && saved.listing_version == LISTING_VERSION
+    {
+        return Ok(if saved.is_listable {
+            Some(serde_json::from_value(saved.listing)?)
+        } else {
+            None
+        });
+    }
+    let path = file_to_path(storage_root, file);
+    inc_metric!(conn, fslist_recursive_listing, 1, path = path.to_str());
+    let listing = tokio::task::spawn_blocking(move || {
+        if !is_disk_image(&path) {
+            return Ok(None);
+        }
+        recursive_listing(std::fs::File::open(path)?).map(Some)
+    })
+    .await??;
+    FileListing::upsert(
+        conn,
+        FileListing {
+            file_hash: file.file_hash.clone(),
+            listing_version: LISTING_VERSION,
+            is_listable: listing.is_some(),
+            listing: serde_json::to_value(listing.as_deref().unwrap_or_default())?,
+            created_on: chrono::Utc::now(),
+        },
+    )
+    .await?;
+    Ok(listing)
+}
+
+/// Diffs two stored disk images, reusing the diff saved for their hashes if
+/// there is one. Returns `None` if either one can't be listed.
+pub async fn cached_diff(
+    conn: &mut sqlx::PgConnection,
+    old_hash: &str,
+    old_path: std::path::PathBuf,
+    new_hash: &str,
+    new_path: std::path::PathBuf,
+) -> Result<Option<Vec<FSChange>>, FSList> {
+    use crate::inc_metric;
+    use crate::models::FileDiff;
+    if let Some(saved) = FileDiff::get_by_hashes(conn, old_hash, new_hash).await?
+        && saved.listing_version == LISTING_VERSION
+    {
+        return Ok(if saved.is_diffable {
+            Some(serde_json::from_value(saved.changes)?)
+        } else {
+            None
+        });
+    }
+    inc_metric!(
+        conn,
+        fslist_diff,
+        1,
+        old_hash = old_hash,
+        new_hash = new_hash
+    );
+    let changes = tokio::task::spawn_blocking(move || {
+        if !is_disk_image(&old_path) || !is_disk_image(&new_path) {
+            return Ok(None);
+        }
+        diff(
+            std::fs::File::open(old_path)?,
+            std::fs::File::open(new_path)?,
+        )
+        .map(Some)
+    })
+    .await??;
+    FileDiff::upsert(
+        conn,
+        FileDiff {
+            old_hash: old_hash.to_string(),
+            new_hash: new_hash.to_string(),
+            listing_version: LISTING_VERSION,
+            is_diffable: changes.is_some(),
+            changes: serde_json::to_value(changes.as_deref().unwrap_or_default())?,
+            created_on: chrono::Utc::now(),
+        },
+    )
+    .await?;
+    Ok(changes)
+}
+
 #[must_use]
 pub fn file_to_path(storage_root: &str, file: &crate::models::File) -> std::path::PathBuf {
     std::path::PathBuf::from(&format!("{storage_root}/{}", file.file_dest_path))
 }
 
-#[tracing::instrument]
-pub fn get_file_at_path(
-    image_file: std::fs::File,
-    path: &std::path::Path,
-) -> Result<(String, Vec<u8>), FSList> {
-    let partitions = get_partitions(&image_file)?;
+/// Splits a `partN/...` path into the partition index and the path inside it
+pub(crate) fn split_partition(path: &std::path::Path) -> Result<(usize, &std::path::Path), FSList> {
     let mut components = path.components();
     let std::path::Component::Normal(partid) = components
         .next()
@@ -170,113 +569,371 @@ pub fn get_file_at_path(
         .strip_prefix("part")
         .ok_or(FSList::Path)?
         .parse::<usize>()?;
-    for (idx, start_byte, sz) in partitions {
-        use fatfs::{FileSystem, FsOptions};
-        use fscommon::{BufStream, StreamSlice};
-        if idx != partid {
-            continue;
-        }
-        let image = BufStream::new(StreamSlice::new(image_file, start_byte, start_byte + sz)?);
-        let fs = FileSystem::new(image, FsOptions::new())?;
-        let subpath = components.as_path();
-        if file_at_path_is_dir_fat(&fs, subpath) {
-            let dir_zipped = get_dir_at_path_fat(&fs, subpath)?;
-            return Ok(("application/zip".to_string(), dir_zipped));
-        }
-        let file = get_file_at_path_fat(&fs, subpath)?;
-        let mime = tree_magic_mini::from_u8(&file);
-        return Ok((mime.to_string(), file));
-    }
-    Err(FSList::FileNotFound(path.to_string_lossy().into_owned()))
+    Ok((partid, components.as_path()))
 }
 
-type FATStorage = fatfs::StdIoWrapper<fscommon::BufStream<fscommon::StreamSlice<std::fs::File>>>;
+/// Finds the start and end byte of partition `partid` of an image
+pub(crate) fn partition_bounds(
+    image: &mut dyn ReadSeek,
+    partid: usize,
+) -> Result<(u64, u64), FSList> {
+    get_partitions(image)?
+        .into_iter()
+        .find(|(idx, _, _)| *idx == partid)
+        .map(|(_, start_byte, end_byte)| (start_byte, end_byte))
+        .ok_or_else(|| FSList::FileNotFound(format!("part{partid}")))
+}
 
-fn file_at_path_is_dir_fat(fs: &fatfs::FileSystem<FATStorage>, path: &std::path::Path) -> bool {
-    let root = fs.root_dir();
-    path.parent().is_none() || root.open_dir(&path.to_string_lossy()).is_ok()
+/// Opens the filesystem holding `path`, whose first component names the
+/// partition (`part0`, `part1`, ...), and gives back the rest of the path
+#[tracing::instrument]
+pub fn open_path(
+    image_file: std::fs::File,
+    path: &std::path::Path,
+) -> Result<(Box<dyn Filesystem>, std::path::PathBuf), FSList> {
+    let mut image = open_image(image_file)?;
+    let (partid, subpath) = split_partition(path)?;
+    let (start_byte, end_byte) = partition_bounds(image.as_mut(), partid)
+        .map_err(|_| FSList::FileNotFound(path.to_string_lossy().into_owned()))?;
+    let fs = open_filesystem(image, start_byte, end_byte)?;
+    Ok((fs, subpath.to_owned()))
 }
 
-#[tracing::instrument(skip(fs))]
-fn get_file_at_path_fat(
-    fs: &fatfs::FileSystem<FATStorage>,
+#[tracing::instrument]
+pub fn get_file_at_path(
+    image_file: std::fs::File,
+    path: &std::path::Path,
+) -> Result<(String, Vec<u8>), FSList> {
+    let (mut fs, subpath) = open_path(image_file, path)?;
+    if fs.is_dir(&subpath)? {
+        let dir_zipped = write_dir_zip(fs.as_mut(), &subpath, vec![])?;
+        return Ok(("application/zip".to_string(), dir_zipped));
+    }
+    let file = fs.read_file(&subpath)?;
+    let mime = tree_magic_mini::from_u8(&file);
+    Ok((mime.to_string(), file))
+}
 
+/// Guesses a file's mime type from its first bytes, leaving it rewound
+pub fn sniff_mime(file: &mut dyn ReadSeek) -> Result<String, FSList> {
+    use std::io::{Read, Seek};
+    let mut head = vec![];
+    (&mut *file).take(SNIFF_LEN).read_to_end(&mut head)?;
+    file.rewind()?;
+    Ok(tree_magic_mini::from_u8(&head).to_string())
+}
+
+/// Writes the directory at `path` to `out` as a zip archive, one file at a
+/// time and without seeking, so `out` can be a network stream
+#[tracing::instrument(skip(fs, out))]
+pub fn write_dir_zip<W: std::io::Write>(
+    fs: &mut dyn Filesystem,
     path: &std::path::Path,
-) -> Result<Vec<u8>, FSList> {
-    use fatfs::Read;
-    let mut file = fs.root_dir().open_file(&path.to_string_lossy()).unwrap();
-    let file_size = file
-        .extents()
-        .try_fold(0, |sz, e| e.map(|ext| sz + ext.size))?;
-    tracing::info!("download single file {path:?}, size {file_size:?}");
-    let mut bytes = vec![0; file_size as usize];
-    loop {
-        match file.read(&mut bytes) {
-            Ok(0) => {
-                return Ok(bytes);
+    out: W,
+) -> Result<W, FSList> {
+    use zip::{ZipWriter, write::SimpleFileOptions};
+    let mut writer = ZipWriter::new_stream(out);
+    let options = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .large_file(true);
+    let mut stack = vec![(path.to_owned(), 0)];
+    while let Some((dir, depth)) = stack.pop() {
+        if depth > DEPTH_LIMIT {
+            return Err(FSList::TraversalDepth);
+        }
+        for entry in fs.read_dir(&dir)? {
+            let path = dir.join(&entry.name);
+            let filepath = path.to_string_lossy().to_string();
+            if entry.is_dir {
+                writer.add_directory(filepath, options)?;
+                stack.push((path, depth + 1));
+            } else {
+                writer.start_file(filepath, options)?;
+                std::io::copy(&mut fs.open_file(&path)?, &mut writer)?;
             }
-            Err(e) => return Err(FSList::from(e)),
-            Ok(_n) => {}
         }
     }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// How a file differs between two images
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FSChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// Size and MD5 hash of one file inside an image
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FSFileSummary {
+    pub size: u64,
+    pub hash: String,
+}
+
+/// One changed file; `path` starts with the partition like listing paths do
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct FSChange {
+    pub path: std::path::PathBuf,
+    pub change: FSChangeKind,
+    pub old: Option<FSFileSummary>,
+    pub new: Option<FSFileSummary>,
 }
 
+type FileSummaries = std::collections::BTreeMap<std::path::PathBuf, FSFileSummary>;
+
 #[tracing::instrument(skip(fs))]
-fn get_dir_at_path_fat(
-    fs: &fatfs::FileSystem<FATStorage>,
-    path: &std::path::Path,
-) -> Result<Vec<u8>, FSList> {
-    use zip::{ZipWriter, write::SimpleFileOptions};
-    let mut out_bytes: Vec<u8> = Vec::with_capacity(16 * 1024);
-    let directory = if path.parent().is_none() {
-        fs.root_dir()
-    } else {
-        fs.root_dir().open_dir(&path.to_string_lossy())?
-    };
-    let mut writer = ZipWriter::new(std::io::Cursor::new(&mut out_bytes));
-    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
-    let mut stack = Vec::with_capacity(1024);
-    for entry in directory.iter() {
-        let entry = entry?;
-        let path = path.join(entry.file_name());
-        stack.push((entry, path));
-    }
-    while let Some((entry, path)) = stack.pop() {
-        let file_name = entry.file_name();
-        if file_name == "." || file_name == ".." {
-            continue;
+fn summarize_files(fs: &mut dyn Filesystem) -> Result<FileSummaries, FSList> {
+    let mut files = FileSummaries::new();
+    let mut stack = vec![(std::path::PathBuf::new(), 0)];
+    while let Some((dir, depth)) = stack.pop() {
+        if depth > DEPTH_LIMIT {
+            return Err(FSList::TraversalDepth);
         }
-        let filepath = path.to_string_lossy().to_string();
-        if entry.is_file() {
-            writer.start_file(filepath, options)?;
-            std::io::copy(&mut entry.to_file(), &mut writer)?;
-        } else if entry.is_dir() {
-            writer.add_directory(filepath, options)?;
-            for subentry in entry.to_dir().iter() {
-                let subentry = subentry?;
-                let subpath = path.join(subentry.file_name());
-                stack.push((subentry, subpath));
+        for entry in fs.read_dir(&dir)? {
+            let path = dir.join(&entry.name);
+            if entry.is_dir {
+                stack.push((path, depth + 1));
+            } else {
+                let (size, hash) =
+                    crate::storage::StorageHandler::get_reader_hash(&mut fs.open_file(&path)?)?;
+                files.insert(path, FSFileSummary { size, hash });
             }
         }
     }
-    writer.finish()?;
-    Ok(out_bytes)
+    Ok(files)
+}
+
+/// Summarizes the files of every partition, keyed by partition index
+fn summarize_partitions(
+    image_file: std::fs::File,
+) -> Result<std::collections::BTreeMap<usize, FileSummaries>, FSList> {
+    let partitions = get_partitions(open_image(image_file.try_clone()?)?.as_mut())?;
+    let mut result = std::collections::BTreeMap::new();
+    for (idx, start_byte, end_byte) in partitions {
+        let files = open_filesystem(open_image(image_file.try_clone()?)?, start_byte, end_byte)
+            .and_then(|mut fs| summarize_files(fs.as_mut()))
+            .unwrap_or_else(|e| {
+                // Same as listings, unreadable partitions count as empty
+                tracing::warn!("Could not read partition {idx}: {e}");
+                FileSummaries::new()
+            });
+        result.insert(idx, files);
+    }
+    Ok(result)
+}
+
+/// Size and hash of every file in the image, with paths starting at the
+/// partition like listing paths do
+pub fn file_summaries(
+    image_file: std::fs::File,
+) -> Result<Vec<(std::path::PathBuf, FSFileSummary)>, FSList> {
+    Ok(summarize_partitions(image_file)?
+        .into_iter()
+        .flat_map(|(idx, files)| {
+            let part = std::path::PathBuf::from(format!("part{idx}"));
+            files
+                .into_iter()
+                .map(move |(path, summary)| (part.join(path), summary))
+        })
+        .collect())
+}
+
+/// Compares the files of two images partition by partition, e.g. a disk and
+/// the disk of an instance cloned from it
+#[tracing::instrument]
+pub fn diff(old_image: std::fs::File, new_image: std::fs::File) -> Result<Vec<FSChange>, FSList> {
+    let mut old = summarize_partitions(old_image)?;
+    let mut new = summarize_partitions(new_image)?;
+    let mut indices: Vec<usize> = old.keys().chain(new.keys()).copied().collect();
+    indices.sort_unstable();
+    indices.dedup();
+    let mut changes = vec![];
+    for idx in indices {
+        let part = std::path::PathBuf::from(format!("part{idx}"));
+        let old_files = old.remove(&idx).unwrap_or_default();
+        let mut new_files = new.remove(&idx).unwrap_or_default();
+        for (path, old_summary) in old_files {
+            let new_summary = new_files.remove(&path);
+            let change = match &new_summary {
+                None => FSChangeKind::Removed,
+                Some(new_summary) if *new_summary != old_summary => FSChangeKind::Modified,
+                Some(_) => continue,
+            };
+            changes.push(FSChange {
+                path: part.join(path),
+                change,
+                old: Some(old_summary),
+                new: new_summary,
+            });
+        }
+        changes.extend(new_files.into_iter().map(|(path, new_summary)| FSChange {
+            path: part.join(path),
+            change: FSChangeKind::Added,
+            old: None,
+            new: Some(new_summary),
+        }));
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
 }
 
+/// Whether the file can be listed, as a disk image, CD image, or archive
 #[must_use]
 pub fn is_disk_image(file: &std::path::Path) -> bool {
     std::fs::File::open(file)
+        .map_err(FSList::from)
+        .and_then(open_image)
         .inspect_err(|e| tracing::warn!("missing file or other issue {e}"))
-        .is_ok_and(|mut f| {
-            mbrman::MBR::read_from(&mut f, 512).is_ok() || {
-                f.metadata().is_ok_and(|md| {
-                    use fatfs::FileSystem;
-                    use fscommon::{BufStream, StreamSlice};
-                    StreamSlice::new(f, 0, md.len()).is_ok_and(|slice| {
-                        let image = BufStream::new(slice);
-                        FileSystem::new(image, FsOptions::new()).is_ok()
-                    })
-                })
-            }
+        .is_ok_and(|mut image| {
+            is_iso(image.as_mut())
+                || gpt_partitions(image.as_mut()).is_some()
+                || mbrman::MBR::read_from(&mut image, 512).is_ok()
+                || image_len(image.as_mut()).is_ok_and(|len| open_filesystem(image, 0, len).is_ok())
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SECTOR: usize = 512;
+
+    /// A 512 byte sector GPT disk whose entries are `(type byte, first lba, last lba)`
+    fn gpt_image(entries_lba: u64, entries: &[(u8, u64, u64)]) -> Vec<u8> {
+        let mut img = vec![0; 64 * SECTOR];
+        let header = &mut img[SECTOR..2 * SECTOR];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&u32::try_from(entries.len()).unwrap().to_le_bytes());
+        header[84..88].copy_from_slice(&128_u32.to_le_bytes());
+        for (i, (type_byte, first, last)) in entries.iter().enumerate() {
+            let at = 2 * SECTOR + i * 128;
+            img[at] = *type_byte;
+            img[at + 32..at + 40].copy_from_slice(&first.to_le_bytes());
+            img[at + 40..at + 48].copy_from_slice(&last.to_le_bytes());
+        }
+        img
+    }
+
+    #[test]
+    fn gpt_partitions_are_found() {
+        let img = gpt_image(2, &[(1, 34, 41), (0, 0, 0), (1, 42, 63)]);
+        assert_eq!(
+            get_partitions(&mut Cursor::new(img)).unwrap(),
+            vec![(1, 34 * 512, 42 * 512), (3, 42 * 512, 64 * 512)]
+        );
+        assert_eq!(gpt_partitions(&mut Cursor::new(vec![0; 64 * SECTOR])), None);
+    }
+
+    #[test]
+    fn gpt_overflows_skip_the_table() {
+        for img in [
+            gpt_image(u64::MAX, &[(1, 34, 41)]),
+            gpt_image(2, &[(1, u64::MAX, u64::MAX)]),
+            gpt_image(2, &[(1, 34, u64::MAX / 256)]),
+        ] {
+            assert_eq!(gpt_partitions(&mut Cursor::new(img)), None);
+        }
+    }
+
+    /// Builds an 8 MB FAT disk holding `files` in a new temporary file
+    fn fat_disk(files: &[(&str, Vec<u8>)]) -> std::path::PathBuf {
+        use crate::diskbuild::{DiskContents, Geometry, build_image};
+        let mut contents = DiskContents::default();
+        for (path, data) in files {
+            contents.add_file(std::path::PathBuf::from(path), data.clone());
+        }
+        let path = std::env::temp_dir().join(format!("fslist-test-{}.img", uuid::Uuid::new_v4()));
+        let mut file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        build_image(&mut file, Geometry::Hdd { megabytes: 8 }, None, &contents).unwrap();
+        path
+    }
+
+    /// `old` and `new` disks with one file added, one removed, one modified,
+    /// and one left alone
+    fn changed_disks() -> (std::path::PathBuf, std::path::PathBuf) {
+        let keen = ("GAMES/KEEN.EXE", vec![7; 3000]);
+        let old = fat_disk(&[
+            ("README.TXT", b"hello".to_vec()),
+            keen.clone(),
+            ("OLD.TXT", b"bye".to_vec()),
+        ]);
+        let new = fat_disk(&[
+            ("README.TXT", b"hello!".to_vec()),
+            keen,
+            ("NEW.TXT", b"hi".to_vec()),
+        ]);
+        (old, new)
+    }
+
+    fn kinds(changes: &[FSChange]) -> Vec<(&str, FSChangeKind)> {
+        changes
+            .iter()
+            .map(|change| (change.path.to_str().unwrap(), change.change))
+            .collect()
+    }
+
+    #[test]
+    fn diffs_fat_images() {
+        let (old, new) = changed_disks();
+        let changes = diff(
+            std::fs::File::open(&old).unwrap(),
+            std::fs::File::open(&new).unwrap(),
+        );
+        let unchanged = diff(
+            std::fs::File::open(&old).unwrap(),
+            std::fs::File::open(&old).unwrap(),
+        );
+        std::fs::remove_file(&old).unwrap();
+        std::fs::remove_file(&new).unwrap();
+        let changes = changes.unwrap();
+        assert_eq!(
+            kinds(&changes),
+            vec![
+                ("part1/NEW.TXT", FSChangeKind::Added),
+                ("part1/OLD.TXT", FSChangeKind::Removed),
+                ("part1/README.TXT", FSChangeKind::Modified),
+            ]
+        );
+        assert_eq!(changes[0].old, None);
+        assert_eq!(changes[1].new, None);
+        assert_eq!(changes[2].old.as_ref().map(|old| old.size), Some(5));
+        assert_eq!(changes[2].new.as_ref().map(|new| new.size), Some(6));
+        assert!(unchanged.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = "../migrations/")]
+    async fn diffs_are_saved_per_hash_pair(pool: sqlx::postgres::PgPool) -> sqlx::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let (old, new) = changed_disks();
+        let first = cached_diff(conn.as_mut(), "old", old.clone(), "new", new.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        std::fs::remove_file(&old)?;
+        std::fs::remove_file(&new)?;
+        // The images are gone, so this can only be the saved diff
+        let saved = cached_diff(conn.as_mut(), "old", old.clone(), "new", new.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(kinds(&saved), kinds(&first));
+        assert_eq!(saved.len(), 3);
+        // The reverse pair is a different diff, and now one that can't be made
+        assert!(
+            cached_diff(conn.as_mut(), "new", new, "old", old)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        Ok(())
+    }
+}
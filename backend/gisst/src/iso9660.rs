@@ -0,0 +1,696 @@
+//! Read-only ISO 9660 filesystems, as found on CD-ROM images.
+//!
+//! Handles plain 2048 byte sector `.iso` images as well as raw 2352 byte
+//! sector `.bin` dumps (MODE1 and MODE2 form 1) and 2336 byte MODE2 dumps.
+//! Names come from Rock Ridge `NM` entries when the disc has them, then from
+//! a Joliet supplementary volume, then from the primary volume with the
+//! `;1` version suffix removed.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::FSList;
+use std::io::{Read, Seek, SeekFrom};
+
+pub const SECTOR_SIZE: usize = 2048;
+const VOLUME_DESCRIPTOR_START: u64 = 16;
+const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+const SYNC_PATTERN: [u8; 12] = [
+    0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00,
+];
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+/// Rock Ridge continuation areas longer than this are surely corrupt
+const MAX_CONTINUATION: usize = 64 * 1024;
+/// Directories longer than this are surely corrupt too
+const MAX_DIRECTORY: usize = 16 * 1024 * 1024;
+
+/// How 2048 byte logical sectors sit in the image file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorLayout {
+    pub raw_size: u64,
+    pub data_offset: u64,
+}
+
+/// Layouts to try, in order: cooked, raw MODE1, raw MODE2 form 1, MODE2 without sync
+const LAYOUTS: [SectorLayout; 4] = [
+    SectorLayout {
+        raw_size: 2048,
+        data_offset: 0,
+    },
+    SectorLayout {
+        raw_size: 2352,
+        data_offset: 16,
+    },
+    SectorLayout {
+        raw_size: 2352,
+        data_offset: 24,
+    },
+    SectorLayout {
+        raw_size: 2336,
+        data_offset: 8,
+    },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsoEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// (first sector, length in bytes) of each extent, in order
+    extents: Vec<(u32, u32)>,
+}
+
+impl IsoEntry {
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.extents.iter().map(|(_, len)| u64::from(*len)).sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Names {
+    RockRidge { skip: usize },
+    Joliet,
+    Primary,
+}
+
+/// An ISO 9660 filesystem over an image file
+pub struct IsoImage<R> {
+    image: R,
+    layout: SectorLayout,
+    root: IsoEntry,
+    names: Names,
+}
+
+impl<R: Read + Seek> std::fmt::Debug for IsoImage<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IsoImage")
+            .field("layout", &self.layout)
+            .field("names", &self.names)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Finds the sector layout of an ISO 9660 image, if `image` is one
+pub fn detect<R: Read + Seek>(image: &mut R) -> Option<SectorLayout> {
+    LAYOUTS.into_iter().find(|layout| {
+        let mut sector = [0; SECTOR_SIZE];
+        if layout.raw_size == 2352 {
+            // Raw sectors start with a sync pattern and say which mode they are
+            let mut header = [0; 16];
+            if image
+                .seek(SeekFrom::Start(VOLUME_DESCRIPTOR_START * layout.raw_size))
+                .and_then(|_| image.read_exact(&mut header))
+                .is_err()
+                || header[..12] != SYNC_PATTERN
+                || (header[15] == 1) != (layout.data_offset == 16)
+            {
+                return false;
+            }
+        }
+        read_sector_with(image, *layout, VOLUME_DESCRIPTOR_START, &mut sector).is_ok()
+            && &sector[1..6] == b"CD001"
+    })
+}
+
+fn read_sector_with<R: Read + Seek>(
+    image: &mut R,
+    layout: SectorLayout,
+    lba: u64,
+    buf: &mut [u8; SECTOR_SIZE],
+) -> std::io::Result<()> {
+    image.seek(SeekFrom::Start(lba * layout.raw_size + layout.data_offset))?;
+    image.read_exact(buf)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn iso_error(what: &str) -> FSList {
+    FSList::ISO9660(what.to_string())
+}
+
+/// A directory record, before its name has been decided
+struct Record<'a> {
+    flags: u8,
+    lba: u32,
+    len: u32,
+    name: &'a [u8],
+    system_use: &'a [u8],
+}
+
+fn parse_record(record: &[u8]) -> Result<Record<'_>, FSList> {
+    if record.len() < 34 {
+        return Err(iso_error("directory record too short"));
+    }
+    let name_len = usize::from(record[32]);
+    let name_end = 33 + name_len;
+    if name_end > record.len() {
+        return Err(iso_error("directory record name overflows record"));
+    }
+    // Names of even length are followed by a padding byte
+    let system_use_start = (name_end + usize::from(name_len.is_multiple_of(2))).min(record.len());
+    Ok(Record {
+        flags: record[25],
+        lba: le_u32(&record[2..6]),
+        len: le_u32(&record[10..14]),
+        name: &record[33..name_end],
+        system_use: &record[system_use_start..],
+    })
+}
+
+impl<R: Read + Seek> IsoImage<R> {
+    pub fn open(mut image: R) -> Result<Self, FSList> {
+        let layout = detect(&mut image).ok_or_else(|| iso_error("no ISO 9660 volume"))?;
+        let mut primary = None;
+        let mut joliet = None;
+        let mut sector = [0; SECTOR_SIZE];
+        for lba in VOLUME_DESCRIPTOR_START..VOLUME_DESCRIPTOR_START + MAX_VOLUME_DESCRIPTORS {
+            read_sector_with(&mut image, layout, lba, &mut sector)?;
+            if &sector[1..6] != b"CD001" {
+                break;
+            }
+            match sector[0] {
+                1 if primary.is_none() => primary = Some(sector[156..190].to_vec()),
+                2 if matches!(&sector[88..91], b"%/@" | b"%/C" | b"%/E") => {
+                    joliet = Some(sector[156..190].to_vec());
+                }
+                255 => break,
+                _ => {}
+            }
+        }
+        let primary = primary.ok_or_else(|| iso_error("no primary volume descriptor"))?;
+        let mut iso = Self {
+            image,
+            layout,
+            root: root_entry(&primary)?,
+            names: Names::Primary,
+        };
+        if let Some(skip) = iso.rock_ridge_skip()? {
+            iso.names = Names::RockRidge { skip };
+        } else if let Some(joliet) = joliet {
+            iso.root = root_entry(&joliet)?;
+            iso.names = Names::Joliet;
+        }
+        Ok(iso)
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &IsoEntry {
+        &self.root
+    }
+
+    fn read_sector(&mut self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), FSList> {
+        Ok(read_sector_with(&mut self.image, self.layout, lba, buf)?)
+    }
+
+    /// Reads `len` bytes starting at the beginning of sector `lba`
+    fn read_extent(&mut self, lba: u32, len: u32, out: &mut Vec<u8>) -> Result<(), FSList> {
+        let mut remaining = usize::try_from(len).map_err(|_| iso_error("extent too big"))?;
+        let mut sector = [0; SECTOR_SIZE];
+        let mut lba = u64::from(lba);
+        while remaining > 0 {
+            self.read_sector(lba, &mut sector)?;
+            let take = remaining.min(SECTOR_SIZE);
+            out.extend_from_slice(&sector[..take]);
+            remaining -= take;
+            lba += 1;
+        }
+        Ok(())
+    }
+
+    /// Looks for the SUSP `SP` entry in the root's `.` record that marks a Rock Ridge disc
+    fn rock_ridge_skip(&mut self) -> Result<Option<usize>, FSList> {
+        let (lba, _) = self.root.extents[0];
+        let mut sector = [0; SECTOR_SIZE];
+        self.read_sector(u64::from(lba), &mut sector)?;
+        let len = usize::from(sector[0]);
+        if len < 34 {
+            return Ok(None);
+        }
+        let record = parse_record(&sector[..len])?;
+        let su = record.system_use;
+        Ok(
+            (su.len() >= 7 && &su[..2] == b"SP" && su[4..6] == [0xBE, 0xEF])
+                .then(|| usize::from(su[6])),
+        )
+    }
+
+    /// Lists a directory, leaving out its `.` and `..` entries
+    pub fn read_dir(&mut self, dir: &IsoEntry) -> Result<Vec<IsoEntry>, FSList> {
+        if !dir.is_dir {
+            return Err(iso_error("not a directory"));
+        }
+        let size = dir
+            .extents
+            .iter()
+            .try_fold(0_usize, |size, (_, len)| {
+                usize::try_from(*len)
+                    .ok()
+                    .and_then(|len| size.checked_add(len))
+                    .filter(|size| *size <= MAX_DIRECTORY)
+            })
+            .ok_or_else(|| iso_error("directory too big"))?;
+        let mut data = Vec::with_capacity(size);
+        for (lba, len) in dir.extents.clone() {
+            self.read_extent(lba, len, &mut data)?;
+        }
+        let mut entries: Vec<IsoEntry> = Vec::new();
+        let mut continues = false;
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = usize::from(data[pos]);
+            if len == 0 {
+                // Records don't cross sectors, the rest of this one is padding
+                pos = (pos / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                continue;
+            }
+            let end = (pos + len).min(data.len());
+            let record = parse_record(&data[pos..end])?;
+            pos = end;
+            if record.name == [0] || record.name == [1] {
+                continue;
+            }
+            let (name, lba, relocated) = self.record_name(&record)?;
+            if relocated {
+                continue;
+            }
+            let extent = (lba.unwrap_or(record.lba), record.len);
+            let is_dir = record.flags & FLAG_DIRECTORY != 0 || lba.is_some();
+            if continues && let Some(last) = entries.last_mut() {
+                // Later extents of a multi-extent file repeat its name
+                last.extents.push(extent);
+            } else {
+                entries.push(IsoEntry {
+                    name,
+                    is_dir,
+                    extents: vec![extent],
+                });
+            }
+            continues = record.flags & FLAG_MULTI_EXTENT != 0;
+        }
+        Ok(entries)
+    }
+
+    /// Decides a record's name. Also returns the Rock Ridge child link (the
+    /// real location of a relocated directory) and whether this record is the
+    /// relocated directory itself, which is listed through its link instead.
+    fn record_name(&mut self, record: &Record<'_>) -> Result<(String, Option<u32>, bool), FSList> {
+        let plain = match self.names {
+            Names::Joliet => {
+                let units: Vec<u16> = record
+                    .name
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            Names::Primary | Names::RockRidge { .. } => {
+                String::from_utf8_lossy(record.name).into_owned()
+            }
+        };
+        let plain = strip_version(&plain, record.flags & FLAG_DIRECTORY != 0);
+        let Names::RockRidge { skip } = self.names else {
+            return Ok((plain, None, false));
+        };
+        let mut name = Vec::new();
+        let mut child_link = None;
+        let mut relocated = false;
+        let mut area = record.system_use.get(skip..).unwrap_or_default().to_vec();
+        let mut areas = 0;
+        loop {
+            let mut continuation = None;
+            let mut pos = 0;
+            while pos + 4 <= area.len() {
+                let (sig, len) = (&area[pos..pos + 2], usize::from(area[pos + 2]));
+                if len < 4 || pos + len > area.len() {
+                    break;
+                }
+                let entry = &area[pos..pos + len];
+                match sig {
+                    // Current and parent directory flags carry no name
+                    b"NM" if len >= 5 && entry[4] & 0x06 == 0 => {
+                        name.extend_from_slice(&entry[5..]);
+                    }
+                    b"CE" if len >= 28 => {
+                        continuation = Some((
+                            le_u32(&entry[4..8]),
+                            le_u32(&entry[12..16]),
+                            le_u32(&entry[20..24]),
+                        ));
+                    }
+                    b"CL" if len >= 12 => child_link = Some(le_u32(&entry[4..8])),
+                    b"RE" => relocated = true,
+                    b"ST" => break,
+                    _ => {}
+                }
+                pos += len;
+            }
+            areas += 1;
+            let Some((lba, offset, len)) = continuation else {
+                break;
+            };
+            let (offset, len) = (
+                usize::try_from(offset).map_err(|_| iso_error("bad continuation"))?,
+                usize::try_from(len).map_err(|_| iso_error("bad continuation"))?,
+            );
+            let end = offset
+                .checked_add(len)
+                .filter(|end| areas <= 16 && *end <= MAX_CONTINUATION)
+                .ok_or_else(|| iso_error("Rock Ridge continuation area too big"))?;
+            let mut data = Vec::new();
+            self.read_extent(
+                lba,
+                u32::try_from(end).map_err(|_| iso_error("bad continuation"))?,
+                &mut data,
+            )?;
+            area = data
+                .get(offset..end)
+                .ok_or_else(|| iso_error("bad continuation"))?
+                .to_vec();
+        }
+        let name = if name.is_empty() {
+            plain
+        } else {
+            String::from_utf8_lossy(&name).into_owned()
+        };
+        Ok((name, child_link, relocated))
+    }
+
+    /// Finds the entry at `path`, relative to the root; an empty path is the root
+    pub fn find(&mut self, path: &std::path::Path) -> Result<IsoEntry, FSList> {
+        let mut entry = self.root.clone();
+        for component in path.components() {
+            let std::path::Component::Normal(name) = component else {
+                continue;
+            };
+            let name = name.to_string_lossy();
+            entry = self
+                .read_dir(&entry)?
+                .into_iter()
+                .find(|e| e.name == name)
+                .ok_or_else(|| FSList::FileNotFound(path.to_string_lossy().into_owned()))?;
+        }
+        Ok(entry)
+    }
+
+    pub fn read_file(&mut self, file: &IsoEntry) -> Result<Vec<u8>, FSList> {
+        let mut data = Vec::with_capacity(usize::try_from(file.size()).unwrap_or(0));
+        for (lba, len) in file.extents.clone() {
+            self.read_extent(lba, len, &mut data)?;
+        }
+        Ok(data)
+    }
+
+    /// Opens a file for reading a piece at a time
+    pub fn open_file(&mut self, file: &IsoEntry) -> IsoFile<'_, R> {
+        IsoFile {
+            size: file.size(),
+            extents: file.extents.clone(),
+            image: self,
+            pos: 0,
+        }
+    }
+}
+
+/// A file on an ISO 9660 filesystem, readable and seekable without loading it whole
+pub struct IsoFile<'a, R> {
+    image: &'a mut IsoImage<R>,
+    extents: Vec<(u32, u32)>,
+    size: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for IsoFile<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut extent_start = 0;
+        for (lba, len) in &self.extents {
+            let len = u64::from(*len);
+            if self.pos >= extent_start + len {
+                extent_start += len;
+                continue;
+            }
+            let within = self.pos - extent_start;
+            let sector_offset =
+                usize::try_from(within % SECTOR_SIZE as u64).map_err(std::io::Error::other)?;
+            let mut sector = [0; SECTOR_SIZE];
+            read_sector_with(
+                &mut self.image.image,
+                self.image.layout,
+                u64::from(*lba) + within / SECTOR_SIZE as u64,
+                &mut sector,
+            )?;
+            let available = usize::try_from(len - within)
+                .unwrap_or(usize::MAX)
+                .min(SECTOR_SIZE - sector_offset);
+            let n = available.min(buf.len());
+            buf[..n].copy_from_slice(&sector[sector_offset..sector_offset + n]);
+            self.pos += n as u64;
+            return Ok(n);
+        }
+        Ok(0)
+    }
+}
+
+impl<R> Seek for IsoFile<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        }
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad seek"))?;
+        Ok(self.pos)
+    }
+}
+
+fn root_entry(record: &[u8]) -> Result<IsoEntry, FSList> {
+    let record = parse_record(record)?;
+    Ok(IsoEntry {
+        name: String::new(),
+        is_dir: true,
+        extents: vec![(record.lba, record.len)],
+    })
+}
+
+/// Removes the `;1` file version, and the dot ISO 9660 adds to names without an extension
+fn strip_version(name: &str, is_dir: bool) -> String {
+    if is_dir {
+        return name.to_string();
+    }
+    let name = name.split_once(';').map_or(name, |(name, _version)| name);
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn record(lba: u32, len: u32, flags: u8, name: &[u8], system_use: &[u8]) -> Vec<u8> {
+        let pad = usize::from(name.len().is_multiple_of(2));
+        let total = 33 + name.len() + pad + system_use.len();
+        let mut out = vec![0; total];
+        out[0] = u8::try_from(total).unwrap();
+        out[2..6].copy_from_slice(&lba.to_le_bytes());
+        out[6..10].copy_from_slice(&lba.to_be_bytes());
+        out[10..14].copy_from_slice(&len.to_le_bytes());
+        out[14..18].copy_from_slice(&len.to_be_bytes());
+        out[25] = flags;
+        out[32] = u8::try_from(name.len()).unwrap();
+        out[33..33 + name.len()].copy_from_slice(name);
+        out[33 + name.len() + pad..].copy_from_slice(system_use);
+        out
+    }
+
+    fn nm(name: &[u8]) -> Vec<u8> {
+        let mut out = vec![b'N', b'M', u8::try_from(5 + name.len()).unwrap(), 1, 0];
+        out.extend_from_slice(name);
+        out
+    }
+
+    fn ce(lba: u32, offset: u32, len: u32) -> Vec<u8> {
+        let mut out = vec![b'C', b'E', 28, 1];
+        for value in [lba, offset, len] {
+            out.extend(value.to_le_bytes());
+            out.extend(value.to_be_bytes());
+        }
+        out
+    }
+
+    fn sector_with(contents: &[u8]) -> Vec<u8> {
+        let mut sector = vec![0; SECTOR_SIZE];
+        sector[..contents.len()].copy_from_slice(contents);
+        sector
+    }
+
+    /// Root at sector 18 holding `README.TXT;1` and `DATA/`, which holds `X.BIN;1`
+    fn image(rock_ridge: bool) -> Vec<u8> {
+        let mut sectors = vec![[0u8; SECTOR_SIZE]; 22];
+        let root = record(18, 2048, FLAG_DIRECTORY, &[0], &[]);
+        sectors[16][0] = 1;
+        sectors[16][1..6].copy_from_slice(b"CD001");
+        sectors[16][156..156 + root.len()].copy_from_slice(&root);
+        sectors[17][0] = 255;
+        sectors[17][1..6].copy_from_slice(b"CD001");
+        let sp = [b'S', b'P', 7, 1, 0xBE, 0xEF, 0];
+        let su = |name: &[u8]| if rock_ridge { nm(name) } else { vec![] };
+        let mut root_dir = record(
+            18,
+            2048,
+            FLAG_DIRECTORY,
+            &[0],
+            if rock_ridge { &sp } else { &[] },
+        );
+        root_dir.extend(record(18, 2048, FLAG_DIRECTORY, &[1], &[]));
+        root_dir.extend(record(20, 11, 0, b"README.TXT;1", &su(b"ReadMe.txt")));
+        root_dir.extend(record(19, 2048, FLAG_DIRECTORY, b"DATA", &su(b"data")));
+        sectors[18][..root_dir.len()].copy_from_slice(&root_dir);
+        let mut data_dir = record(19, 2048, FLAG_DIRECTORY, &[0], &[]);
+        data_dir.extend(record(18, 2048, FLAG_DIRECTORY, &[1], &[]));
+        data_dir.extend(record(21, 3, 0, b"X.BIN;1", &su(b"x.bin")));
+        sectors[19][..data_dir.len()].copy_from_slice(&data_dir);
+        sectors[20][..11].copy_from_slice(b"hello world");
+        sectors[21][..3].copy_from_slice(&[1, 2, 3]);
+        sectors.concat()
+    }
+
+    /// Wraps 2048 byte sectors into raw MODE1 2352 byte sectors
+    fn raw_mode1(cooked: &[u8]) -> Vec<u8> {
+        cooked
+            .chunks_exact(SECTOR_SIZE)
+            .flat_map(|sector| {
+                let mut raw = SYNC_PATTERN.to_vec();
+                raw.extend([0, 2, 0, 1]);
+                raw.extend_from_slice(sector);
+                raw.extend([0; 288]);
+                raw
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists_and_reads_files() {
+        for data in [image(false), raw_mode1(&image(false))] {
+            let mut iso = IsoImage::open(Cursor::new(data)).unwrap();
+            let root = iso.root().clone();
+            let names: Vec<_> = iso
+                .read_dir(&root)
+                .unwrap()
+                .into_iter()
+                .map(|e| (e.name, e.is_dir))
+                .collect();
+            assert_eq!(
+                names,
+                vec![
+                    ("README.TXT".to_string(), false),
+                    ("DATA".to_string(), true)
+                ]
+            );
+            let file = iso.find(std::path::Path::new("DATA/X.BIN")).unwrap();
+            assert_eq!(iso.read_file(&file).unwrap(), vec![1, 2, 3]);
+            let file = iso.find(std::path::Path::new("README.TXT")).unwrap();
+            assert_eq!(iso.read_file(&file).unwrap(), b"hello world");
+        }
+        assert!(detect(&mut Cursor::new(vec![0; 40 * SECTOR_SIZE])).is_none());
+    }
+
+    #[test]
+    fn rock_ridge_names() {
+        let mut iso = IsoImage::open(Cursor::new(image(true))).unwrap();
+        let file = iso.find(std::path::Path::new("data/x.bin")).unwrap();
+        assert_eq!(iso.read_file(&file).unwrap(), vec![1, 2, 3]);
+        let root = iso.root().clone();
+        assert_eq!(iso.read_dir(&root).unwrap()[0].name, "ReadMe.txt");
+    }
+
+    #[test]
+    fn streams_files() {
+        let mut iso = IsoImage::open(Cursor::new(raw_mode1(&image(false)))).unwrap();
+        let file = iso.find(std::path::Path::new("README.TXT")).unwrap();
+        let mut stream = iso.open_file(&file);
+        stream.seek(SeekFrom::Start(6)).unwrap();
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "world");
+        stream.seek(SeekFrom::End(-11)).unwrap();
+        let mut first = [0; 5];
+        stream.read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"hello");
+    }
+
+    #[test]
+    fn joliet_names() {
+        let utf16 =
+            |name: &str| -> Vec<u8> { name.encode_utf16().flat_map(u16::to_be_bytes).collect() };
+        let mut data = image(false);
+        // A Joliet supplementary volume whose root, at sector 22, has long mixed case names
+        let mut svd = vec![0; SECTOR_SIZE];
+        svd[0] = 2;
+        svd[1..6].copy_from_slice(b"CD001");
+        svd[88..91].copy_from_slice(b"%/E");
+        let root = record(22, 2048, FLAG_DIRECTORY, &[0], &[]);
+        svd[156..156 + root.len()].copy_from_slice(&root);
+        data[17 * SECTOR_SIZE..18 * SECTOR_SIZE].copy_from_slice(&svd);
+        let mut root_dir = record(22, 2048, FLAG_DIRECTORY, &[0], &[]);
+        root_dir.extend(record(22, 2048, FLAG_DIRECTORY, &[1], &[]));
+        root_dir.extend(record(20, 11, 0, &utf16("Read Me First.txt;1"), &[]));
+        data.extend(sector_with(&root_dir));
+
+        let mut iso = IsoImage::open(Cursor::new(data)).unwrap();
+        assert_eq!(iso.names, Names::Joliet);
+        let root = iso.root().clone();
+        let names: Vec<_> = iso
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec!["Read Me First.txt".to_string()]);
+        let file = iso.find(std::path::Path::new("Read Me First.txt")).unwrap();
+        assert_eq!(iso.read_file(&file).unwrap(), b"hello world");
+    }
+
+    /// Root directory whose `README.TXT;1` record has Rock Ridge system use `su`
+    fn with_readme_system_use(mut data: Vec<u8>, su: &[u8]) -> Vec<u8> {
+        let sp = [b'S', b'P', 7, 1, 0xBE, 0xEF, 0];
+        let mut root_dir = record(18, 2048, FLAG_DIRECTORY, &[0], &sp);
+        root_dir.extend(record(18, 2048, FLAG_DIRECTORY, &[1], &[]));
+        root_dir.extend(record(20, 11, 0, b"README.TXT;1", su));
+        data[18 * SECTOR_SIZE..19 * SECTOR_SIZE].copy_from_slice(&sector_with(&root_dir));
+        data
+    }
+
+    #[test]
+    fn rock_ridge_continued_names() {
+        let rest = nm(b"-name.txt");
+        let su = [nm(b"A-long"), ce(22, 8, u32::try_from(rest.len()).unwrap())].concat();
+        // Another name entry after the continuation area must not be read
+        let area = [vec![0; 8], rest, nm(b"-junk")].concat();
+        let mut data = with_readme_system_use(image(true), &su);
+        data.extend(sector_with(&area));
+        let mut iso = IsoImage::open(Cursor::new(data)).unwrap();
+        let root = iso.root().clone();
+        assert_eq!(iso.read_dir(&root).unwrap()[0].name, "A-long-name.txt");
+        let file = iso.find(std::path::Path::new("A-long-name.txt")).unwrap();
+        assert_eq!(iso.read_file(&file).unwrap(), b"hello world");
+
+        for bad in [ce(22, 8, u32::MAX), ce(22, u32::MAX, 8), ce(99, 0, 8)] {
+            let mut data = with_readme_system_use(image(true), &bad);
+            data.extend(sector_with(&[]));
+            let mut iso = IsoImage::open(Cursor::new(data)).unwrap();
+            let root = iso.root().clone();
+            assert!(iso.read_dir(&root).is_err());
+        }
+    }
+
+    #[test]
+    fn huge_directories() {
+        let mut iso = IsoImage::open(Cursor::new(image(false))).unwrap();
+        let mut dir = iso.root().clone();
+        dir.extents = vec![(18, u32::MAX)];
+        assert!(iso.read_dir(&dir).is_err());
+        // Every extent of a multi-extent directory counts
+        dir.extents = vec![(18, 2048); MAX_DIRECTORY / SECTOR_SIZE + 1];
+        assert!(iso.read_dir(&dir).is_err());
+    }
+}
@@ -1,10 +1,26 @@
+pub mod archive;
+pub mod artifact;
+pub mod chdimage;
 pub mod danger;
+pub mod diskbuild;
+pub mod diskedit;
+pub mod diskindex;
+pub mod extfs;
 pub mod fslist;
+pub mod iso9660;
 pub mod model_enums;
 pub mod models;
+pub mod replay;
+pub mod replaystates;
+pub mod rompatch;
+pub mod screenshot;
 pub mod search;
 pub mod storage;
+pub mod subobject;
+pub mod task;
 pub mod v86clone;
+pub mod v86state;
+pub mod videometa;
 
 pub mod error;
 
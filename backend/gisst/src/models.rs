@@ -7,7 +7,6 @@ use chrono::{DateTime, Utc};
 use sqlx::postgres::{PgConnection, PgQueryResult};
 
 use crate::{model_enums::Framework, search::SearchIndexer};
-use serde_with::{base64::Base64, serde_as};
 use uuid::Uuid;
 
 use crate::error::{Action, Insert, RecordSQL, Table};
@@ -34,6 +33,18 @@ pub struct Video {
     #[serde(default = "utc_datetime_now")]
     pub created_on: DateTime<Utc>,
     pub creator_id: Uuid, // Creator
+    #[serde(default)]
+    pub video_duration: Option<f64>, // seconds
+    #[serde(default)]
+    pub video_width: Option<i32>,
+    #[serde(default)]
+    pub video_height: Option<i32>,
+    #[serde(default)]
+    pub video_codec: Option<String>,
+    #[serde(default)]
+    pub video_frame_rate: Option<f64>,
+    #[serde(default)]
+    pub video_poster_id: Option<Uuid>, // Screenshot
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -74,6 +85,31 @@ pub struct File {
     pub creator_id: Option<Uuid>,
 }
 
+/// A saved `gisst::fslist` listing, shared by all files with the same hash
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileListing {
+    pub file_hash: String,
+    /// `gisst::fslist::LISTING_VERSION` at the time of listing
+    pub listing_version: i32,
+    pub is_listable: bool,
+    pub listing: sqlx::types::JsonValue,
+    #[serde(default = "utc_datetime_now")]
+    pub created_on: DateTime<Utc>,
+}
+
+/// A saved `gisst::fslist` diff, shared by all pairs of files with the same hashes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileDiff {
+    pub old_hash: String,
+    pub new_hash: String,
+    /// `gisst::fslist::LISTING_VERSION` at the time of diffing
+    pub listing_version: i32,
+    pub is_diffable: bool,
+    pub changes: sqlx::types::JsonValue,
+    #[serde(default = "utc_datetime_now")]
+    pub created_on: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Instance {
     pub instance_id: Uuid,
@@ -87,12 +123,13 @@ pub struct Instance {
     pub creator_id: Option<Uuid>,
 }
 
-#[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Screenshot {
     pub screenshot_id: Uuid,
-    #[serde_as(as = "Base64")]
-    pub screenshot_data: Vec<u8>,
+    // Files are only missing for screenshots still stored in the database,
+    // see `gisst::screenshot::migrate_legacy_screenshots`
+    pub file_id: Option<Uuid>,           // File
+    pub thumbnail_file_id: Option<Uuid>, // File
     #[serde(default = "utc_datetime_now")]
     pub created_on: DateTime<Utc>,
     pub creator_id: Option<Uuid>,
@@ -105,6 +142,8 @@ pub enum ObjectRole {
     Content,
     Dependency,
     Config,
+    /// A ROM patch that was applied to the content at the same index
+    Patch,
 }
 impl FromStr for ObjectRole {
     type Err = &'static str;
@@ -114,6 +153,7 @@ impl FromStr for ObjectRole {
             "content" => Ok(ObjectRole::Content),
             "dependency" => Ok(ObjectRole::Dependency),
             "config" => Ok(ObjectRole::Config),
+            "patch" => Ok(ObjectRole::Patch),
             _ => Err("Attempting to convert ObjectRole that does not exist."),
         }
     }
@@ -157,6 +197,12 @@ pub struct Object {
     #[serde(default = "utc_datetime_now")]
     pub created_on: DateTime<Utc>,
     pub creator_id: Option<Uuid>,
+    /// The disk image object this one was extracted from, if any
+    #[serde(default)]
+    pub object_parent_id: Option<Uuid>,
+    /// Where in the parent's image this object was found, as `partN/...`
+    #[serde(default)]
+    pub object_parent_path: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -173,6 +219,19 @@ pub struct Replay {
     pub created_on: DateTime<Utc>,
     pub hidden: bool,
     pub video_id: Option<Uuid>,
+    // Read from the replay file, see `gisst::replay`
+    #[serde(default)]
+    pub replay_frame_count: Option<i64>,
+    #[serde(default)]
+    pub replay_duration: Option<f64>, // seconds
+    #[serde(default)]
+    pub replay_checkpoint_count: Option<i32>,
+    #[serde(default)]
+    pub replay_checkpoints: Option<serde_json::Value>, // [{frame, time}]
+    #[serde(default)]
+    pub replay_identifier: Option<String>,
+    #[serde(default)]
+    pub replay_content_crc: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -223,7 +282,6 @@ pub struct Work {
     pub creator_id: Option<Uuid>,
 }
 
-#[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreatorStateInfo {
     pub work_id: Uuid,
@@ -235,8 +293,6 @@ pub struct CreatorStateInfo {
     pub state_name: String,
     pub state_description: String,
     pub screenshot_id: Uuid,
-    #[serde_as(as = "Base64")]
-    pub screenshot_data: Vec<u8>,
     pub file_id: Uuid,
     pub instance_id: Uuid,
     pub created_on: DateTime<Utc>,
@@ -272,6 +328,40 @@ pub struct CreatorReplayInfo {
     pub environment_created_on: chrono::DateTime<chrono::Utc>,
     pub hidden: bool,
     pub video_id: Option<Uuid>,
+    pub replay_frame_count: Option<i64>,
+    pub replay_duration: Option<f64>,
+    pub replay_checkpoint_count: Option<i32>,
+}
+
+/// A file inside a disk image, shared by all image files with the same hash
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiskEntry {
+    pub disk_entry_id: Uuid,
+    pub file_hash: String,
+    /// Path from the partition down, as in `/objects/{id}/{entry_path}`
+    pub entry_path: String,
+    pub entry_name: String,
+    pub entry_size: i64,
+    pub entry_hash: String,
+    #[serde(default = "utc_datetime_now")]
+    pub created_on: DateTime<Utc>,
+}
+
+/// Search document for a disk entry as seen through one object
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiskEntryInfo {
+    /// `{object_id}-{disk_entry_id}`, since one image can back several objects
+    pub disk_entry_search_id: String,
+    pub disk_entry_id: Uuid,
+    pub object_id: Uuid,
+    pub entry_path: String,
+    pub entry_name: String,
+    pub entry_size: i64,
+    pub entry_hash: String,
+    pub image_filename: String,
+    pub instance_ids: Vec<Uuid>,
+    pub work_names: Vec<String>,
+    pub work_platforms: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -320,8 +410,9 @@ impl CreatorSaveInfo {
         .fetch_all(conn)
         .await
     }
-    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
-        use futures::StreamExt;
+    pub fn fetch_stream(
+        conn: &mut sqlx::PgConnection,
+    ) -> impl futures::Stream<Item = sqlx::Result<Self>> {
         sqlx::query_as!(
             Self,
             r#"SELECT work_id, work_name, work_version, work_platform, work.creator_id as work_creator,
@@ -335,7 +426,11 @@ impl CreatorSaveInfo {
                     JOIN save USING (instance_id)
                     JOIN creator ON (save.creator_id = creator.creator_id)
                     JOIN environment ON (environment.environment_id = instance.environment_id)"#
-        ).fetch(conn).filter_map(|f| futures::future::ready(f.ok()))
+        ).fetch(conn)
+    }
+    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
+        use futures::StreamExt;
+        Self::fetch_stream(conn).filter_map(|f| futures::future::ready(f.ok()))
     }
 }
 impl CreatorStateInfo {
@@ -343,7 +438,7 @@ impl CreatorStateInfo {
         sqlx::query_as!(
             Self,
             r#"SELECT work_id, work_name, work_version, work_platform, work.creator_id as work_creator,
-                      state_id, state_name, state_description, state.screenshot_id, screenshot.screenshot_data,
+                      state_id, state_name, state_description, state.screenshot_id,
                       file_id, instance_id, state.created_on, creator.creator_id,
                       creator.creator_username, creator.creator_full_name,
                       environment.environment_framework as "environment_framework:_",
@@ -352,18 +447,18 @@ impl CreatorStateInfo {
                     JOIN instance USING (work_id)
                     JOIN state USING (instance_id)
                     JOIN creator ON (state.creator_id = creator.creator_id)
-                    JOIN screenshot ON (screenshot.screenshot_id = state.screenshot_id)
                     JOIN environment ON (environment.environment_id = instance.environment_id)
                WHERE state.state_id = $1"#,
             state.state_id
         ).fetch_one(conn).await
     }
-    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
-        use futures::StreamExt;
+    pub fn fetch_stream(
+        conn: &mut sqlx::PgConnection,
+    ) -> impl futures::Stream<Item = sqlx::Result<Self>> {
         sqlx::query_as!(
             Self,
             r#"SELECT work_id, work_name, work_version, work_platform, work.creator_id as work_creator,
-                      state_id, state_name, state_description, state.screenshot_id, screenshot.screenshot_data,
+                      state_id, state_name, state_description, state.screenshot_id,
                       file_id, instance_id, state.created_on, creator.creator_id,
                       creator.creator_username, creator.creator_full_name,
                       environment.environment_framework as "environment_framework:_",
@@ -372,9 +467,12 @@ impl CreatorStateInfo {
                     JOIN instance USING (work_id)
                     JOIN state USING (instance_id)
                     JOIN creator ON (state.creator_id = creator.creator_id)
-                    JOIN screenshot ON (screenshot.screenshot_id = state.screenshot_id)
                     JOIN environment ON (environment.environment_id = instance.environment_id)"#
-        ).fetch(conn).filter_map(|f| futures::future::ready(f.ok()))
+        ).fetch(conn)
+    }
+    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
+        use futures::StreamExt;
+        Self::fetch_stream(conn).filter_map(|f| futures::future::ready(f.ok()))
     }
 }
 impl CreatorReplayInfo {
@@ -386,7 +484,8 @@ impl CreatorReplayInfo {
                       file_id, instance_id, replay.created_on, creator.creator_id,
                       creator.creator_username, creator.creator_full_name,
                       environment.environment_framework as "environment_framework:_",
-                      environment.environment_core_name, environment.environment_core_version, environment.created_on as environment_created_on, replay.hidden, replay.video_id
+                      environment.environment_core_name, environment.environment_core_version, environment.created_on as environment_created_on, replay.hidden, replay.video_id,
+                      replay.replay_frame_count, replay.replay_duration, replay.replay_checkpoint_count
                FROM work
                     JOIN instance USING (work_id)
                     JOIN replay USING (instance_id)
@@ -396,8 +495,9 @@ impl CreatorReplayInfo {
             replay.replay_id
         ).fetch_one(conn).await
     }
-    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
-        use futures::StreamExt;
+    pub fn fetch_stream(
+        conn: &mut sqlx::PgConnection,
+    ) -> impl futures::Stream<Item = sqlx::Result<Self>> {
         sqlx::query_as!(
             Self,
             r#"SELECT work_id, work_name, work_version, work_platform, work.creator_id as work_creator,
@@ -405,13 +505,18 @@ impl CreatorReplayInfo {
                       file_id, instance_id, replay.created_on, creator.creator_id,
                       creator.creator_username, creator.creator_full_name,
                       environment.environment_framework as "environment_framework:_",
-                      environment.environment_core_name, environment.environment_core_version, environment.created_on as environment_created_on, replay.hidden, replay.video_id
+                      environment.environment_core_name, environment.environment_core_version, environment.created_on as environment_created_on, replay.hidden, replay.video_id,
+                      replay.replay_frame_count, replay.replay_duration, replay.replay_checkpoint_count
                FROM work
                     JOIN instance USING (work_id)
                     JOIN replay USING (instance_id)
                     JOIN creator ON (replay.creator_id = creator.creator_id)
                     JOIN environment ON (environment.environment_id = instance.environment_id)"#
-        ).fetch(conn).filter_map(|f| futures::future::ready(f.ok()))
+        ).fetch(conn)
+    }
+    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
+        use futures::StreamExt;
+        Self::fetch_stream(conn).filter_map(|f| futures::future::ready(f.ok()))
     }
 }
 
@@ -426,11 +531,14 @@ impl Creator {
         .fetch_optional(conn)
         .await
     }
+    pub fn fetch_stream(
+        conn: &mut sqlx::PgConnection,
+    ) -> impl futures::Stream<Item = sqlx::Result<Self>> {
+        sqlx::query_as!(Self, r#"SELECT * FROM creator"#).fetch(conn)
+    }
     pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
         use futures::StreamExt;
-        sqlx::query_as!(Self, r#"SELECT * FROM creator"#)
-            .fetch(conn)
-            .filter_map(|f| futures::future::ready(f.ok()))
+        Self::fetch_stream(conn).filter_map(|f| futures::future::ready(f.ok()))
     }
     pub async fn insert(
         conn: &mut PgConnection,
@@ -471,12 +579,18 @@ impl Video {
     pub async fn insert(conn: &mut PgConnection, model: Self) -> Result<Self, Insert> {
         let record = sqlx::query_as!(
             Self,
-            r#"INSERT INTO video VALUES($1, $2, $3, $4) RETURNING *
+            r#"INSERT INTO video VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING *
             "#,
             model.video_id,
             model.file_id,
             model.created_on,
-            model.creator_id
+            model.creator_id,
+            model.video_duration,
+            model.video_width,
+            model.video_height,
+            model.video_codec,
+            model.video_frame_rate,
+            model.video_poster_id
         )
         .fetch_one(conn.as_mut())
         .await
@@ -487,8 +601,101 @@ impl Video {
         })?;
         Ok(record)
     }
+
+    /// A new video record for `file_id`, with its media fields read from the
+    /// video's container headers at `path`. Files that can't be parsed leave
+    /// the media fields empty rather than failing.
+    pub async fn for_file(file_id: Uuid, creator_id: Uuid, path: std::path::PathBuf) -> Self {
+        let mut video = Self {
+            video_id: Uuid::new_v4(),
+            file_id,
+            created_on: Utc::now(),
+            creator_id,
+            video_duration: None,
+            video_width: None,
+            video_height: None,
+            video_codec: None,
+            video_frame_rate: None,
+            video_poster_id: None,
+        };
+        match tokio::task::spawn_blocking(move || crate::videometa::probe_path(&path)).await {
+            Ok(Ok(meta)) => {
+                video.video_duration = meta.duration;
+                video.video_width = meta.width.and_then(|w| i32::try_from(w).ok());
+                video.video_height = meta.height.and_then(|h| i32::try_from(h).ok());
+                video.video_codec = meta.codec;
+                video.video_frame_rate = meta.frame_rate;
+            }
+            Ok(Err(e)) => tracing::warn!("Couldn't read metadata of video file {file_id}: {e}"),
+            Err(e) => tracing::warn!("Couldn't read metadata of video file {file_id}: {e}"),
+        }
+        video
+    }
+
+    /// Gives the video recording `replay_id` the screenshot of the replay's
+    /// earliest checkpoint as its poster frame, unless it already has one
+    pub async fn set_replay_poster(conn: &mut PgConnection, replay_id: Uuid) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"UPDATE video SET video_poster_id = (
+                   SELECT state.screenshot_id FROM state
+                   WHERE state.replay_id = $1
+                   ORDER BY state.state_replay_index ASC NULLS LAST, state.created_on ASC
+                   LIMIT 1)
+               FROM replay
+               WHERE replay.replay_id = $1 AND video.video_id = replay.video_id
+                 AND video.video_poster_id IS NULL"#,
+            replay_id
+        )
+        .execute(conn)
+        .await
+        .map(|_qr| ())
+    }
 }
 impl File {
+    /// One file per distinct hash among the files that back objects
+    pub async fn get_all_for_objects(conn: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT DISTINCT ON (file.file_hash) file.*
+               FROM file JOIN object USING (file_id)
+               ORDER BY file.file_hash, file.created_on"#
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    pub async fn get_batch(
+        conn: &mut PgConnection,
+        offset: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT * FROM file ORDER BY file_id OFFSET $1 LIMIT $2",
+            offset,
+            limit
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    pub async fn update_sizes(
+        conn: &mut PgConnection,
+        id: Uuid,
+        file_size: i64,
+        file_compressed_size: Option<i64>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"UPDATE file SET file_size=$1, file_compressed_size=$2 WHERE file_id=$3"#,
+            file_size,
+            file_compressed_size,
+            id,
+        )
+        .execute(conn)
+        .await
+        .map(|_qr| ())
+    }
+
     pub async fn get_by_hash(conn: &mut PgConnection, hash: &str) -> sqlx::Result<Option<Self>> {
         sqlx::query_as!(Self, r#"SELECT * FROM file WHERE file_hash = $1"#, hash)
             .fetch_optional(conn)
@@ -496,6 +703,157 @@ impl File {
     }
 }
 
+impl DiskEntry {
+    pub async fn count_for_file_hash(conn: &mut PgConnection, hash: &str) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"SELECT count(*) as "count!" FROM disk_entry WHERE file_hash = $1"#,
+            hash
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    pub async fn delete_for_file_hash(
+        conn: &mut PgConnection,
+        hash: &str,
+    ) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!("DELETE FROM disk_entry WHERE file_hash = $1", hash)
+            .execute(conn)
+            .await
+    }
+
+    pub async fn insert(conn: &mut PgConnection, model: DiskEntry) -> Result<Self, Insert> {
+        sqlx::query_as!(
+            Self,
+            r#"INSERT INTO disk_entry VALUES($1, $2, $3, $4, $5, $6, $7) RETURNING *"#,
+            model.disk_entry_id,
+            model.file_hash,
+            model.entry_path,
+            model.entry_name,
+            model.entry_size,
+            model.entry_hash,
+            model.created_on,
+        )
+        .fetch_one(conn)
+        .await
+        .map_err(|e| {
+            Insert::Sql(RecordSQL {
+                table: Table::DiskEntry,
+                action: Action::Insert,
+                source: e,
+            })
+        })
+    }
+}
+
+impl DiskEntryInfo {
+    pub async fn get_for_file_hash(conn: &mut PgConnection, hash: &str) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT object.object_id::text || '-' || disk_entry.disk_entry_id::text as "disk_entry_search_id!",
+                      disk_entry.disk_entry_id, object.object_id, entry_path, entry_name, entry_size, entry_hash,
+                      file.file_filename as image_filename,
+                      array_remove(array_agg(DISTINCT instanceObject.instance_id), NULL) as "instance_ids!",
+                      array_remove(array_agg(DISTINCT work.work_name), NULL) as "work_names!",
+                      array_remove(array_agg(DISTINCT work.work_platform), NULL) as "work_platforms!"
+               FROM disk_entry
+                    JOIN file ON (file.file_hash = disk_entry.file_hash)
+                    JOIN object ON (object.file_id = file.file_id)
+                    LEFT JOIN instanceObject ON (instanceObject.object_id = object.object_id)
+                    LEFT JOIN instance ON (instance.instance_id = instanceObject.instance_id)
+                    LEFT JOIN work ON (work.work_id = instance.work_id)
+               WHERE disk_entry.file_hash = $1
+               GROUP BY disk_entry.disk_entry_id, object.object_id, file.file_filename"#,
+            hash
+        )
+        .fetch_all(conn)
+        .await
+    }
+    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
+        use futures::StreamExt;
+        sqlx::query_as!(
+            Self,
+            r#"SELECT object.object_id::text || '-' || disk_entry.disk_entry_id::text as "disk_entry_search_id!",
+                      disk_entry.disk_entry_id, object.object_id, entry_path, entry_name, entry_size, entry_hash,
+                      file.file_filename as image_filename,
+                      array_remove(array_agg(DISTINCT instanceObject.instance_id), NULL) as "instance_ids!",
+                      array_remove(array_agg(DISTINCT work.work_name), NULL) as "work_names!",
+                      array_remove(array_agg(DISTINCT work.work_platform), NULL) as "work_platforms!"
+               FROM disk_entry
+                    JOIN file ON (file.file_hash = disk_entry.file_hash)
+                    JOIN object ON (object.file_id = file.file_id)
+                    LEFT JOIN instanceObject ON (instanceObject.object_id = object.object_id)
+                    LEFT JOIN instance ON (instance.instance_id = instanceObject.instance_id)
+                    LEFT JOIN work ON (work.work_id = instance.work_id)
+               GROUP BY disk_entry.disk_entry_id, object.object_id, file.file_filename"#
+        ).fetch(conn).filter_map(|f| futures::future::ready(f.ok()))
+    }
+}
+
+impl FileListing {
+    pub async fn get_by_hash(conn: &mut PgConnection, hash: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT * FROM file_listing WHERE file_hash = $1"#,
+            hash
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
+    pub async fn upsert(conn: &mut PgConnection, model: FileListing) -> sqlx::Result<Self> {
+        sqlx::query_as!(
+            Self,
+            r#"INSERT INTO file_listing VALUES($1, $2, $3, $4, $5)
+            ON CONFLICT(file_hash) DO UPDATE
+            SET listing_version=$2, is_listable=$3, listing=$4, created_on=$5
+            RETURNING *"#,
+            model.file_hash,
+            model.listing_version,
+            model.is_listable,
+            model.listing,
+            model.created_on,
+        )
+        .fetch_one(conn)
+        .await
+    }
+}
+
+impl FileDiff {
+    pub async fn get_by_hashes(
+        conn: &mut PgConnection,
+        old_hash: &str,
+        new_hash: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT * FROM file_diff WHERE old_hash = $1 AND new_hash = $2"#,
+            old_hash,
+            new_hash
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
+    pub async fn upsert(conn: &mut PgConnection, model: FileDiff) -> sqlx::Result<Self> {
+        sqlx::query_as!(
+            Self,
+            r#"INSERT INTO file_diff VALUES($1, $2, $3, $4, $5, $6)
+            ON CONFLICT(old_hash, new_hash) DO UPDATE
+            SET listing_version=$3, is_diffable=$4, changes=$5, created_on=$6
+            RETURNING *"#,
+            model.old_hash,
+            model.new_hash,
+            model.listing_version,
+            model.is_diffable,
+            model.changes,
+            model.created_on,
+        )
+        .fetch_one(conn)
+        .await
+    }
+}
+
 impl File {
     pub async fn get_by_id(conn: &mut PgConnection, id: Uuid) -> sqlx::Result<Option<Self>> {
         sqlx::query_as!(Self, r#"SELECT * FROM file WHERE file_id = $1"#, id)
@@ -567,6 +925,28 @@ impl Instance {
 }
 
 impl Instance {
+    pub async fn set_config(
+        conn: &mut PgConnection,
+        id: Uuid,
+        config: Option<sqlx::types::JsonValue>,
+    ) -> Result<Self, Insert> {
+        sqlx::query_as!(
+            Self,
+            r#"UPDATE instance SET instance_config = $2 WHERE instance_id = $1 RETURNING *"#,
+            id,
+            config
+        )
+        .fetch_one(conn)
+        .await
+        .map_err(|e| {
+            Insert::Sql(RecordSQL {
+                table: Table::Instance,
+                action: Action::Update,
+                source: e,
+            })
+        })
+    }
+
     pub async fn get_all_for_work_id(
         conn: &mut PgConnection,
         work_id: Uuid,
@@ -610,6 +990,21 @@ impl Environment {
         .await
     }
 
+    pub async fn get_all(conn: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT environment_id, environment_name,
+                  environment_framework as "environment_framework:_",
+                  environment_platform,
+                  environment_core_name, environment_core_version,
+                  environment_derived_from, environment_config, created_on, creator_id
+               FROM environment
+               ORDER BY created_on"#
+        )
+        .fetch_all(conn)
+        .await
+    }
+
     pub async fn get_for_core(
         conn: &mut PgConnection,
         core_name: &str,
@@ -631,6 +1026,33 @@ impl Environment {
         .fetch_all(conn)
         .await
     }
+    pub async fn set_config(
+        conn: &mut PgConnection,
+        id: Uuid,
+        config: Option<sqlx::types::JsonValue>,
+    ) -> Result<Self, Insert> {
+        sqlx::query_as!(
+            Self,
+            r#"UPDATE environment SET environment_config = $2
+               WHERE environment_id = $1
+               RETURNING environment_id, environment_name,
+                  environment_framework as "environment_framework:_",
+                  environment_platform,
+                  environment_core_name, environment_core_version,
+                  environment_derived_from, environment_config, created_on, creator_id"#,
+            id,
+            config
+        )
+        .fetch_one(conn)
+        .await
+        .map_err(|e| {
+            Insert::Sql(RecordSQL {
+                table: Table::Environment,
+                action: Action::Update,
+                source: e,
+            })
+        })
+    }
     pub async fn insert(conn: &mut PgConnection, model: Environment) -> Result<Self, Insert> {
         sqlx::query_as!(
             Self,
@@ -683,15 +1105,60 @@ impl Object {
             .await
     }
 
+    /// Objects that were extracted from the disk image of object `id`
+    pub async fn get_children(conn: &mut PgConnection, id: Uuid) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT * FROM object WHERE object_parent_id = $1 ORDER BY object_parent_path"#,
+            id
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    pub async fn get_all(conn: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(Self, r#"SELECT * FROM object ORDER BY created_on"#)
+            .fetch_all(conn)
+            .await
+    }
+
+    pub async fn set_parent(
+        conn: &mut PgConnection,
+        id: Uuid,
+        parent_id: Uuid,
+        parent_path: &str,
+    ) -> Result<Self, Insert> {
+        sqlx::query_as!(
+            Self,
+            r#"UPDATE object SET object_parent_id = $2, object_parent_path = $3
+               WHERE object_id = $1 RETURNING *"#,
+            id,
+            parent_id,
+            parent_path
+        )
+        .fetch_one(conn)
+        .await
+        .map_err(|e| {
+            Insert::Sql(RecordSQL {
+                table: Table::Object,
+                action: Action::Update,
+                source: e,
+            })
+        })
+    }
+
     pub async fn insert(conn: &mut PgConnection, object: Object) -> Result<Self, Insert> {
         // Note: the "!" following the AS statements after RETURNING are forcing not-null status on those fields
         // from: https://docs.rs/sqlx/latest/sqlx/macro.query.html#type-overrides-output-columns
         sqlx::query_as!(
             Object,
-            r#"INSERT INTO object VALUES ($1, $2, $3, current_timestamp) RETURNING *"#,
+            r#"INSERT INTO object VALUES ($1, $2, $3, current_timestamp, $4, $5, $6) RETURNING *"#,
             object.object_id,
             object.file_id,
-            object.object_description
+            object.object_description,
+            object.creator_id,
+            object.object_parent_id,
+            object.object_parent_path
         )
         .fetch_one(conn)
         .await
@@ -724,6 +1191,20 @@ impl Object {
         .await
     }
 
+    pub async fn unlink_object_from_instance(
+        conn: &mut PgConnection,
+        object_id: Uuid,
+        instance_id: Uuid,
+    ) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            r#"DELETE FROM instanceObject WHERE instance_id = $1 AND object_id = $2"#,
+            instance_id,
+            object_id
+        )
+        .execute(conn)
+        .await
+    }
+
     pub async fn get_object_instance_by_ids(
         conn: &mut PgConnection,
         object_id: Uuid,
@@ -749,6 +1230,15 @@ impl Replay {
             .fetch_optional(conn)
             .await
     }
+    pub async fn get_by_hash(conn: &mut PgConnection, hash: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT replay.* FROM replay JOIN file USING (file_id) WHERE file.file_hash = $1"#,
+            hash
+        )
+        .fetch_optional(conn)
+        .await
+    }
     pub async fn get_all_for_instance(
         conn: &mut PgConnection,
         id: Uuid,
@@ -780,6 +1270,43 @@ impl Replay {
         Ok(())
     }
 
+    pub async fn set_video(
+        conn: &mut PgConnection,
+        id: Uuid,
+        video_id: Uuid,
+        indexer: &impl crate::search::SearchIndexer,
+    ) -> Result<Self, Insert> {
+        let replay = sqlx::query_as!(
+            Self,
+            "UPDATE replay SET video_id=$2 WHERE replay_id=$1 RETURNING *",
+            id,
+            video_id
+        )
+        .fetch_one(conn.as_mut())
+        .await
+        .map_err(|e| RecordSQL {
+            table: Table::Replay,
+            action: Action::Update,
+            source: e,
+        })?;
+        indexer.upsert_replay(conn, &replay).await?;
+        Ok(replay)
+    }
+
+    /// Fills in the fields read from the replay file itself
+    #[must_use]
+    pub fn with_file_info(self, info: &crate::replay::ReplayInfo) -> Self {
+        Self {
+            replay_frame_count: i64::try_from(info.frame_count).ok(),
+            replay_duration: info.duration(),
+            replay_checkpoint_count: i32::try_from(info.checkpoints.len()).ok(),
+            replay_checkpoints: serde_json::to_value(&info.checkpoints).ok(),
+            replay_identifier: Some(info.identifier.clone()),
+            replay_content_crc: info.content_crc.map(i64::from),
+            ..self
+        }
+    }
+
     pub async fn insert(
         conn: &mut PgConnection,
         model: Self,
@@ -787,7 +1314,7 @@ impl Replay {
     ) -> Result<Self, Insert> {
         let record = sqlx::query_as!(
             Replay,
-            r#"INSERT INTO replay VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING *"#,
+            r#"INSERT INTO replay VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16) RETURNING *"#,
             model.replay_id,
             model.replay_name,
             model.replay_description,
@@ -797,7 +1324,13 @@ impl Replay {
             model.file_id,
             model.created_on,
             model.hidden,
-            model.video_id
+            model.video_id,
+            model.replay_frame_count,
+            model.replay_duration,
+            model.replay_checkpoint_count,
+            model.replay_checkpoints,
+            model.replay_identifier,
+            model.replay_content_crc
         )
         .fetch_one(conn.as_mut())
         .await
@@ -960,6 +1493,20 @@ impl State {
             .await
     }
 
+    /// Checkpoint states extracted from a replay, in replay order
+    pub async fn get_all_for_replay(
+        conn: &mut PgConnection,
+        replay_id: Uuid,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            r#"SELECT * FROM state WHERE replay_id=$1 ORDER BY state_replay_index"#,
+            replay_id
+        )
+        .fetch_all(conn)
+        .await
+    }
+
     pub async fn insert(
         conn: &mut PgConnection,
         state: Self,
@@ -1017,6 +1564,29 @@ impl State {
         indexer.upsert_state(conn, &ret).await?;
         Ok(())
     }
+
+    pub async fn set_screenshot(
+        conn: &mut PgConnection,
+        id: Uuid,
+        screenshot_id: Uuid,
+        indexer: &impl crate::search::SearchIndexer,
+    ) -> Result<Self, Insert> {
+        let state = sqlx::query_as!(
+            Self,
+            "UPDATE state SET screenshot_id=$2 WHERE state_id=$1 RETURNING *",
+            id,
+            screenshot_id
+        )
+        .fetch_one(conn.as_mut())
+        .await
+        .map_err(|e| RecordSQL {
+            table: Table::State,
+            action: Action::Update,
+            source: e,
+        })?;
+        indexer.upsert_state(conn, &state).await?;
+        Ok(state)
+    }
 }
 
 impl State {
@@ -1079,6 +1649,12 @@ impl Work {
             .await
     }
 
+    pub async fn get_all(conn: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(Self, r#"SELECT * FROM work ORDER BY created_on"#)
+            .fetch_all(conn)
+            .await
+    }
+
     pub async fn get_works_for_platform(
         conn: &mut PgConnection,
         platform: &str,
@@ -1097,19 +1673,37 @@ impl Screenshot {
     pub async fn get_by_id(conn: &mut PgConnection, id: Uuid) -> sqlx::Result<Option<Self>> {
         sqlx::query_as!(
             Screenshot,
-            r#" SELECT * FROM screenshot WHERE screenshot_id = $1"#,
+            r#"SELECT screenshot_id, file_id, thumbnail_file_id, created_on, creator_id
+               FROM screenshot WHERE screenshot_id = $1"#,
             id
         )
         .fetch_optional(conn)
         .await
     }
 
+    pub async fn get_by_hash(conn: &mut PgConnection, hash: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            Screenshot,
+            r#"SELECT screenshot_id, screenshot.file_id, thumbnail_file_id,
+                      screenshot.created_on, screenshot.creator_id
+               FROM screenshot JOIN file USING (file_id)
+               WHERE file.file_hash = $1
+               ORDER BY screenshot.created_on LIMIT 1"#,
+            hash
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
     pub async fn insert(conn: &mut PgConnection, model: Self) -> Result<Self, Insert> {
         sqlx::query_as!(
             Screenshot,
-            r#"INSERT INTO screenshot VALUES ($1, $2, $3, $4) RETURNING *"#,
+            r#"INSERT INTO screenshot (screenshot_id, file_id, thumbnail_file_id, created_on, creator_id)
+               VALUES ($1, $2, $3, $4, $5)
+               RETURNING screenshot_id, file_id, thumbnail_file_id, created_on, creator_id"#,
             model.screenshot_id,
-            model.screenshot_data,
+            model.file_id,
+            model.thumbnail_file_id,
             model.created_on,
             model.creator_id
         )
@@ -1123,6 +1717,71 @@ impl Screenshot {
             })
         })
     }
+
+    /// Image data of a screenshot that hasn't been moved out to a file yet
+    pub async fn get_legacy_data(
+        conn: &mut PgConnection,
+        id: Uuid,
+    ) -> sqlx::Result<Option<Vec<u8>>> {
+        sqlx::query_scalar!(
+            r#"SELECT screenshot_data as "screenshot_data!" FROM screenshot
+               WHERE screenshot_id = $1 AND file_id IS NULL AND screenshot_data IS NOT NULL"#,
+            id
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
+    pub async fn get_legacy_batch(
+        conn: &mut PgConnection,
+        offset: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<(Self, Vec<u8>)>> {
+        let rows = sqlx::query!(
+            r#"SELECT screenshot_id, created_on, creator_id, screenshot_data as "screenshot_data!"
+               FROM screenshot
+               WHERE file_id IS NULL AND screenshot_data IS NOT NULL
+               ORDER BY created_on, screenshot_id OFFSET $1 LIMIT $2"#,
+            offset,
+            limit
+        )
+        .fetch_all(conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                (
+                    Self {
+                        screenshot_id: row.screenshot_id,
+                        file_id: None,
+                        thumbnail_file_id: None,
+                        created_on: row.created_on,
+                        creator_id: row.creator_id,
+                    },
+                    row.screenshot_data,
+                )
+            })
+            .collect())
+    }
+
+    /// Points a screenshot at its stored files, dropping the copy in the database
+    pub async fn set_files(
+        conn: &mut PgConnection,
+        id: Uuid,
+        file_id: Uuid,
+        thumbnail_file_id: Uuid,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"UPDATE screenshot SET file_id=$2, thumbnail_file_id=$3, screenshot_data=NULL
+               WHERE screenshot_id=$1"#,
+            id,
+            file_id,
+            thumbnail_file_id
+        )
+        .execute(conn)
+        .await
+        .map(|_qr| ())
+    }
 }
 
 #[must_use]
@@ -1154,8 +1813,9 @@ pub struct InstanceWork {
 }
 
 impl InstanceWork {
-    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
-        use futures::StreamExt;
+    pub fn fetch_stream(
+        conn: &mut sqlx::PgConnection,
+    ) -> impl futures::Stream<Item = sqlx::Result<Self>> {
         sqlx::query_as!(
             Self,
             r#"SELECT work_id, work_name, work_version, work_platform, work.created_on as work_created_on, work.creator_id as work_creator, work.work_derived_from as work_derived_from,
@@ -1164,7 +1824,10 @@ environment_name, environment_framework as "environment_framework:_", environmen
                FROM instance JOIN environment USING (environment_id) JOIN work USING (work_id)"#
         )
         .fetch(conn)
-        .filter_map(|f| futures::future::ready(f.ok()))
+    }
+    pub fn get_stream(conn: &mut sqlx::PgConnection) -> impl futures::Stream<Item = Self> {
+        use futures::StreamExt;
+        Self::fetch_stream(conn).filter_map(|f| futures::future::ready(f.ok()))
     }
     pub async fn get_for_instance(
         conn: &mut sqlx::PgConnection,
@@ -1367,6 +2030,12 @@ pub struct ReplayLink {
     pub created_on: Option<chrono::DateTime<chrono::Utc>>,
     pub hidden: bool,
     pub video_id: Option<Uuid>,
+    pub replay_frame_count: Option<i64>,
+    pub replay_duration: Option<f64>,
+    pub replay_checkpoint_count: Option<i32>,
+    pub replay_checkpoints: Option<serde_json::Value>,
+    pub replay_identifier: Option<String>,
+    pub replay_content_crc: Option<i64>,
     pub video_file_dest_path: Option<String>,
     pub file_id: Uuid,
     pub file_hash: String,
@@ -1544,6 +2213,8 @@ pub async fn insert_file_object(
                     object_description,
                     created_on,
                     creator_id,
+                    object_parent_id: None,
+                    object_parent_path: None,
                 };
                 Object::insert(conn, object).await?;
                 Some(object_id)
@@ -1575,6 +2246,8 @@ pub async fn insert_file_object(
             object_description,
             created_on,
             creator_id,
+            object_parent_id: None,
+            object_parent_path: None,
         };
         Object::insert(conn, object).await?;
         Ok(object_id)
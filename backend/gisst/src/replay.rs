@@ -0,0 +1,546 @@
+//! Parsers for the replay files players upload.
+//!
+//! v86 replays (`frontend/embedv86/src/replay_worker.ts`) start with the
+//! magic `VRPL`, the replay's UUID, a version byte and 15 reserved bytes,
+//! then event and checkpoint counts (little-endian u32s), the input events,
+//! and the checkpoints. Version 0 checkpoints hold whole save states, version 1
+//! checkpoints hold the state's info block plus deduplicated 256 byte blocks
+//! grouped into 256-entry superblocks.
+//!
+//! Retroarch replays (BSV movies) have a header of six little-endian u32s:
+//! magic `BSV2`, version, content CRC32, initial state size, and a 64-bit
+//! identifier, followed by the initial state. Each frame is then a count of
+//! key events (u8) and the 12 byte events, a count of input events (u16) and
+//! the 8 byte events, and a token: `f` for a regular frame, or `c` followed by
+//! a u64 size and a save state for a checkpoint.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::Replay as ReplayError;
+use crate::model_enums::Framework;
+use serde::Serialize;
+use std::ops::Range;
+use std::path::Path;
+use uuid::Uuid;
+
+pub const V86_REPLAY_MAGIC: u32 = 0x4C50_5256;
+pub const V86_REPLAY_VERSION: u8 = 1;
+pub const V86_BLOCK_SIZE: usize = 256;
+pub const V86_SUPERBLOCK_SIZE: usize = 256 * 4;
+/// The player's estimate of v86 instructions per second, used for durations
+pub const V86_INSTRUCTIONS_PER_SECOND: f64 = 100_003_000.0;
+
+pub const BSV_MAGIC: [u8; 4] = *b"BSV2";
+pub const BSV_VERSION: u32 = 1;
+const BSV_HEADER_LEN: usize = 6 * 4;
+const BSV_KEY_EVENT_LEN: usize = 12;
+const BSV_INPUT_EVENT_LEN: usize = 8;
+const BSV_TOKEN_FRAME: u8 = b'f';
+const BSV_TOKEN_CHECKPOINT: u8 = b'c';
+
+/// What a replay file says about itself
+#[derive(Debug)]
+pub struct ReplayInfo {
+    pub framework: Framework,
+    pub version: u32,
+    /// The replay's own ID: a UUID for v86, a 64-bit number for BSV
+    pub identifier: String,
+    /// CRC32 of the content the replay was recorded with (BSV only)
+    pub content_crc: Option<u32>,
+    /// Input events (v86) or emulated frames (BSV) in the replay
+    pub frame_count: u64,
+    /// Instruction counter (v86) or frame number (BSV) at the end of the replay
+    pub end_time: u64,
+    pub checkpoints: Vec<Checkpoint>,
+    /// Offsets of the deduplicated blocks of a v86 replay, in index order
+    blocks: Vec<usize>,
+    superblocks: Vec<usize>,
+}
+
+/// A point in the replay the player can seek to
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Input events (v86) or frames (BSV) played before this checkpoint
+    pub frame: u64,
+    /// Instruction counter (v86) or frame number (BSV) of this checkpoint
+    pub time: u64,
+    #[serde(skip)]
+    pub thumbnail: Option<Range<usize>>,
+    #[serde(skip)]
+    state: CheckpointState,
+}
+
+/// Where a checkpoint's save state lives in the replay file
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CheckpointState {
+    /// A complete save state
+    Whole(Range<usize>),
+    /// A v86 state's info block and the superblocks holding the rest of it
+    Blocks {
+        info: Range<usize>,
+        superblock_seq: Range<usize>,
+    },
+}
+
+impl ReplayInfo {
+    /// Replay length in seconds, if the format records time
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn duration(&self) -> Option<f64> {
+        match self.framework {
+            Framework::V86 => Some(self.end_time as f64 / V86_INSTRUCTIONS_PER_SECOND),
+            // Frame rates are up to the core
+            Framework::RetroArch => None,
+        }
+    }
+
+    /// Checks that the replay was recorded in the kind of environment it's being attached to
+    pub fn check_framework(&self, framework: Framework) -> Result<(), ReplayError> {
+        if self.framework == framework {
+            Ok(())
+        } else {
+            Err(ReplayError::WrongFramework {
+                expected: framework,
+                found: self.framework,
+            })
+        }
+    }
+
+    /// Rebuilds the save state of checkpoint `index` from the replay file it was parsed from
+    pub fn checkpoint_state(&self, data: &[u8], index: usize) -> Result<Vec<u8>, ReplayError> {
+        let checkpoint = self
+            .checkpoints
+            .get(index)
+            .ok_or(ReplayError::Malformed("no such checkpoint"))?;
+        let (info, superblock_seq) = match &checkpoint.state {
+            CheckpointState::Whole(range) => return Ok(data[range.clone()].to_vec()),
+            CheckpointState::Blocks {
+                info,
+                superblock_seq,
+            } => (&data[info.clone()], &data[superblock_seq.clone()]),
+        };
+        // Like the player's restore_checkpoint: the info block records the
+        // state's full size, and the blocks fill in everything after it
+        let full_size = info
+            .get(8..12)
+            .map(|size| usize::try_from(u32::from_le_bytes([size[0], size[1], size[2], size[3]])))
+            .ok_or(ReplayError::Malformed("state info too short"))??;
+        if full_size < info.len() {
+            return Err(ReplayError::Malformed("state smaller than its info"));
+        }
+        let superblock_bytes = (V86_SUPERBLOCK_SIZE / 4) * V86_BLOCK_SIZE;
+        let mut state = vec![0; full_size];
+        state[..info.len()].copy_from_slice(info);
+        for (i, superblock) in superblock_seq.chunks_exact(4).enumerate() {
+            let superblock =
+                u32::from_le_bytes([superblock[0], superblock[1], superblock[2], superblock[3]]);
+            let superblock = self.superblocks[usize::try_from(superblock)?];
+            for (j, block) in data[superblock..superblock + V86_SUPERBLOCK_SIZE]
+                .chunks_exact(4)
+                .enumerate()
+            {
+                let offset = info.len() + i * superblock_bytes + j * V86_BLOCK_SIZE;
+                if offset >= full_size {
+                    break;
+                }
+                let block = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+                let block = self.blocks[usize::try_from(block)?];
+                let len = V86_BLOCK_SIZE.min(full_size - offset);
+                state[offset..offset + len].copy_from_slice(&data[block..block + len]);
+            }
+        }
+        Ok(state)
+    }
+
+    /// The PNG thumbnail stored with checkpoint `index`, if it has one
+    #[must_use]
+    pub fn checkpoint_thumbnail<'a>(&self, data: &'a [u8], index: usize) -> Option<&'a [u8]> {
+        let range = self.checkpoints.get(index)?.thumbnail.clone()?;
+        Some(&data[range])
+    }
+
+    /// Makes sure every superblock a checkpoint uses, and every block those
+    /// superblocks use, is stored in the replay
+    fn check_block_references(&self, data: &[u8]) -> Result<(), ReplayError> {
+        for checkpoint in &self.checkpoints {
+            let CheckpointState::Blocks { superblock_seq, .. } = &checkpoint.state else {
+                continue;
+            };
+            for superblock in data[superblock_seq.clone()].chunks_exact(4) {
+                let superblock = u32::from_le_bytes([
+                    superblock[0],
+                    superblock[1],
+                    superblock[2],
+                    superblock[3],
+                ]);
+                let offset = *self
+                    .superblocks
+                    .get(usize::try_from(superblock)?)
+                    .ok_or(ReplayError::MissingBlock(superblock))?;
+                for block in data[offset..offset + V86_SUPERBLOCK_SIZE].chunks_exact(4) {
+                    let block = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+                    if usize::try_from(block)? >= self.blocks.len() {
+                        return Err(ReplayError::MissingBlock(block));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Little-endian reader over a replay file that reports running off the end
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+    fn skip(&mut self, len: usize, what: &'static str) -> Result<Range<usize>, ReplayError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(ReplayError::Truncated(what))?;
+        let range = self.pos..end;
+        self.pos = end;
+        Ok(range)
+    }
+    fn bytes<const N: usize>(&mut self, what: &'static str) -> Result<[u8; N], ReplayError> {
+        let range = self.skip(N, what)?;
+        let mut out = [0; N];
+        out.copy_from_slice(&self.data[range]);
+        Ok(out)
+    }
+    fn u8(&mut self, what: &'static str) -> Result<u8, ReplayError> {
+        Ok(self.bytes::<1>(what)?[0])
+    }
+    fn u16(&mut self, what: &'static str) -> Result<u16, ReplayError> {
+        Ok(u16::from_le_bytes(self.bytes(what)?))
+    }
+    fn u32(&mut self, what: &'static str) -> Result<u32, ReplayError> {
+        Ok(u32::from_le_bytes(self.bytes(what)?))
+    }
+    fn u64(&mut self, what: &'static str) -> Result<u64, ReplayError> {
+        Ok(u64::from_le_bytes(self.bytes(what)?))
+    }
+    fn len32(&mut self, what: &'static str) -> Result<usize, ReplayError> {
+        Ok(usize::try_from(self.u32(what)?)?)
+    }
+}
+
+/// Parses a replay file of either format, telling them apart by magic
+pub fn parse(data: &[u8]) -> Result<ReplayInfo, ReplayError> {
+    if data.starts_with(&V86_REPLAY_MAGIC.to_le_bytes()) {
+        parse_v86(data)
+    } else if data.starts_with(&BSV_MAGIC) {
+        parse_bsv(data)
+    } else {
+        Err(ReplayError::UnknownFormat)
+    }
+}
+
+pub fn parse_path(path: &Path) -> Result<ReplayInfo, ReplayError> {
+    parse(&std::fs::read(path)?)
+}
+
+fn parse_v86(data: &[u8]) -> Result<ReplayInfo, ReplayError> {
+    let mut r = Reader::new(data);
+    r.skip(4, "magic")?;
+    let id = Uuid::from_bytes(r.bytes("replay id")?);
+    let version = r.u8("version")?;
+    if version > V86_REPLAY_VERSION {
+        return Err(ReplayError::UnsupportedVersion(u32::from(version)));
+    }
+    r.skip(15, "reserved header")?;
+    let event_count = r.u32("event count")?;
+    let checkpoint_count = r.u32("checkpoint count")?;
+    let mut end_time = 0;
+    for _ in 0..event_count {
+        let code = r.u8("event code")?;
+        end_time = end_time.max(r.u64("event time")?);
+        let len = match code {
+            // key code
+            0 => 1,
+            // mouse click
+            1 => 3,
+            // mouse delta, mouse wheel
+            2 | 4 => 8 * 2,
+            // mouse absolute
+            3 => 8 * 4,
+            other => return Err(ReplayError::UnknownEvent(other)),
+        };
+        r.skip(len, "event value")?;
+    }
+    let mut checkpoints = Vec::with_capacity(usize::try_from(checkpoint_count)?.min(1024));
+    let (mut blocks, mut superblocks) = (vec![], vec![]);
+    for _ in 0..checkpoint_count {
+        let time = r.u64("checkpoint time")?;
+        let frame = u64::from(r.u32("checkpoint event index")?);
+        if frame > u64::from(event_count) {
+            return Err(ReplayError::Malformed("checkpoint after the last event"));
+        }
+        end_time = end_time.max(time);
+        let thumbnail_len = r.len32("thumbnail length")?;
+        let thumbnail = r.skip(thumbnail_len, "thumbnail")?;
+        let state = if version == 0 {
+            let state_len = r.len32("state length")?;
+            CheckpointState::Whole(r.skip(state_len, "state")?)
+        } else {
+            let info_len = r.len32("state info length")?;
+            let info = r.skip(info_len, "state info")?;
+            let new_blocks = r.len32("block count")?;
+            for _ in 0..new_blocks {
+                blocks.push(r.skip(V86_BLOCK_SIZE, "block")?.start);
+            }
+            let new_superblocks = r.len32("superblock count")?;
+            for _ in 0..new_superblocks {
+                superblocks.push(r.skip(V86_SUPERBLOCK_SIZE, "superblock")?.start);
+            }
+            let seq_len = r.len32("superblock sequence length")?;
+            let superblock_seq = r.skip(
+                seq_len
+                    .checked_mul(4)
+                    .ok_or(ReplayError::Truncated("superblock sequence"))?,
+                "superblock sequence",
+            )?;
+            CheckpointState::Blocks {
+                info,
+                superblock_seq,
+            }
+        };
+        checkpoints.push(Checkpoint {
+            frame,
+            time,
+            thumbnail: (!thumbnail.is_empty()).then_some(thumbnail),
+            state,
+        });
+    }
+    if !r.at_end() {
+        return Err(ReplayError::Malformed("data after the last checkpoint"));
+    }
+    let info = ReplayInfo {
+        framework: Framework::V86,
+        version: u32::from(version),
+        identifier: id.to_string(),
+        content_crc: None,
+        frame_count: u64::from(event_count),
+        end_time,
+        checkpoints,
+        blocks,
+        superblocks,
+    };
+    info.check_block_references(data)?;
+    Ok(info)
+}
+
+fn parse_bsv(data: &[u8]) -> Result<ReplayInfo, ReplayError> {
+    let mut r = Reader::new(data);
+    if data.len() < BSV_HEADER_LEN {
+        return Err(ReplayError::Truncated("header"));
+    }
+    r.skip(4, "magic")?;
+    let version = r.u32("version")?;
+    if version != BSV_VERSION {
+        return Err(ReplayError::UnsupportedVersion(version));
+    }
+    let content_crc = r.u32("content crc")?;
+    let state_len = r.len32("initial state size")?;
+    // Shown as a signed number by RetroArch and the player
+    let identifier = i64::from_le_bytes(r.bytes("identifier")?);
+    let mut checkpoints = vec![];
+    if state_len > 0 {
+        checkpoints.push(Checkpoint {
+            frame: 0,
+            time: 0,
+            thumbnail: None,
+            state: CheckpointState::Whole(r.skip(state_len, "initial state")?),
+        });
+    }
+    let mut frames = 0;
+    while !r.at_end() {
+        let key_events = usize::from(r.u8("key event count")?);
+        r.skip(key_events * BSV_KEY_EVENT_LEN, "key events")?;
+        let input_events = usize::from(r.u16("input event count")?);
+        r.skip(input_events * BSV_INPUT_EVENT_LEN, "input events")?;
+        frames += 1;
+        match r.u8("frame token")? {
+            BSV_TOKEN_FRAME => {}
+            BSV_TOKEN_CHECKPOINT => {
+                let len = usize::try_from(r.u64("checkpoint size")?)?;
+                checkpoints.push(Checkpoint {
+                    frame: frames,
+                    time: frames,
+                    thumbnail: None,
+                    state: CheckpointState::Whole(r.skip(len, "checkpoint")?),
+                });
+            }
+            other => return Err(ReplayError::UnknownToken(other)),
+        }
+    }
+    Ok(ReplayInfo {
+        framework: Framework::RetroArch,
+        version,
+        identifier: identifier.to_string(),
+        content_crc: Some(content_crc),
+        frame_count: frames,
+        end_time: frames,
+        checkpoints,
+        blocks: vec![],
+        superblocks: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v86_replay(version: u8, events: &[(u8, u64, &[u8])], checkpoints: &[(u64, u32)]) -> Vec<u8> {
+        let mut out = V86_REPLAY_MAGIC.to_le_bytes().to_vec();
+        out.extend([7; 16]);
+        out.push(version);
+        out.extend([0; 15]);
+        out.extend(u32::try_from(events.len()).unwrap().to_le_bytes());
+        out.extend(u32::try_from(checkpoints.len()).unwrap().to_le_bytes());
+        for (code, when, value) in events {
+            out.push(*code);
+            out.extend(when.to_le_bytes());
+            out.extend(*value);
+        }
+        for (i, (when, event_index)) in checkpoints.iter().enumerate() {
+            out.extend(when.to_le_bytes());
+            out.extend(event_index.to_le_bytes());
+            out.extend(4u32.to_le_bytes());
+            out.extend(b"\x89PNG");
+            if version == 0 {
+                out.extend(3u32.to_le_bytes());
+                out.extend([1, 2, 3]);
+            } else {
+                out.extend(20u32.to_le_bytes());
+                out.extend([0; 20]);
+                // One new block and superblock for the first checkpoint only
+                let new = u32::from(i == 0);
+                out.extend(new.to_le_bytes());
+                out.extend(vec![0; V86_BLOCK_SIZE * new as usize]);
+                out.extend(new.to_le_bytes());
+                out.extend(vec![0; V86_SUPERBLOCK_SIZE * new as usize]);
+                out.extend(1u32.to_le_bytes());
+                out.extend(0u32.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn v86() {
+        let data = v86_replay(
+            1,
+            &[(0, 10, &[30]), (3, 500, &[0; 32]), (1, 900, &[1, 0, 0])],
+            &[(0, 0), (600, 2)],
+        );
+        let info = parse(&data).unwrap();
+        assert_eq!(info.framework, Framework::V86);
+        assert_eq!(info.identifier, Uuid::from_bytes([7; 16]).to_string());
+        assert_eq!(info.frame_count, 3);
+        assert_eq!(info.end_time, 900);
+        assert_eq!(
+            info.checkpoints
+                .iter()
+                .map(|c| (c.frame, c.time))
+                .collect::<Vec<_>>(),
+            vec![(0, 0), (2, 600)]
+        );
+        assert!(info.checkpoints[0].thumbnail.is_some());
+        assert!(parse(&v86_replay(0, &[], &[(0, 0)])).is_ok());
+    }
+
+    #[test]
+    fn v86_rejects_bad_files() {
+        let data = v86_replay(1, &[(9, 10, &[])], &[]);
+        assert!(matches!(parse(&data), Err(ReplayError::UnknownEvent(9))));
+        let data = v86_replay(2, &[], &[]);
+        assert!(matches!(
+            parse(&data),
+            Err(ReplayError::UnsupportedVersion(2))
+        ));
+        let data = v86_replay(1, &[(0, 10, &[30])], &[(0, 0)]);
+        assert!(matches!(
+            parse(&data[..data.len() - 1]),
+            Err(ReplayError::Truncated(_))
+        ));
+        // The second checkpoint refers to a superblock that was never stored
+        let mut data = v86_replay(1, &[], &[(0, 0), (5, 0)]);
+        let len = data.len();
+        data[len - 4..].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(parse(&data), Err(ReplayError::MissingBlock(1))));
+        assert!(matches!(
+            parse(b"not a replay"),
+            Err(ReplayError::UnknownFormat)
+        ));
+    }
+
+    #[test]
+    fn v86_checkpoint_state() {
+        let mut data = v86_replay(1, &[], &[(0, 0)]);
+        let info = parse(&data).unwrap();
+        let CheckpointState::Blocks { info: header, .. } = info.checkpoints[0].state.clone() else {
+            panic!("version 1 checkpoints are stored as blocks");
+        };
+        // 20 bytes of info followed by one and a bit blocks of 0xAB
+        data[header.start + 8..header.start + 12].copy_from_slice(&300u32.to_le_bytes());
+        data[info.blocks[0]..info.blocks[0] + V86_BLOCK_SIZE].fill(0xAB);
+        let state = info.checkpoint_state(&data, 0).unwrap();
+        assert_eq!(state.len(), 300);
+        assert_eq!(state[8..12], 300u32.to_le_bytes());
+        assert!(state[20..].iter().all(|b| *b == 0xAB));
+        assert_eq!(info.checkpoint_thumbnail(&data, 0), Some(&b"\x89PNG"[..]));
+
+        let data = v86_replay(0, &[], &[(0, 0)]);
+        let info = parse(&data).unwrap();
+        assert_eq!(info.checkpoint_state(&data, 0).unwrap(), vec![1, 2, 3]);
+        assert!(info.checkpoint_state(&data, 1).is_err());
+    }
+
+    fn bsv_frame(out: &mut Vec<u8>, inputs: u16, checkpoint: Option<&[u8]>) {
+        out.push(0);
+        out.extend(inputs.to_le_bytes());
+        out.extend(vec![0; usize::from(inputs) * BSV_INPUT_EVENT_LEN]);
+        if let Some(state) = checkpoint {
+            out.push(BSV_TOKEN_CHECKPOINT);
+            out.extend((state.len() as u64).to_le_bytes());
+            out.extend(state);
+        } else {
+            out.push(BSV_TOKEN_FRAME);
+        }
+    }
+
+    #[test]
+    fn bsv() {
+        let mut data = BSV_MAGIC.to_vec();
+        data.extend(1u32.to_le_bytes());
+        data.extend(0xDEAD_BEEFu32.to_le_bytes());
+        data.extend(4u32.to_le_bytes());
+        data.extend((-5i64).to_le_bytes());
+        data.extend([1, 2, 3, 4]);
+        bsv_frame(&mut data, 2, None);
+        bsv_frame(&mut data, 0, Some(&[9; 10]));
+        bsv_frame(&mut data, 1, None);
+        let info = parse(&data).unwrap();
+        assert_eq!(info.framework, Framework::RetroArch);
+        assert_eq!(info.identifier, "-5");
+        assert_eq!(info.content_crc, Some(0xDEAD_BEEF));
+        assert_eq!(info.frame_count, 3);
+        assert_eq!(
+            info.checkpoints.iter().map(|c| c.frame).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(info.duration(), None);
+        data.push(BSV_TOKEN_FRAME);
+        assert!(matches!(parse(&data), Err(ReplayError::Truncated(_))));
+    }
+}
@@ -0,0 +1,196 @@
+//! Turns the checkpoints embedded in a replay file into `State` records, so
+//! they can be found in search and booted like any other state.
+use crate::error::ReplayStates;
+use crate::model_enums::Framework;
+use crate::models::{File, Replay, State, Video};
+use crate::replay::ReplayInfo;
+use crate::search::NullIndexer;
+use crate::storage::StorageHandler;
+use log::{error, info};
+use serde::Serialize;
+use sqlx::{Connection, PgConnection};
+use std::path::Path;
+use uuid::Uuid;
+
+/// Progress reports emitted while checkpoints are extracted, e.g. for task status updates
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum ExtractProgress {
+    ParsingReplay,
+    StoringCheckpoint { index: usize, total: usize },
+    Indexing,
+}
+
+/// Creates a state for each checkpoint of the replay that doesn't have one
+/// yet, returning the IDs of the new states
+#[allow(clippy::missing_errors_doc)]
+#[tracing::instrument(skip(conn, indexer, on_progress))]
+pub async fn extract_replay_states(
+    conn: &mut PgConnection,
+    replay_id: Uuid,
+    storage_root: &str,
+    depth: u8,
+    indexer: &impl crate::search::SearchIndexer,
+    mut on_progress: impl FnMut(ExtractProgress) + Send,
+) -> Result<Vec<Uuid>, ReplayStates> {
+    let replay = Replay::get_by_id(conn, replay_id)
+        .await?
+        .ok_or(ReplayStates::ReplayNotFound(replay_id))?;
+    let file = File::get_by_id(conn, replay.file_id)
+        .await?
+        .ok_or(ReplayStates::FileNotFound(replay.file_id))?;
+    on_progress(ExtractProgress::ParsingReplay);
+    let path = Path::new(storage_root).join(&file.file_dest_path);
+    let (data, info) = tokio::task::spawn_blocking(move || {
+        let data = std::fs::read(path)?;
+        let info = crate::replay::parse(&data)?;
+        Ok::<_, ReplayStates>((data, info))
+    })
+    .await??;
+    let existing: Vec<_> = State::get_all_for_replay(conn, replay_id)
+        .await?
+        .into_iter()
+        .filter_map(|state| state.state_replay_index)
+        .collect();
+    let total = info.checkpoints.len();
+    let mut states = vec![];
+    for index in 0..total {
+        if i32::try_from(index).is_ok_and(|index| existing.contains(&index)) {
+            continue;
+        }
+        on_progress(ExtractProgress::StoringCheckpoint { index, total });
+        // Each checkpoint gets its own transaction so a failure keeps the ones before it
+        let mut written = None;
+        let result = store_checkpoint(
+            conn,
+            &replay,
+            &info,
+            &data,
+            index,
+            storage_root,
+            depth,
+            &mut written,
+        )
+        .await;
+        match result {
+            Ok(state) => states.push(state),
+            Err(e) => {
+                if let Some((file_uuid, dest_filename)) = written
+                    && let Err(e) = StorageHandler::delete_file_with_uuid(
+                        storage_root,
+                        depth,
+                        file_uuid,
+                        &dest_filename,
+                    )
+                    .await
+                {
+                    error!("Could not remove checkpoint file {file_uuid}: {e:?}");
+                }
+                return Err(e);
+            }
+        }
+    }
+    if !states.is_empty() {
+        Video::set_replay_poster(conn, replay_id).await?;
+    }
+    on_progress(ExtractProgress::Indexing);
+    for state in &states {
+        indexer.upsert_state(conn, state).await?;
+    }
+    info!(
+        "Extracted {} of {total} checkpoints from replay {replay_id}",
+        states.len()
+    );
+    Ok(states.into_iter().map(|state| state.state_id).collect())
+}
+
+/// Stores checkpoint `index`'s state file and screenshot and creates its state
+#[allow(clippy::too_many_arguments)]
+async fn store_checkpoint(
+    conn: &mut PgConnection,
+    replay: &Replay,
+    info: &ReplayInfo,
+    data: &[u8],
+    index: usize,
+    storage_root: &str,
+    depth: u8,
+    written: &mut Option<(Uuid, String)>,
+) -> Result<State, ReplayStates> {
+    let state_data = info.checkpoint_state(data, index)?;
+    let replay_index = i32::try_from(index).map_err(|_| ReplayStates::IndexOutOfRange(index))?;
+    let extension = match info.framework {
+        Framework::V86 => "v86state",
+        Framework::RetroArch => "state",
+    };
+    let file_name = format!("checkpoint{index}.{extension}");
+    let temp_path = std::env::temp_dir().join(format!("{}-{file_name}", Uuid::new_v4()));
+    tokio::fs::write(&temp_path, &state_data).await?;
+    let file_id = Uuid::new_v4();
+    let file_info = StorageHandler::write_file_to_uuid_folder(
+        storage_root,
+        depth,
+        file_id,
+        &file_name,
+        &temp_path,
+    )
+    .await;
+    tokio::fs::remove_file(&temp_path).await?;
+    let file_info = file_info?;
+    *written = Some((file_id, file_info.dest_filename.clone()));
+
+    let mut tx = conn.begin().await?;
+    File::insert(
+        &mut tx,
+        File {
+            file_id,
+            file_hash: file_info.file_hash,
+            file_filename: file_info.source_filename,
+            file_source_path: String::new(),
+            file_dest_path: file_info.dest_path,
+            file_size: file_info.file_size,
+            file_compressed_size: file_info.file_compressed_size,
+            created_on: chrono::Utc::now(),
+            creator_id: Some(replay.creator_id),
+        },
+    )
+    .await?;
+    let thumbnail = info
+        .checkpoint_thumbnail(data, index)
+        .filter(|png| crate::screenshot::png_dimensions(png).is_ok());
+    let screenshot = match thumbnail {
+        Some(png) => png.to_vec(),
+        None => crate::screenshot::placeholder_png()?,
+    };
+    let screenshot = crate::screenshot::store_screenshot(
+        &mut tx,
+        storage_root,
+        depth,
+        &screenshot,
+        Uuid::new_v4(),
+        Some(replay.creator_id),
+    )
+    .await?;
+    let state = State::insert(
+        &mut tx,
+        State {
+            state_id: Uuid::new_v4(),
+            instance_id: replay.instance_id,
+            is_checkpoint: true,
+            file_id,
+            state_name: format!("{} checkpoint {index}", replay.replay_name),
+            state_description: format!("Checkpoint {index} of replay {}", replay.replay_name),
+            screenshot_id: screenshot.screenshot_id,
+            replay_id: Some(replay.replay_id),
+            creator_id: replay.creator_id,
+            state_replay_index: Some(replay_index),
+            state_derived_from: None,
+            save_derived_from: None,
+            created_on: chrono::Utc::now(),
+            hidden: replay.hidden,
+        },
+        &NullIndexer,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(state)
+}
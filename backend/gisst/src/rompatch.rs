@@ -0,0 +1,625 @@
+//! Applies IPS, UPS, and BPS patches, the formats romhacks and translations
+//! are usually distributed in, and derives instances running the patched
+//! content. The patch itself is kept as a `patch` object next to it.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::diskedit::DerivedWork;
+use crate::error::{PatchInstance, RomPatch, Table};
+use crate::models::{
+    Duplicate, Instance, Object, ObjectLink, ObjectRole, Work, insert_file_object,
+};
+use crate::search::{NullIndexer, SearchIndexer};
+use sqlx::Connection;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl PatchFormat {
+    #[must_use]
+    pub fn detect(patch: &[u8]) -> Option<Self> {
+        if patch.starts_with(b"PATCH") {
+            Some(Self::Ips)
+        } else if patch.starts_with(b"UPS1") {
+            Some(Self::Ups)
+        } else if patch.starts_with(b"BPS1") {
+            Some(Self::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+/// Patches a copy of `source`. UPS and BPS patches are checked against the
+/// source and output checksums they carry; IPS patches have none.
+pub fn apply_patch(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomPatch> {
+    match PatchFormat::detect(patch).ok_or(RomPatch::UnknownFormat)? {
+        PatchFormat::Ips => apply_ips(source, patch),
+        PatchFormat::Ups => apply_ups(source, patch),
+        PatchFormat::Bps => apply_bps(source, patch),
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                0xEDB8_8320 ^ (crc >> 1)
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The zlib CRC-32 that UPS and BPS footers use
+#[must_use]
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc: u32, byte| {
+        CRC_TABLE[usize::from(crc.to_le_bytes()[0] ^ byte)] ^ (crc >> 8)
+    })
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+    fn bytes(&mut self, len: usize, what: &'static str) -> Result<&'a [u8], RomPatch> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or(RomPatch::Truncated(what))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn byte(&mut self, what: &'static str) -> Result<u8, RomPatch> {
+        Ok(self.bytes(1, what)?[0])
+    }
+    fn big_endian(&mut self, len: usize, what: &'static str) -> Result<usize, RomPatch> {
+        Ok(self
+            .bytes(len, what)?
+            .iter()
+            .fold(0, |n, byte| (n << 8) | usize::from(*byte)))
+    }
+    /// The variable length numbers of UPS and BPS, where each byte adds 7
+    /// bits and the high bit marks the last byte
+    fn number(&mut self, what: &'static str) -> Result<usize, RomPatch> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.byte(what)?;
+            value = usize::from(byte & 0x7f)
+                .checked_mul(shift)
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or(RomPatch::Malformed(what))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or(RomPatch::Malformed(what))?;
+            value = value.checked_add(shift).ok_or(RomPatch::Malformed(what))?;
+        }
+    }
+}
+
+fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomPatch> {
+    let mut out = source.to_vec();
+    let mut reader = PatchReader::new(patch, 5);
+    loop {
+        if reader.data.get(reader.pos..reader.pos + 3) == Some(b"EOF") {
+            reader.pos += 3;
+            break;
+        }
+        let offset = reader.big_endian(3, "record offset")?;
+        let size = reader.big_endian(2, "record size")?;
+        // Zero-sized records are runs of a single byte
+        let (len, fill) = if size == 0 {
+            let len = reader.big_endian(2, "run length")?;
+            (len, Some(reader.byte("run value")?))
+        } else {
+            (size, None)
+        };
+        let end = offset + len;
+        if out.len() < end {
+            out.resize(end, 0);
+        }
+        match fill {
+            Some(value) => out[offset..end].fill(value),
+            None => out[offset..end].copy_from_slice(reader.bytes(len, "record data")?),
+        }
+    }
+    // Some patchers append the size to cut the output down to
+    if let Ok(len) = reader.big_endian(3, "truncation") {
+        out.truncate(len);
+    }
+    Ok(out)
+}
+
+/// Checksums at the end of UPS and BPS patches
+struct Footer {
+    source: u32,
+    target: u32,
+}
+
+/// Checks the patch's own checksum and gives back where its body ends
+fn read_footer(patch: &[u8]) -> Result<(usize, Footer), RomPatch> {
+    let body_end = patch
+        .len()
+        .checked_sub(12)
+        .filter(|end| *end >= 4)
+        .ok_or(RomPatch::Truncated("footer"))?;
+    let crc =
+        |at: usize| u32::from_le_bytes([patch[at], patch[at + 1], patch[at + 2], patch[at + 3]]);
+    let expected = crc(body_end + 8);
+    let found = crc32(&patch[..body_end + 8]);
+    if expected != found {
+        return Err(RomPatch::PatchChecksum { expected, found });
+    }
+    Ok((
+        body_end,
+        Footer {
+            source: crc(body_end),
+            target: crc(body_end + 4),
+        },
+    ))
+}
+
+fn check_source(source: &[u8], size: usize, footer: &Footer) -> Result<(), RomPatch> {
+    if source.len() != size {
+        return Err(RomPatch::SourceSize {
+            expected: size,
+            found: source.len(),
+        });
+    }
+    let found = crc32(source);
+    if found != footer.source {
+        return Err(RomPatch::SourceChecksum {
+            expected: footer.source,
+            found,
+        });
+    }
+    Ok(())
+}
+
+fn check_target(target: Vec<u8>, footer: &Footer) -> Result<Vec<u8>, RomPatch> {
+    let found = crc32(&target);
+    if found != footer.target {
+        return Err(RomPatch::TargetChecksum {
+            expected: footer.target,
+            found,
+        });
+    }
+    Ok(target)
+}
+
+/// Patched output larger than this is surely a corrupt or hostile patch
+const MAX_TARGET_SIZE: usize = 1 << 30;
+
+/// Rejects target sizes too large to allocate up front
+fn check_target_size(target_size: usize) -> Result<usize, RomPatch> {
+    if target_size > MAX_TARGET_SIZE {
+        return Err(RomPatch::TooLarge(target_size));
+    }
+    Ok(target_size)
+}
+
+fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomPatch> {
+    let (body_end, footer) = read_footer(patch)?;
+    let mut reader = PatchReader::new(&patch[..body_end], 4);
+    let source_size = reader.number("source size")?;
+    let target_size = check_target_size(reader.number("target size")?)?;
+    check_source(source, source_size, &footer)?;
+    let mut out = vec![0; target_size];
+    let kept = source.len().min(target_size);
+    out[..kept].copy_from_slice(&source[..kept]);
+    let mut pos: usize = 0;
+    while !reader.is_empty() {
+        pos = pos
+            .checked_add(reader.number("skip")?)
+            .ok_or(RomPatch::Malformed("skip"))?;
+        // XORed bytes run up to a zero, which leaves its own byte alone
+        loop {
+            let byte = reader.byte("xor run")?;
+            pos += 1;
+            if byte == 0 {
+                break;
+            }
+            *out.get_mut(pos - 1)
+                .ok_or(RomPatch::OutOfBounds("target"))? ^= byte;
+        }
+    }
+    check_target(out, &footer)
+}
+
+/// Moves a BPS copy offset, whose low bit gives the direction
+fn relative_offset(offset: usize, delta: usize, what: &'static str) -> Result<usize, RomPatch> {
+    if delta & 1 == 0 {
+        offset.checked_add(delta >> 1)
+    } else {
+        offset.checked_sub(delta >> 1)
+    }
+    .ok_or(RomPatch::OutOfBounds(what))
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomPatch> {
+    let (body_end, footer) = read_footer(patch)?;
+    let mut reader = PatchReader::new(&patch[..body_end], 4);
+    let source_size = reader.number("source size")?;
+    let target_size = check_target_size(reader.number("target size")?)?;
+    let metadata_size = reader.number("metadata size")?;
+    reader.bytes(metadata_size, "metadata")?;
+    check_source(source, source_size, &footer)?;
+    let source_range = |start: usize, len: usize| {
+        start
+            .checked_add(len)
+            .and_then(|end| source.get(start..end))
+            .ok_or(RomPatch::OutOfBounds("source"))
+    };
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while !reader.is_empty() {
+        let action = reader.number("action")?;
+        let len = (action >> 2) + 1;
+        // Every action writes `len` bytes, so none may run past the target
+        if len > target_size - out.len() {
+            return Err(RomPatch::OutOfBounds("target"));
+        }
+        match action & 3 {
+            // SourceRead
+            0 => out.extend_from_slice(source_range(out.len(), len)?),
+            // TargetRead
+            1 => out.extend_from_slice(reader.bytes(len, "target data")?),
+            // SourceCopy
+            2 => {
+                source_offset =
+                    relative_offset(source_offset, reader.number("source offset")?, "source")?;
+                out.extend_from_slice(source_range(source_offset, len)?);
+                source_offset += len;
+            }
+            // TargetCopy, which may overlap the bytes it writes
+            _ => {
+                target_offset =
+                    relative_offset(target_offset, reader.number("target offset")?, "target")?;
+                for _ in 0..len {
+                    let byte = *out
+                        .get(target_offset)
+                        .ok_or(RomPatch::OutOfBounds("target"))?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size {
+        return Err(RomPatch::Truncated("target"));
+    }
+    check_target(out, &footer)
+}
+
+/// A patch file to apply to the content at `role_index`
+#[derive(Clone, Debug)]
+pub struct PatchFile {
+    pub role_index: u16,
+    pub path: PathBuf,
+    pub file_name: String,
+}
+
+/// The objects stored for one applied patch
+#[derive(Clone, Copy, Debug)]
+pub struct PatchedObjects {
+    pub content_id: Uuid,
+    pub patch_id: Uuid,
+}
+
+/// Applies `patch` to the content `base` links to, storing the output and
+/// the patch as new objects
+#[tracing::instrument(skip(conn))]
+pub async fn store_patch(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    base: &ObjectLink,
+    patch: &PatchFile,
+    creator_id: Option<Uuid>,
+) -> Result<PatchedObjects, PatchInstance> {
+    let base_path = PathBuf::from(format!("{storage_root}/{}", base.file_dest_path));
+    let temp_path = std::env::temp_dir().join(format!("{}-{}", Uuid::new_v4(), base.file_filename));
+    let result = {
+        let patch_path = patch.path.clone();
+        let temp_path = temp_path.clone();
+        tokio::task::spawn_blocking(move || -> Result<(), PatchInstance> {
+            let patched = apply_patch(&std::fs::read(&base_path)?, &std::fs::read(&patch_path)?)?;
+            Ok(std::fs::write(&temp_path, patched)?)
+        })
+        .await?
+    };
+    let content_id = match result {
+        Ok(()) => {
+            insert_file_object(
+                conn,
+                storage_root,
+                depth,
+                &temp_path,
+                Some(base.file_filename.clone()),
+                Some(format!(
+                    "{} patched with {}",
+                    base.file_filename, patch.file_name
+                )),
+                base.file_source_path.clone(),
+                Duplicate::ReuseData,
+                creator_id,
+            )
+            .await
+        }
+        Err(e) => {
+            std::fs::remove_file(&temp_path).ok();
+            return Err(e);
+        }
+    };
+    tokio::fs::remove_file(&temp_path).await?;
+    let patch_id = insert_file_object(
+        conn,
+        storage_root,
+        depth,
+        &patch.path,
+        Some(patch.file_name.clone()),
+        Some(format!("Patch for {}", base.file_filename)),
+        patch.file_name.clone(),
+        Duplicate::ReuseData,
+        creator_id,
+    )
+    .await?;
+    Ok(PatchedObjects {
+        content_id: content_id?,
+        patch_id,
+    })
+}
+
+/// Applies `patches` to the content of instance `instance_id`, returning a
+/// new instance of a new work derived from the originals. Each patched
+/// content object is swapped for its patched copy, with the patch linked at
+/// the same index in the `patch` role.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(conn, indexer))]
+pub async fn derive_patched_instance(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    instance_id: Uuid,
+    patches: Vec<PatchFile>,
+    derived: DerivedWork,
+    indexer: &impl SearchIndexer,
+    creator_id: Option<Uuid>,
+) -> Result<Instance, PatchInstance> {
+    let inst = Instance::get_by_id(conn, instance_id)
+        .await?
+        .ok_or(PatchInstance::Missing(Table::Instance, instance_id))?;
+    let work = Work::get_by_id(conn, inst.work_id)
+        .await?
+        .ok_or(PatchInstance::Missing(Table::Work, inst.work_id))?;
+    let links = ObjectLink::get_all_for_instance_id(conn, instance_id).await?;
+    let content_at = |role_index: u16| {
+        links.iter().find(|link| {
+            link.object_role == ObjectRole::Content
+                && link.object_role_index == i32::from(role_index)
+        })
+    };
+
+    let mut tx = conn.begin().await?;
+    let mut patched = Vec::with_capacity(patches.len());
+    for patch in &patches {
+        let base =
+            content_at(patch.role_index).ok_or(PatchInstance::NoContent(patch.role_index))?;
+        let objects = store_patch(&mut tx, storage_root, depth, base, patch, creator_id).await?;
+        patched.push((patch.role_index, objects));
+    }
+    let (new_work, new_inst) = derived.records(work, inst, "patched", creator_id);
+    Work::insert(&mut tx, new_work).await?;
+    let new_inst = Instance::insert(&mut tx, new_inst, &NullIndexer).await?;
+    for link in &links {
+        let role_index = u16::try_from(link.object_role_index)?;
+        // Patches the replaced content was made with no longer apply to it
+        if matches!(link.object_role, ObjectRole::Content | ObjectRole::Patch)
+            && patched.iter().any(|(index, _)| *index == role_index)
+        {
+            continue;
+        }
+        Object::link_object_to_instance(
+            &mut tx,
+            link.object_id,
+            new_inst.instance_id,
+            link.object_role,
+            role_index,
+        )
+        .await?;
+    }
+    for (role_index, objects) in patched {
+        Object::link_object_to_instance(
+            &mut tx,
+            objects.content_id,
+            new_inst.instance_id,
+            ObjectRole::Content,
+            role_index,
+        )
+        .await?;
+        Object::link_object_to_instance(
+            &mut tx,
+            objects.patch_id,
+            new_inst.instance_id,
+            ObjectRole::Patch,
+            role_index,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    // Only index once the whole instance has committed
+    indexer.upsert_instance(conn, &new_inst).await?;
+    Ok(new_inst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut out = vec![];
+        loop {
+            let bits = u8::try_from(value & 0x7f).unwrap();
+            value >>= 7;
+            if value == 0 {
+                out.push(bits | 0x80);
+                return out;
+            }
+            out.push(bits);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        for value in [0, 1, 127, 128, 16_511, 16_512, 1 << 30] {
+            let encoded = number(value);
+            let mut reader = PatchReader::new(&encoded, 0);
+            assert_eq!(reader.number("test").unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn applies_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, b'X', b'Y']);
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 3, b'Z']);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply_patch(b"abcd", &patch).unwrap(), b"aXYd\0\0ZZZ");
+        patch.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(apply_patch(b"abcd", &patch).unwrap(), b"aXY");
+        assert!(matches!(
+            apply_patch(b"abcd", b"PATCH\0\0"),
+            Err(RomPatch::Truncated(_))
+        ));
+    }
+
+    #[test]
+    fn applies_ups() {
+        let source = b"hello world";
+        let target = b"hello there!";
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(6));
+        for (a, b) in b"world\0".iter().zip(b"there!") {
+            patch.push(a ^ b);
+        }
+        patch.push(0);
+        let patch = with_footer(patch, source, target);
+        assert_eq!(apply_patch(source, &patch).unwrap(), target);
+        assert!(matches!(
+            apply_patch(b"hello wurld", &patch),
+            Err(RomPatch::SourceChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn applies_bps() {
+        let source = b"abcdefgh";
+        let target = b"abcdXYXYXYefgh";
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(3));
+        patch.extend_from_slice(b"hi!");
+        // SourceRead 4, TargetRead 2, TargetCopy 4 from 4, SourceCopy 4 from 4
+        patch.extend(number(3 << 2));
+        patch.extend(number((1 << 2) | 1));
+        patch.extend_from_slice(b"XY");
+        patch.extend(number((3 << 2) | 3));
+        patch.extend(number(4 << 1));
+        patch.extend(number((3 << 2) | 2));
+        patch.extend(number(4 << 1));
+        let patch = with_footer(patch, source, target);
+        assert_eq!(apply_patch(source, &patch).unwrap(), target);
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 1;
+        assert!(matches!(
+            apply_patch(source, &corrupt),
+            Err(RomPatch::PatchChecksum { .. })
+        ));
+        assert!(matches!(
+            apply_patch(b"abcdefg", &patch),
+            Err(RomPatch::SourceSize { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_targets() {
+        for header in [b"UPS1", b"BPS1"] {
+            let mut patch = header.to_vec();
+            patch.extend(number(4));
+            patch.extend(number(MAX_TARGET_SIZE + 1));
+            patch.extend(number(0));
+            let patch = with_footer(patch, b"abcd", b"");
+            assert!(matches!(
+                apply_patch(b"abcd", &patch),
+                Err(RomPatch::TooLarge(size)) if size == MAX_TARGET_SIZE + 1
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_bps_overruns() {
+        let source = b"abcd";
+        // TargetRead of 8 bytes into a 6 byte target
+        let mut read = b"BPS1".to_vec();
+        read.extend(number(source.len()));
+        read.extend(number(6));
+        read.extend(number(0));
+        read.extend(number((7 << 2) | 1));
+        read.extend_from_slice(b"12345678");
+        // SourceRead 4, then a TargetCopy far longer than the target
+        let mut copy = b"BPS1".to_vec();
+        copy.extend(number(source.len()));
+        copy.extend(number(6));
+        copy.extend(number(0));
+        copy.extend(number(3 << 2));
+        copy.extend(number((1_000_000 << 2) | 3));
+        copy.extend(number(0));
+        for patch in [read, copy] {
+            let patch = with_footer(patch, source, b"abcdab");
+            assert!(matches!(
+                apply_patch(source, &patch),
+                Err(RomPatch::OutOfBounds("target"))
+            ));
+        }
+    }
+}
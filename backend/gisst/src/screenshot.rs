@@ -0,0 +1,366 @@
+//! Screenshots are PNG files kept in storage like any other `File`, deduped
+//! by hash, each with a small fixed-size thumbnail for listings.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::Screenshot as ScreenshotError;
+use crate::models::{File, Screenshot, insert_new_file};
+use crate::storage::StorageHandler;
+use std::path::Path;
+use uuid::Uuid;
+
+pub const THUMBNAIL_WIDTH: u32 = 160;
+pub const THUMBNAIL_HEIGHT: u32 = 120;
+
+/// Screenshots bigger than this are certainly not emulator framebuffers
+const MAX_DIMENSION: u32 = 8192;
+
+/// Checks that `data` is a PNG image of sensible size, returning its dimensions
+pub fn png_dimensions(data: &[u8]) -> Result<(u32, u32), ScreenshotError> {
+    let decoder = png::Decoder::new(std::io::Cursor::new(data));
+    let reader = decoder.read_info()?;
+    let info = reader.info();
+    if info.width == 0
+        || info.height == 0
+        || info.width > MAX_DIMENSION
+        || info.height > MAX_DIMENSION
+    {
+        return Err(ScreenshotError::Dimensions(info.width, info.height));
+    }
+    Ok((info.width, info.height))
+}
+
+/// Scales a PNG image to fit in a `THUMBNAIL_WIDTH`x`THUMBNAIL_HEIGHT` PNG,
+/// centered on a transparent background
+pub fn make_thumbnail(data: &[u8]) -> Result<Vec<u8>, ScreenshotError> {
+    png_dimensions(data)?;
+    let mut decoder = png::Decoder::new(std::io::Cursor::new(data));
+    decoder.set_transformations(
+        png::Transformations::EXPAND | png::Transformations::STRIP_16 | png::Transformations::ALPHA,
+    );
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size().ok_or(ScreenshotError::TooBig)?];
+    let frame = reader.next_frame(&mut pixels)?;
+    let rgba = match frame.color_type {
+        png::ColorType::Rgba => pixels,
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks_exact(2)
+            .flat_map(|ga| [ga[0], ga[0], ga[0], ga[1]])
+            .collect(),
+        other => return Err(ScreenshotError::ColorType(other)),
+    };
+    let thumbnail = scale_rgba(&rgba, frame.width, frame.height);
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&thumbnail)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+/// A plain thumbnail-sized image for states that come without a screenshot,
+/// e.g. Retroarch replay checkpoints. It is the same every time, so storing it
+/// always yields the same screenshot.
+pub fn placeholder_png() -> Result<Vec<u8>, ScreenshotError> {
+    let pixels = [0x20, 0x20, 0x20].repeat(
+        usize::try_from(THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT).map_err(|_| ScreenshotError::TooBig)?,
+    );
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+/// Box-filters an RGBA image down (or nearest-neighbors it up) into the thumbnail frame
+fn scale_rgba(rgba: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (tw, th) = (u64::from(THUMBNAIL_WIDTH), u64::from(THUMBNAIL_HEIGHT));
+    let (w, h) = (u64::from(width), u64::from(height));
+    // Fit the whole image, keeping its aspect ratio
+    let (sw, sh) = if w * th > h * tw {
+        (tw, (h * tw / w).max(1))
+    } else {
+        ((w * th / h).max(1), th)
+    };
+    let (ox, oy) = ((tw - sw) / 2, (th - sh) / 2);
+    let mut out = vec![0u8; usize::try_from(tw * th * 4).unwrap_or(0)];
+    for y in 0..sh {
+        let (y0, y1) = (y * h / sh, ((y + 1) * h / sh).max(y * h / sh + 1));
+        for x in 0..sw {
+            let (x0, x1) = (x * w / sw, ((x + 1) * w / sw).max(x * w / sw + 1));
+            let mut sum = [0u64; 4];
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    let i = usize::try_from((sy * w + sx) * 4).unwrap_or(0);
+                    for (c, s) in sum.iter_mut().enumerate() {
+                        *s += u64::from(rgba[i + c]);
+                    }
+                }
+            }
+            let n = (y1 - y0) * (x1 - x0);
+            let o = usize::try_from(((oy + y) * tw + ox + x) * 4).unwrap_or(0);
+            for (c, s) in sum.iter().enumerate() {
+                out[o + c] = u8::try_from(s / n).unwrap_or(u8::MAX);
+            }
+        }
+    }
+    out
+}
+
+/// Writes `data` to a scratch file and stores it like an uploaded file
+async fn store_png(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    file_name: &str,
+    data: &[u8],
+    creator_id: Option<Uuid>,
+) -> Result<File, ScreenshotError> {
+    let temp_path = std::env::temp_dir().join(format!("{}-{file_name}", Uuid::new_v4()));
+    tokio::fs::write(&temp_path, data).await?;
+    let file = insert_new_file(
+        conn,
+        storage_root,
+        depth,
+        file_name,
+        &temp_path,
+        "",
+        chrono::Utc::now(),
+        creator_id,
+    )
+    .await;
+    tokio::fs::remove_file(&temp_path).await?;
+    Ok(file?)
+}
+
+/// Stores the image and thumbnail files for PNG `data`, reusing the files of an
+/// existing screenshot with the same contents. Returns the image and thumbnail file IDs.
+async fn store_screenshot_files(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    data: &[u8],
+    creator_id: Option<Uuid>,
+) -> Result<(Uuid, Uuid), ScreenshotError> {
+    png_dimensions(data)?;
+    let hash = StorageHandler::get_bytes_hash(data);
+    if let Some(existing) = Screenshot::get_by_hash(conn, &hash).await?
+        && let (Some(file_id), Some(thumbnail_file_id)) =
+            (existing.file_id, existing.thumbnail_file_id)
+    {
+        return Ok((file_id, thumbnail_file_id));
+    }
+    let thumbnail = make_thumbnail(data)?;
+    let file = store_png(
+        conn,
+        storage_root,
+        depth,
+        "screenshot.png",
+        data,
+        creator_id,
+    )
+    .await?;
+    let thumbnail = store_png(
+        conn,
+        storage_root,
+        depth,
+        "thumbnail.png",
+        &thumbnail,
+        creator_id,
+    )
+    .await?;
+    Ok((file.file_id, thumbnail.file_id))
+}
+
+/// Stores a new screenshot, or returns the existing one with the same image
+pub async fn store_screenshot(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    data: &[u8],
+    screenshot_id: Uuid,
+    creator_id: Option<Uuid>,
+) -> Result<Screenshot, ScreenshotError> {
+    png_dimensions(data)?;
+    if let Some(existing) =
+        Screenshot::get_by_hash(conn, &StorageHandler::get_bytes_hash(data)).await?
+    {
+        return Ok(existing);
+    }
+    let (file_id, thumbnail_file_id) =
+        store_screenshot_files(conn, storage_root, depth, data, creator_id).await?;
+    Ok(Screenshot::insert(
+        conn,
+        Screenshot {
+            screenshot_id,
+            file_id: Some(file_id),
+            thumbnail_file_id: Some(thumbnail_file_id),
+            created_on: chrono::Utc::now(),
+            creator_id,
+        },
+    )
+    .await?)
+}
+
+/// Makes a screenshot out of an already stored PNG file, adding its thumbnail
+pub async fn screenshot_for_file(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    file: &File,
+    creator_id: Option<Uuid>,
+) -> Result<Screenshot, ScreenshotError> {
+    if let Some(existing) = Screenshot::get_by_hash(conn, &file.file_hash).await? {
+        return Ok(existing);
+    }
+    let data = tokio::fs::read(Path::new(storage_root).join(&file.file_dest_path)).await?;
+    let thumbnail = make_thumbnail(&data)?;
+    let thumbnail = store_png(
+        conn,
+        storage_root,
+        depth,
+        "thumbnail.png",
+        &thumbnail,
+        creator_id,
+    )
+    .await?;
+    Ok(Screenshot::insert(
+        conn,
+        Screenshot {
+            screenshot_id: Uuid::new_v4(),
+            file_id: Some(file.file_id),
+            thumbnail_file_id: Some(thumbnail.file_id),
+            created_on: chrono::Utc::now(),
+            creator_id,
+        },
+    )
+    .await?)
+}
+
+/// Reads a screenshot's image, or its thumbnail
+pub async fn read_screenshot(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    screenshot: &Screenshot,
+    thumbnail: bool,
+) -> Result<Vec<u8>, ScreenshotError> {
+    let file_id = if thumbnail {
+        screenshot.thumbnail_file_id.or(screenshot.file_id)
+    } else {
+        screenshot.file_id
+    };
+    let Some(file_id) = file_id else {
+        // Not moved out of the database yet
+        return Screenshot::get_legacy_data(conn, screenshot.screenshot_id)
+            .await?
+            .ok_or(ScreenshotError::Missing(screenshot.screenshot_id));
+    };
+    let file = File::get_by_id(conn, file_id)
+        .await?
+        .ok_or(ScreenshotError::Missing(screenshot.screenshot_id))?;
+    Ok(tokio::fs::read(Path::new(storage_root).join(&file.file_dest_path)).await?)
+}
+
+/// Outcome of one `migrate_legacy_screenshots` batch
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LegacyMigration {
+    pub moved: usize,
+    pub skipped: usize,
+}
+
+/// Moves up to `limit` screenshots still stored in the database out into
+/// files, passing over the first `skip` of them. Images that aren't valid
+/// PNGs are left in place, logged, and counted as skipped.
+pub async fn migrate_legacy_screenshots(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    skip: i64,
+    limit: i64,
+) -> Result<LegacyMigration, ScreenshotError> {
+    let legacy = Screenshot::get_legacy_batch(conn, skip, limit).await?;
+    let mut outcome = LegacyMigration::default();
+    for (screenshot, data) in legacy {
+        match store_screenshot_files(conn, storage_root, depth, &data, screenshot.creator_id).await
+        {
+            Ok((file_id, thumbnail_file_id)) => {
+                Screenshot::set_files(conn, screenshot.screenshot_id, file_id, thumbnail_file_id)
+                    .await?;
+                outcome.moved += 1;
+            }
+            Err(
+                e @ (ScreenshotError::Png(_)
+                | ScreenshotError::Dimensions(..)
+                | ScreenshotError::ColorType(_)),
+            ) => {
+                tracing::warn!(
+                    "Screenshot {} is not a valid PNG, leaving it in place: {e}",
+                    screenshot.screenshot_id
+                );
+                outcome.skipped += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(width: u32, height: u32, color: png::ColorType, pixels: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(pixels).unwrap();
+        writer.finish().unwrap();
+        out
+    }
+
+    #[test]
+    fn validates_png() {
+        let image = encode(4, 2, png::ColorType::Rgb, &[255; 4 * 2 * 3]);
+        assert_eq!(png_dimensions(&image).unwrap(), (4, 2));
+        assert!(matches!(
+            png_dimensions(b"GIF89a not a png"),
+            Err(ScreenshotError::Png(_))
+        ));
+    }
+
+    #[test]
+    fn thumbnail_is_letterboxed() {
+        // A wide white image fills the width and is centered vertically
+        let image = encode(640, 240, png::ColorType::Rgb, &[255; 640 * 240 * 3]);
+        let thumbnail = make_thumbnail(&image).unwrap();
+        let decoder = png::Decoder::new(std::io::Cursor::new(&thumbnail));
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let frame = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(
+            (frame.width, frame.height),
+            (THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT)
+        );
+        let pixel = |x: u32, y: u32| {
+            let i = ((y * THUMBNAIL_WIDTH + x) * 4) as usize;
+            &pixels[i..i + 4]
+        };
+        // 160x60 image area, 30 rows of padding above and below
+        assert_eq!(pixel(0, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(0, 30), [255, 255, 255, 255]);
+        assert_eq!(pixel(159, 89), [255, 255, 255, 255]);
+        assert_eq!(pixel(80, 90), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn thumbnail_of_grayscale() {
+        let image = encode(2, 2, png::ColorType::Grayscale, &[0, 255, 255, 0]);
+        assert!(make_thumbnail(&image).is_ok());
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
     error,
     models::{
-        Creator, CreatorReplayInfo, CreatorSaveInfo, CreatorStateInfo, Instance, InstanceWork,
-        Replay, Save, State,
+        Creator, CreatorReplayInfo, CreatorSaveInfo, CreatorStateInfo, DiskEntryInfo, Instance,
+        InstanceWork, Replay, Save, State,
     },
 };
 use meilisearch_sdk::client::Client as Meili;
@@ -11,6 +11,28 @@ use sqlx::postgres::PgConnection;
 
 const CHUNK_SIZE: usize = 10000;
 
+/// The search indices records are kept in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    Instance,
+    Save,
+    State,
+    Replay,
+    DiskEntry,
+}
+
+impl IndexKind {
+    fn name(self) -> &'static str {
+        match self {
+            IndexKind::Instance => "instance",
+            IndexKind::Save => "save",
+            IndexKind::State => "state",
+            IndexKind::Replay => "replay",
+            IndexKind::DiskEntry => "disk_entry",
+        }
+    }
+}
+
 pub trait SearchIndexer {
     type IndexOut: std::fmt::Debug;
     fn upsert_instance(
@@ -38,12 +60,88 @@ pub trait SearchIndexer {
         conn: &mut PgConnection,
         creator: &Creator,
     ) -> impl std::future::Future<Output = Result<Self::IndexOut, error::SearchIndex>> + Send;
+    /// Indexes the entries of every object backed by an image with this hash
+    fn upsert_disk_entries(
+        &self,
+        conn: &mut PgConnection,
+        file_hash: &str,
+    ) -> impl std::future::Future<Output = Result<Self::IndexOut, error::SearchIndex>> + Send;
+    /// Drops documents by their primary keys, e.g. once their records are deleted
+    fn remove_documents(
+        &self,
+        index: IndexKind,
+        ids: &[String],
+    ) -> impl std::future::Future<Output = Result<Self::IndexOut, error::SearchIndex>> + Send;
     fn reindex(
         &self,
         conn: &mut PgConnection,
     ) -> impl std::future::Future<Output = Vec<Result<Self::IndexOut, error::SearchIndex>>> + Send;
 }
 
+/// Indexer that does nothing, for writes that must only be indexed after
+/// their transaction commits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullIndexer;
+
+impl SearchIndexer for NullIndexer {
+    type IndexOut = ();
+    async fn upsert_instance(
+        &self,
+        _conn: &mut PgConnection,
+        _instance: &Instance,
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        Ok(())
+    }
+    async fn upsert_save(
+        &self,
+        _conn: &mut PgConnection,
+        _save: &Save,
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        Ok(())
+    }
+    async fn upsert_state(
+        &self,
+        _conn: &mut PgConnection,
+        _state: &State,
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        Ok(())
+    }
+    async fn upsert_replay(
+        &self,
+        _conn: &mut PgConnection,
+        _replay: &Replay,
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        Ok(())
+    }
+    async fn upsert_creator(
+        &self,
+        _conn: &mut PgConnection,
+        _creator: &Creator,
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        Ok(())
+    }
+    async fn upsert_disk_entries(
+        &self,
+        _conn: &mut PgConnection,
+        _file_hash: &str,
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        Ok(())
+    }
+    async fn remove_documents(
+        &self,
+        _index: IndexKind,
+        _ids: &[String],
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        Ok(())
+    }
+    async fn reindex(
+        &self,
+        _conn: &mut PgConnection,
+    ) -> Vec<Result<Self::IndexOut, error::SearchIndex>> {
+        vec![]
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MeiliIndexer {
     meili: Meili,
@@ -241,6 +339,7 @@ impl MeiliIndexer {
                 "creator_username",
                 "creator_full_name",
                 "replay_name",
+                "replay_duration",
                 "created_on",
             ])
             .await?;
@@ -268,6 +367,25 @@ impl MeiliIndexer {
             .await?;
         let mut creators = self.create_index("creator", "creator_id").await?;
         creators.set_primary_key("creator_id").await?;
+        let mut disk_entries = self
+            .create_index("disk_entry", "disk_entry_search_id")
+            .await?;
+        disk_entries.set_primary_key("disk_entry_search_id").await?;
+        disk_entries
+            .set_searchable_attributes(["entry_name", "entry_path", "image_filename", "work_names"])
+            .await?;
+        disk_entries
+            .set_filterable_attributes([
+                "work_platforms",
+                "instance_ids",
+                "object_id",
+                "entry_hash",
+                "entry_size",
+            ])
+            .await?;
+        disk_entries
+            .set_sortable_attributes(["entry_name", "entry_path", "entry_size"])
+            .await?;
         Ok(())
     }
 }
@@ -337,6 +455,30 @@ impl SearchIndexer for MeiliIndexer {
             .await
             .map_err(crate::error::SearchIndex::from)
     }
+
+    async fn upsert_disk_entries(
+        &self,
+        conn: &mut PgConnection,
+        file_hash: &str,
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        let infos = DiskEntryInfo::get_for_file_hash(conn, file_hash).await?;
+        self.meili
+            .index("disk_entry")
+            .add_or_update(&infos, Some("disk_entry_search_id"))
+            .await
+            .map_err(crate::error::SearchIndex::from)
+    }
+    async fn remove_documents(
+        &self,
+        index: IndexKind,
+        ids: &[String],
+    ) -> Result<Self::IndexOut, error::SearchIndex> {
+        self.meili
+            .index(index.name())
+            .delete_documents(ids)
+            .await
+            .map_err(crate::error::SearchIndex::from)
+    }
     async fn reindex(
         &self,
         conn: &mut PgConnection,
@@ -396,11 +538,22 @@ impl SearchIndexer for MeiliIndexer {
             })
             .collect()
             .await;
+        let disk_entries: Vec<_> = DiskEntryInfo::get_stream(conn)
+            .chunks(CHUNK_SIZE)
+            .then(async |chunk| {
+                let idx = self.meili.index("disk_entry");
+                idx.add_or_update(&(chunk), Some("disk_entry_search_id"))
+                    .await
+                    .map_err(crate::error::SearchIndex::from)
+            })
+            .collect()
+            .await;
         outputs.extend(instances);
         outputs.extend(saves);
         outputs.extend(states);
         outputs.extend(replays);
         outputs.extend(creators);
+        outputs.extend(disk_entries);
         outputs
     }
 }
@@ -452,4 +605,8 @@ impl MeiliSearch {
     pub fn creators(&self) -> meilisearch_sdk::indexes::Index {
         self.meili.index("creator")
     }
+    #[must_use]
+    pub fn disk_entries(&self) -> meilisearch_sdk::indexes::Index {
+        self.meili.index("disk_entry")
+    }
 }
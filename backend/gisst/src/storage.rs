@@ -24,6 +24,8 @@ pub struct PendingUpload {
     pub file_information: FileInformation,
     pub length: usize,
     pub offset: usize,
+    /// The claimed task a worker is uploading this file for, if any
+    pub task_id: Option<Uuid>,
 }
 
 #[derive(Clone, Debug)]
@@ -64,13 +66,24 @@ impl StorageHandler {
     // TODO fixme: this is synchronous but should probably be async.
     // But we don't have an async md5 hasher out there.
     pub fn get_file_hash(path: impl AsRef<Path>) -> Result<String, Storage> {
+        let mut file = std::fs::File::open(path)?;
+        Ok(Self::get_reader_hash(&mut file)?.1)
+    }
+
+    /// Length and hash of everything `reader` yields, read a piece at a time
+    pub fn get_reader_hash(reader: &mut impl std::io::Read) -> std::io::Result<(u64, String)> {
         use digest_io::IoWrapper;
         use md5::Digest;
         let mut hasher = IoWrapper(md5::Md5::new());
-        let mut file = std::fs::File::open(path)?;
-        std::io::copy(&mut file, &mut hasher)?;
+        let len = std::io::copy(reader, &mut hasher)?;
         let hash = hasher.0.finalize();
-        Ok(base16ct::lower::encode_string(&hash))
+        Ok((len, base16ct::lower::encode_string(&hash)))
+    }
+
+    #[must_use]
+    pub fn get_bytes_hash(data: &[u8]) -> String {
+        use md5::Digest;
+        base16ct::lower::encode_string(&md5::Md5::digest(data))
     }
 
     #[must_use]
@@ -92,6 +105,31 @@ impl StorageHandler {
         path
     }
 
+    /// Path of the gzipped copy stored alongside `path`, e.g. `a.txt` -> `a.txt.gz`
+    #[must_use]
+    pub fn get_gz_path(path: &Path) -> PathBuf {
+        if let Some(e) = path.extension().and_then(|e| e.to_str()) {
+            let mut s = e.to_string();
+            s.push_str(".gz");
+            path.with_extension(s)
+        } else {
+            path.with_extension("gz")
+        }
+    }
+
+    /// Returns the on-disk size of a stored file and of its gzipped copy, if there is one
+    pub fn get_stored_sizes(
+        root_path: &str,
+        dest_path: &str,
+    ) -> Result<(i64, Option<i64>), Storage> {
+        let path = Path::new(root_path).join(dest_path);
+        let file_size = i64::try_from(std::fs::metadata(&path)?.len())?;
+        let file_compressed_size = std::fs::metadata(Self::get_gz_path(&path))
+            .ok()
+            .and_then(|md| i64::try_from(md.len()).ok());
+        Ok((file_size, file_compressed_size))
+    }
+
     #[must_use]
     pub fn get_folder_depth_from_path(path: &Path, filename: Option<String>) -> u8 {
         let mut depth = 1;
@@ -118,13 +156,7 @@ impl StorageHandler {
             Path::new(root_path).join(Self::split_uuid_to_path_buf(uuid, folder_depth).as_path());
         path.push(dest_filename);
         info!("Deleting file at path: {}", path.to_string_lossy());
-        let gz_path = if let Some(e) = path.extension().and_then(|e| e.to_str()) {
-            let mut s = e.to_string();
-            s.push_str(".gz");
-            path.with_extension(s)
-        } else {
-            path.with_extension("gz")
-        };
+        let gz_path = Self::get_gz_path(&path);
         if let Err(err) = remove_file(&path).await {
             tracing::warn!("Error deleting file {path:?} with uuid {uuid}: {err:?}");
         }
@@ -280,13 +312,7 @@ impl StorageHandler {
         if let Some("chd" | "img" | "iso" | "bin") = ext {
             return Ok(None);
         }
-        let gz_path = if let Some(e) = ext {
-            let mut s = e.to_string();
-            s.push_str(".gz");
-            path.with_extension(s)
-        } else {
-            path.with_extension("gz")
-        };
+        let gz_path = Self::get_gz_path(path);
         let gz_file = File::create(&gz_path).await?;
         let mut gz_enc = async_compression::tokio::write::GzipEncoder::with_quality(
             gz_file,
@@ -0,0 +1,144 @@
+//! Promotes a file found inside a disk image object to an object of its own,
+//! so it can be cited directly. The new object remembers its parent object
+//! and the path it was extracted from.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::{Subobject, Table};
+use crate::fslist::{Filesystem, file_to_path, open_path, write_dir_zip};
+use crate::models::{Duplicate, File, Object, insert_file_object};
+use crate::storage::StorageHandler;
+use sqlx::Connection;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Extracts `path` (`partN/...`) from the image of object `parent_id` and
+/// stores it as a new object. Directories are stored as zip archives.
+#[tracing::instrument(skip(conn))]
+pub async fn promote_subobject(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    parent_id: Uuid,
+    path: &str,
+    object_description: Option<String>,
+    creator_id: Option<Uuid>,
+) -> Result<Object, Subobject> {
+    let parent = Object::get_by_id(conn, parent_id)
+        .await?
+        .ok_or(Subobject::Missing(Table::Object, parent_id))?;
+    let parent_file = File::get_by_id(conn, parent.file_id)
+        .await?
+        .ok_or(Subobject::Missing(Table::File, parent.file_id))?;
+    let path = path.trim_matches('/').to_string();
+    let mut file_name = Path::new(&path)
+        .file_name()
+        .ok_or_else(|| Subobject::Path(path.clone()))?
+        .to_string_lossy()
+        .into_owned();
+    let image_path = file_to_path(storage_root, &parent_file);
+    let temp_dir = std::env::temp_dir();
+    let (file_name, temp_path) = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || -> Result<_, Subobject> {
+            let (mut fs, subpath) = open_path(std::fs::File::open(&image_path)?, Path::new(&path))?;
+            let is_dir = fs.is_dir(&subpath)?;
+            if is_dir {
+                file_name.push_str(".zip");
+            }
+            let temp_path = temp_dir.join(format!("{}-{file_name}", Uuid::new_v4()));
+            extract(fs.as_mut(), &subpath, is_dir, &temp_path).inspect_err(|_| {
+                let _ = std::fs::remove_file(&temp_path);
+            })?;
+            Ok((file_name, temp_path))
+        })
+        .await??
+    };
+
+    let mut tx = conn.begin().await?;
+    let mut written = None;
+    let stored = store(
+        &mut tx,
+        storage_root,
+        depth,
+        &temp_path,
+        file_name,
+        object_description.or_else(|| Some(format!("{path} from {}", parent_file.file_filename))),
+        format!("{}/{path}", parent_file.file_filename),
+        creator_id,
+        &mut written,
+    )
+    .await;
+    if let Err(e) = tokio::fs::remove_file(&temp_path).await {
+        tracing::warn!("Could not remove temporary file {temp_path:?}: {e:?}");
+    }
+    let result = async {
+        let object = Object::set_parent(&mut tx, stored?, parent_id, &path).await?;
+        tx.commit().await?;
+        Ok::<_, Subobject>(object)
+    }
+    .await;
+    if result.is_err()
+        && let Some((file_id, dest_filename)) = written
+        && let Err(e) =
+            StorageHandler::delete_file_with_uuid(storage_root, depth, file_id, &dest_filename)
+                .await
+    {
+        tracing::error!("Could not remove subobject file {file_id}: {e:?}");
+    }
+    result
+}
+
+/// Writes the file at `subpath` to `temp_path`, zipping it if it's a directory
+fn extract(
+    fs: &mut dyn Filesystem,
+    subpath: &Path,
+    is_dir: bool,
+    temp_path: &Path,
+) -> Result<(), Subobject> {
+    let mut out = std::fs::File::create(temp_path)?;
+    if is_dir {
+        write_dir_zip(fs, subpath, out)?;
+    } else {
+        std::io::copy(&mut fs.open_file(subpath)?, &mut out)?;
+    }
+    Ok(())
+}
+
+/// Stores the extracted file as a new object, noting the stored file in
+/// `written` so it can be removed if the transaction doesn't go through
+#[allow(clippy::too_many_arguments)]
+async fn store(
+    conn: &mut sqlx::PgConnection,
+    storage_root: &str,
+    depth: u8,
+    temp_path: &Path,
+    file_name: String,
+    object_description: Option<String>,
+    file_source_path: String,
+    creator_id: Option<Uuid>,
+    written: &mut Option<(Uuid, String)>,
+) -> Result<Uuid, Subobject> {
+    let object_id = insert_file_object(
+        conn,
+        storage_root,
+        depth,
+        temp_path,
+        Some(file_name),
+        object_description,
+        file_source_path,
+        // Always a new object, since its provenance is its own
+        Duplicate::ReuseData,
+        creator_id,
+    )
+    .await?;
+    let object = Object::get_by_id(conn, object_id)
+        .await?
+        .ok_or(Subobject::Missing(Table::Object, object_id))?;
+    let file = File::get_by_id(conn, object.file_id)
+        .await?
+        .ok_or(Subobject::Missing(Table::File, object.file_id))?;
+    if let Some(dest_filename) = Path::new(&file.file_dest_path).file_name() {
+        *written = Some((file.file_id, dest_filename.to_string_lossy().into_owned()));
+    }
+    Ok(object_id)
+}
@@ -1,3 +1,9 @@
+#![allow(clippy::missing_errors_doc)]
+
+use crate::artifact::{ARTIFACTS_KEY, Artifact, ArtifactLink};
+use crate::error::Table;
+use crate::models::{File, Replay, Screenshot, State, Video};
+use crate::search::{NullIndexer, SearchIndexer};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::Connection;
@@ -13,6 +19,48 @@ pub enum TimeoutTaskError {
     Sqlx(#[from] sqlx::Error),
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum TaskArtifactError {
+    #[error("database error")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("insert error")]
+    Insert(#[from] crate::error::Insert),
+    #[error("search index error")]
+    SearchIndex(#[from] crate::error::SearchIndex),
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("malformed artifact list")]
+    Malformed(serde_json::Error),
+    #[error("{} record with uuid {} is missing", .table, .uuid)]
+    RecordMissing { table: Table, uuid: Uuid },
+    #[error("file {0} was not uploaded for this task")]
+    ForeignFile(Uuid),
+    #[error("task input does not refer to {} record {}", .table, .uuid)]
+    ForeignRecord { table: Table, uuid: Uuid },
+    #[error("task input has no creator_id to own its artifacts")]
+    NoCreator,
+    #[error("artifact can't be linked to {}.{}", .table, .field)]
+    UnsupportedLink { table: String, field: String },
+    #[error("screenshot error")]
+    Screenshot(#[from] crate::error::Screenshot),
+}
+
+/// Records created from a completed task's artifacts, to be indexed once committed
+enum CreatedArtifact {
+    Video(Uuid, Option<Replay>),
+    Screenshot(Uuid, Option<State>),
+    State(State),
+}
+
+impl CreatedArtifact {
+    fn record_id(&self) -> Uuid {
+        match self {
+            Self::Video(id, _) | Self::Screenshot(id, _) => *id,
+            Self::State(state) => state.state_id,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, sqlx::Type, PartialEq, Eq)]
 #[sqlx(rename_all = "lowercase", type_name = "task_state")]
 #[serde(rename_all = "lowercase")]
@@ -68,9 +116,55 @@ pub struct Task {
     pub task_output: sqlx::types::JsonValue,
 }
 
-const TASK_RETRY_LIMIT: i32 = 5;
+pub const TASK_RETRY_LIMIT: i32 = 5;
+
+/// Clones a v86 instance from one of its states, see `gisst::v86clone`
+pub const V86_CLONE_TASK: &str = "v86_clone";
+
+/// Turns a replay's checkpoints into states, see `gisst::replaystates`
+pub const REPLAY_CHECKPOINTS_TASK: &str = "replay_checkpoints";
 
 impl Task {
+    pub async fn create(
+        conn: &mut PgConnection,
+        task_type: &str,
+        task_input: sqlx::types::JsonValue,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as!(
+            Self,
+            r#"INSERT INTO task (task_type, task_input) VALUES ($1, $2)
+               RETURNING task_id, task_created_on, task_retry_count,
+                  task_type, task_claimant, task_claimed_on, task_updated_on,
+                  task_state as "task_state:_",
+                  task_status, task_last_status, task_input, task_output"#,
+            task_type,
+            task_input
+        )
+        .fetch_one(conn)
+        .await
+    }
+    /// Records that `file_id` was uploaded by this task's worker, so only
+    /// this task may claim it as an artifact
+    pub async fn record_upload(
+        conn: &mut PgConnection,
+        task_id: Uuid,
+        file_id: Uuid,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO task_upload (file_id, task_id) VALUES ($1, $2)",
+            file_id,
+            task_id
+        )
+        .execute(conn)
+        .await
+        .map(|_qr| ())
+    }
+    /// Whether the task will never be picked up again
+    #[must_use]
+    pub fn is_failed(&self) -> bool {
+        self.task_state == TaskState::Cancel
+            || (self.task_state == TaskState::Error && self.task_retry_count >= TASK_RETRY_LIMIT)
+    }
     pub async fn get_by_id(conn: &mut PgConnection, id: Uuid) -> sqlx::Result<Option<Self>> {
         sqlx::query_as!(
             Self,
@@ -225,21 +319,237 @@ impl Task {
         .await
         .map(|_qr| ())
     }
+    /// Marks the task done with `result` as its output. Any artifacts the
+    /// result declares (see `gisst::artifact`) are created and linked in the
+    /// same transaction, and their new record IDs are added to the output.
     pub async fn complete(
         conn: &mut PgConnection,
-        task_id: Uuid,
-        result: sqlx::types::JsonValue,
-    ) -> sqlx::Result<()> {
+        task: &Task,
+        mut result: sqlx::types::JsonValue,
+        storage_root: &str,
+        depth: u8,
+        indexer: &impl SearchIndexer,
+    ) -> Result<(), TaskArtifactError> {
+        let artifacts: Vec<Artifact> = match result.get(ARTIFACTS_KEY) {
+            Some(declared) => {
+                serde_json::from_value(declared.clone()).map_err(TaskArtifactError::Malformed)?
+            }
+            None => vec![],
+        };
+        let mut tx = conn.begin().await?;
+        let mut created = Vec::with_capacity(artifacts.len());
+        for artifact in &artifacts {
+            created.push(
+                task.create_artifact(&mut tx, artifact, storage_root, depth)
+                    .await?,
+            );
+        }
+        if let Some(declared) = result.get_mut(ARTIFACTS_KEY).and_then(|a| a.as_array_mut()) {
+            for (decl, record) in declared.iter_mut().zip(&created) {
+                decl["record_id"] = serde_json::json!(record.record_id());
+            }
+        }
         sqlx::query!(
             r#"UPDATE task
                SET task_output=$2, task_updated_on=current_timestamp, task_state='done'
                WHERE task_id=$1"#,
-            task_id,
+            task.task_id,
             result
         )
-        .execute(conn)
-        .await
-        .map(|_qr| ())
+        .execute(tx.as_mut())
+        .await?;
+        tx.commit().await?;
+        for record in &created {
+            match record {
+                CreatedArtifact::Video(_, Some(replay)) => {
+                    indexer.upsert_replay(conn, replay).await?;
+                }
+                CreatedArtifact::Screenshot(_, Some(state)) | CreatedArtifact::State(state) => {
+                    indexer.upsert_state(conn, state).await?;
+                }
+                CreatedArtifact::Video(_, None) | CreatedArtifact::Screenshot(_, None) => {}
+            }
+        }
+        Ok(())
+    }
+    async fn create_artifact(
+        &self,
+        conn: &mut PgConnection,
+        artifact: &Artifact,
+        storage_root: &str,
+        depth: u8,
+    ) -> Result<CreatedArtifact, TaskArtifactError> {
+        match artifact {
+            Artifact::Video { file_id, link } => {
+                if let Some(link) = link {
+                    self.check_link(link, Table::Replay, "video_id")?;
+                }
+                let file = self.uploaded_file(conn, *file_id).await?;
+                let path = std::path::Path::new(storage_root).join(&file.file_dest_path);
+                let video = Video::for_file(file.file_id, self.input_creator()?, path).await;
+                let video = Video::insert(conn, video).await?;
+                let replay = match link {
+                    Some(link) => {
+                        Replay::get_by_id(conn, link.id).await?.ok_or(
+                            TaskArtifactError::RecordMissing {
+                                table: Table::Replay,
+                                uuid: link.id,
+                            },
+                        )?;
+                        let replay =
+                            Replay::set_video(conn, link.id, video.video_id, &NullIndexer).await?;
+                        // Replays that already have checkpoints can give the video its poster
+                        Video::set_replay_poster(conn, link.id).await?;
+                        Some(replay)
+                    }
+                    None => None,
+                };
+                Ok(CreatedArtifact::Video(video.video_id, replay))
+            }
+            Artifact::Screenshot { file_id, link } => {
+                if let Some(link) = link {
+                    self.check_link(link, Table::State, "screenshot_id")?;
+                }
+                let screenshot = self
+                    .screenshot_from_file(conn, *file_id, storage_root, depth)
+                    .await?;
+                let state = match link {
+                    Some(link) => {
+                        State::get_by_id(conn, link.id).await?.ok_or(
+                            TaskArtifactError::RecordMissing {
+                                table: Table::State,
+                                uuid: link.id,
+                            },
+                        )?;
+                        Some(
+                            State::set_screenshot(
+                                conn,
+                                link.id,
+                                screenshot.screenshot_id,
+                                &NullIndexer,
+                            )
+                            .await?,
+                        )
+                    }
+                    None => None,
+                };
+                Ok(CreatedArtifact::Screenshot(screenshot.screenshot_id, state))
+            }
+            Artifact::State {
+                file_id,
+                screenshot_file_id,
+                instance_id,
+                name,
+                description,
+                replay_id,
+                replay_index,
+            } => {
+                self.check_input_ref(Table::Instance, *instance_id)?;
+                if let Some(replay_id) = replay_id {
+                    self.check_input_ref(Table::Replay, *replay_id)?;
+                }
+                let file = self.uploaded_file(conn, *file_id).await?;
+                let screenshot = self
+                    .screenshot_from_file(conn, *screenshot_file_id, storage_root, depth)
+                    .await?;
+                let state = State::insert(
+                    conn,
+                    State {
+                        state_id: Uuid::new_v4(),
+                        instance_id: *instance_id,
+                        is_checkpoint: replay_id.is_some(),
+                        file_id: file.file_id,
+                        state_name: name.clone(),
+                        state_description: description.clone(),
+                        screenshot_id: screenshot.screenshot_id,
+                        replay_id: *replay_id,
+                        creator_id: self.input_creator()?,
+                        state_replay_index: *replay_index,
+                        state_derived_from: None,
+                        save_derived_from: None,
+                        created_on: Utc::now(),
+                        hidden: false,
+                    },
+                    &NullIndexer,
+                )
+                .await?;
+                if let Some(replay_id) = replay_id {
+                    Video::set_replay_poster(conn, *replay_id).await?;
+                }
+                Ok(CreatedArtifact::State(state))
+            }
+        }
+    }
+    fn input_uuid(&self, key: &str) -> Option<Uuid> {
+        self.task_input.get(key)?.as_str()?.parse().ok()
+    }
+    /// Artifacts belong to the user the task was queued for
+    fn input_creator(&self) -> Result<Uuid, TaskArtifactError> {
+        self.input_uuid("creator_id")
+            .ok_or(TaskArtifactError::NoCreator)
+    }
+    /// A task may only touch records named in its input, e.g. `replay_id`
+    fn check_input_ref(&self, table: Table, uuid: Uuid) -> Result<(), TaskArtifactError> {
+        if self.input_uuid(&format!("{table}_id")) == Some(uuid) {
+            Ok(())
+        } else {
+            Err(TaskArtifactError::ForeignRecord { table, uuid })
+        }
+    }
+    fn check_link(
+        &self,
+        link: &ArtifactLink,
+        table: Table,
+        field: &str,
+    ) -> Result<(), TaskArtifactError> {
+        if link.table != table.to_string() || link.field != field {
+            return Err(TaskArtifactError::UnsupportedLink {
+                table: link.table.clone(),
+                field: link.field.clone(),
+            });
+        }
+        self.check_input_ref(table, link.id)
+    }
+    /// Artifact files must have been uploaded by this task's worker
+    async fn uploaded_file(
+        &self,
+        conn: &mut PgConnection,
+        file_id: Uuid,
+    ) -> Result<File, TaskArtifactError> {
+        let file =
+            File::get_by_id(conn, file_id)
+                .await?
+                .ok_or(TaskArtifactError::RecordMissing {
+                    table: Table::File,
+                    uuid: file_id,
+                })?;
+        let uploader = sqlx::query_scalar!(
+            "SELECT task_id FROM task_upload WHERE file_id = $1",
+            file_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if uploader != Some(self.task_id) {
+            return Err(TaskArtifactError::ForeignFile(file_id));
+        }
+        Ok(file)
+    }
+    async fn screenshot_from_file(
+        &self,
+        conn: &mut PgConnection,
+        file_id: Uuid,
+        storage_root: &str,
+        depth: u8,
+    ) -> Result<Screenshot, TaskArtifactError> {
+        let file = self.uploaded_file(conn, file_id).await?;
+        Ok(crate::screenshot::screenshot_for_file(
+            conn,
+            storage_root,
+            depth,
+            &file,
+            self.input_uuid("creator_id"),
+        )
+        .await?)
     }
     pub async fn error(
         conn: &mut PgConnection,
@@ -321,8 +631,8 @@ impl Task {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use sqlx::postgres::PgPool;
     use serde_json::json;
+    use sqlx::postgres::PgPool;
     #[sqlx::test(migrations = "../migrations/")]
     async fn sorting_filtering(pool: PgPool) -> sqlx::Result<()> {
         let mut conn = pool.acquire().await?;
@@ -386,7 +696,16 @@ mod tests {
                 .task_status,
             json!({"changed":1})
         );
-        Task::complete(conn.as_mut(), conntest_claim.task_id, json!({"finished":1})).await?;
+        Task::complete(
+            conn.as_mut(),
+            &conntest_claim,
+            json!({"finished":1}),
+            "",
+            4,
+            &NullIndexer,
+        )
+        .await
+        .unwrap();
         assert_eq!(
             Task::get_by_id(conn.as_mut(), conntest_claim.task_id)
                 .await?
@@ -419,6 +738,89 @@ mod tests {
         Ok(())
     }
     #[sqlx::test(migrations = "../migrations/")]
+    async fn creating(pool: PgPool) -> sqlx::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let task = Task::create(conn.as_mut(), "conntest", json!({"example":1})).await?;
+        assert_eq!(task.task_state, TaskState::Idle);
+        assert_eq!(task.task_retry_count, 0);
+        assert!(!task.is_failed());
+        let claimed = Task::claim_available(conn.as_mut(), Some("conntest"), "test")
+            .await?
+            .unwrap();
+        assert_eq!(claimed.task_id, task.task_id);
+        assert_eq!(claimed.task_input, json!({"example":1}));
+        Ok(())
+    }
+    #[sqlx::test(migrations = "../migrations/")]
+    async fn completing_with_artifacts(pool: PgPool) -> sqlx::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let replay_id = Uuid::new_v4();
+        Task::create(conn.as_mut(), "conntest", json!({"replay_id": replay_id})).await?;
+        let claimed = Task::claim_available(conn.as_mut(), Some("conntest"), "test")
+            .await?
+            .unwrap();
+        // Only records named in the task input may be linked to, even with
+        // a file this task did upload
+        let file = File::insert(
+            conn.as_mut(),
+            File {
+                file_id: Uuid::new_v4(),
+                file_hash: "video-hash".to_string(),
+                file_filename: "replay.mp4".to_string(),
+                file_source_path: String::new(),
+                file_dest_path: "video-hash-replay.mp4".to_string(),
+                file_size: 0,
+                created_on: Utc::now(),
+                file_compressed_size: None,
+                creator_id: None,
+            },
+        )
+        .await
+        .unwrap();
+        Task::record_upload(conn.as_mut(), claimed.task_id, file.file_id).await?;
+        let other_replay = Uuid::new_v4();
+        let output = json!({"artifacts": [{
+            "kind": "video", "file_id": file.file_id,
+            "link": {"table": "replay", "id": other_replay, "field": "video_id"}
+        }]});
+        assert!(matches!(
+            Task::complete(conn.as_mut(), &claimed, output, "", 4, &NullIndexer).await,
+            Err(TaskArtifactError::ForeignRecord { table: Table::Replay, uuid })
+                if uuid == other_replay
+        ));
+        let output = json!({"artifacts": [{"kind": "video"}]});
+        assert!(matches!(
+            Task::complete(conn.as_mut(), &claimed, output, "", 4, &NullIndexer).await,
+            Err(TaskArtifactError::Malformed(_))
+        ));
+        // Failed completions leave the task as it was
+        assert_eq!(
+            Task::get_by_id(conn.as_mut(), claimed.task_id)
+                .await?
+                .unwrap()
+                .task_state,
+            TaskState::Active
+        );
+        Task::complete(
+            conn.as_mut(),
+            &claimed,
+            json!({"artifacts": []}),
+            "",
+            4,
+            &NullIndexer,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            Task::get_by_id(conn.as_mut(), claimed.task_id)
+                .await?
+                .unwrap()
+                .task_state,
+            TaskState::Done
+        );
+        Ok(())
+    }
+    #[sqlx::test(migrations = "../migrations/")]
     async fn timeout_stale(pool: PgPool) -> sqlx::Result<()> {
         let mut conn = pool.acquire().await?;
         let t1id = Uuid::new_v4();
@@ -1,17 +1,37 @@
+use crate::error::V86Clone;
 use crate::model_enums::Framework;
 use crate::models::{Environment, File, Instance, Object, ObjectLink, ObjectRole, StateLink};
+use crate::search::NullIndexer;
 use crate::storage::StorageHandler;
-use crate::{
-    error::V86Clone,
-    models::{CoreFileLink, CoreFileRole},
-};
+use crate::v86state::{V86State, assign_disks};
 use log::{error, info};
-use sqlx::PgConnection;
-use std::path::Path;
+use serde::Serialize;
+use sqlx::{Connection, PgConnection};
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
-#[allow(clippy::too_many_lines, clippy::missing_errors_doc)]
-#[tracing::instrument(skip(conn, indexer))]
+/// Progress reports emitted while a clone runs, e.g. for task status updates
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum CloneProgress {
+    DumpingDisks,
+    StoringDisk {
+        drive: String,
+        index: usize,
+        total: usize,
+    },
+    Indexing,
+}
+
+/// A disk image written out by the dump, still sitting in its temporary folder
+#[derive(Debug)]
+struct DumpedDisk {
+    drive: String,
+    path: PathBuf,
+}
+
+#[allow(clippy::too_many_arguments, clippy::missing_errors_doc)]
+#[tracing::instrument(skip(conn, indexer, on_progress))]
 pub async fn clone_v86_machine(
     conn: &mut PgConnection,
     instance_id: Uuid,
@@ -20,14 +40,75 @@ pub async fn clone_v86_machine(
     depth: u8,
     indexer: &impl crate::search::SearchIndexer,
     creator_id: Option<Uuid>,
+    mut on_progress: impl FnMut(CloneProgress) + Send,
 ) -> Result<Uuid, V86Clone> {
     use crate::inc_metric;
-    use std::process::Command;
     inc_metric!(conn, v86_clones, 1);
     let instance = Instance::get_by_id(conn, instance_id)
         .await?
         .ok_or(V86Clone::InstanceNotFound(instance_id))?;
+    on_progress(CloneProgress::DumpingDisks);
+    let temp_folder = std::env::temp_dir().join(format!("v86clone-{}", Uuid::new_v4()));
+    let disks = dump_v86_disks(conn, &instance, state_id, storage_root, &temp_folder).await;
+
+    // Files written to storage so far, to be removed again if the clone fails
+    let mut written = vec![];
+    let result = match disks {
+        Ok(disks) => {
+            store_clone(
+                conn,
+                instance,
+                state_id,
+                &disks,
+                storage_root,
+                depth,
+                creator_id,
+                &mut written,
+                &mut on_progress,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    if temp_folder.exists()
+        && let Err(e) = tokio::fs::remove_dir_all(&temp_folder).await
+    {
+        error!("Could not remove clone temp folder {temp_folder:?}: {e:?}");
+    }
+    match result {
+        Ok(new_instance) => {
+            // Only index once the whole clone has committed
+            on_progress(CloneProgress::Indexing);
+            indexer.upsert_instance(conn, &new_instance).await?;
+            Ok(new_instance.instance_id)
+        }
+        Err(e) => {
+            for (file_uuid, dest_filename) in written {
+                StorageHandler::delete_file_with_uuid(
+                    storage_root,
+                    depth,
+                    file_uuid,
+                    &dest_filename,
+                )
+                .await?;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Drives whose contents a clone carries over, in the order v86 saves them
+const CLONED_DRIVES: [&str; 4] = ["fda", "fdb", "hda", "hdb"];
 
+/// Reconstructs the machine's disks as they are in the state, writing them to `out_folder`
+async fn dump_v86_disks(
+    conn: &mut PgConnection,
+    instance: &Instance,
+    state_id: Uuid,
+    storage_root: &str,
+    out_folder: &Path,
+) -> Result<Vec<DumpedDisk>, V86Clone> {
+    use crate::inc_metric;
     let env = Environment::get_by_id(conn, instance.environment_id)
         .await?
         .ok_or(V86Clone::EnvironmentNotFound(instance.environment_id))?;
@@ -37,62 +118,39 @@ pub async fn clone_v86_machine(
     let state = StateLink::get_by_id(conn, state_id)
         .await?
         .ok_or(V86Clone::StateNotFound(state_id))?;
-    if state.instance_id != instance_id {
+    if state.instance_id != instance.instance_id {
         return Err(V86Clone::WrongInstanceForState);
     }
-    let state_file_path = format!("{storage_root}/{}", state.file_dest_path);
-    let objects = ObjectLink::get_all_for_instance_id(conn, instance_id).await?;
-    let mut env_json = env
+    let state_file_path = PathBuf::from(format!("{storage_root}/{}", state.file_dest_path));
+    let objects = ObjectLink::get_all_for_instance_id(conn, instance.instance_id).await?;
+    let env_json = env
         .environment_config
         .ok_or(V86Clone::EnvironmentInvalid(instance.environment_id))?;
-    env_json["wasm_path"] = "v86.wasm".into();
-    let mut env_json = env_json.to_string();
-    for obj in &objects {
-        let file_path = format!("{storage_root}/{}", obj.file_dest_path);
-        if let ObjectRole::Content = obj.object_role {
-            let idx = obj.object_role_index;
-            env_json = env_json.replace(&format!("$CONTENT{idx}"), &file_path);
-            if idx == 0 {
-                env_json = env_json.replace("$CONTENT\"", &format!("{file_path}\""));
-            }
-        }
-    }
-    let mut libv86_js = None;
-    for link in CoreFileLink::get_all_for_core(
-        conn,
-        &env.environment_core_name,
-        &env.environment_core_version,
-    )
-    .await?
-    {
-        match link.core_role {
-            CoreFileRole::Entrypoint => {
-                if libv86_js.is_some() {
-                    tracing::warn!("Second entrypoint for v86 detected in core files!");
-                }
-                libv86_js = Some(format!("{storage_root}/{}", link.file_dest_path));
-            }
-            CoreFileRole::Dependency => {
-                let file_path = format!("{storage_root}/{}", link.file_dest_path);
-                env_json = env_json.replace(&link.file_filename, &file_path);
-            }
-            CoreFileRole::Config => {
-                // nop
-                tracing::warn!("Configs are not currently supported in v86 clone");
-            }
-        }
+    // Resolve each drive's $CONTENT placeholder to the image in storage
+    let mut drives = vec![];
+    for drive in CLONED_DRIVES {
+        let Some(url) = env_json[drive]["url"].as_str() else {
+            continue;
+        };
+        let path = objects
+            .iter()
+            .filter(|obj| matches!(obj.object_role, ObjectRole::Content))
+            .find(|obj| {
+                let idx = obj.object_role_index;
+                url == format!("$CONTENT{idx}") || (idx == 0 && url == "$CONTENT")
+            })
+            .map(|obj| PathBuf::from(format!("{storage_root}/{}", obj.file_dest_path)))
+            .ok_or_else(|| V86Clone::DiskNotFound(format!("{drive}: {url}")))?;
+        drives.push((drive.to_string(), path));
     }
-    info!("Input: {libv86_js:?}\n{env_json}\n{state_file_path}");
-    let libv86_js = libv86_js.ok_or(crate::error::V86Clone::EnvironmentInvalid(
-        env.environment_id,
-    ))?;
+    info!("Input: {drives:?}\n{state_file_path:?}");
     let now = std::time::Instant::now();
-    let proc_output = Command::new("node")
-        .arg("v86dump.js")
-        .arg(libv86_js)
-        .arg(env_json)
-        .arg(state_file_path)
-        .output()?;
+    let out_folder = out_folder.to_path_buf();
+    // Decoding and writing out big disks takes a while, keep it off the runtime
+    let disks = tokio::task::spawn_blocking(move || {
+        write_state_disks(&state_file_path, &drives, &out_folder)
+    })
+    .await??;
     let delta = now.elapsed().as_secs();
     if delta > 0 {
         // This is fine since delta is > 0 and smaller than 2^63
@@ -100,13 +158,65 @@ pub async fn clone_v86_machine(
         let delta = delta as i64;
         inc_metric!(conn, v86_clone_dump_time, delta);
     }
-    let err = String::from_utf8(proc_output.stderr)?;
-    info!("{err}");
-    let output = String::from_utf8(proc_output.stdout)?;
-    info!("Output {output}");
-    if !proc_output.status.success() {
-        return Err(V86Clone::V86Dump(err));
+    Ok(disks)
+}
+
+fn write_state_disks(
+    state_file_path: &Path,
+    drives: &[(String, PathBuf)],
+    out_folder: &Path,
+) -> Result<Vec<DumpedDisk>, V86Clone> {
+    let state = V86State::from_path(state_file_path)?;
+    let mut sizes = Vec::with_capacity(drives.len());
+    for (drive, path) in drives {
+        sizes.push((drive.clone(), std::fs::metadata(path)?.len()));
     }
+    let state_disks = state.disks();
+    // Only the configured drives are carried over, other buffers in the state
+    // (a CD-ROM, say) are left out
+    let assigned = assign_disks(&state, &state_disks, &sizes);
+    std::fs::create_dir_all(out_folder)?;
+    let mut dumped = Vec::with_capacity(drives.len());
+    for (drive, original) in drives {
+        let file_name = original
+            .file_name()
+            .ok_or_else(|| V86Clone::DiskNotFound(original.to_string_lossy().to_string()))?
+            .to_string_lossy()
+            .to_string();
+        // Stored files are named {hash}-{filename}, the copy gets a new hash
+        let file_name = file_name
+            .split_once('-')
+            .map_or(file_name.as_str(), |(_hash, name)| name)
+            .to_string();
+        let out_path = out_folder.join(&file_name);
+        if let Some((_, disk)) = assigned.iter().find(|(name, _)| name == drive) {
+            state.write_disk(disk, Some(original.as_path()), &out_path)?;
+        } else {
+            std::fs::copy(original, &out_path)?;
+        }
+        info!("{drive}:{out_path:?}");
+        dumped.push(DumpedDisk {
+            drive: drive.clone(),
+            path: out_path,
+        });
+    }
+    Ok(dumped)
+}
+
+/// Creates the new instance and its disk objects in a single transaction
+#[allow(clippy::too_many_arguments)]
+async fn store_clone(
+    conn: &mut PgConnection,
+    instance: Instance,
+    state_id: Uuid,
+    disks: &[DumpedDisk],
+    storage_root: &str,
+    depth: u8,
+    creator_id: Option<Uuid>,
+    written: &mut Vec<(Uuid, String)>,
+    on_progress: &mut (impl FnMut(CloneProgress) + Send),
+) -> Result<Instance, V86Clone> {
+    let mut tx = conn.begin().await?;
     // create the new instance
     let mut instance = instance;
     instance.created_on = chrono::Utc::now();
@@ -114,42 +224,27 @@ pub async fn clone_v86_machine(
     instance.derived_from_instance = Some(instance.instance_id);
     instance.derived_from_state = Some(state_id);
     instance.instance_id = Uuid::new_v4();
-    let new_id = instance.instance_id;
-    let mut temp_folder = None;
-    Instance::insert(conn, instance, indexer).await?;
+    let new_instance = Instance::insert(&mut tx, instance, &NullIndexer).await?;
     // add the requisite objects and link them
-    // TODO: the ? inside of this loop should get caught and I should delete the outFGSFDS/ folder either way after.
-    let mut content_index = 0;
-    for line in output.lines() {
-        if line.trim().is_empty() {
-            continue;
-        }
-        let (drive, diskpath) = match line.find(':').ok_or(V86Clone::V86Dump(
-            "Invalid output from v86dump:{line}".to_owned(),
-        )) {
-            Ok(split) => line.split_at(split + 1),
-            Err(err) => {
-                if let Some(temp) = temp_folder {
-                    std::fs::remove_dir_all(temp)?;
-                }
-                return Err(err);
-            }
-        };
-        temp_folder = Some(
-            Path::new(diskpath)
-                .parent()
-                .ok_or_else(|| V86Clone::DiskNotFound(diskpath.to_string()))?,
+    for (content_index, disk) in disks.iter().enumerate() {
+        on_progress(CloneProgress::StoringDisk {
+            drive: disk.drive.clone(),
+            index: content_index,
+            total: disks.len(),
+        });
+        let diskpath = &disk.path;
+        info!(
+            "Linking {}:{diskpath:?} as CONTENT{content_index}",
+            disk.drive
         );
-        info!("Linking {drive}{diskpath} as CONTENT{content_index}");
-        let file_name = Path::new(diskpath)
-            .to_path_buf()
+        let file_name = diskpath
             .file_name()
-            .ok_or_else(|| V86Clone::DiskNotFound(diskpath.to_string()))?
+            .ok_or_else(|| V86Clone::DiskNotFound(diskpath.to_string_lossy().to_string()))?
             .to_string_lossy()
             .to_string();
         let file_size =
             i64::try_from(std::fs::metadata(diskpath)?.len()).map_err(V86Clone::DiskTooBig)?;
-        let mut file_record = crate::models::File {
+        let mut file_record = File {
             file_id: Uuid::new_v4(),
             file_hash: StorageHandler::get_file_hash(diskpath)?,
             file_filename: file_name.clone(),
@@ -166,6 +261,8 @@ pub async fn clone_v86_machine(
             object_description: Some(file_name),
             created_on: chrono::Utc::now(),
             creator_id,
+            object_parent_id: None,
+            object_parent_path: None,
         };
         let file_info = StorageHandler::write_file_to_uuid_folder(
             storage_root,
@@ -175,49 +272,26 @@ pub async fn clone_v86_machine(
             diskpath,
         )
         .await?;
+        written.push((file_record.file_id, file_info.dest_filename.clone()));
         info!(
             "Wrote file {} to {}",
             file_info.dest_filename, file_info.dest_path
         );
         let obj_uuid = object.object_id;
-        let file_uuid = file_record.file_id;
         file_record.file_dest_path = file_info.dest_path;
-        let file_insert = File::insert(conn, file_record).await;
-        let obj_insert = Object::insert(conn, object).await;
-        if file_insert.as_ref().and(obj_insert.as_ref()).is_ok() {
-            if let Err(link_err) = Object::link_object_to_instance(
-                conn,
-                obj_uuid,
-                new_id,
-                ObjectRole::Content,
-                content_index,
-            )
-            .await
-            {
-                error!("Could not link object {link_err:?}");
-                if let Some(temp) = temp_folder {
-                    std::fs::remove_dir_all(temp)?;
-                }
-                return Err(link_err.into());
-            }
-            content_index += 1;
-        } else {
-            error!("Could not insert either file or object:\nf:{file_insert:?}\no:{obj_insert:?}");
-            StorageHandler::delete_file_with_uuid(
-                storage_root,
-                depth,
-                file_uuid,
-                &file_info.dest_filename,
-            )
-            .await?;
-            if let Some(temp) = temp_folder {
-                std::fs::remove_dir_all(temp)?;
-            }
-            return Err(V86Clone::IncompleteClone(new_id));
-        }
-    }
-    if let Some(temp) = temp_folder {
-        std::fs::remove_dir_all(temp)?;
+        file_record.file_compressed_size = file_info.file_compressed_size;
+        File::insert(&mut tx, file_record).await?;
+        Object::insert(&mut tx, object).await?;
+        Object::link_object_to_instance(
+            &mut tx,
+            obj_uuid,
+            new_instance.instance_id,
+            ObjectRole::Content,
+            u16::try_from(content_index)
+                .map_err(|_| V86Clone::IncompleteClone(new_instance.instance_id))?,
+        )
+        .await?;
     }
-    Ok(new_id)
+    tx.commit().await?;
+    Ok(new_instance)
 }
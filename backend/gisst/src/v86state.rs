@@ -0,0 +1,523 @@
+//! Decoder for v86 save states (`emulator.save_state()`).
+//!
+//! A state is a 16 byte header (magic, version, total length, info length as
+//! little-endian i32s), a JSON info block, and a block of binary buffers
+//! aligned to 4 bytes. The whole file may be zstd compressed, and so may
+//! individual buffers. Typed arrays in the JSON state tree are references into
+//! the buffer block of the form `{"__state_type__": ..., "buffer_id": n}`.
+//!
+//! Disks show up in the state tree in one of two shapes:
+//! - async (url-backed) buffers save only their written blocks as
+//!   `[[[index, <Uint8Array>], ...]]`, each block living at `index * 256`;
+//! - sync buffers save their whole contents as `[byte_length, <Uint8Array>]`.
+#![allow(clippy::missing_errors_doc)]
+
+use crate::error::V86State as V86StateError;
+use serde_json::Value;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const STATE_MAGIC: u32 = 0x8676_8676;
+pub const STATE_VERSION: i32 = 6;
+const STATE_INFO_BLOCK_START: usize = 16;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+/// Size of the blocks async disk buffers track writes in
+pub const OVERLAY_BLOCK_SIZE: u64 = 256;
+
+#[derive(Debug)]
+pub struct V86State {
+    pub version: i32,
+    /// The `state` tree from the info block
+    pub state: Value,
+    buffers: Vec<Vec<u8>>,
+}
+
+/// A disk's contents as recorded in a state
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiskState {
+    /// Written blocks on top of the original image: `(byte offset, buffer id)`
+    Overlay(Vec<(u64, usize)>),
+    /// The complete image, held in one buffer
+    Full(usize),
+}
+
+impl DiskState {
+    /// The smallest image size this disk state can belong to
+    #[must_use]
+    pub fn min_len(&self, state: &V86State) -> u64 {
+        match self {
+            Self::Overlay(blocks) => blocks
+                .iter()
+                .map(|(offset, id)| {
+                    offset.saturating_add(state.buffer(*id).map_or(0, |b| b.len() as u64))
+                })
+                .max()
+                .unwrap_or(0),
+            Self::Full(id) => state.buffer(*id).map_or(0, |b| b.len() as u64),
+        }
+    }
+}
+
+fn read_i32(data: &[u8], idx: usize) -> i32 {
+    let start = idx * 4;
+    i32::from_le_bytes([
+        data[start],
+        data[start + 1],
+        data[start + 2],
+        data[start + 3],
+    ])
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn decompress_if_zstd(data: &[u8]) -> Result<Option<Vec<u8>>, V86StateError> {
+    if data.starts_with(&ZSTD_MAGIC) {
+        Ok(Some(zstd::stream::decode_all(data)?))
+    } else {
+        Ok(None)
+    }
+}
+
+impl V86State {
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, V86StateError> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, V86StateError> {
+        if let Some(decompressed) = decompress_if_zstd(data)? {
+            return Self::parse(&decompressed);
+        }
+        Self::parse(data)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, V86StateError> {
+        if data.len() < STATE_INFO_BLOCK_START {
+            return Err(V86StateError::TooShort(data.len()));
+        }
+        #[allow(clippy::cast_sign_loss)]
+        let magic = read_i32(data, 0) as u32;
+        if magic != STATE_MAGIC {
+            return Err(V86StateError::BadMagic(magic));
+        }
+        let version = read_i32(data, 1);
+        if version != STATE_VERSION {
+            return Err(V86StateError::UnsupportedVersion(version));
+        }
+        let total_len = usize::try_from(read_i32(data, 2))?;
+        if total_len != data.len() {
+            return Err(V86StateError::Length {
+                expected: total_len,
+                actual: data.len(),
+            });
+        }
+        let info_len = usize::try_from(read_i32(data, 3))?;
+        let info_end = STATE_INFO_BLOCK_START + info_len;
+        let info_block = data
+            .get(STATE_INFO_BLOCK_START..info_end)
+            .ok_or(V86StateError::TooShort(data.len()))?;
+        let mut info: Value = serde_json::from_slice(info_block)?;
+        let buffer_block = data.get(align4(info_end)..).unwrap_or_default();
+
+        let mut buffers = vec![];
+        for (id, buffer_info) in info["buffer_infos"]
+            .as_array()
+            .ok_or(V86StateError::MissingField("buffer_infos"))?
+            .iter()
+            .enumerate()
+        {
+            let offset = buffer_info["offset"]
+                .as_u64()
+                .and_then(|o| usize::try_from(o).ok())
+                .ok_or(V86StateError::MissingField("offset"))?;
+            let length = buffer_info["length"]
+                .as_u64()
+                .and_then(|l| usize::try_from(l).ok())
+                .ok_or(V86StateError::MissingField("length"))?;
+            let raw = offset
+                .checked_add(length)
+                .and_then(|end| buffer_block.get(offset..end))
+                .ok_or(V86StateError::BufferOutOfRange(id))?;
+            buffers.push(decompress_if_zstd(raw)?.unwrap_or_else(|| raw.to_vec()));
+        }
+        let state = info
+            .get_mut("state")
+            .map(Value::take)
+            .ok_or(V86StateError::MissingField("state"))?;
+        Ok(Self {
+            version,
+            state,
+            buffers,
+        })
+    }
+
+    #[must_use]
+    pub fn buffer(&self, id: usize) -> Option<&[u8]> {
+        self.buffers.get(id).map(Vec::as_slice)
+    }
+
+    #[must_use]
+    pub fn buffer_count(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Every disk found in the state tree, in tree order
+    #[must_use]
+    pub fn disks(&self) -> Vec<DiskState> {
+        let mut disks = vec![];
+        self.collect_disks(&self.state, &mut disks);
+        disks
+    }
+
+    fn collect_disks(&self, node: &Value, disks: &mut Vec<DiskState>) {
+        match node {
+            Value::Array(items) => {
+                if let Some(disk) = self.as_disk(items) {
+                    disks.push(disk);
+                } else {
+                    for item in items {
+                        self.collect_disks(item, disks);
+                    }
+                }
+            }
+            Value::Object(fields) => {
+                for value in fields.values() {
+                    self.collect_disks(value, disks);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn as_disk(&self, items: &[Value]) -> Option<DiskState> {
+        match items {
+            // sync buffer: [byte_length, bytes]
+            [Value::Number(len), bytes] => {
+                let id = uint8_buffer_id(bytes)?;
+                (len.as_u64()? == self.buffer(id)?.len() as u64).then_some(DiskState::Full(id))
+            }
+            // async buffer: [[[index, block], ...]]
+            [Value::Array(blocks)] => {
+                let mut overlay = Vec::with_capacity(blocks.len());
+                for block in blocks {
+                    let [index, bytes] = block.as_array()?.as_slice() else {
+                        return None;
+                    };
+                    let id = uint8_buffer_id(bytes)?;
+                    self.buffer(id)?;
+                    overlay.push((index.as_u64()?.checked_mul(OVERLAY_BLOCK_SIZE)?, id));
+                }
+                // An empty list here is indistinguishable from other device
+                // state, so only non-empty overlays are reported
+                (!overlay.is_empty()).then_some(DiskState::Overlay(overlay))
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the disk as it is in this state to `out`, starting from `original`
+    /// for overlays.
+    pub fn write_disk(
+        &self,
+        disk: &DiskState,
+        original: Option<&Path>,
+        out: &Path,
+    ) -> Result<(), V86StateError> {
+        match disk {
+            DiskState::Full(id) => {
+                std::fs::write(
+                    out,
+                    self.buffer(*id).ok_or(V86StateError::MissingBuffer(*id))?,
+                )?;
+            }
+            DiskState::Overlay(blocks) => {
+                let original = original.ok_or(V86StateError::MissingOriginal)?;
+                std::fs::copy(original, out)?;
+                let mut file = std::fs::OpenOptions::new().write(true).open(out)?;
+                for (offset, id) in blocks {
+                    file.seek(SeekFrom::Start(*offset))?;
+                    file.write_all(self.buffer(*id).ok_or(V86StateError::MissingBuffer(*id))?)?;
+                }
+                file.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn uint8_buffer_id(value: &Value) -> Option<usize> {
+    // Older states name the constructor, newer ones index v86's constructor
+    // table, where Uint8Array comes first
+    let is_uint8 = match &value["__state_type__"] {
+        Value::String(name) => name == "Uint8Array",
+        Value::Number(idx) => idx.as_u64() == Some(0),
+        _ => false,
+    };
+    if !is_uint8 {
+        return None;
+    }
+    value["buffer_id"]
+        .as_u64()
+        .and_then(|id| usize::try_from(id).ok())
+}
+
+/// Pairs the machine's drives with the disks found in a state.
+///
+/// The state doesn't name its disks, so each drive gets a disk that fits
+/// inside its image: a full buffer must be exactly the image's size, an
+/// overlay can't write past its end. As many drives as possible get a disk,
+/// preferring earlier disks for earlier drives (`drives` should be ordered
+/// like v86 saves them, floppies before hard disks). Disks that fit no drive,
+/// like a CD-ROM or other devices' buffers, are left out, and so are drives
+/// the state has no disk for. `drives` holds `(name, image size)` pairs.
+#[must_use]
+pub fn assign_disks<'d>(
+    state: &V86State,
+    disks: &'d [DiskState],
+    drives: &[(String, u64)],
+) -> Vec<(String, &'d DiskState)> {
+    // Finds `disk` a drive, moving the disks on already taken drives
+    // elsewhere if that frees one up
+    fn place(
+        disk: usize,
+        fits: &[Vec<bool>],
+        seen: &mut [bool],
+        on_drive: &mut [Option<usize>],
+    ) -> bool {
+        for drive in 0..on_drive.len() {
+            if seen[drive] || !fits[disk][drive] {
+                continue;
+            }
+            seen[drive] = true;
+            if on_drive[drive].is_none_or(|other| place(other, fits, seen, on_drive)) {
+                on_drive[drive] = Some(disk);
+                return true;
+            }
+        }
+        false
+    }
+    let fits: Vec<Vec<bool>> = disks
+        .iter()
+        .map(|disk| {
+            drives
+                .iter()
+                .map(|(_, size)| match disk {
+                    DiskState::Full(id) => state.buffer(*id).map(|b| b.len() as u64) == Some(*size),
+                    DiskState::Overlay(_) => disk.min_len(state) <= *size,
+                })
+                .collect()
+        })
+        .collect();
+    let mut on_drive = vec![None; drives.len()];
+    for disk in 0..disks.len() {
+        place(disk, &fits, &mut vec![false; drives.len()], &mut on_drive);
+    }
+    drives
+        .iter()
+        .zip(on_drive)
+        .filter_map(|((name, _), disk)| Some((name.clone(), &disks[disk?])))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn build_raw(info: &Value, buffer_block: &[u8]) -> Vec<u8> {
+        let info = serde_json::to_vec(info).unwrap();
+        let buffer_start = align4(STATE_INFO_BLOCK_START + info.len());
+        let total = buffer_start + buffer_block.len();
+        let mut out = vec![0_u8; total];
+        #[allow(clippy::cast_possible_wrap)]
+        for (i, v) in [
+            STATE_MAGIC as i32,
+            STATE_VERSION,
+            i32::try_from(total).unwrap(),
+            i32::try_from(info.len()).unwrap(),
+        ]
+        .iter()
+        .enumerate()
+        {
+            out[i * 4..i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        out[STATE_INFO_BLOCK_START..STATE_INFO_BLOCK_START + info.len()].copy_from_slice(&info);
+        out[buffer_start..].copy_from_slice(buffer_block);
+        out
+    }
+
+    fn build_state(state: &Value, buffers: &[&[u8]]) -> Vec<u8> {
+        let mut infos = vec![];
+        let mut buffer_block = vec![];
+        for b in buffers {
+            infos.push(json!({"offset": buffer_block.len(), "length": b.len()}));
+            buffer_block.extend_from_slice(b);
+            buffer_block.resize(align4(buffer_block.len()), 0);
+        }
+        build_raw(
+            &json!({"buffer_infos": infos, "state": state}),
+            &buffer_block,
+        )
+    }
+
+    fn buf(id: usize) -> Value {
+        json!({"__state_type__": "Uint8Array", "buffer_id": id})
+    }
+
+    #[test]
+    fn parse_and_find_disks() {
+        let tree = json!([
+            640,
+            buf(0),
+            {"fdc": [[[[2, buf(1)], [4, buf(2)]]], 1]},
+            [[]],
+            [5, buf(3)],
+        ]);
+        let data = build_state(&tree, &[&[0; 7], &[1; 256], &[2; 256], &[3; 5]]);
+        let state = V86State::from_bytes(&data).unwrap();
+        assert_eq!(state.buffer_count(), 4);
+        assert_eq!(state.buffer(3), Some(&[3_u8; 5][..]));
+        let disks = state.disks();
+        assert_eq!(
+            disks,
+            vec![
+                DiskState::Overlay(vec![(512, 1), (1024, 2)]),
+                DiskState::Full(3)
+            ]
+        );
+        assert_eq!(disks[0].min_len(&state), 1280);
+    }
+
+    #[test]
+    fn zstd_state() {
+        let data = build_state(&json!([[[[0, buf(0)]]]]), &[&[9; 256]]);
+        let compressed = zstd::stream::encode_all(data.as_slice(), 3).unwrap();
+        let state = V86State::from_bytes(&compressed).unwrap();
+        assert_eq!(state.disks(), vec![DiskState::Overlay(vec![(0, 0)])]);
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(matches!(
+            V86State::from_bytes(&[0; 8]),
+            Err(V86StateError::TooShort(8))
+        ));
+        assert!(matches!(
+            V86State::from_bytes(&[0; 32]),
+            Err(V86StateError::BadMagic(0))
+        ));
+    }
+
+    #[test]
+    fn assignment_respects_sizes() {
+        let tree = json!([[[[10_000, buf(0)]]], [[[1, buf(1)]]]]);
+        let data = build_state(&tree, &[&[0; 256], &[1; 256]]);
+        let state = V86State::from_bytes(&data).unwrap();
+        let disks = state.disks();
+        let drives = vec![
+            ("fda".to_string(), 1_474_560),
+            ("hda".to_string(), 10_000_000),
+        ];
+        // The first disk writes past the end of a floppy, so it must be hda
+        let assigned = assign_disks(&state, &disks, &drives);
+        assert_eq!(
+            assigned,
+            vec![
+                ("fda".to_string(), &disks[1]),
+                ("hda".to_string(), &disks[0])
+            ]
+        );
+    }
+
+    #[test]
+    fn extra_buffers_are_left_out() {
+        // Shaped like a machine with a CD-ROM on the secondary IDE channel
+        // and a sync floppy, saved as v86's CPU state array: bare typed
+        // arrays for memory, `[len, bytes]` for sync buffers (the CD-ROM
+        // among them) and written blocks for the async hard disk
+        let tree = json!([
+            4096,
+            buf(0),
+            [0, 1, [2, buf(1)]],
+            [[0, [2048, buf(2)]], [1, [[[0, buf(3)], [3, buf(4)]]]]],
+            [[368_640, buf(5)], [[]]],
+        ]);
+        let data = build_state(
+            &tree,
+            &[
+                &[0; 4096],
+                &[1; 2],
+                &[2; 2048],
+                &[3; 256],
+                &[4; 256],
+                &[5; 368_640],
+            ],
+        );
+        let state = V86State::from_bytes(&data).unwrap();
+        let disks = state.disks();
+        assert_eq!(
+            disks,
+            vec![
+                DiskState::Full(1),
+                DiskState::Full(2),
+                DiskState::Overlay(vec![(0, 3), (768, 4)]),
+                DiskState::Full(5),
+            ]
+        );
+        let drives = vec![("fda".to_string(), 368_640), ("hda".to_string(), 1 << 20)];
+        assert_eq!(
+            assign_disks(&state, &disks, &drives),
+            vec![
+                ("fda".to_string(), &disks[3]),
+                ("hda".to_string(), &disks[2])
+            ]
+        );
+        // A drive the state has no disk for is left out
+        let drives = vec![
+            ("fda".to_string(), 368_640),
+            ("hda".to_string(), 1 << 20),
+            ("hdb".to_string(), 1 << 20),
+        ];
+        assert_eq!(
+            assign_disks(&state, &disks, &drives),
+            vec![
+                ("fda".to_string(), &disks[3]),
+                ("hda".to_string(), &disks[2])
+            ]
+        );
+    }
+
+    #[test]
+    fn buffer_offsets_overflowing() {
+        let info = json!({
+            "buffer_infos": [{"offset": u64::MAX, "length": 4}],
+            "state": [],
+        });
+        assert!(matches!(
+            V86State::from_bytes(&build_raw(&info, &[0; 4])),
+            Err(V86StateError::BufferOutOfRange(0))
+        ));
+    }
+
+    #[test]
+    fn apply_overlay() {
+        let tree = json!([[[[1, buf(0)]]]]);
+        let data = build_state(&tree, &[&[7; 256]]);
+        let state = V86State::from_bytes(&data).unwrap();
+        let dir = std::env::temp_dir().join(format!("v86state-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let original = dir.join("orig.img");
+        let out = dir.join("out.img");
+        std::fs::write(&original, vec![0_u8; 1024]).unwrap();
+        state
+            .write_disk(&state.disks()[0], Some(&original), &out)
+            .unwrap();
+        let written = std::fs::read(&out).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written.len(), 1024);
+        assert!(written[..256].iter().all(|b| *b == 0));
+        assert!(written[256..512].iter().all(|b| *b == 7));
+        assert!(written[512..].iter().all(|b| *b == 0));
+    }
+}
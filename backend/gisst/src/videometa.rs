@@ -0,0 +1,531 @@
+//! Container-level metadata for recorded videos.
+//!
+//! Only MP4 (ISO base media) and WebM/Matroska headers are read, which is
+//! enough for a player to lay out a video before fetching it. Nothing is
+//! decoded: the duration, resolution, codec and frame rate all come from
+//! the container's own bookkeeping.
+
+use crate::error::VideoMeta as VideoMetaError;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Largest header structure (`moov`, or Matroska `Info`/`Tracks`) read into memory
+const MAX_HEADER_LEN: u64 = 64 * 1024 * 1024;
+
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VideoMetadata {
+    /// In seconds
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Codec as the container names it, e.g. `avc1` or `V_VP9`
+    pub codec: Option<String>,
+    pub frame_rate: Option<f64>,
+}
+
+/// Reads the metadata of the video file at `path`
+///
+/// # Errors
+/// Fails if the file can't be read or isn't a well-formed video container
+pub fn probe_path(path: &Path) -> Result<VideoMetadata, VideoMetaError> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    probe(&mut file)
+}
+
+/// Reads video metadata from the start of `r`
+///
+/// # Errors
+/// Fails if reading fails or the data isn't a well-formed video container
+pub fn probe<R: Read + Seek>(r: &mut R) -> Result<VideoMetadata, VideoMetaError> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)
+        .map_err(|_| VideoMetaError::UnknownContainer)?;
+    r.seek(SeekFrom::Start(0))?;
+    if magic[..4] == EBML_MAGIC {
+        matroska::probe(r)
+    } else if &magic[4..] == b"ftyp" {
+        mp4::probe(r)
+    } else {
+        Err(VideoMetaError::UnknownContainer)
+    }
+}
+
+fn read_header_payload<R: Read>(r: &mut R, len: u64) -> Result<Vec<u8>, VideoMetaError> {
+    if len > MAX_HEADER_LEN {
+        return Err(VideoMetaError::HeaderTooLarge(len));
+    }
+    let mut buf = vec![0; usize::try_from(len).map_err(|_| VideoMetaError::HeaderTooLarge(len))?];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn be_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+fn be_at(data: &[u8], offset: usize, len: usize) -> Option<u64> {
+    data.get(offset..offset.checked_add(len)?).map(be_uint)
+}
+
+// Frame and tick counts are far below 2^52, so the float conversions are exact
+#[allow(clippy::cast_precision_loss)]
+fn ratio(num: u64, den: u64) -> Option<f64> {
+    (num > 0 && den > 0).then(|| num as f64 / den as f64)
+}
+
+mod mp4 {
+    use super::{VideoMetaError, VideoMetadata, be_at, ratio, read_header_payload};
+    use std::io::{Read, Seek, SeekFrom};
+
+    /// Iterates over the boxes packed in a buffer, yielding their types and payloads
+    struct Boxes<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Iterator for Boxes<'a> {
+        type Item = ([u8; 4], &'a [u8]);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let size = be_at(self.data, 0, 4)?;
+            let kind: [u8; 4] = self.data.get(4..8)?.try_into().ok()?;
+            let (size, header) = match size {
+                0 => (self.data.len() as u64, 8),
+                1 => (be_at(self.data, 8, 8)?, 16),
+                n => (n, 8),
+            };
+            let size = usize::try_from(size).ok()?;
+            if size < header || size > self.data.len() {
+                self.data = &[];
+                return None;
+            }
+            let payload = &self.data[header..size];
+            self.data = &self.data[size..];
+            Some((kind, payload))
+        }
+    }
+
+    fn child<'a>(data: &'a [u8], kind: [u8; 4]) -> Option<&'a [u8]> {
+        Boxes { data }.find(|(k, _)| *k == kind).map(|(_, p)| p)
+    }
+
+    /// Timescale and duration from an `mvhd` or `mdhd` payload
+    fn header_times(data: &[u8]) -> Option<(u64, u64)> {
+        match *data.first()? {
+            1 => Some((be_at(data, 20, 4)?, be_at(data, 24, 8)?)),
+            _ => Some((be_at(data, 12, 4)?, be_at(data, 16, 4)?)),
+        }
+    }
+
+    pub(super) fn probe<R: Read + Seek>(r: &mut R) -> Result<VideoMetadata, VideoMetaError> {
+        let len = r.seek(SeekFrom::End(0))?;
+        let mut pos = 0;
+        // moov may come after the media data, so walk the top level boxes
+        while len.saturating_sub(pos) >= 8 {
+            r.seek(SeekFrom::Start(pos))?;
+            let mut header = [0u8; 8];
+            r.read_exact(&mut header)?;
+            let mut header_len = 8;
+            let size = match be_at(&header, 0, 4).unwrap_or(0) {
+                0 => len - pos,
+                1 => {
+                    let mut large = [0u8; 8];
+                    r.read_exact(&mut large)?;
+                    header_len = 16;
+                    u64::from_be_bytes(large)
+                }
+                n => n,
+            };
+            if size < header_len {
+                return Err(VideoMetaError::Malformed("box size"));
+            }
+            if &header[4..] == b"moov" {
+                let moov = read_header_payload(r, size - header_len)?;
+                return Ok(parse_moov(&moov));
+            }
+            pos = pos
+                .checked_add(size)
+                .ok_or(VideoMetaError::Malformed("box size"))?;
+        }
+        Err(VideoMetaError::Malformed("no moov box"))
+    }
+
+    fn parse_moov(moov: &[u8]) -> VideoMetadata {
+        let mut meta = VideoMetadata::default();
+        if let Some((timescale, duration)) = child(moov, *b"mvhd").and_then(header_times) {
+            meta.duration = ratio(duration, timescale);
+        }
+        let Some(trak) = (Boxes { data: moov })
+            .filter(|(kind, _)| kind == b"trak")
+            .map(|(_, trak)| trak)
+            .find(|trak| {
+                child(trak, *b"mdia")
+                    .and_then(|mdia| child(mdia, *b"hdlr"))
+                    .and_then(|hdlr| hdlr.get(8..12))
+                    == Some(b"vide")
+            })
+        else {
+            return meta;
+        };
+        if let Some(tkhd) = child(trak, *b"tkhd")
+            && let Some(dims) = tkhd.len().checked_sub(8)
+        {
+            // 16.16 fixed point width and height end the track header
+            meta.width = be_at(tkhd, dims, 2).and_then(|w| u32::try_from(w).ok());
+            meta.height = be_at(tkhd, dims + 4, 2).and_then(|h| u32::try_from(h).ok());
+        }
+        let mdia = child(trak, *b"mdia");
+        let stbl = mdia
+            .and_then(|mdia| child(mdia, *b"minf"))
+            .and_then(|minf| child(minf, *b"stbl"));
+        if let Some(entries) = stbl
+            .and_then(|stbl| child(stbl, *b"stsd"))
+            .and_then(|stsd| stsd.get(8..))
+            && let Some((format, entry)) = (Boxes { data: entries }).next()
+        {
+            meta.codec = Some(String::from_utf8_lossy(&format).into_owned());
+            // Visual sample entries repeat the size, for files whose tkhd doesn't have it
+            if meta.width.is_none_or(|w| w == 0) {
+                meta.width = be_at(entry, 24, 2).and_then(|w| u32::try_from(w).ok());
+                meta.height = be_at(entry, 26, 2).and_then(|h| u32::try_from(h).ok());
+            }
+        }
+        if let Some((timescale, duration)) = mdia
+            .and_then(|mdia| child(mdia, *b"mdhd"))
+            .and_then(header_times)
+            && let Some(stts) = stbl.and_then(|stbl| child(stbl, *b"stts"))
+        {
+            let entries = be_at(stts, 4, 4).unwrap_or(0);
+            let (mut samples, mut ticks) = (0u64, 0u64);
+            for i in 0..usize::try_from(entries).unwrap_or(0) {
+                let (Some(count), Some(delta)) =
+                    (be_at(stts, 8 + i * 8, 4), be_at(stts, 12 + i * 8, 4))
+                else {
+                    break;
+                };
+                samples = samples.saturating_add(count);
+                ticks = ticks.saturating_add(count.saturating_mul(delta));
+            }
+            let ticks = if duration > 0 { duration } else { ticks };
+            meta.frame_rate = ratio(samples.saturating_mul(timescale), ticks);
+            if meta.duration.is_none() {
+                meta.duration = ratio(ticks, timescale);
+            }
+        }
+        meta
+    }
+}
+
+mod matroska {
+    use super::{VideoMetaError, VideoMetadata, be_uint, ratio, read_header_payload};
+    use std::io::{Read, Seek, SeekFrom};
+
+    const SEGMENT: u64 = 0x1853_8067;
+    const INFO: u64 = 0x1549_A966;
+    const TRACKS: u64 = 0x1654_AE6B;
+    const CLUSTER: u64 = 0x1F43_B675;
+    const TIMESTAMP_SCALE: u64 = 0x2A_D7B1;
+    const DURATION: u64 = 0x4489;
+    const TRACK_ENTRY: u64 = 0xAE;
+    const TRACK_TYPE: u64 = 0x83;
+    const CODEC_ID: u64 = 0x86;
+    const DEFAULT_DURATION: u64 = 0x23_E383;
+    const VIDEO: u64 = 0xE0;
+    const PIXEL_WIDTH: u64 = 0xB0;
+    const PIXEL_HEIGHT: u64 = 0xBA;
+
+    const VIDEO_TRACK: u64 = 1;
+    const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+    /// Decodes an EBML variable length integer, returning it and its length.
+    /// IDs keep their length marker bit; sizes of all ones mean "unknown".
+    fn vint(data: &[u8], is_id: bool) -> Option<(Option<u64>, usize)> {
+        let first = *data.first()?;
+        let len = first.leading_zeros() as usize + 1;
+        if len > 8 {
+            return None;
+        }
+        let bytes = data.get(..len)?;
+        if is_id {
+            return Some((Some(be_uint(bytes)), len));
+        }
+        let mask = (1u64 << (7 * len)) - 1;
+        let value = be_uint(bytes) & mask;
+        Some(((value != mask).then_some(value), len))
+    }
+
+    /// Iterates over the elements packed in a buffer, yielding their IDs and payloads
+    struct Elements<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Iterator for Elements<'a> {
+        type Item = (u64, &'a [u8]);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let (id, id_len) = vint(self.data, true)?;
+            let (size, size_len) = vint(self.data.get(id_len..)?, false)?;
+            let start = id_len + size_len;
+            let end = match size {
+                Some(size) => start.checked_add(usize::try_from(size).ok()?)?,
+                None => self.data.len(),
+            };
+            let Some(payload) = self.data.get(start..end) else {
+                self.data = &[];
+                return None;
+            };
+            self.data = &self.data[end..];
+            Some((id?, payload))
+        }
+    }
+
+    fn child(data: &[u8], id: u64) -> Option<&[u8]> {
+        Elements { data }.find(|(i, _)| *i == id).map(|(_, p)| p)
+    }
+
+    fn float(data: &[u8]) -> Option<f64> {
+        match data.len() {
+            4 => Some(f64::from(f32::from_be_bytes(data.try_into().ok()?))),
+            8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    fn read_vint<R: Read>(r: &mut R, is_id: bool) -> Result<Option<u64>, VideoMetaError> {
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf[..1])?;
+        let len = buf[0].leading_zeros() as usize + 1;
+        if len > 8 {
+            return Err(VideoMetaError::Malformed("element header"));
+        }
+        r.read_exact(&mut buf[1..len])?;
+        vint(&buf[..len], is_id)
+            .map(|(value, _)| value)
+            .ok_or(VideoMetaError::Malformed("element header"))
+    }
+
+    /// Reads an element ID and size from the stream
+    fn read_header<R: Read>(r: &mut R) -> Result<(u64, Option<u64>), VideoMetaError> {
+        let id = read_vint(r, true)?.ok_or(VideoMetaError::Malformed("element id"))?;
+        let size = read_vint(r, false)?;
+        Ok((id, size))
+    }
+
+    pub(super) fn probe<R: Read + Seek>(r: &mut R) -> Result<VideoMetadata, VideoMetaError> {
+        let end = r.seek(SeekFrom::End(0))?;
+        r.seek(SeekFrom::Start(0))?;
+        let segment_end = loop {
+            let (id, size) = read_header(r)?;
+            if id == SEGMENT {
+                let start = r.stream_position()?;
+                break size.map_or(end, |size| start.saturating_add(size).min(end));
+            }
+            let size = size.ok_or(VideoMetaError::Malformed("unsized top level element"))?;
+            r.seek(SeekFrom::Current(
+                i64::try_from(size).map_err(|_| VideoMetaError::Malformed("element size"))?,
+            ))?;
+        };
+        let (mut info, mut tracks) = (None, None);
+        while r.stream_position()? < segment_end && (info.is_none() || tracks.is_none()) {
+            let (id, size) = read_header(r)?;
+            match (id, size) {
+                // The headers we need come before the media, unless the file is broken
+                (CLUSTER, _) | (_, None) => break,
+                (INFO, Some(size)) => info = Some(read_header_payload(r, size)?),
+                (TRACKS, Some(size)) => tracks = Some(read_header_payload(r, size)?),
+                (_, Some(size)) => {
+                    r.seek(SeekFrom::Current(
+                        i64::try_from(size)
+                            .map_err(|_| VideoMetaError::Malformed("element size"))?,
+                    ))?;
+                }
+            }
+        }
+        let mut meta = VideoMetadata::default();
+        if let Some(info) = info {
+            let scale = child(&info, TIMESTAMP_SCALE).map_or(DEFAULT_TIMESTAMP_SCALE, be_uint);
+            // Scale is in nanoseconds per tick
+            #[allow(clippy::cast_precision_loss)]
+            let scale = scale as f64 / 1e9;
+            meta.duration = child(&info, DURATION)
+                .and_then(float)
+                .map(|ticks| ticks * scale);
+        }
+        let track = tracks.as_deref().and_then(|tracks| {
+            Elements { data: tracks }
+                .filter(|(id, _)| *id == TRACK_ENTRY)
+                .map(|(_, entry)| entry)
+                .find(|entry| child(entry, TRACK_TYPE).map(be_uint) == Some(VIDEO_TRACK))
+        });
+        if let Some(track) = track {
+            meta.codec = child(track, CODEC_ID).map(|codec| {
+                String::from_utf8_lossy(codec)
+                    .trim_end_matches('\0')
+                    .to_string()
+            });
+            meta.frame_rate = child(track, DEFAULT_DURATION)
+                .map(be_uint)
+                .and_then(|ns| ratio(1_000_000_000, ns));
+            if let Some(video) = child(track, VIDEO) {
+                meta.width = child(video, PIXEL_WIDTH).and_then(|w| u32::try_from(be_uint(w)).ok());
+                meta.height =
+                    child(video, PIXEL_HEIGHT).and_then(|h| u32::try_from(be_uint(h)).ok());
+            }
+        }
+        Ok(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut out = u32::try_from(payload.len() + 8)
+            .unwrap()
+            .to_be_bytes()
+            .to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// Version 0 `mvhd`/`mdhd` style payload
+    fn times(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut out = vec![0; 12];
+        out.extend_from_slice(&timescale.to_be_bytes());
+        out.extend_from_slice(&duration.to_be_bytes());
+        out.extend_from_slice(&[0; 8]);
+        out
+    }
+
+    #[test]
+    fn mp4() {
+        let mut tkhd = vec![0; 76];
+        tkhd.extend_from_slice(&(320u32 << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(240u32 << 16).to_be_bytes());
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0; 13]);
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend(mp4_box(b"avc1", &[0; 78]));
+        let mut stts = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stts.extend_from_slice(&60u32.to_be_bytes());
+        stts.extend_from_slice(&1u32.to_be_bytes());
+        let stbl = mp4_box(
+            b"stbl",
+            &[mp4_box(b"stsd", &stsd), mp4_box(b"stts", &stts)].concat(),
+        );
+        let mdia = [
+            mp4_box(b"mdhd", &times(30, 60)),
+            mp4_box(b"hdlr", &hdlr),
+            mp4_box(b"minf", &stbl),
+        ]
+        .concat();
+        let trak = [mp4_box(b"tkhd", &tkhd), mp4_box(b"mdia", &mdia)].concat();
+        let moov = [
+            mp4_box(b"mvhd", &times(1000, 2000)),
+            mp4_box(b"trak", &trak),
+        ]
+        .concat();
+        // moov after the media data, as unoptimized files have it
+        let file = [
+            mp4_box(b"ftyp", b"isom\0\0\0\0"),
+            mp4_box(b"mdat", &[0; 100]),
+            mp4_box(b"moov", &moov),
+        ]
+        .concat();
+        assert_eq!(
+            probe(&mut Cursor::new(file)).unwrap(),
+            VideoMetadata {
+                duration: Some(2.0),
+                width: Some(320),
+                height: Some(240),
+                codec: Some("avc1".to_string()),
+                frame_rate: Some(30.0),
+            }
+        );
+    }
+
+    #[test]
+    fn mp4_box_size_overflow() {
+        let mut file = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        // A 64 bit box size that runs past the end of any file
+        file.extend_from_slice(&1u32.to_be_bytes());
+        file.extend_from_slice(b"free");
+        file.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            probe(&mut Cursor::new(file)),
+            Err(VideoMetaError::Malformed("box size"))
+        ));
+    }
+
+    fn element(id: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        // 8 byte size, as muxers that patch sizes in afterwards write them
+        out.push(0x01);
+        out.extend_from_slice(&(payload.len() as u64).to_be_bytes()[1..]);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    #[test]
+    fn webm() {
+        let info = [
+            element(&[0x2A, 0xD7, 0xB1], &[0x0F, 0x42, 0x40]),
+            element(&[0x44, 0x89], &5000f32.to_be_bytes()),
+        ]
+        .concat();
+        let video = [
+            element(&[0xB0], &[0x02, 0x80]),
+            element(&[0xBA], &[0x01, 0xE0]),
+        ]
+        .concat();
+        let audio_track = [element(&[0x83], &[2]), element(&[0x86], b"A_OPUS")].concat();
+        let video_track = [
+            element(&[0x83], &[1]),
+            element(&[0x86], b"V_VP9"),
+            element(&[0x23, 0xE3, 0x83], &40_000_000u32.to_be_bytes()),
+            element(&[0xE0], &video),
+        ]
+        .concat();
+        let tracks = [
+            element(&[0xAE], &audio_track),
+            element(&[0xAE], &video_track),
+        ]
+        .concat();
+        let mut file = element(&[0x1A, 0x45, 0xDF, 0xA3], &element(&[0x42, 0x82], b"webm"));
+        // Live recordings leave the segment size unknown
+        file.extend_from_slice(&[
+            0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ]);
+        file.extend(element(&[0xEC], &[0; 16]));
+        file.extend(element(&[0x15, 0x49, 0xA9, 0x66], &info));
+        file.extend(element(&[0x16, 0x54, 0xAE, 0x6B], &tracks));
+        file.extend(element(&[0x1F, 0x43, 0xB6, 0x75], &[0; 32]));
+        assert_eq!(
+            probe(&mut Cursor::new(file)).unwrap(),
+            VideoMetadata {
+                duration: Some(5.0),
+                width: Some(640),
+                height: Some(480),
+                codec: Some("V_VP9".to_string()),
+                frame_rate: Some(25.0),
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_container() {
+        assert!(matches!(
+            probe(&mut Cursor::new(b"GIF89a\0\0\0\0".to_vec())),
+            Err(VideoMetaError::UnknownContainer)
+        ));
+        assert!(matches!(
+            probe(&mut Cursor::new(b"tiny".to_vec())),
+            Err(VideoMetaError::UnknownContainer)
+        ));
+    }
+}
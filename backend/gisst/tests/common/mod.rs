@@ -19,3 +19,7 @@ pub fn object_id() -> Uuid {
 pub fn instance_id() -> Uuid {
     Uuid::parse_str("00000000-0000-0000-0000-000000000040").unwrap()
 }
+#[allow(dead_code)]
+pub fn state_id() -> Uuid {
+    Uuid::parse_str("00000000-0000-0000-0000-000000000050").unwrap()
+}
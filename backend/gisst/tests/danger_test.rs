@@ -1,6 +1,8 @@
-use crate::common::{instance_id, object_id};
-use gisst::danger::{DestructiveEnvironment, DestructiveObject};
-use gisst::models::{Environment, Object};
+use crate::common::{file_id, instance_id, object_id, state_id};
+use gisst::danger::{Deletion, DestructiveEnvironment, DestructiveObject, RecordKind};
+use gisst::error::Delete;
+use gisst::models::{Environment, File, Instance, Object, ObjectLink, State};
+use gisst::search::NullIndexer;
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -151,3 +153,143 @@ async fn unlink_wrong_object_affects_zero_rows(pool: PgPool) -> sqlx::Result<()>
 
     Ok(())
 }
+
+// --------------------------------------------------------------------
+// Deletion
+// --------------------------------------------------------------------
+
+#[sqlx::test(
+    migrations = "../migrations/",
+    fixtures(
+        "core",
+        "environment",
+        "work",
+        "file",
+        "object",
+        "instance",
+        "instance_object",
+        "state"
+    )
+)]
+async fn delete_instance_requires_cascade_for_states(pool: PgPool) -> Result<(), Delete> {
+    let mut conn = pool.acquire().await?;
+    let storage_root = std::env::temp_dir().to_string_lossy().to_string();
+
+    let plan = Deletion::plan(&mut conn, RecordKind::Instance, instance_id()).await?;
+    assert_eq!(plan.dependents(), &[(RecordKind::State, state_id())]);
+    assert!(plan.needs_cascade());
+    // The object still uses the state's file
+    assert!(plan.files.is_empty());
+
+    let refused = plan
+        .apply(&mut conn, &storage_root, false, &NullIndexer)
+        .await;
+    assert!(matches!(refused, Err(Delete::Dependents(1))));
+    assert!(
+        Instance::get_by_id(&mut conn, instance_id())
+            .await?
+            .is_some()
+    );
+
+    let plan = Deletion::plan(&mut conn, RecordKind::Instance, instance_id()).await?;
+    plan.apply(&mut conn, &storage_root, true, &NullIndexer)
+        .await?;
+    assert!(
+        Instance::get_by_id(&mut conn, instance_id())
+            .await?
+            .is_none()
+    );
+    assert!(State::get_by_id(&mut conn, state_id()).await?.is_none());
+    assert!(Object::get_by_id(&mut conn, object_id()).await?.is_some());
+    Ok(())
+}
+
+#[sqlx::test(
+    migrations = "../migrations/",
+    fixtures(
+        "core",
+        "environment",
+        "work",
+        "file",
+        "object",
+        "instance",
+        "instance_object"
+    )
+)]
+async fn delete_linked_object_removes_link_and_file(pool: PgPool) -> Result<(), Delete> {
+    let mut conn = pool.acquire().await?;
+    let storage_root = std::env::temp_dir().to_string_lossy().to_string();
+
+    let plan = Deletion::plan(&mut conn, RecordKind::Object, object_id()).await?;
+    assert!(plan.dependents().is_empty());
+    assert_eq!(plan.links, vec![(instance_id(), object_id())]);
+    assert!(plan.needs_cascade());
+    assert_eq!(plan.files.len(), 1);
+
+    plan.apply(&mut conn, &storage_root, true, &NullIndexer)
+        .await?;
+    assert!(Object::get_by_id(&mut conn, object_id()).await?.is_none());
+    assert!(File::get_by_id(&mut conn, file_id()).await?.is_none());
+    assert!(
+        Instance::get_by_id(&mut conn, instance_id())
+            .await?
+            .is_some()
+    );
+    Ok(())
+}
+
+#[sqlx::test(
+    migrations = "../migrations/",
+    fixtures("core", "environment", "work", "file", "object", "instance")
+)]
+async fn delete_missing_record_is_an_error(pool: PgPool) -> Result<(), Delete> {
+    let mut conn = pool.acquire().await?;
+    let missing = Uuid::new_v4();
+
+    let result = Deletion::plan(&mut conn, RecordKind::State, missing).await;
+    assert!(matches!(result, Err(Delete::Missing(_, id)) if id == missing));
+    Ok(())
+}
+
+#[sqlx::test(
+    migrations = "../migrations/",
+    fixtures(
+        "core",
+        "environment",
+        "work",
+        "file",
+        "object",
+        "instance",
+        "instance_object"
+    )
+)]
+async fn plan_unlink_without_states_only_removes_link(pool: PgPool) -> Result<(), Delete> {
+    let mut conn = pool.acquire().await?;
+    let storage_root = std::env::temp_dir().to_string_lossy().to_string();
+
+    let plan = Deletion::plan_unlink(&mut conn, object_id(), instance_id()).await?;
+    assert!(!plan.needs_cascade());
+    assert!(plan.files.is_empty());
+
+    plan.apply(&mut conn, &storage_root, false, &NullIndexer)
+        .await?;
+    assert!(
+        ObjectLink::get_all_for_instance_id(&mut conn, instance_id())
+            .await?
+            .is_empty()
+    );
+    assert!(Object::get_by_id(&mut conn, object_id()).await?.is_some());
+    Ok(())
+}
+
+#[sqlx::test(
+    migrations = "../migrations/",
+    fixtures("core", "environment", "work", "file", "object", "instance")
+)]
+async fn plan_unlink_requires_a_link(pool: PgPool) -> Result<(), Delete> {
+    let mut conn = pool.acquire().await?;
+
+    let result = Deletion::plan_unlink(&mut conn, object_id(), instance_id()).await;
+    assert!(matches!(result, Err(Delete::NotLinked(..))));
+    Ok(())
+}
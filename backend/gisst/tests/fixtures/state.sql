@@ -0,0 +1,7 @@
+-- Depends on: file.sql, instance.sql
+INSERT INTO creator (creator_id, creator_username, creator_full_name) VALUES
+    ('00000000-0000-0000-0000-000000000060', 'test', 'Test Creator');
+INSERT INTO screenshot (screenshot_id, screenshot_data) VALUES
+    ('00000000-0000-0000-0000-000000000070', '\x00');
+INSERT INTO state (state_id, instance_id, is_checkpoint, file_id, state_name, state_description, screenshot_id, creator_id) VALUES
+    ('00000000-0000-0000-0000-000000000050', '00000000-0000-0000-0000-000000000040', false, '00000000-0000-0000-0000-000000000020', 'test state', 'a state on the test instance', '00000000-0000-0000-0000-000000000070', '00000000-0000-0000-0000-000000000060');
@@ -0,0 +1,6 @@
+ALTER TABLE video DROP COLUMN video_poster_id;
+ALTER TABLE video DROP COLUMN video_frame_rate;
+ALTER TABLE video DROP COLUMN video_codec;
+ALTER TABLE video DROP COLUMN video_height;
+ALTER TABLE video DROP COLUMN video_width;
+ALTER TABLE video DROP COLUMN video_duration;
@@ -0,0 +1,7 @@
+ALTER TABLE video ADD COLUMN video_duration double precision;
+ALTER TABLE video ADD COLUMN video_width integer;
+ALTER TABLE video ADD COLUMN video_height integer;
+ALTER TABLE video ADD COLUMN video_codec text;
+ALTER TABLE video ADD COLUMN video_frame_rate double precision;
+ALTER TABLE video ADD COLUMN video_poster_id uuid;
+ALTER TABLE video ADD FOREIGN KEY (video_poster_id) REFERENCES screenshot(screenshot_id);
@@ -0,0 +1,4 @@
+-- Screenshots stored as files have no screenshot_data to go back to, so the
+-- column stays nullable
+ALTER TABLE screenshot DROP COLUMN thumbnail_file_id;
+ALTER TABLE screenshot DROP COLUMN file_id;
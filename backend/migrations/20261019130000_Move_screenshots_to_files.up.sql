@@ -0,0 +1,5 @@
+ALTER TABLE screenshot ADD COLUMN file_id uuid;
+ALTER TABLE screenshot ADD FOREIGN KEY (file_id) REFERENCES file(file_id);
+ALTER TABLE screenshot ADD COLUMN thumbnail_file_id uuid;
+ALTER TABLE screenshot ADD FOREIGN KEY (thumbnail_file_id) REFERENCES file(file_id);
+ALTER TABLE screenshot ALTER COLUMN screenshot_data DROP NOT NULL;
@@ -0,0 +1,6 @@
+ALTER TABLE replay DROP COLUMN replay_content_crc;
+ALTER TABLE replay DROP COLUMN replay_identifier;
+ALTER TABLE replay DROP COLUMN replay_checkpoints;
+ALTER TABLE replay DROP COLUMN replay_checkpoint_count;
+ALTER TABLE replay DROP COLUMN replay_duration;
+ALTER TABLE replay DROP COLUMN replay_frame_count;
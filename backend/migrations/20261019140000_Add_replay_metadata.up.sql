@@ -0,0 +1,6 @@
+ALTER TABLE replay ADD COLUMN replay_frame_count bigint;
+ALTER TABLE replay ADD COLUMN replay_duration double precision;
+ALTER TABLE replay ADD COLUMN replay_checkpoint_count integer;
+ALTER TABLE replay ADD COLUMN replay_checkpoints jsonb;
+ALTER TABLE replay ADD COLUMN replay_identifier text;
+ALTER TABLE replay ADD COLUMN replay_content_crc bigint;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS file_listing (
+  file_hash text PRIMARY KEY,
+  listing_version integer NOT NULL,
+  is_listable boolean NOT NULL,
+  listing jsonb NOT NULL DEFAULT jsonb('[]'),
+  created_on timestamptz NOT NULL DEFAULT current_timestamp
+);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS disk_entry;
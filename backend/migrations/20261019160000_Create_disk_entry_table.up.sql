@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS disk_entry (
+  disk_entry_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  file_hash text NOT NULL,
+  entry_path text NOT NULL,
+  entry_name text NOT NULL,
+  entry_size bigint NOT NULL,
+  entry_hash text NOT NULL,
+  created_on timestamptz NOT NULL DEFAULT current_timestamp,
+  UNIQUE (file_hash, entry_path)
+);
+CREATE INDEX IF NOT EXISTS disk_entry_entry_hash_idx ON disk_entry (entry_hash);
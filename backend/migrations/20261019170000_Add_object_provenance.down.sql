@@ -0,0 +1,3 @@
+ALTER TABLE object DROP CONSTRAINT object_object_parent_id_fkey;
+ALTER TABLE object DROP COLUMN object_parent_path;
+ALTER TABLE object DROP COLUMN object_parent_id;
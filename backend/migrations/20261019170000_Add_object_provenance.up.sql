@@ -0,0 +1,3 @@
+ALTER TABLE object ADD COLUMN object_parent_id uuid;
+ALTER TABLE object ADD COLUMN object_parent_path text;
+ALTER TABLE object ADD FOREIGN KEY (object_parent_id) REFERENCES object(object_id) ON DELETE SET NULL;
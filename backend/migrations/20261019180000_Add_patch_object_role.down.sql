@@ -0,0 +1,7 @@
+-- Enum values can't be dropped, so rebuild the type without 'patch'
+DELETE FROM instanceObject WHERE object_role = 'patch';
+ALTER TYPE object_role RENAME TO object_role_old;
+CREATE TYPE object_role AS ENUM ('content', 'dependency', 'config');
+ALTER TABLE instanceObject
+    ALTER COLUMN object_role TYPE object_role USING object_role::text::object_role;
+DROP TYPE object_role_old;
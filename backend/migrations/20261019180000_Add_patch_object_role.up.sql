@@ -0,0 +1,2 @@
+-- Patch files (IPS/BPS/UPS) kept alongside the content they were applied to
+ALTER TYPE object_role ADD VALUE IF NOT EXISTS 'patch';
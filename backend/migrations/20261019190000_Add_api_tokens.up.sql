@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS api_tokens (
+    token_id uuid PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash text NOT NULL UNIQUE,
+    token_description text,
+    created_on timestamptz NOT NULL DEFAULT current_timestamp,
+    last_used_on timestamptz
+);
+CREATE INDEX IF NOT EXISTS api_tokens_user_id ON api_tokens(user_id);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS task_upload (
+    file_id uuid PRIMARY KEY REFERENCES file(file_id) ON DELETE CASCADE,
+    task_id uuid NOT NULL REFERENCES task(task_id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS task_upload_task_id ON task_upload(task_id);
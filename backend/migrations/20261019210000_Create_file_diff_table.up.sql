@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS file_diff (
+  old_hash text NOT NULL,
+  new_hash text NOT NULL,
+  listing_version integer NOT NULL,
+  is_diffable boolean NOT NULL,
+  changes jsonb NOT NULL DEFAULT jsonb('[]'),
+  created_on timestamptz NOT NULL DEFAULT current_timestamp,
+  PRIMARY KEY (old_hash, new_hash)
+);
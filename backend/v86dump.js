@@ -1,80 +0,0 @@
-#!/usr/bin/env node
-"use strict";
-
-var fs = require("fs");
-var path = require("path");
-
-var args = process.argv.slice(2);
-if (args.length != 3) {
-  console.error("Usage: libv86-js-path v86-json state-path");
-  process.exit(1);
-}
-var libv86_path = args[0];
-var emu_info = JSON.parse(args[1]);
-var state_path = args[2];
-
-const disks = ["fda", "hda"];
-const bios = ["bios", "vgabios"];
-
-function readfile(path) {
-    return new Uint8Array(fs.readFileSync(path)).buffer;
-}
-
-var V86 = require(libv86_path).V86;
-
-// console.log("Now booting, please stand by ...");
-// let state=readfile(__dirname+"/../storage/0/3/1/e/087bbdc6dbb84234b43ce0b562d8486c-state2.v86state");
-let state=readfile(state_path);
-var emulator = new V86(emu_info);
-
-function get_disk(emulator, disk) {
-  if (emulator.disk_images) {
-    return emulator.disk_images[disk];
-  }
-  let idecontroller = emulator.v86.cpu.devices.ide.primary;
-  if (!idecontroller) {
-    idecontroller = emulator.v86.cpu.devices.ide.secondary;
-  }
-  switch (disk) {
-  case "hda": return idecontroller.master.buffer;
-  case "hdb": return idecontroller.slave.buffer;
-  case "fda": return emulator.v86.cpu.devices.fdc.drives[0].buffer;
-  case "fdb": return emulator.v86.cpu.devices.fdc.drives[1].buffer;
-  case "cdrom": return emulator.v86.cpu.devices.cdrom.buffer;
-  }
-  return null;
-}
-
-emulator.add_listener("emulator-loaded", async function(){
-  // console.log("load state")
-  await emulator.stop();
-  await emulator.restore_state(state);
-  await emulator.run();
-  let out = fs.mkdtempSync("out");
-  for (let disk of disks) {
-    let buf = get_disk(emulator, disk);
-    if (buf == null) {
-      continue;
-    }
-    let in_path = emu_info[disk].url;
-    let filename = path.basename(in_path);
-    filename = filename.substring(filename.indexOf("-")+1);
-    let out_path = path.join(out,filename);
-    if (buf.block_cache) {
-      fs.copyFileSync(in_path, out_path);
-      let outfile = fs.openSync(out_path, 'r+');
-      for(let [index, block] of buf.block_cache) {
-        if(buf.block_cache_is_write.has(index)) {
-          fs.writeSync(outfile, block, 0, block.length, index*256)
-        }
-      }
-      fs.close(outfile);
-    } else {
-      let outfile = fs.openSync(out_path, 'w');
-      fs.writeSync(outfile, new Uint8Array(buf.buffer), 0, buf.byteLength, 0);
-      fs.close(outfile);
-    }
-    console.log(disk+":"+out_path);
-  }
-  process.exit(0);
-});
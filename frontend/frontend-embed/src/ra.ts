@@ -87,7 +87,8 @@ export async function init(gisst_root:string, environment:Environment, work:Work
         let fetch_manifest = `${gisst_root}/storage/\n`;
         /* TODO many of these awaits could be instead done simultaneously with Promise.all() */
         for(const file of manifest) {
-          if (file.object_role == "config") { continue; }
+          // RetroArch would apply patches next to the content a second time
+          if (file.object_role == "config" || file.object_role == "patch") { continue; }
           let sep = file.file_source_path.startsWith("/") ? "" : "/";
           let download_source_path_full = "/fetch/content" + sep + file.file_source_path;
           let download_source_path = download_source_path_full;
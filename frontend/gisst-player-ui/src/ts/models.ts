@@ -36,7 +36,8 @@ export interface Metadata {
 export enum ObjectRole {
     Content = "content",
     Dependency = "dependency",
-    Config = "config"
+    Config = "config",
+    Patch = "patch"
 }
 
 export interface PlayerStartTemplateInfo {
@@ -232,6 +233,9 @@ export interface Instance {
 }
 export interface Screenshot {
     screenshot_id: string,
+    file_id?: string,
+    thumbnail_file_id?: string,
+    // Inlined by the server when fetching a single screenshot as JSON
     screenshot_data: string
 }
 
@@ -244,6 +248,12 @@ export interface Video {
     file_id: string,
     creator_id: string,
     created_on: string, // a date
+    video_duration: number | null, // seconds
+    video_width: number | null,
+    video_height: number | null,
+    video_codec: string | null,
+    video_frame_rate: number | null,
+    video_poster_id: string | null, // a screenshot
 }
 
 export interface VideoCreateData {
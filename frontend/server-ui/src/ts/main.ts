@@ -50,6 +50,19 @@ export function search_replays(search_host:string, search_key:string, params:Sea
   });
 }
 
+function replay_details(duration:number|null, checkpoint_count:number|null):string {
+  const details = [];
+  if (duration != null) {
+    const minutes = Math.floor(duration / 60);
+    const seconds = Math.floor(duration % 60);
+    details.push(`${minutes}:${seconds.toString().padStart(2, "0")}`);
+  }
+  if (checkpoint_count != null) {
+    details.push(`${checkpoint_count} checkpoint${checkpoint_count == 1 ? "" : "s"}`);
+  }
+  return details.join(" • ");
+}
+
 // TODO: create wrappers for these four element types using a single div, string template, innerHTML like frontend-embed?
 
 class GISSTInstanceSearch extends HTMLElement {
@@ -230,7 +243,7 @@ class GISSTStateSearch extends HTMLElement {
            ${hit.hidden ? "gisst-Search-item-hidden" : ""}
            ${!show_instance_info ? "gisst-Search-no-instance":""}">
               <div class="gisst-Search-cell gisst-Search-screenshot-cell">
-                <img class="gisst-Search-screenshot" src="data:image/png;base64,${hit.screenshot_data}" alt="${hit.state_description} from instance ${hit.work_name}"/>
+                <img class="gisst-Search-screenshot" src="${base_url}/screenshots/${hit.screenshot_id}/thumbnail" alt="${hit.state_description} from instance ${hit.work_name}"/>
               </div>
               <div class="gisst-Search-cell gisst-Search-state-info">
                 <div class="gisst-Search-name">
@@ -584,6 +597,9 @@ class GISSTPerformanceSearch extends HTMLElement {
                 <div class="gisst-Search-cell gisst-Search-description">
                   ${components.Highlight({hit, attribute: "replay_description"})}
                 </div>
+                <div class="gisst-Search-replay-details">
+                  ${replay_details(hit.replay_duration, hit.replay_checkpoint_count)}
+                </div>
               </div>
               ${show_instance_info ? html`
                 <div class="gisst-Search-cell gisst-Search-instance-info">
@@ -698,10 +714,11 @@ interface Environment {
 }
 
 type Role = "content" | "dependency" | "config";
+type LinkRole = Role | "patch";
 
 interface ObjectLink {
   object_id: string,
-  object_role: Role,
+  object_role: LinkRole,
   object_role_index: number,
   file_hash: string,
   file_filename: string,
@@ -1256,6 +1273,8 @@ ${filename} <button type="button" class="move-up">^</button> <button type="butto
     this.clear_file_lists();
     full_instance.objects.sort((a:ObjectLink,b:ObjectLink) => (a.object_role_index - b.object_role_index));
     for (const lnk of full_instance.objects) {
+      // Patches were already applied to the content they came with
+      if (lnk.object_role == "patch") { continue; }
       this.add_to_file_list(lnk.object_role, lnk.file_filename, {existing:lnk.object_id});
     }
     this.environment = full_instance.environment;